@@ -6,7 +6,8 @@
 //! - Every start is a fresh start, so the data directory is removed and
 //!   initialized again on each run.
 //! - Next it will put configuration files into the `PGDATA` directory.
-//! - Sync safekeepers and get commit LSN.
+//! - Sync safekeepers and get commit LSN. Read-only replicas (`"mode": "replica"`
+//!   in the spec) skip this step, as they don't vote in safekeepers.
 //! - Get `basebackup` from pageserver using the returned on the previous step LSN.
 //! - Try to start `postgres` and wait until it is ready to accept connections.
 //! - Check and alter/drop/create roles and databases.
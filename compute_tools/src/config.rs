@@ -3,10 +3,17 @@ use std::io;
 use std::io::prelude::*;
 use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use urlencoding::encode;
 
-use crate::pg_helpers::PgOptionsSerialize;
-use crate::spec::ComputeSpec;
+use crate::pg_helpers::{GenericOption, GenericOptions, GenericOptionsSearch, PgOptionsSerialize};
+use crate::spec::{ComputeMode, ComputeSpec};
+
+/// Settings of the primary, which make no sense or are harmful on the read-only
+/// hot standby. Without `safekeepers` Postgres doesn't start the walproposer, so
+/// the replica never votes.
+const REPLICA_EXCLUDED_SETTINGS: &[&str] =
+    &["safekeepers", "synchronous_standby_names", "hot_standby"];
 
 /// Check that `line` is inside a text file and put it there if it is not.
 /// Create file if it doesn't exist.
@@ -36,11 +43,77 @@ pub fn write_postgres_conf(path: &Path, spec: &ComputeSpec) -> Result<()> {
     // File::create() destroys the file content if it exists.
     let mut postgres_conf = File::create(path)?;
 
-    write_auto_managed_block(&mut postgres_conf, &spec.cluster.settings.as_pg_settings())?;
+    let settings = match spec.mode {
+        ComputeMode::Primary => spec.cluster.settings.as_pg_settings(),
+        ComputeMode::Replica => {
+            replica_settings(&spec.cluster.settings, &spec.cluster.name)?.as_pg_settings()
+        }
+    };
+    write_auto_managed_block(&mut postgres_conf, &settings)?;
 
     Ok(())
 }
 
+/// Turn the cluster settings into the settings of a read-only hot standby,
+/// which streams WAL from safekeepers instead of proposing it. libpq tries
+/// the hosts in order, so the replica fails over to the next safekeeper on
+/// disconnect.
+pub fn replica_settings(settings: &GenericOptions, replica_name: &str) -> Result<GenericOptions> {
+    let safekeepers = settings
+        .find("safekeepers")
+        .context("safekeepers should be provided for the replica")?;
+    let tenant = settings
+        .find("neon.tenant_id")
+        .context("tenant id should be provided")?;
+    let timeline = settings
+        .find("neon.timeline_id")
+        .context("timeline id should be provided")?;
+
+    let mut ops: Vec<GenericOption> = settings
+        .iter()
+        .flatten()
+        .filter(|op| !REPLICA_EXCLUDED_SETTINGS.contains(&op.name.as_str()))
+        .cloned()
+        .collect();
+
+    ops.push(GenericOption {
+        name: "hot_standby".to_string(),
+        value: Some("on".to_string()),
+        vartype: "bool".to_string(),
+    });
+    ops.push(GenericOption {
+        name: "primary_conninfo".to_string(),
+        value: Some(replica_primary_conninfo(
+            &safekeepers,
+            &tenant,
+            &timeline,
+            replica_name,
+        )),
+        vartype: "string".to_string(),
+    });
+
+    Ok(Some(ops))
+}
+
+/// `primary_conninfo` of the replica streaming WAL from `safekeepers`, a
+/// comma separated list of `host:port`. Safekeepers take tenant and timeline
+/// from the startup options, which are percent-encoded to avoid spaces and
+/// keep the connection string parseable from postgresql.conf.
+pub fn replica_primary_conninfo(
+    safekeepers: &str,
+    tenant_id: &str,
+    timeline_id: &str,
+    replica_name: &str,
+) -> String {
+    format!(
+        "postgresql://{}/?options=-c%20ztimelineid%3D{}%20ztenantid%3D{}&application_name={}",
+        safekeepers,
+        timeline_id,
+        tenant_id,
+        encode(&format!("replica_{}", replica_name))
+    )
+}
+
 // Write Postgres config block wrapped with generated comment section
 fn write_auto_managed_block(file: &mut File, buf: &str) -> Result<()> {
     writeln!(file, "# Managed by compute_ctl: begin")?;
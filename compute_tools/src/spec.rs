@@ -21,6 +21,26 @@ pub struct ComputeSpec {
     /// Expected cluster state at the end of transition process.
     pub cluster: Cluster,
     pub delta_operations: Option<Vec<DeltaOp>>,
+    /// Whether compute runs as a primary or as a read-only hot standby.
+    #[serde(default)]
+    pub mode: ComputeMode,
+}
+
+/// Role of the compute node on its timeline.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ComputeMode {
+    /// Single writer, running the walproposer and voting in safekeepers.
+    Primary,
+    /// Read-only hot standby. It starts from the latest `last_record_lsn` of the
+    /// pageserver and follows the WAL streamed from safekeepers without voting.
+    Replica,
+}
+
+impl Default for ComputeMode {
+    fn default() -> Self {
+        ComputeMode::Primary
+    }
 }
 
 /// Cluster state seen from the perspective of the external tools
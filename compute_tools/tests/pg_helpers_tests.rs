@@ -3,6 +3,7 @@ mod pg_helpers_tests {
 
     use std::fs::File;
 
+    use compute_tools::config::{replica_primary_conninfo, replica_settings};
     use compute_tools::pg_helpers::*;
     use compute_tools::spec::ComputeSpec;
 
@@ -32,6 +33,35 @@ mod pg_helpers_tests {
         );
     }
 
+    #[test]
+    fn replica_settings_serialize() {
+        let file = File::open("tests/cluster_spec.json").unwrap();
+        let spec: ComputeSpec = serde_json::from_reader(file).unwrap();
+
+        let settings = replica_settings(&spec.cluster.settings, "test replica").unwrap();
+        assert_eq!(settings.find("safekeepers"), None);
+        assert_eq!(settings.find("synchronous_standby_names"), None);
+        assert_eq!(settings.find("hot_standby"), Some("on".to_string()));
+        assert_eq!(
+            settings.find("primary_conninfo"),
+            Some("postgresql://127.0.0.1:6502,127.0.0.1:6503,127.0.0.1:6501/?options=-c%20ztimelineid%3D2414a61ffc94e428f14b5758fe308e13%20ztenantid%3Db0554b632bd4d547a63b86c3630317e8&application_name=replica_test%20replica".to_string())
+        );
+        // the same control plane puts into the config of a local replica
+        assert_eq!(
+            settings.find("primary_conninfo"),
+            Some(replica_primary_conninfo(
+                "127.0.0.1:6502,127.0.0.1:6503,127.0.0.1:6501",
+                "b0554b632bd4d547a63b86c3630317e8",
+                "2414a61ffc94e428f14b5758fe308e13",
+                "test replica"
+            ))
+        );
+        assert_eq!(
+            settings.find("neon.pageserver_connstring"),
+            Some("host=127.0.0.1 port=6400".to_string())
+        );
+    }
+
     #[test]
     fn quote_ident() {
         let ident: PgIdent = PgIdent::from("\"name\";\\n select 1;");
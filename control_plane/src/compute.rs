@@ -11,6 +11,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
+use compute_tools::config::replica_primary_conninfo;
 use utils::{
     connstring::connection_host_port,
     lsn::Lsn,
@@ -81,7 +82,14 @@ impl ComputeControlPlane {
         timeline_id: ZTimelineId,
         lsn: Option<Lsn>,
         port: Option<u16>,
+        replica: bool,
     ) -> Result<Arc<PostgresNode>> {
+        if replica && lsn.is_some() {
+            anyhow::bail!("hot standby replica always follows the end of the timeline, cannot pin it to an LSN");
+        }
+        if replica && self.env.safekeepers.is_empty() {
+            anyhow::bail!("hot standby replica requires safekeepers to stream WAL from");
+        }
         let port = port.unwrap_or_else(|| self.get_port());
         let node = Arc::new(PostgresNode {
             name: name.to_owned(),
@@ -91,6 +99,7 @@ impl ComputeControlPlane {
             is_test: false,
             timeline_id,
             lsn,
+            replica,
             tenant_id,
             uses_wal_proposer: false,
         });
@@ -116,6 +125,8 @@ pub struct PostgresNode {
     is_test: bool,
     pub timeline_id: ZTimelineId,
     pub lsn: Option<Lsn>, // if it's a read-only node. None for primary
+    /// Hot standby, following the end of the timeline by streaming WAL from safekeepers
+    pub replica: bool,
     pub tenant_id: ZTenantId,
     uses_wal_proposer: bool,
 }
@@ -151,6 +162,7 @@ impl PostgresNode {
         let timeline_id: ZTimelineId = conf.parse_field("neon.timeline_id", &context)?;
         let tenant_id: ZTenantId = conf.parse_field("neon.tenant_id", &context)?;
         let uses_wal_proposer = conf.get("safekeepers").is_some();
+        let replica = conf.get("primary_conninfo").is_some();
 
         // parse recovery_target_lsn, if any
         let recovery_target_lsn: Option<Lsn> =
@@ -165,6 +177,7 @@ impl PostgresNode {
             is_test: false,
             timeline_id,
             lsn: recovery_target_lsn,
+            replica,
             tenant_id,
             uses_wal_proposer,
         })
@@ -301,7 +314,24 @@ impl PostgresNode {
             // Also note that not all parameters are supported here. Because in compute we substitute $ZENITH_AUTH_TOKEN
             // We parse this string and build it back with token from env var, and for simplicity rebuild
             // uses only needed variables namely host, port, user, password.
-            format!("postgresql://no_user:{}@{}:{}", password, host, port)
+            let connstr = format!("postgresql://no_user:{}@{}:{}", password, host, port);
+
+            // Read-only node at a fixed LSN opens its page stream as a snapshot: pageserver
+            // takes the LSN from the startup options and keeps GC from removing the data the
+            // node reads. The options don't survive the rebuild with the auth token though.
+            match self.lsn {
+                Some(lsn) if password.is_empty() => {
+                    format!("{}/?options=-c%20snapshot_lsn%3D{}", connstr, lsn)
+                }
+                Some(lsn) => {
+                    eprintln!(
+                        "WARNING: pageserver GC is not held back for node {} at {} with auth enabled",
+                        self.name, lsn
+                    );
+                    connstr
+                }
+                None => connstr,
+            }
         };
         conf.append("shared_preload_libraries", "neon");
         conf.append_line("");
@@ -330,7 +360,28 @@ impl PostgresNode {
         conf.append("max_replication_write_lag", "500MB");
         conf.append("max_replication_flush_lag", "10GB");
 
-        if !self.env.safekeepers.is_empty() {
+        if self.replica {
+            // Hot standby follows the WAL committed by the primary, streaming it
+            // from any of the safekeepers. It doesn't run the walproposer, so it
+            // never takes part in the consensus. libpq tries the hosts in order,
+            // so the replica fails over to the next safekeeper on disconnect.
+            let safekeepers = self
+                .env
+                .safekeepers
+                .iter()
+                .map(|sk| format!("localhost:{}", sk.pg_port))
+                .collect::<Vec<String>>()
+                .join(",");
+            // Same as compute_ctl sets up for the replica in the cloud.
+            let primary_conninfo = replica_primary_conninfo(
+                &safekeepers,
+                &self.tenant_id.to_string(),
+                &self.timeline_id.to_string(),
+                &self.name,
+            );
+            conf.append("primary_conninfo", &primary_conninfo);
+            conf.append("hot_standby_feedback", "on");
+        } else if !self.env.safekeepers.is_empty() {
             // Configure the node to connect to the safekeepers
             conf.append("synchronous_standby_names", "walproposer");
 
@@ -363,6 +414,10 @@ impl PostgresNode {
     fn load_basebackup(&self, auth_token: &Option<String>) -> Result<()> {
         let backup_lsn = if let Some(lsn) = self.lsn {
             Some(lsn)
+        } else if self.replica {
+            // Replica starts from the latest last_record_lsn of the pageserver
+            // and catches up with the rest of the WAL from safekeepers.
+            None
         } else if self.uses_wal_proposer {
             // LSN 0 means that it is bootstrap and we need to download just
             // latest data from the pageserver. That is a bit clumsy but whole bootstrap
@@ -459,7 +514,7 @@ impl PostgresNode {
         // 3. Load basebackup
         self.load_basebackup(auth_token)?;
 
-        if self.lsn.is_some() {
+        if self.lsn.is_some() || self.replica {
             File::create(self.pgdata().join("standby.signal"))?;
         }
 
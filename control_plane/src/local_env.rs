@@ -177,6 +177,7 @@ pub struct SafekeeperConf {
     pub sync: bool,
     pub remote_storage: Option<String>,
     pub backup_threads: Option<u32>,
+    pub wal_backup_compression: bool,
     pub auth_enabled: bool,
 }
 
@@ -189,6 +190,7 @@ impl Default for SafekeeperConf {
             sync: true,
             remote_storage: None,
             backup_threads: None,
+            wal_backup_compression: false,
             auth_enabled: false,
         }
     }
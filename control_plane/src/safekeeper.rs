@@ -16,6 +16,7 @@ use reqwest::{IntoUrl, Method};
 use safekeeper::http::models::TimelineCreateRequest;
 use thiserror::Error;
 use utils::{
+    auth::{Claims, Scope},
     connstring::connection_address,
     http::error::HttpErrorBody,
     zid::{NodeId, ZTenantId, ZTimelineId},
@@ -149,10 +150,19 @@ impl SafekeeperNode {
         if let Some(ref remote_storage) = self.conf.remote_storage {
             cmd.args(&["--remote-storage", remote_storage]);
         }
+        if self.conf.wal_backup_compression {
+            cmd.args(&["--wal-backup-compression", "true"]);
+        }
         if self.conf.auth_enabled {
             cmd.arg("--auth-validation-public-key-path");
             // PathBuf is better be passed as is, not via `String`.
             cmd.arg(self.env.base_data_dir.join("auth_public_key.pem"));
+            // for requests to peers
+            cmd.env(
+                "SAFEKEEPER_AUTH_TOKEN",
+                self.env
+                    .generate_auth_token(&Claims::new(None, Scope::PageServerApi))?,
+            );
         }
 
         fill_aws_secrets_vars(&mut cmd);
@@ -272,6 +272,37 @@ impl PageServerNode {
         bail!("pageserver failed to start in {} seconds", RETRIES);
     }
 
+    /// Downloads all timelines from the remote storage, configured for the pageserver, into its workdir.
+    /// The pageserver has to be stopped, the progress is printed into stdout.
+    pub fn bootstrap_from_remote(&self, config_overrides: &[&str]) -> anyhow::Result<()> {
+        if self.check_status().is_ok() {
+            bail!("Pageserver has to be stopped before bootstrapping it from the remote storage");
+        }
+        println!(
+            "Bootstrapping pageserver in '{}' from the remote storage",
+            self.repo_path().display()
+        );
+
+        let repo_path = self.repo_path();
+        let mut args = vec!["-D", repo_path.to_str().unwrap(), "--bootstrap-from-remote"];
+
+        for config_override in config_overrides {
+            args.extend(["-c", config_override]);
+        }
+
+        let mut cmd = Command::new(self.env.pageserver_bin()?);
+        let mut filled_cmd = fill_rust_env_vars(cmd.args(&args));
+        filled_cmd = fill_aws_secrets_vars(filled_cmd);
+
+        let status = filled_cmd.status().with_context(|| {
+            format!("failed to bootstrap pageserver with command {filled_cmd:?}")
+        })?;
+        if !status.success() {
+            bail!("Pageserver bootstrap failed, {status}");
+        }
+        Ok(())
+    }
+
     ///
     /// Stop the server.
     ///
@@ -427,6 +458,11 @@ impl PageServerNode {
                     .map(|x| x.parse::<NonZeroU64>())
                     .transpose()
                     .context("Failed to parse 'max_lsn_wal_lag' as non zero integer")?,
+                walreceiver_standby_connection: settings
+                    .get("walreceiver_standby_connection")
+                    .map(|x| x.parse::<bool>())
+                    .transpose()
+                    .context("Failed to parse 'walreceiver_standby_connection' as bool")?,
             })
             .send()?
             .error_from_body()?
@@ -485,6 +521,11 @@ impl PageServerNode {
                     .map(|x| x.parse::<NonZeroU64>())
                     .transpose()
                     .context("Failed to parse 'max_lsn_wal_lag' as non zero integer")?,
+                walreceiver_standby_connection: settings
+                    .get("walreceiver_standby_connection")
+                    .map(|x| x.parse::<bool>())
+                    .transpose()
+                    .context("Failed to parse 'walreceiver_standby_connection' as bool")?,
             })
             .send()?
             .error_from_body()?;
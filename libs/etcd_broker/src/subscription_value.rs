@@ -8,6 +8,10 @@ use utils::lsn::Lsn;
 #[serde_as]
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SkTimelineInfo {
+    /// Current term of the safekeeper. Missing in data of older safekeepers,
+    /// which in turn ignore it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub term: Option<u64>,
     /// Term of the last entry.
     pub last_log_term: Option<u64>,
     /// LSN of the last record.
@@ -33,3 +37,27 @@ pub struct SkTimelineInfo {
     #[serde(default)]
     pub safekeeper_connstr: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn term_compatibility() {
+        // value put by a safekeeper which doesn't know about the term
+        let old_value = r#"{"last_log_term":2,"flush_lsn":"0/16B9188","commit_lsn":"0/16B9188"}"#;
+        let info: SkTimelineInfo = serde_json::from_str(old_value).unwrap();
+        assert_eq!(info.term, None);
+        assert_eq!(info.last_log_term, Some(2));
+        assert_eq!(info.commit_lsn, Some(Lsn(0x16B9188)));
+        assert!(!serde_json::to_string(&info).unwrap().contains("\"term\""));
+
+        let new_value = r#"{"term":3,"last_log_term":2,"flush_lsn":"0/16B9188"}"#;
+        let info: SkTimelineInfo = serde_json::from_str(new_value).unwrap();
+        assert_eq!(info.term, Some(3));
+        let reparsed: SkTimelineInfo =
+            serde_json::from_str(&serde_json::to_string(&info).unwrap()).unwrap();
+        assert_eq!(reparsed.term, Some(3));
+        assert_eq!(reparsed.flush_lsn, Some(Lsn(0x16B9188)));
+    }
+}
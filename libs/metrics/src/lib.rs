@@ -15,6 +15,7 @@ pub use prometheus::{register_int_gauge, IntGauge};
 pub use prometheus::{register_int_gauge_vec, IntGaugeVec};
 pub use prometheus::{Encoder, TextEncoder};
 
+pub mod remote_storage;
 mod wrappers;
 pub use wrappers::{CountedReader, CountedWriter};
 
@@ -0,0 +1,55 @@
+//! Latency, error and retry metrics of the remote storage requests, common for all storage kinds.
+//!
+//! The requests are labelled with the storage kind (e.g. `s3`) and the request type (e.g. `put_object`),
+//! the errors additionally with their kind (e.g. `throttled`).
+
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+use crate::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+
+lazy_static! {
+    static ref REMOTE_STORAGE_REQUEST_SECONDS: HistogramVec = register_histogram_vec!(
+        "remote_storage_request_seconds",
+        "Time spent on a single remote storage request of particular type, including the failed ones",
+        &["storage", "request_type"],
+        vec![0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0],
+    )
+    .expect("failed to define a metric");
+    static ref REMOTE_STORAGE_REQUEST_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "remote_storage_request_errors_count",
+        "Number of failed remote storage requests of particular type, by the error kind",
+        &["storage", "request_type", "error_kind"],
+    )
+    .expect("failed to define a metric");
+    static ref REMOTE_STORAGE_REQUEST_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "remote_storage_request_retries_count",
+        "Number of remote storage requests of particular type, retried after a transient error",
+        &["storage", "request_type"],
+    )
+    .expect("failed to define a metric");
+}
+
+/// Records a finished request, along with its error kind, if it failed.
+pub fn observe_request(
+    storage: &str,
+    request_type: &str,
+    elapsed: Duration,
+    error_kind: Option<&str>,
+) {
+    REMOTE_STORAGE_REQUEST_SECONDS
+        .with_label_values(&[storage, request_type])
+        .observe(elapsed.as_secs_f64());
+    if let Some(error_kind) = error_kind {
+        REMOTE_STORAGE_REQUEST_ERRORS
+            .with_label_values(&[storage, request_type, error_kind])
+            .inc();
+    }
+}
+
+pub fn inc_request_retry(storage: &str, request_type: &str) {
+    REMOTE_STORAGE_REQUEST_RETRIES
+        .with_label_values(&[storage, request_type])
+        .inc();
+}
@@ -0,0 +1,1045 @@
+//! Azure Blob Storage wrapper around its REST API, using [Shared Key](https://docs.microsoft.com/en-us/rest/api/storageservices/authorize-with-shared-key)
+//! authorization with the storage account access key.
+//!
+//! Respects `prefix_in_container` property from [`AzureConfig`],
+//! allowing multiple api users to independently work with the same container, if
+//! their container prefixes are both specified and different.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Context};
+use hmac::{Hmac, Mac};
+use hyper::{
+    body::HttpBody, client::HttpConnector, header::CONTENT_LENGTH, Body, Client, Method, Request,
+    Response, StatusCode, Uri,
+};
+use hyper_tls::HttpsConnector;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::events::Event;
+use sha2::Sha256;
+use tokio::{
+    io::{self, AsyncWriteExt},
+    sync::Semaphore,
+};
+use tokio_util::io::ReaderStream;
+use tracing::debug;
+
+use crate::{
+    retry::{measured, with_retries},
+    strip_path_prefix, AzureConfig, RemoteStorage, RemoteStorageError,
+};
+
+use super::StorageMetadata;
+
+pub(super) mod metrics {
+    use metrics::{register_int_counter_vec, IntCounterVec};
+    use once_cell::sync::Lazy;
+
+    static AZURE_REQUESTS_COUNT: Lazy<IntCounterVec> = Lazy::new(|| {
+        register_int_counter_vec!(
+            "remote_storage_azure_requests_count",
+            "Number of Azure Blob Storage requests of particular type",
+            &["request_type"],
+        )
+        .expect("failed to define a metric")
+    });
+
+    static AZURE_REQUESTS_FAIL_COUNT: Lazy<IntCounterVec> = Lazy::new(|| {
+        register_int_counter_vec!(
+            "remote_storage_azure_failures_count",
+            "Number of failed Azure Blob Storage requests of particular type",
+            &["request_type"],
+        )
+        .expect("failed to define a metric")
+    });
+
+    pub fn inc_request(request_type: &str) {
+        AZURE_REQUESTS_COUNT
+            .with_label_values(&[request_type])
+            .inc();
+    }
+
+    pub fn inc_request_fail(request_type: &str) {
+        AZURE_REQUESTS_FAIL_COUNT
+            .with_label_values(&[request_type])
+            .inc();
+    }
+}
+
+const AZURE_STORAGE: &str = "azure";
+const AZURE_PREFIX_SEPARATOR: char = '/';
+
+/// Version of the Blob service REST API the requests are made with.
+const AZURE_STORAGE_API_VERSION: &str = "2020-10-02";
+
+/// Characters to escape in blob names when putting them into the request path.
+const BLOB_NAME_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Characters to escape in the request query values.
+const QUERY_VALUE_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+#[derive(Debug, Eq, PartialEq, PartialOrd, Ord, Hash)]
+pub struct AzureBlobName(String);
+
+impl AzureBlobName {
+    fn name(&self) -> &str {
+        &self.0
+    }
+
+    fn download_destination(&self, workdir: &Path, prefix_to_strip: Option<&str>) -> PathBuf {
+        let path_without_prefix = match prefix_to_strip {
+            Some(prefix) => self.0.strip_prefix(prefix).unwrap_or_else(|| {
+                panic!(
+                    "Could not strip prefix '{}' from Azure blob name '{}'",
+                    prefix, self.0
+                )
+            }),
+            None => &self.0,
+        };
+
+        workdir.join(
+            path_without_prefix
+                .split(AZURE_PREFIX_SEPARATOR)
+                .collect::<PathBuf>(),
+        )
+    }
+}
+
+/// Azure Blob Storage container.
+pub struct AzureBlob {
+    workdir: PathBuf,
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// Base URL of the blob service, without the trailing slash.
+    endpoint: String,
+    /// Path part of the endpoint, included into the signed resource
+    /// (non-empty for the path-style endpoints, like Azurite's `http://127.0.0.1:10000/devstoreaccount1`).
+    endpoint_path: String,
+    storage_account: String,
+    access_key: Vec<u8>,
+    container_name: String,
+    prefix_in_container: Option<String>,
+    // Azure throttles the storage accounts that exceed their request rate targets,
+    // this helps to stay within them.
+    concurrency_limiter: Semaphore,
+}
+
+impl AzureBlob {
+    /// Creates the Azure storage, errors if incorrect Azure configuration provided.
+    pub fn new(azure_config: &AzureConfig, workdir: PathBuf) -> anyhow::Result<Self> {
+        debug!(
+            "Creating azure remote storage for container {}",
+            azure_config.container_name
+        );
+
+        let storage_account = match &azure_config.storage_account {
+            Some(storage_account) => storage_account.clone(),
+            None => std::env::var("AZURE_STORAGE_ACCOUNT").context(
+                "Neither 'storage_account' option nor AZURE_STORAGE_ACCOUNT environment variable is set",
+            )?,
+        };
+        let access_key = base64::decode(
+            std::env::var("AZURE_STORAGE_ACCESS_KEY")
+                .context("AZURE_STORAGE_ACCESS_KEY environment variable is not set")?,
+        )
+        .context("Failed to decode AZURE_STORAGE_ACCESS_KEY as base64")?;
+
+        let endpoint = match &azure_config.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://{storage_account}.blob.core.windows.net"),
+        };
+        let endpoint_path = endpoint
+            .parse::<Uri>()
+            .with_context(|| format!("Failed to parse Azure endpoint '{endpoint}'"))?
+            .path()
+            .trim_end_matches('/')
+            .to_string();
+
+        let prefix_in_container = azure_config
+            .prefix_in_container
+            .as_deref()
+            .map(|prefix| prefix.trim_matches(AZURE_PREFIX_SEPARATOR).to_string())
+            .filter(|prefix| !prefix.is_empty());
+
+        Ok(Self {
+            workdir,
+            client: Client::builder().build(HttpsConnector::new()),
+            endpoint,
+            endpoint_path,
+            storage_account,
+            access_key,
+            container_name: azure_config.container_name.clone(),
+            prefix_in_container,
+            concurrency_limiter: Semaphore::new(azure_config.concurrency_limit.get()),
+        })
+    }
+
+    /// Sends a signed request to the container or, if `blob` is given, to the blob.
+    /// Errors on any non-success response, with the error from the response body, classified by the response status.
+    async fn request(
+        &self,
+        method: Method,
+        blob: Option<&AzureBlobName>,
+        query: &[(&str, &str)],
+        mut ms_headers: Vec<(String, String)>,
+        body: Body,
+        content_length: u64,
+    ) -> Result<Response<Body>, RemoteStorageError> {
+        let request_type = match (&method, blob) {
+            (&Method::PUT, Some(_)) => "put_blob",
+            (&Method::DELETE, Some(_)) => "delete_blob",
+            (_, Some(_)) => "get_blob",
+            (_, None) => "list_blobs",
+        };
+
+        let mut resource_path = format!("/{}", self.container_name);
+        if let Some(blob) = blob {
+            resource_path.push('/');
+            resource_path
+                .push_str(&utf8_percent_encode(blob.name(), BLOB_NAME_ENCODE_SET).to_string());
+        }
+        let query_string = query
+            .iter()
+            .map(|(name, value)| {
+                format!(
+                    "{name}={}",
+                    utf8_percent_encode(value, QUERY_VALUE_ENCODE_SET)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+
+        ms_headers.push((
+            "x-ms-date".to_string(),
+            httpdate::fmt_http_date(SystemTime::now()),
+        ));
+        ms_headers.push((
+            "x-ms-version".to_string(),
+            AZURE_STORAGE_API_VERSION.to_string(),
+        ));
+        let canonicalized_resource = canonicalized_resource(
+            &self.storage_account,
+            &format!("{}{resource_path}", self.endpoint_path),
+            query,
+        );
+        let signature = sign(
+            &self.access_key,
+            &string_to_sign(
+                &method,
+                content_length,
+                &ms_headers,
+                &canonicalized_resource,
+            ),
+        );
+
+        let mut uri = format!("{}{resource_path}", self.endpoint);
+        if !query_string.is_empty() {
+            uri.push('?');
+            uri.push_str(&query_string);
+        }
+
+        // Put Blob does not accept chunked uploads, so the length is always sent,
+        // even for the empty blobs
+        let send_content_length = content_length > 0 || method == Method::PUT;
+        let mut request = Request::builder().method(method).uri(&uri).header(
+            "Authorization",
+            format!("SharedKey {}:{signature}", self.storage_account),
+        );
+        if send_content_length {
+            request = request.header(CONTENT_LENGTH, content_length);
+        }
+        for (name, value) in &ms_headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        let request = request
+            .body(body)
+            .with_context(|| format!("Failed to build Azure request to '{uri}'"))?;
+
+        metrics::inc_request(request_type);
+        let response = match self.client.request(request).await {
+            Ok(response) => response,
+            Err(e) => {
+                metrics::inc_request_fail(request_type);
+                return Err(RemoteStorageError::Transient(
+                    anyhow::Error::new(e).context(format!("Azure request to '{uri}' failed")),
+                ));
+            }
+        };
+
+        let status = response.status();
+        if !status.is_success() {
+            metrics::inc_request_fail(request_type);
+            let body = hyper::body::to_bytes(response.into_body())
+                .await
+                .unwrap_or_default();
+            let error = anyhow!(
+                "Azure request to '{uri}' failed with status {status}: {}",
+                String::from_utf8_lossy(&body)
+            );
+            return Err(match status {
+                StatusCode::NOT_FOUND => RemoteStorageError::NotFound,
+                // Azure responds with 503 Server Busy when the request rate is too high
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                    RemoteStorageError::Throttled(error)
+                }
+                StatusCode::REQUEST_TIMEOUT => RemoteStorageError::Transient(error),
+                status if status.is_server_error() => RemoteStorageError::Transient(error),
+                _ => RemoteStorageError::Permanent(error),
+            });
+        }
+        Ok(response)
+    }
+
+    /// Streams the blob contents, or its byte range, into the writer given.
+    /// Only the request gets retried: if streaming of the contents fails, the error is returned as a transient one.
+    async fn download_object(
+        &self,
+        from: &AzureBlobName,
+        range: Option<String>,
+        to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+    ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
+        let ms_headers = range
+            .map(|range| vec![("x-ms-range".to_string(), range)])
+            .unwrap_or_default();
+        let (_guard, response) = with_retries(AZURE_STORAGE, "get_blob", || async {
+            let guard = self
+                .concurrency_limiter
+                .acquire()
+                .await
+                .context("Concurrency limiter semaphore got closed during Azure download")?;
+            self.request(
+                Method::GET,
+                Some(from),
+                &[],
+                ms_headers.clone(),
+                Body::empty(),
+                0,
+            )
+            .await
+            .map(|response| (guard, response))
+        })
+        .await?;
+
+        let metadata = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| {
+                let key = name.as_str().strip_prefix("x-ms-meta-")?;
+                Some((key.to_string(), value.to_str().ok()?.to_string()))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut body = response.into_body();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk
+                .context("Failed to read Azure blob contents")
+                .map_err(RemoteStorageError::Transient)?;
+            to.write_all(&chunk)
+                .await
+                .context("Failed to write Azure blob contents")?;
+        }
+        to.flush()
+            .await
+            .context("Failed to flush Azure blob contents")?;
+
+        Ok(if metadata.is_empty() {
+            None
+        } else {
+            Some(StorageMetadata(metadata))
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl RemoteStorage for AzureBlob {
+    type RemoteObjectId = AzureBlobName;
+
+    fn remote_object_id(&self, local_path: &Path) -> anyhow::Result<Self::RemoteObjectId> {
+        let relative_path = strip_path_prefix(&self.workdir, local_path)?;
+        let mut name = self.prefix_in_container.clone().unwrap_or_default();
+        for segment in relative_path {
+            if !name.is_empty() {
+                name.push(AZURE_PREFIX_SEPARATOR);
+            }
+            name.push_str(&segment.to_string_lossy());
+        }
+        Ok(AzureBlobName(name))
+    }
+
+    fn local_path(&self, storage_path: &Self::RemoteObjectId) -> anyhow::Result<PathBuf> {
+        let prefix_to_strip = self
+            .prefix_in_container
+            .as_ref()
+            .map(|prefix| format!("{prefix}{AZURE_PREFIX_SEPARATOR}"));
+        Ok(storage_path.download_destination(&self.workdir, prefix_to_strip.as_deref()))
+    }
+
+    async fn list(&self) -> Result<Vec<Self::RemoteObjectId>, RemoteStorageError> {
+        let mut blob_names = Vec::new();
+        // List the blobs in the prefix "subfolder" only, not the ones with the names starting with the prefix
+        let list_prefix = self
+            .prefix_in_container
+            .as_ref()
+            .map(|prefix| format!("{prefix}{AZURE_PREFIX_SEPARATOR}"));
+
+        let mut marker = None;
+        loop {
+            let mut query = vec![("comp", "list"), ("restype", "container")];
+            if let Some(prefix) = list_prefix.as_deref() {
+                query.push(("prefix", prefix));
+            }
+            if let Some(marker) = marker.as_deref() {
+                query.push(("marker", marker));
+            }
+            let response_body = with_retries(AZURE_STORAGE, "list_blobs", || async {
+                let _guard = self
+                    .concurrency_limiter
+                    .acquire()
+                    .await
+                    .context("Concurrency limiter semaphore got closed during Azure list")?;
+
+                let response = self
+                    .request(Method::GET, None, &query, Vec::new(), Body::empty(), 0)
+                    .await?;
+                hyper::body::to_bytes(response.into_body())
+                    .await
+                    .context("Failed to read Azure list response")
+                    .map_err(RemoteStorageError::Transient)
+            })
+            .await?;
+
+            let page = parse_list_blobs_response(&response_body)
+                .context("Failed to parse Azure list response")?;
+            blob_names.extend(page.blob_names.into_iter().map(AzureBlobName));
+
+            match page.next_marker {
+                Some(next_marker) => marker = Some(next_marker),
+                None => break,
+            }
+        }
+
+        Ok(blob_names)
+    }
+
+    async fn upload(
+        &self,
+        from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        to: &Self::RemoteObjectId,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<(), RemoteStorageError> {
+        let _guard = self
+            .concurrency_limiter
+            .acquire()
+            .await
+            .context("Concurrency limiter semaphore got closed during Azure upload")?;
+
+        let mut ms_headers = vec![("x-ms-blob-type".to_string(), "BlockBlob".to_string())];
+        if let Some(metadata) = metadata {
+            ms_headers.extend(
+                metadata
+                    .0
+                    .into_iter()
+                    .map(|(key, value)| (format!("x-ms-meta-{key}"), value)),
+            );
+        }
+
+        // The source can be read once only, so failed uploads are retried by the storage user, with `upload_with_retries`
+        measured(
+            AZURE_STORAGE,
+            "put_blob",
+            self.request(
+                Method::PUT,
+                Some(to),
+                &[],
+                ms_headers,
+                Body::wrap_stream(ReaderStream::new(from)),
+                from_size_bytes as u64,
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn download(
+        &self,
+        from: &Self::RemoteObjectId,
+        to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+    ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
+        self.download_object(from, None, to).await
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &Self::RemoteObjectId,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+        to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+    ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
+        // Same as S3, Azure accepts ranges with both ends inclusive
+        let end_inclusive = end_exclusive.map(|end| end.saturating_sub(1));
+        let range = match end_inclusive {
+            Some(end_inclusive) => format!("bytes={}-{}", start_inclusive, end_inclusive),
+            None => format!("bytes={}-", start_inclusive),
+        };
+        self.download_object(from, Some(range), to).await
+    }
+
+    async fn delete(&self, path: &Self::RemoteObjectId) -> Result<(), RemoteStorageError> {
+        with_retries(AZURE_STORAGE, "delete_blob", || async {
+            let _guard = self
+                .concurrency_limiter
+                .acquire()
+                .await
+                .context("Concurrency limiter semaphore got closed during Azure delete")?;
+
+            self.request(
+                Method::DELETE,
+                Some(path),
+                &[],
+                Vec::new(),
+                Body::empty(),
+                0,
+            )
+            .await
+            .map(|_| ())
+        })
+        .await
+    }
+}
+
+/// Resource part of the string to sign: the account name, the encoded request path
+/// and the query parameters, sorted by name with their values not encoded.
+fn canonicalized_resource(account: &str, encoded_path: &str, query: &[(&str, &str)]) -> String {
+    let mut resource = format!("/{account}{encoded_path}");
+    let mut query = query.to_vec();
+    query.sort();
+    for (name, value) in query {
+        resource.push_str(&format!("\n{}:{value}", name.to_lowercase()));
+    }
+    resource
+}
+
+/// Builds the Shared Key string to sign. Of all standard headers, the requests
+/// only use Content-Length, the range is passed in the `x-ms-range` header.
+fn string_to_sign(
+    method: &Method,
+    content_length: u64,
+    ms_headers: &[(String, String)],
+    canonicalized_resource: &str,
+) -> String {
+    let content_length = if content_length > 0 {
+        content_length.to_string()
+    } else {
+        String::new()
+    };
+
+    let mut ms_headers = ms_headers
+        .iter()
+        .map(|(name, value)| (name.to_lowercase(), value.trim()))
+        .collect::<Vec<_>>();
+    ms_headers.sort();
+    let canonicalized_headers = ms_headers
+        .into_iter()
+        .map(|(name, value)| format!("{name}:{value}\n"))
+        .collect::<String>();
+
+    // VERB, Content-Encoding, Content-Language, Content-Length, Content-MD5, Content-Type, Date,
+    // If-Modified-Since, If-Match, If-None-Match, If-Unmodified-Since, Range
+    format!(
+        "{method}\n\n\n{content_length}\n\n\n\n\n\n\n\n\n{canonicalized_headers}{canonicalized_resource}"
+    )
+}
+
+fn sign(access_key: &[u8], string_to_sign: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(access_key).expect("HMAC accepts keys of any size");
+    mac.update(string_to_sign.as_bytes());
+    base64::encode(mac.finalize().into_bytes())
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ListBlobsPage {
+    blob_names: Vec<String>,
+    next_marker: Option<String>,
+}
+
+/// Parses the `EnumerationResults` document, returned by the List Blobs request.
+fn parse_list_blobs_response(body: &[u8]) -> anyhow::Result<ListBlobsPage> {
+    let mut reader = quick_xml::Reader::from_reader(body);
+    reader.trim_text(true);
+
+    let mut page = ListBlobsPage::default();
+    let mut element_path: Vec<Vec<u8>> = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(element) => element_path.push(element.name().to_vec()),
+            Event::End(_) => {
+                element_path.pop();
+            }
+            Event::Text(text) => {
+                let text = text.unescape_and_decode(&reader)?;
+                match element_path
+                    .iter()
+                    .map(Vec::as_slice)
+                    .collect::<Vec<_>>()
+                    .as_slice()
+                {
+                    [b"EnumerationResults", b"Blobs", b"Blob", b"Name"] => {
+                        page.blob_names.push(text)
+                    }
+                    [b"EnumerationResults", b"NextMarker"] if !text.is_empty() => {
+                        page.next_marker = Some(text)
+                    }
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        convert::Infallible,
+        net::SocketAddr,
+        num::NonZeroUsize,
+        sync::{Arc, Mutex},
+    };
+
+    use hyper::{
+        body::Bytes,
+        service::{make_service_fn, service_fn},
+        Server,
+    };
+    use percent_encoding::percent_decode_str;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    /// Well-known Azurite development account.
+    const DEV_ACCOUNT: &str = "devstoreaccount1";
+    const DEV_ACCESS_KEY: &str =
+        "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+    const CONTAINER: &str = "test-container";
+
+    #[test]
+    fn shared_key_signature() {
+        let ms_headers = vec![
+            ("x-ms-version".to_string(), "2020-10-02".to_string()),
+            (
+                "x-ms-date".to_string(),
+                "Fri, 26 Jun 2015 23:39:12 GMT".to_string(),
+            ),
+            ("x-ms-blob-type".to_string(), "BlockBlob".to_string()),
+        ];
+        let resource = canonicalized_resource(
+            DEV_ACCOUNT,
+            "/devstoreaccount1/test-container/prefix/some%20name",
+            &[],
+        );
+        let string_to_sign = string_to_sign(&Method::PUT, 11, &ms_headers, &resource);
+        assert_eq!(
+            string_to_sign,
+            "PUT\n\n\n11\n\n\n\n\n\n\n\n\n\
+             x-ms-blob-type:BlockBlob\n\
+             x-ms-date:Fri, 26 Jun 2015 23:39:12 GMT\n\
+             x-ms-version:2020-10-02\n\
+             /devstoreaccount1/devstoreaccount1/test-container/prefix/some%20name"
+        );
+        assert_eq!(
+            sign(&base64::decode(DEV_ACCESS_KEY).unwrap(), &string_to_sign),
+            "fazWYdmttqZuiNjiF+xT5O3voCDGJRwP7VEYD4559oc="
+        );
+
+        let resource = canonicalized_resource(
+            "myaccount",
+            "/mycontainer",
+            &[
+                ("restype", "container"),
+                ("comp", "list"),
+                ("prefix", "a/b"),
+            ],
+        );
+        assert_eq!(
+            resource,
+            "/myaccount/mycontainer\ncomp:list\nprefix:a/b\nrestype:container"
+        );
+    }
+
+    #[test]
+    fn list_blobs_response() -> anyhow::Result<()> {
+        let page = parse_list_blobs_response(
+            br#"<?xml version="1.0" encoding="utf-8"?>
+            <EnumerationResults ServiceEndpoint="http://127.0.0.1:10000/devstoreaccount1" ContainerName="test-container">
+              <Prefix>prefix</Prefix>
+              <Blobs>
+                <Blob><Name>prefix/one</Name><Properties><Content-Length>1</Content-Length></Properties></Blob>
+                <Blob><Name>prefix/a &amp; b</Name><Properties><Content-Length>2</Content-Length></Properties></Blob>
+              </Blobs>
+              <NextMarker>marker</NextMarker>
+            </EnumerationResults>"#,
+        )?;
+        assert_eq!(
+            page,
+            ListBlobsPage {
+                blob_names: vec!["prefix/one".to_string(), "prefix/a & b".to_string()],
+                next_marker: Some("marker".to_string()),
+            }
+        );
+
+        let page = parse_list_blobs_response(
+            br#"<?xml version="1.0" encoding="utf-8"?>
+            <EnumerationResults ContainerName="test-container"><Blobs /><NextMarker /></EnumerationResults>"#,
+        )?;
+        assert_eq!(page, ListBlobsPage::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn storage_path_positive() -> anyhow::Result<()> {
+        let workdir = tempdir()?.path().to_owned();
+        let storage = dummy_storage(workdir.clone(), "http://127.0.0.1:1".to_string());
+
+        let local_path = workdir.join("matching").join("file");
+        let blob_name = storage.remote_object_id(&local_path)?;
+        assert_eq!(
+            blob_name,
+            AzureBlobName("dummy_prefix/matching/file".to_string()),
+            "Blob name should contain all segments after the workdir prefix, separated with '/'"
+        );
+        assert_eq!(
+            storage.local_path(&blob_name)?,
+            local_path,
+            "'original path -> blob name -> matching fs path' transformation should produce the same path"
+        );
+
+        let error_message = storage
+            .remote_object_id(&PathBuf::from("somewhere").join("else"))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error_message.contains("is not prefixed with"),
+            "Message '{}' does not contain a required string",
+            error_message
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn operations_against_azurite_stand_in() -> anyhow::Result<()> {
+        let endpoint = start_azurite_stand_in().await;
+        let workdir = tempdir()?.path().to_owned();
+        let storage = dummy_storage(workdir.clone(), endpoint);
+
+        let mut uploaded = Vec::new();
+        for (i, file_name) in ["first", "second", "third with spaces"].iter().enumerate() {
+            let contents = format!("contents of file number {i}");
+            let blob_name = storage.remote_object_id(&workdir.join("timelines").join(file_name))?;
+            storage
+                .upload(
+                    std::io::Cursor::new(contents.clone().into_bytes()),
+                    contents.len(),
+                    &blob_name,
+                    Some(StorageMetadata(HashMap::from([(
+                        "index".to_string(),
+                        i.to_string(),
+                    )]))),
+                )
+                .await?;
+            uploaded.push((blob_name, contents));
+        }
+
+        // The stand-in returns two blobs per page, so listing has to follow the markers
+        let mut listed = storage.list().await?;
+        listed.sort();
+        let mut expected = uploaded
+            .iter()
+            .map(|(blob_name, _)| AzureBlobName(blob_name.0.clone()))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        let (blob_name, contents) = &uploaded[2];
+        let mut downloaded = Vec::new();
+        let metadata = storage.download(blob_name, &mut downloaded).await?;
+        assert_eq!(String::from_utf8(downloaded)?, *contents);
+        assert_eq!(
+            metadata,
+            Some(StorageMetadata(HashMap::from([(
+                "index".to_string(),
+                "2".to_string()
+            )])))
+        );
+
+        let mut downloaded = Vec::new();
+        storage
+            .download_byte_range(blob_name, 3, Some(8), &mut downloaded)
+            .await?;
+        assert_eq!(String::from_utf8(downloaded)?, contents[3..8]);
+
+        let mut downloaded = Vec::new();
+        storage
+            .download_byte_range(blob_name, 9, None, &mut downloaded)
+            .await?;
+        assert_eq!(String::from_utf8(downloaded)?, contents[9..]);
+
+        storage.delete(blob_name).await?;
+        assert_eq!(storage.list().await?.len(), 2);
+        assert!(
+            matches!(
+                storage.download(blob_name, &mut Vec::<u8>::new()).await,
+                Err(RemoteStorageError::NotFound)
+            ),
+            "Deleted blob should not be downloadable"
+        );
+
+        // Requests signed with a wrong key are rejected, with no retries
+        let mut wrong_key_storage = dummy_storage(workdir, storage.endpoint.clone());
+        wrong_key_storage.access_key = b"wrong key".to_vec();
+        assert!(matches!(
+            wrong_key_storage.list().await,
+            Err(RemoteStorageError::Permanent(_))
+        ));
+
+        Ok(())
+    }
+
+    fn dummy_storage(workdir: PathBuf, endpoint: String) -> AzureBlob {
+        let endpoint_path = endpoint.parse::<Uri>().unwrap().path().to_string();
+        AzureBlob {
+            workdir,
+            client: Client::builder().build(HttpsConnector::new()),
+            endpoint,
+            endpoint_path: endpoint_path.trim_end_matches('/').to_string(),
+            storage_account: DEV_ACCOUNT.to_string(),
+            access_key: base64::decode(DEV_ACCESS_KEY).unwrap(),
+            container_name: CONTAINER.to_string(),
+            prefix_in_container: Some("dummy_prefix".to_string()),
+            concurrency_limiter: Semaphore::new(1),
+        }
+    }
+
+    type StandInBlobs = Arc<Mutex<BTreeMap<String, (Bytes, Vec<(String, String)>)>>>;
+
+    /// Starts a minimal in-memory server, speaking the subset of Azurite's
+    /// (path-style) Blob service API used by [`AzureBlob`], returns its endpoint.
+    async fn start_azurite_stand_in() -> String {
+        let blobs = StandInBlobs::default();
+        let make_service = make_service_fn(move |_| {
+            let blobs = Arc::clone(&blobs);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let blobs = Arc::clone(&blobs);
+                    async move { Ok::<_, Infallible>(handle_stand_in_request(request, blobs).await) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let endpoint = format!("http://{}/{DEV_ACCOUNT}", server.local_addr());
+        tokio::spawn(server);
+        endpoint
+    }
+
+    async fn handle_stand_in_request(
+        request: Request<Body>,
+        blobs: StandInBlobs,
+    ) -> Response<Body> {
+        let error = |status: StatusCode, code: &str| {
+            Response::builder()
+                .status(status)
+                .body(Body::from(format!("<Error><Code>{code}</Code></Error>")))
+                .unwrap()
+        };
+
+        let query = request
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (
+                    name.to_string(),
+                    percent_decode_str(value).decode_utf8_lossy().to_string(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let query_value = |name: &str| {
+            query
+                .iter()
+                .find(|(query_name, _)| query_name == name)
+                .map(|(_, value)| value.clone())
+        };
+        let header_value = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+
+        // Verify the Shared Key signature, reconstructing the string to sign from the received request
+        let ms_headers = request
+            .headers()
+            .iter()
+            .filter(|(name, _)| name.as_str().starts_with("x-ms-"))
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+            .collect::<Vec<_>>();
+        let content_length = header_value("content-length")
+            .map(|length| length.parse::<u64>().unwrap())
+            .unwrap_or_default();
+        let signed_query = query
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        let expected_signature = sign(
+            &base64::decode(DEV_ACCESS_KEY).unwrap(),
+            &string_to_sign(
+                request.method(),
+                content_length,
+                &ms_headers,
+                &canonicalized_resource(DEV_ACCOUNT, request.uri().path(), &signed_query),
+            ),
+        );
+        if header_value("authorization")
+            != Some(format!("SharedKey {DEV_ACCOUNT}:{expected_signature}"))
+        {
+            return error(StatusCode::FORBIDDEN, "AuthenticationFailed");
+        }
+
+        let container_path = format!("/{DEV_ACCOUNT}/{CONTAINER}");
+        let blob_name = match request.uri().path().strip_prefix(&container_path) {
+            Some("") => None,
+            Some(blob_path) => match blob_path.strip_prefix('/') {
+                Some(blob_path) => Some(
+                    percent_decode_str(blob_path)
+                        .decode_utf8_lossy()
+                        .to_string(),
+                ),
+                None => return error(StatusCode::NOT_FOUND, "ContainerNotFound"),
+            },
+            None => return error(StatusCode::NOT_FOUND, "ContainerNotFound"),
+        };
+
+        match (request.method().clone(), blob_name) {
+            (Method::GET, None) if query_value("comp").as_deref() == Some("list") => {
+                const PAGE_SIZE: usize = 2;
+                let prefix = query_value("prefix").unwrap_or_default();
+                let marker = query_value("marker").unwrap_or_default();
+                let blobs = blobs.lock().unwrap();
+                let mut matching = blobs
+                    .keys()
+                    .filter(|name| name.starts_with(&prefix) && **name >= marker);
+                let page = matching.by_ref().take(PAGE_SIZE).collect::<Vec<_>>();
+                let next_marker = matching.next().cloned().unwrap_or_default();
+
+                let blobs_xml = page
+                    .iter()
+                    .map(|name| format!("<Blob><Name>{name}</Name></Blob>"))
+                    .collect::<String>();
+                Response::new(Body::from(format!(
+                    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                     <EnumerationResults ContainerName=\"{CONTAINER}\">\
+                     <Prefix>{prefix}</Prefix><Blobs>{blobs_xml}</Blobs>\
+                     <NextMarker>{next_marker}</NextMarker></EnumerationResults>"
+                )))
+            }
+            (Method::PUT, Some(blob_name)) => {
+                if header_value("x-ms-blob-type").as_deref() != Some("BlockBlob") {
+                    return error(StatusCode::BAD_REQUEST, "InvalidHeaderValue");
+                }
+                let metadata = ms_headers
+                    .into_iter()
+                    .filter(|(name, _)| name.starts_with("x-ms-meta-"))
+                    .collect();
+                let contents = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                if contents.len() as u64 != content_length {
+                    return error(StatusCode::BAD_REQUEST, "InvalidHeaderValue");
+                }
+                blobs
+                    .lock()
+                    .unwrap()
+                    .insert(blob_name, (contents, metadata));
+                Response::builder()
+                    .status(StatusCode::CREATED)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            (Method::GET, Some(blob_name)) => {
+                let (contents, metadata) = match blobs.lock().unwrap().get(&blob_name) {
+                    Some(blob) => blob.clone(),
+                    None => return error(StatusCode::NOT_FOUND, "BlobNotFound"),
+                };
+                let (status, contents) = match header_value("x-ms-range") {
+                    Some(range) => {
+                        let (start, end) = range
+                            .strip_prefix("bytes=")
+                            .and_then(|range| range.split_once('-'))
+                            .unwrap();
+                        let start = start.parse::<usize>().unwrap();
+                        let end = match end {
+                            "" => contents.len(),
+                            end => end.parse::<usize>().unwrap() + 1,
+                        };
+                        (StatusCode::PARTIAL_CONTENT, contents.slice(start..end))
+                    }
+                    None => (StatusCode::OK, contents),
+                };
+                let mut response = Response::builder().status(status);
+                for (name, value) in metadata {
+                    response = response.header(name, value);
+                }
+                response.body(Body::from(contents)).unwrap()
+            }
+            (Method::DELETE, Some(blob_name)) => match blobs.lock().unwrap().remove(&blob_name) {
+                Some(_) => Response::builder()
+                    .status(StatusCode::ACCEPTED)
+                    .body(Body::empty())
+                    .unwrap(),
+                None => error(StatusCode::NOT_FOUND, "BlobNotFound"),
+            },
+            _ => error(StatusCode::BAD_REQUEST, "UnsupportedHttpVerb"),
+        }
+    }
+
+    #[test]
+    fn default_endpoint() -> anyhow::Result<()> {
+        std::env::set_var("AZURE_STORAGE_ACCESS_KEY", DEV_ACCESS_KEY);
+        let storage = AzureBlob::new(
+            &AzureConfig {
+                container_name: CONTAINER.to_string(),
+                storage_account: Some("myaccount".to_string()),
+                prefix_in_container: Some("/some/prefix/".to_string()),
+                endpoint: None,
+                concurrency_limit: NonZeroUsize::new(1).unwrap(),
+            },
+            tempdir()?.path().to_owned(),
+        )?;
+        assert_eq!(storage.endpoint, "https://myaccount.blob.core.windows.net");
+        assert_eq!(storage.endpoint_path, "");
+        assert_eq!(storage.prefix_in_container.as_deref(), Some("some/prefix"));
+        Ok(())
+    }
+}
@@ -0,0 +1,1170 @@
+//! Client-side encryption wrapper around any [`RemoteStorage`], making the stored objects unreadable
+//! for anybody with access to the storage, but without the master key.
+//!
+//! Every tenant gets its own random data key, which encrypts the tenant's objects and is stored
+//! with every object, wrapped (encrypted) with the master key from the [`EncryptionConfig`].
+//! Tenant is determined by the first tenant id-like component of the object's local path.
+//! The wrapped key is also stored in the tenant's [`DATA_KEY_FILE_NAME`] object, so the tenant keeps
+//! its data key across restarts, until the master key changes.
+//!
+//! Objects stored before the encryption was enabled have no encryption header. They are read as plaintext,
+//! until they are uploaded again, only with [`EncryptionConfig::allow_plaintext_reads`] enabled for the
+//! migration, and are rejected otherwise: anybody able to write to the storage could replace an encrypted
+//! object with a plaintext one.
+//!
+//! Encrypted object layout:
+//!   * header: magic, master key id, wrapped data key, random nonce prefix
+//!   * plaintext, split into [`CHUNK_SIZE`] chunks, each sealed separately with AES-256-GCM
+//!
+//! Chunks are sealed with the nonce made of the object nonce prefix and the chunk index,
+//! and their last chunk flag as additional data, so neither reordering nor truncation of the chunks
+//! goes unnoticed. Independent chunks allow range downloads to fetch and decrypt the covering chunks only.
+//!
+//! The master key id is also put into the object's [`StorageMetadata`] under [`ENCRYPTION_KEY_ID_METADATA_KEY`],
+//! to find objects that need re-encryption after the master key rotation without downloading them.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, ensure, Context};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    sync::Mutex,
+};
+use tracing::{debug, info};
+
+use crate::{EncryptionConfig, RemoteStorage, RemoteStorageError, StorageMetadata};
+
+/// [`StorageMetadata`] key with the id of the master key the object's data key is wrapped with.
+pub const ENCRYPTION_KEY_ID_METADATA_KEY: &str = "encryption_key_id";
+
+/// Environment variable with the base64-encoded 256-bit master key.
+pub const ENCRYPTION_KEY_ENV_VAR: &str = "REMOTE_STORAGE_ENCRYPTION_KEY";
+
+/// Name of the object in the tenant directory, keeping the tenant's data key, wrapped with the master key.
+/// The object is not listed by the [`EncryptedStorage`].
+pub const DATA_KEY_FILE_NAME: &str = "encryption_data_key";
+
+/// Size of the plaintext chunks, sealed separately.
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const ENCRYPTED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_LEN;
+
+const MAGIC: &[u8; 8] = b"NEONENC1";
+/// Master key ids are stored zero-padded to this length.
+pub const MAX_KEY_ID_LEN: usize = 32;
+const DATA_KEY_LEN: usize = 32;
+const WRAPPED_DATA_KEY_LEN: usize = NONCE_LEN + DATA_KEY_LEN + TAG_LEN;
+const NONCE_PREFIX_LEN: usize = NONCE_LEN - 4;
+const HEADER_LEN: usize = MAGIC.len() + MAX_KEY_ID_LEN + WRAPPED_DATA_KEY_LEN + NONCE_PREFIX_LEN;
+
+const DATA_KEY_MAGIC: &[u8; 8] = b"NEONKEY1";
+const STORED_DATA_KEY_LEN: usize = DATA_KEY_MAGIC.len() + MAX_KEY_ID_LEN + WRAPPED_DATA_KEY_LEN;
+
+/// A [`RemoteStorage`] that encrypts the uploads and decrypts the downloads of the inner storage.
+/// Without the encryption configured, passes everything to the inner storage as is.
+pub struct EncryptedStorage<S> {
+    inner: S,
+    encryption: Option<Encryption>,
+}
+
+struct Encryption {
+    master_key_id: String,
+    master_key: LessSafeKey,
+    allow_plaintext_reads: bool,
+    rng: SystemRandom,
+    /// Data keys of the tenants, by their key object paths, loaded or generated on the first upload after the start.
+    data_keys: Mutex<HashMap<PathBuf, Arc<DataKey>>>,
+}
+
+struct DataKey {
+    key: LessSafeKey,
+    wrapped: [u8; WRAPPED_DATA_KEY_LEN],
+}
+
+struct ObjectHeader {
+    master_key_id: String,
+    wrapped_data_key: [u8; WRAPPED_DATA_KEY_LEN],
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+}
+
+/// Contents of the [`DATA_KEY_FILE_NAME`] object.
+struct StoredDataKey {
+    master_key_id: String,
+    wrapped_data_key: [u8; WRAPPED_DATA_KEY_LEN],
+}
+
+impl<S> EncryptedStorage<S> {
+    /// Wraps the storage, taking the master key from the [`ENCRYPTION_KEY_ENV_VAR`] environment variable,
+    /// if the encryption is configured.
+    pub fn new(inner: S, config: Option<&EncryptionConfig>) -> anyhow::Result<Self> {
+        let config = match config {
+            Some(config) => config,
+            None => {
+                return Ok(Self {
+                    inner,
+                    encryption: None,
+                })
+            }
+        };
+        let master_key =
+            base64::decode(std::env::var(ENCRYPTION_KEY_ENV_VAR).with_context(|| {
+                format!("{ENCRYPTION_KEY_ENV_VAR} environment variable is not set")
+            })?)
+            .with_context(|| format!("Failed to decode {ENCRYPTION_KEY_ENV_VAR} as base64"))?;
+
+        Self::with_master_key(
+            inner,
+            &config.key_id,
+            &master_key,
+            config.allow_plaintext_reads,
+        )
+    }
+
+    fn with_master_key(
+        inner: S,
+        master_key_id: &str,
+        master_key: &[u8],
+        allow_plaintext_reads: bool,
+    ) -> anyhow::Result<Self> {
+        ensure!(
+            !master_key_id.is_empty()
+                && master_key_id.len() <= MAX_KEY_ID_LEN
+                && !master_key_id.contains('\0'),
+            "Encryption key id should be non-empty and not longer than {MAX_KEY_ID_LEN} bytes"
+        );
+        ensure!(
+            master_key.len() == DATA_KEY_LEN,
+            "Encryption master key should be {DATA_KEY_LEN} bytes long, got {}",
+            master_key.len()
+        );
+        debug!("Encrypting remote storage objects with master key '{master_key_id}'");
+
+        Ok(Self {
+            inner,
+            encryption: Some(Encryption {
+                master_key_id: master_key_id.to_string(),
+                master_key: aes_key(master_key),
+                allow_plaintext_reads,
+                rng: SystemRandom::new(),
+                data_keys: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+}
+
+impl<S> EncryptedStorage<S>
+where
+    S: RemoteStorage + Send + Sync,
+    S::RemoteObjectId: Send + Sync,
+{
+    /// Gets the data key of the tenant the object belongs to: the one stored in the tenant's data key object,
+    /// if it's wrapped with the current master key, or a new one, stored in place of the old one otherwise.
+    async fn data_key(
+        &self,
+        encryption: &Encryption,
+        object: &S::RemoteObjectId,
+    ) -> Result<Arc<DataKey>, RemoteStorageError> {
+        let key_path = data_key_path(&self.inner.local_path(object)?);
+        let mut data_keys = encryption.data_keys.lock().await;
+        if let Some(data_key) = data_keys.get(&key_path) {
+            return Ok(Arc::clone(data_key));
+        }
+
+        let key_object = self.inner.remote_object_id(&key_path)?;
+        let mut stored_key_bytes = Vec::with_capacity(STORED_DATA_KEY_LEN);
+        let stored_key = match self
+            .inner
+            .download(&key_object, &mut stored_key_bytes)
+            .await
+        {
+            Ok(_) => Some(StoredDataKey::parse(&stored_key_bytes)?),
+            Err(RemoteStorageError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+
+        let data_key = match stored_key {
+            Some(stored_key) if stored_key.master_key_id == encryption.master_key_id => {
+                let key = encryption
+                    .unwrap_key(&stored_key.master_key_id, stored_key.wrapped_data_key)
+                    .with_context(|| {
+                        format!("Failed to unwrap the data key '{}'", key_path.display())
+                    })?;
+                DataKey {
+                    key,
+                    wrapped: stored_key.wrapped_data_key,
+                }
+            }
+            stored_key => {
+                if let Some(stored_key) = stored_key {
+                    info!(
+                        "Data key '{}' is wrapped with master key '{}', replacing it with a new one",
+                        key_path.display(),
+                        stored_key.master_key_id
+                    );
+                }
+                let data_key = encryption.generate_data_key()?;
+                let stored_key = StoredDataKey {
+                    master_key_id: encryption.master_key_id.clone(),
+                    wrapped_data_key: data_key.wrapped,
+                }
+                .serialize();
+                self.inner
+                    .upload(
+                        std::io::Cursor::new(stored_key),
+                        STORED_DATA_KEY_LEN,
+                        &key_object,
+                        None,
+                    )
+                    .await?;
+                data_key
+            }
+        };
+
+        let data_key = Arc::new(data_key);
+        data_keys.insert(key_path, Arc::clone(&data_key));
+        Ok(data_key)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> RemoteStorage for EncryptedStorage<S>
+where
+    S: RemoteStorage + Send + Sync,
+    S::RemoteObjectId: Send + Sync,
+{
+    type RemoteObjectId = S::RemoteObjectId;
+
+    fn remote_object_id(&self, local_path: &Path) -> anyhow::Result<Self::RemoteObjectId> {
+        self.inner.remote_object_id(local_path)
+    }
+
+    fn local_path(&self, remote_object_id: &Self::RemoteObjectId) -> anyhow::Result<PathBuf> {
+        self.inner.local_path(remote_object_id)
+    }
+
+    async fn list(&self) -> Result<Vec<Self::RemoteObjectId>, RemoteStorageError> {
+        let mut objects = self.inner.list().await?;
+        objects.retain(|object| match self.inner.local_path(object) {
+            Ok(local_path) => local_path.file_name() != Some(OsStr::new(DATA_KEY_FILE_NAME)),
+            Err(_) => true,
+        });
+        Ok(objects)
+    }
+
+    async fn upload(
+        &self,
+        mut from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        to: &Self::RemoteObjectId,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<(), RemoteStorageError> {
+        let encryption = match &self.encryption {
+            Some(encryption) => encryption,
+            None => return self.inner.upload(from, from_size_bytes, to, metadata).await,
+        };
+
+        let data_key = self.data_key(encryption, to).await?;
+        let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+        encryption
+            .rng
+            .fill(&mut nonce_prefix)
+            .map_err(|_| anyhow!("Failed to generate the nonce prefix"))?;
+        let header = ObjectHeader {
+            master_key_id: encryption.master_key_id.clone(),
+            wrapped_data_key: data_key.wrapped,
+            nonce_prefix,
+        }
+        .serialize();
+
+        let mut metadata = metadata.map(|metadata| metadata.0).unwrap_or_default();
+        metadata.insert(
+            ENCRYPTION_KEY_ID_METADATA_KEY.to_string(),
+            encryption.master_key_id.clone(),
+        );
+
+        let (mut pipe_writer, pipe_reader) = io::duplex(2 * ENCRYPTED_CHUNK_SIZE);
+        let encrypt = async move {
+            pipe_writer.write_all(&header).await?;
+            encrypt_chunks(
+                &mut from,
+                from_size_bytes,
+                &data_key.key,
+                nonce_prefix,
+                &mut pipe_writer,
+            )
+            .await?;
+            pipe_writer.shutdown().await?;
+            Ok::<_, anyhow::Error>(())
+        };
+        let encrypt = async { encrypt.await.map_err(RemoteStorageError::Permanent) };
+        let upload = self.inner.upload(
+            pipe_reader,
+            encrypted_size(from_size_bytes),
+            to,
+            Some(StorageMetadata(metadata)),
+        );
+        tokio::try_join!(encrypt, upload)?;
+        Ok(())
+    }
+
+    async fn download(
+        &self,
+        from: &Self::RemoteObjectId,
+        to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+    ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
+        let encryption = match &self.encryption {
+            Some(encryption) => encryption,
+            None => return self.inner.download(from, to).await,
+        };
+
+        let (pipe_writer, mut pipe_reader) = io::duplex(2 * ENCRYPTED_CHUNK_SIZE);
+        let download = async {
+            let mut pipe_writer = pipe_writer;
+            let metadata = self.inner.download(from, &mut pipe_writer).await?;
+            pipe_writer
+                .shutdown()
+                .await
+                .context("Failed to finish the encrypted object download")?;
+            Ok::<_, RemoteStorageError>(metadata)
+        };
+        let decrypt = async {
+            let mut header = Vec::with_capacity(HEADER_LEN);
+            (&mut pipe_reader)
+                .take(HEADER_LEN as u64)
+                .read_to_end(&mut header)
+                .await
+                .context("Failed to read the encrypted object header")?;
+            if !header.starts_with(MAGIC) {
+                encryption.check_plaintext_read()?;
+                debug!("Object is not encrypted, reading it as plaintext");
+                to.write_all(&header)
+                    .await
+                    .context("Failed to write the plaintext object")?;
+                io::copy(&mut pipe_reader, to)
+                    .await
+                    .context("Failed to write the plaintext object")?;
+                to.flush()
+                    .await
+                    .context("Failed to write the plaintext object")?;
+                return Ok(());
+            }
+            let header = ObjectHeader::parse(&header)?;
+            let data_key = encryption.unwrap_data_key(&header)?;
+            decrypt_chunks(
+                &mut pipe_reader,
+                &data_key,
+                header.nonce_prefix,
+                0,
+                0,
+                None,
+                true,
+                to,
+            )
+            .await
+            .map_err(RemoteStorageError::Permanent)
+        };
+        let (metadata, ()) = tokio::try_join!(download, decrypt)?;
+        Ok(strip_encryption_metadata(metadata))
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &Self::RemoteObjectId,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+        to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+    ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
+        let encryption = match &self.encryption {
+            Some(encryption) => encryption,
+            None => {
+                return self
+                    .inner
+                    .download_byte_range(from, start_inclusive, end_exclusive, to)
+                    .await
+            }
+        };
+        if let Some(end_exclusive) = end_exclusive {
+            if end_exclusive <= start_inclusive {
+                return Err(anyhow!(
+                    "Invalid range, start ({}) is bigger then end ({:?})",
+                    start_inclusive,
+                    end_exclusive
+                )
+                .into());
+            }
+        }
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        self.inner
+            .download_byte_range(from, 0, Some(HEADER_LEN as u64), &mut header)
+            .await?;
+        if !header.starts_with(MAGIC) {
+            encryption.check_plaintext_read()?;
+            debug!("Object is not encrypted, reading it as plaintext");
+            let metadata = self
+                .inner
+                .download_byte_range(from, start_inclusive, end_exclusive, to)
+                .await?;
+            return Ok(strip_encryption_metadata(metadata));
+        }
+        let header = ObjectHeader::parse(&header)?;
+        let data_key = encryption.unwrap_data_key(&header)?;
+
+        // Download the chunks covering the range only
+        let chunk_size = CHUNK_SIZE as u64;
+        let encrypted_chunk_size = ENCRYPTED_CHUNK_SIZE as u64;
+        let first_chunk = start_inclusive / chunk_size;
+        let encrypted_start = HEADER_LEN as u64 + first_chunk * encrypted_chunk_size;
+        let encrypted_end = end_exclusive.map(|end_exclusive| {
+            HEADER_LEN as u64
+                + ((end_exclusive + chunk_size - 1) / chunk_size) * encrypted_chunk_size
+        });
+
+        let (pipe_writer, mut pipe_reader) = io::duplex(2 * ENCRYPTED_CHUNK_SIZE);
+        let download = async {
+            let mut pipe_writer = pipe_writer;
+            let metadata = self
+                .inner
+                .download_byte_range(from, encrypted_start, encrypted_end, &mut pipe_writer)
+                .await?;
+            pipe_writer
+                .shutdown()
+                .await
+                .context("Failed to finish the encrypted object range download")?;
+            Ok::<_, RemoteStorageError>(metadata)
+        };
+        let first_chunk = u32::try_from(first_chunk).context("Range start is too large")?;
+        let decrypt = decrypt_chunks(
+            &mut pipe_reader,
+            &data_key,
+            header.nonce_prefix,
+            first_chunk,
+            start_inclusive - u64::from(first_chunk) * chunk_size,
+            end_exclusive.map(|end_exclusive| end_exclusive - start_inclusive),
+            end_exclusive.is_none(),
+            to,
+        );
+        let decrypt = async { decrypt.await.map_err(RemoteStorageError::Permanent) };
+        let (metadata, ()) = tokio::try_join!(download, decrypt)?;
+        Ok(strip_encryption_metadata(metadata))
+    }
+
+    async fn delete(&self, path: &Self::RemoteObjectId) -> Result<(), RemoteStorageError> {
+        self.inner.delete(path).await
+    }
+
+    async fn abort_stale_uploads(&self) -> Result<(), RemoteStorageError> {
+        self.inner.abort_stale_uploads().await
+    }
+}
+
+impl Encryption {
+    fn check_plaintext_read(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.allow_plaintext_reads,
+            "Object is not encrypted, and plaintext reads are not allowed"
+        );
+        Ok(())
+    }
+
+    fn generate_data_key(&self) -> anyhow::Result<DataKey> {
+        let mut key = [0; DATA_KEY_LEN];
+        let mut wrapped = [0; WRAPPED_DATA_KEY_LEN];
+        self.rng
+            .fill(&mut key)
+            .and_then(|()| self.rng.fill(&mut wrapped[..NONCE_LEN]))
+            .map_err(|_| anyhow!("Failed to generate a data key"))?;
+        let (wrap_nonce, wrapped_key) = wrapped.split_at_mut(NONCE_LEN);
+        let (wrapped_key, wrap_tag) = wrapped_key.split_at_mut(DATA_KEY_LEN);
+        wrapped_key.copy_from_slice(&key);
+        let tag = Nonce::try_assume_unique_for_key(wrap_nonce)
+            .and_then(|wrap_nonce| {
+                self.master_key.seal_in_place_separate_tag(
+                    wrap_nonce,
+                    Aad::from(self.master_key_id.as_bytes()),
+                    wrapped_key,
+                )
+            })
+            .map_err(|_| anyhow!("Failed to wrap a data key"))?;
+        wrap_tag.copy_from_slice(tag.as_ref());
+
+        Ok(DataKey {
+            key: aes_key(&key),
+            wrapped,
+        })
+    }
+
+    fn unwrap_data_key(&self, header: &ObjectHeader) -> anyhow::Result<LessSafeKey> {
+        self.unwrap_key(&header.master_key_id, header.wrapped_data_key)
+    }
+
+    fn unwrap_key(
+        &self,
+        master_key_id: &str,
+        mut wrapped: [u8; WRAPPED_DATA_KEY_LEN],
+    ) -> anyhow::Result<LessSafeKey> {
+        ensure!(
+            master_key_id == self.master_key_id,
+            "Object is encrypted with master key '{}', but only '{}' is configured",
+            master_key_id,
+            self.master_key_id
+        );
+        let (wrap_nonce, wrapped_key) = wrapped.split_at_mut(NONCE_LEN);
+        let key = Nonce::try_assume_unique_for_key(wrap_nonce)
+            .and_then(|wrap_nonce| {
+                self.master_key.open_in_place(
+                    wrap_nonce,
+                    Aad::from(self.master_key_id.as_bytes()),
+                    wrapped_key,
+                )
+            })
+            .map_err(|_| anyhow!("Failed to unwrap the data key with the master key"))?;
+        Ok(aes_key(key))
+    }
+}
+
+impl ObjectHeader {
+    fn serialize(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&serialize_key_id(&self.master_key_id));
+        header.extend_from_slice(&self.wrapped_data_key);
+        header.extend_from_slice(&self.nonce_prefix);
+        header
+    }
+
+    fn parse(header: &[u8]) -> anyhow::Result<Self> {
+        ensure!(
+            header.len() == HEADER_LEN && header.starts_with(MAGIC),
+            "Object header is corrupt"
+        );
+        let (key_id, rest) = header[MAGIC.len()..].split_at(MAX_KEY_ID_LEN);
+        let (wrapped_data_key, nonce_prefix) = rest.split_at(WRAPPED_DATA_KEY_LEN);
+
+        Ok(Self {
+            master_key_id: parse_key_id(key_id).context("Object header is corrupt")?,
+            wrapped_data_key: wrapped_data_key.try_into()?,
+            nonce_prefix: nonce_prefix.try_into()?,
+        })
+    }
+}
+
+impl StoredDataKey {
+    fn serialize(&self) -> Vec<u8> {
+        let mut stored = Vec::with_capacity(STORED_DATA_KEY_LEN);
+        stored.extend_from_slice(DATA_KEY_MAGIC);
+        stored.extend_from_slice(&serialize_key_id(&self.master_key_id));
+        stored.extend_from_slice(&self.wrapped_data_key);
+        stored
+    }
+
+    fn parse(stored: &[u8]) -> anyhow::Result<Self> {
+        ensure!(
+            stored.len() == STORED_DATA_KEY_LEN && stored.starts_with(DATA_KEY_MAGIC),
+            "Stored data key is corrupt"
+        );
+        let (key_id, wrapped_data_key) = stored[DATA_KEY_MAGIC.len()..].split_at(MAX_KEY_ID_LEN);
+        Ok(Self {
+            master_key_id: parse_key_id(key_id)?,
+            wrapped_data_key: wrapped_data_key.try_into()?,
+        })
+    }
+}
+
+fn serialize_key_id(key_id: &str) -> [u8; MAX_KEY_ID_LEN] {
+    let mut serialized = [0; MAX_KEY_ID_LEN];
+    serialized[..key_id.len()].copy_from_slice(key_id.as_bytes());
+    serialized
+}
+
+fn parse_key_id(serialized: &[u8]) -> anyhow::Result<String> {
+    let key_id_len = serialized
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(MAX_KEY_ID_LEN);
+    String::from_utf8(serialized[..key_id_len].to_vec()).context("Invalid master key id")
+}
+
+fn aes_key(key: &[u8]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).expect("AES-256 key has proper length"))
+}
+
+/// Path of the data key object for the given object: in the tenant directory, if the path has a component
+/// looking like a tenant id (pageserver keeps tenant files under `tenants/<tenant_id>/`, safekeeper under `<tenant_id>/`),
+/// next to the object otherwise.
+fn data_key_path(local_path: &Path) -> PathBuf {
+    let mut tenant_path = PathBuf::new();
+    for component in local_path.iter() {
+        tenant_path.push(component);
+        let is_tenant_id = component
+            .to_str()
+            .map(|component| {
+                component.len() == 32 && component.chars().all(|c| c.is_ascii_hexdigit())
+            })
+            .unwrap_or(false);
+        if is_tenant_id {
+            return tenant_path.join(DATA_KEY_FILE_NAME);
+        }
+    }
+    local_path.with_file_name(DATA_KEY_FILE_NAME)
+}
+
+fn chunk_count(plaintext_size: usize) -> usize {
+    // Empty plaintext still gets a chunk, to tell it from a truncated object
+    std::cmp::max(1, (plaintext_size + CHUNK_SIZE - 1) / CHUNK_SIZE)
+}
+
+fn encrypted_size(plaintext_size: usize) -> usize {
+    HEADER_LEN + plaintext_size + TAG_LEN * chunk_count(plaintext_size)
+}
+
+fn chunk_nonce(nonce_prefix: [u8; NONCE_PREFIX_LEN], chunk_index: u32) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(&nonce_prefix);
+    nonce[NONCE_PREFIX_LEN..].copy_from_slice(&chunk_index.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+async fn encrypt_chunks(
+    from: &mut (impl io::AsyncRead + Unpin),
+    plaintext_size: usize,
+    key: &LessSafeKey,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    to: &mut (impl io::AsyncWrite + Unpin),
+) -> anyhow::Result<()> {
+    let chunk_count =
+        u32::try_from(chunk_count(plaintext_size)).context("File is too large to encrypt")?;
+    let mut chunk = Vec::with_capacity(ENCRYPTED_CHUNK_SIZE);
+    for chunk_index in 0..chunk_count {
+        let chunk_start = chunk_index as usize * CHUNK_SIZE;
+        chunk.resize(std::cmp::min(CHUNK_SIZE, plaintext_size - chunk_start), 0);
+        from.read_exact(&mut chunk)
+            .await
+            .context("Failed to read the file to encrypt")?;
+
+        let is_last = chunk_index + 1 == chunk_count;
+        key.seal_in_place_append_tag(
+            chunk_nonce(nonce_prefix, chunk_index),
+            Aad::from([is_last as u8]),
+            &mut chunk,
+        )
+        .map_err(|_| anyhow!("Failed to encrypt chunk {chunk_index}"))?;
+        to.write_all(&chunk).await?;
+    }
+    Ok(())
+}
+
+/// Decrypts the chunks, starting from `first_chunk_index`, writing `len` bytes (or everything) of the
+/// plaintext after skipping `skip` bytes. If the chunks are known to end at the object end, the last
+/// chunk read has to be the object's last chunk, otherwise it's checked for being either.
+#[allow(clippy::too_many_arguments)]
+async fn decrypt_chunks(
+    from: &mut (impl io::AsyncRead + Unpin),
+    key: &LessSafeKey,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    first_chunk_index: u32,
+    mut skip: u64,
+    mut len: Option<u64>,
+    ends_at_object_end: bool,
+    to: &mut (impl io::AsyncWrite + Unpin),
+) -> anyhow::Result<()> {
+    let mut chunk = read_encrypted_chunk(from).await?;
+    ensure!(
+        !chunk.is_empty(),
+        "No encrypted data in the object at the requested position"
+    );
+
+    let mut chunk_index = first_chunk_index;
+    loop {
+        let next_chunk = if chunk.len() == ENCRYPTED_CHUNK_SIZE {
+            read_encrypted_chunk(from).await?
+        } else {
+            Vec::new()
+        };
+        let is_last = next_chunk.is_empty();
+        // A full chunk at the end of a range download is not necessarily the object's last one
+        let may_be_not_last = !ends_at_object_end && chunk.len() == ENCRYPTED_CHUNK_SIZE;
+
+        let mut candidates = vec![is_last];
+        if is_last && may_be_not_last {
+            candidates.insert(0, false);
+        }
+        let mut plaintext = None;
+        for is_last in candidates {
+            let mut attempt = chunk.clone();
+            if let Ok(opened) = key.open_in_place(
+                chunk_nonce(nonce_prefix, chunk_index),
+                Aad::from([is_last as u8]),
+                &mut attempt,
+            ) {
+                let opened_len = opened.len();
+                attempt.truncate(opened_len);
+                plaintext = Some(attempt);
+                break;
+            }
+        }
+        let plaintext = plaintext.with_context(|| {
+            format!("Failed to decrypt chunk {chunk_index}, the object is corrupt or truncated")
+        })?;
+
+        let from_offset = std::cmp::min(skip, plaintext.len() as u64) as usize;
+        skip -= from_offset as u64;
+        let mut to_offset = plaintext.len();
+        if let Some(len) = len.as_mut() {
+            to_offset = std::cmp::min(to_offset as u64, from_offset as u64 + *len) as usize;
+            *len -= (to_offset - from_offset) as u64;
+        }
+        to.write_all(&plaintext[from_offset..to_offset]).await?;
+
+        if is_last {
+            break;
+        }
+        chunk = next_chunk;
+        chunk_index = chunk_index
+            .checked_add(1)
+            .context("Too many chunks in the encrypted object")?;
+    }
+    to.flush().await?;
+    Ok(())
+}
+
+/// Reads a full encrypted chunk, or less, if the stream ends before that.
+async fn read_encrypted_chunk(from: &mut (impl io::AsyncRead + Unpin)) -> anyhow::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(ENCRYPTED_CHUNK_SIZE);
+    from.take(ENCRYPTED_CHUNK_SIZE as u64)
+        .read_to_end(&mut chunk)
+        .await
+        .context("Failed to read the encrypted object")?;
+    Ok(chunk)
+}
+
+fn strip_encryption_metadata(metadata: Option<StorageMetadata>) -> Option<StorageMetadata> {
+    let mut metadata = metadata?.0;
+    metadata.remove(ENCRYPTION_KEY_ID_METADATA_KEY);
+    if metadata.is_empty() {
+        None
+    } else {
+        Some(StorageMetadata(metadata))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::LocalFs;
+
+    const TENANT_1: &str = "0a0b0c0d0e0f00010203040506070809";
+    const TENANT_2: &str = "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff";
+    const MASTER_KEY: [u8; DATA_KEY_LEN] = [7; DATA_KEY_LEN];
+
+    struct TestStorage {
+        workdir: PathBuf,
+        storage: EncryptedStorage<LocalFs>,
+    }
+
+    fn create_storage() -> anyhow::Result<TestStorage> {
+        let workdir = tempdir()?.path().to_owned();
+        let storage = EncryptedStorage::with_master_key(
+            LocalFs::new(tempdir()?.path().to_owned(), workdir.clone())?,
+            "test-key",
+            &MASTER_KEY,
+            false,
+        )?;
+        Ok(TestStorage { workdir, storage })
+    }
+
+    fn layer_path(workdir: &Path, tenant_id: &str, name: &str) -> PathBuf {
+        workdir
+            .join("tenants")
+            .join(tenant_id)
+            .join("timelines")
+            .join("some_timeline")
+            .join(name)
+    }
+
+    async fn upload(
+        storage: &EncryptedStorage<LocalFs>,
+        local_path: &Path,
+        contents: &[u8],
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<PathBuf> {
+        let remote_path = storage.remote_object_id(local_path)?;
+        storage
+            .upload(
+                std::io::Cursor::new(contents.to_vec()),
+                contents.len(),
+                &remote_path,
+                metadata,
+            )
+            .await?;
+        Ok(remote_path)
+    }
+
+    fn test_contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn full_and_range_downloads() -> anyhow::Result<()> {
+        let TestStorage { workdir, storage } = create_storage()?;
+
+        for len in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            2 * CHUNK_SIZE,
+            3 * CHUNK_SIZE + 100,
+        ] {
+            let contents = test_contents(len);
+            let remote_path = upload(
+                &storage,
+                &layer_path(&workdir, TENANT_1, &format!("layer_{len}")),
+                &contents,
+                None,
+            )
+            .await?;
+
+            let stored = std::fs::read(&remote_path)?;
+            assert_eq!(stored.len(), encrypted_size(len));
+            if len > 0 {
+                assert!(
+                    !stored[HEADER_LEN..]
+                        .windows(len.min(64))
+                        .any(|w| w == &contents[..len.min(64)]),
+                    "Plaintext should not be stored"
+                );
+            }
+
+            let mut downloaded: Vec<u8> = Vec::new();
+            let metadata = storage.download(&remote_path, &mut downloaded).await?;
+            assert!(downloaded == contents, "Full download of {len} bytes");
+            assert!(metadata.is_none(), "Encryption metadata should be hidden");
+
+            let ranges = [
+                (0, None),
+                (0, Some(1)),
+                (10, Some(CHUNK_SIZE as u64 + 10)),
+                (CHUNK_SIZE as u64 - 1, Some(CHUNK_SIZE as u64 + 1)),
+                (CHUNK_SIZE as u64, None),
+                (2 * CHUNK_SIZE as u64 + 5, Some(3 * CHUNK_SIZE as u64)),
+                (len as u64 / 2, None),
+                (len as u64 / 3, Some(len as u64)),
+            ];
+            for (start, end) in ranges {
+                if start >= len as u64 || end.map_or(false, |end| end > len as u64) {
+                    continue;
+                }
+                let mut downloaded: Vec<u8> = Vec::new();
+                storage
+                    .download_byte_range(&remote_path, start, end, &mut downloaded)
+                    .await?;
+                let end = end.unwrap_or(len as u64);
+                assert!(
+                    downloaded == contents[start as usize..end as usize],
+                    "Range {start}..{end} download of {len} bytes"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metadata_and_tenant_keys() -> anyhow::Result<()> {
+        let TestStorage { workdir, storage } = create_storage()?;
+        let contents = test_contents(1000);
+
+        let metadata = StorageMetadata(HashMap::from([("key".to_string(), "value".to_string())]));
+        let remote_path_1 = upload(
+            &storage,
+            &layer_path(&workdir, TENANT_1, "layer_1"),
+            &contents,
+            Some(metadata.clone()),
+        )
+        .await?;
+        let remote_path_2 = upload(
+            &storage,
+            &layer_path(&workdir, TENANT_1, "layer_2"),
+            &contents,
+            None,
+        )
+        .await?;
+        let remote_path_3 = upload(
+            &storage,
+            &layer_path(&workdir, TENANT_2, "layer_1"),
+            &contents,
+            None,
+        )
+        .await?;
+
+        let stored_metadata = storage
+            .inner
+            .download(&remote_path_1, &mut Vec::<u8>::new())
+            .await?;
+        assert_eq!(
+            stored_metadata
+                .as_ref()
+                .and_then(|m| m.0.get(ENCRYPTION_KEY_ID_METADATA_KEY))
+                .map(String::as_str),
+            Some("test-key"),
+            "Master key id should be stored in the metadata"
+        );
+        assert_eq!(
+            storage
+                .download(&remote_path_1, &mut Vec::<u8>::new())
+                .await?,
+            Some(metadata),
+            "User metadata should be returned as is"
+        );
+
+        let header = |remote_path: &Path| -> anyhow::Result<ObjectHeader> {
+            ObjectHeader::parse(&std::fs::read(remote_path)?[..HEADER_LEN])
+        };
+        let (header_1, header_2, header_3) = (
+            header(&remote_path_1)?,
+            header(&remote_path_2)?,
+            header(&remote_path_3)?,
+        );
+        assert_eq!(
+            header_1.wrapped_data_key, header_2.wrapped_data_key,
+            "Same tenant objects should share the data key"
+        );
+        assert_ne!(
+            header_1.wrapped_data_key, header_3.wrapped_data_key,
+            "Different tenants should have different data keys"
+        );
+        assert_ne!(
+            header_1.nonce_prefix, header_2.nonce_prefix,
+            "Every object should have its own nonces"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_objects() -> anyhow::Result<()> {
+        let TestStorage { workdir, storage } = create_storage()?;
+        let contents = test_contents(2 * CHUNK_SIZE);
+        let remote_path = upload(
+            &storage,
+            &layer_path(&workdir, TENANT_1, "layer"),
+            &contents,
+            None,
+        )
+        .await?;
+        let stored = std::fs::read(&remote_path)?;
+
+        // Flipped bit in the data
+        let mut corrupt = stored.clone();
+        corrupt[HEADER_LEN + 100] ^= 1;
+        std::fs::write(&remote_path, &corrupt)?;
+        assert!(storage
+            .download(&remote_path, &mut Vec::<u8>::new())
+            .await
+            .is_err());
+        assert!(storage
+            .download_byte_range(&remote_path, 0, Some(10), &mut Vec::<u8>::new())
+            .await
+            .is_err());
+
+        // Last chunk removed
+        std::fs::write(&remote_path, &stored[..HEADER_LEN + ENCRYPTED_CHUNK_SIZE])?;
+        assert!(storage
+            .download(&remote_path, &mut Vec::<u8>::new())
+            .await
+            .is_err());
+        assert!(storage
+            .download_byte_range(&remote_path, 10, None, &mut Vec::<u8>::new())
+            .await
+            .is_err());
+
+        // Chunks swapped
+        let mut swapped = stored[..HEADER_LEN].to_vec();
+        swapped.extend_from_slice(&stored[HEADER_LEN + ENCRYPTED_CHUNK_SIZE..]);
+        swapped.extend_from_slice(&stored[HEADER_LEN..HEADER_LEN + ENCRYPTED_CHUNK_SIZE]);
+        std::fs::write(&remote_path, &swapped)?;
+        assert!(storage
+            .download(&remote_path, &mut Vec::<u8>::new())
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plaintext_objects() -> anyhow::Result<()> {
+        let TestStorage { workdir, storage } = create_storage()?;
+        let EncryptedStorage { inner, .. } = storage;
+        let migrating_storage =
+            EncryptedStorage::with_master_key(inner, "test-key", &MASTER_KEY, true)?;
+
+        // Objects stored before the encryption was enabled
+        for len in [0, 10, HEADER_LEN + 10, 2 * CHUNK_SIZE] {
+            let contents = test_contents(len);
+            let local_path = layer_path(&workdir, TENANT_1, &format!("layer_{len}"));
+            let remote_path = migrating_storage.remote_object_id(&local_path)?;
+            migrating_storage
+                .inner
+                .upload(
+                    std::io::Cursor::new(contents.clone()),
+                    len,
+                    &remote_path,
+                    None,
+                )
+                .await?;
+
+            let mut downloaded: Vec<u8> = Vec::new();
+            migrating_storage
+                .download(&remote_path, &mut downloaded)
+                .await?;
+            assert!(downloaded == contents, "Full download of {len} bytes");
+
+            if len > 5 {
+                let mut downloaded: Vec<u8> = Vec::new();
+                migrating_storage
+                    .download_byte_range(&remote_path, 5, Some(len as u64), &mut downloaded)
+                    .await?;
+                assert!(downloaded == contents[5..], "Range download of {len} bytes");
+            }
+        }
+
+        // Without the migration flag, plaintext objects are rejected
+        let EncryptedStorage { inner, .. } = migrating_storage;
+        let storage = EncryptedStorage::with_master_key(inner, "test-key", &MASTER_KEY, false)?;
+        for len in [0, 10, HEADER_LEN + 10, 2 * CHUNK_SIZE] {
+            let local_path = layer_path(&workdir, TENANT_1, &format!("layer_{len}"));
+            let remote_path = storage.remote_object_id(&local_path)?;
+
+            let error = storage
+                .download(&remote_path, &mut Vec::<u8>::new())
+                .await
+                .unwrap_err();
+            assert!(
+                error
+                    .to_string()
+                    .contains("plaintext reads are not allowed"),
+                "Unexpected error for {len} bytes: {error}"
+            );
+            assert!(storage
+                .download_byte_range(&remote_path, 0, Some(5), &mut Vec::<u8>::new())
+                .await
+                .is_err());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn data_key_survives_restart() -> anyhow::Result<()> {
+        let TestStorage { workdir, storage } = create_storage()?;
+        let contents = test_contents(1000);
+        let remote_path_1 = upload(
+            &storage,
+            &layer_path(&workdir, TENANT_1, "layer_1"),
+            &contents,
+            None,
+        )
+        .await?;
+        let listed = storage.list().await?;
+        assert!(listed.contains(&remote_path_1));
+        assert!(
+            listed
+                .iter()
+                .all(|path| path.file_name() != Some(OsStr::new(DATA_KEY_FILE_NAME))),
+            "Data key object should not be listed"
+        );
+
+        let EncryptedStorage { inner, .. } = storage;
+        let restarted_storage =
+            EncryptedStorage::with_master_key(inner, "test-key", &MASTER_KEY, false)?;
+        let remote_path_2 = upload(
+            &restarted_storage,
+            &layer_path(&workdir, TENANT_1, "layer_2"),
+            &contents,
+            None,
+        )
+        .await?;
+
+        let header = |remote_path: &Path| -> anyhow::Result<ObjectHeader> {
+            ObjectHeader::parse(&std::fs::read(remote_path)?[..HEADER_LEN])
+        };
+        assert_eq!(
+            header(&remote_path_1)?.wrapped_data_key,
+            header(&remote_path_2)?.wrapped_data_key,
+            "Tenant data key should be reused after the restart"
+        );
+
+        // Master key rotation replaces the data key, objects with the old one are read with the old master key only
+        let EncryptedStorage { inner, .. } = restarted_storage;
+        let rotated_storage =
+            EncryptedStorage::with_master_key(inner, "new-key", &[8; DATA_KEY_LEN], false)?;
+        let remote_path_3 = upload(
+            &rotated_storage,
+            &layer_path(&workdir, TENANT_1, "layer_3"),
+            &contents,
+            None,
+        )
+        .await?;
+        let header_3 = header(&remote_path_3)?;
+        assert_eq!(header_3.master_key_id, "new-key");
+        assert_ne!(
+            header_3.wrapped_data_key,
+            header(&remote_path_1)?.wrapped_data_key
+        );
+        let mut downloaded: Vec<u8> = Vec::new();
+        rotated_storage
+            .download(&remote_path_3, &mut downloaded)
+            .await?;
+        assert!(downloaded == contents);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrong_master_key() -> anyhow::Result<()> {
+        let TestStorage { workdir, storage } = create_storage()?;
+        let remote_path = upload(
+            &storage,
+            &layer_path(&workdir, TENANT_1, "layer"),
+            &test_contents(100),
+            None,
+        )
+        .await?;
+
+        let EncryptedStorage { inner, .. } = storage;
+        let other_key_storage =
+            EncryptedStorage::with_master_key(inner, "test-key", &[8; DATA_KEY_LEN], false)?;
+        assert!(other_key_storage
+            .download(&remote_path, &mut Vec::<u8>::new())
+            .await
+            .is_err());
+
+        let EncryptedStorage { inner, .. } = other_key_storage;
+        let other_key_id_storage =
+            EncryptedStorage::with_master_key(inner, "other-key", &MASTER_KEY, false)?;
+        let error = other_key_id_storage
+            .download(&remote_path, &mut Vec::<u8>::new())
+            .await
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("encrypted with master key 'test-key'"),
+            "Unexpected error: {error}"
+        );
+
+        Ok(())
+    }
+}
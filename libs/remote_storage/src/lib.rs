@@ -4,41 +4,93 @@
 //! [`RemoteStorage`] trait a CRUD-like generic abstraction to use for adapting external storages with a few implementations:
 //!   * [`local_fs`] allows to use local file system as an external storage
 //!   * [`s3_bucket`] uses AWS S3 bucket as an external storage
+//!   * [`azure_blob`] uses Azure Blob Storage container as an external storage
+//!   * [`gcs_bucket`] uses Google Cloud Storage bucket as an external storage
 //!
+//! [`encryption`] wraps any of the storages to encrypt the objects on the client side.
+//!
+//! Storage operations fail with [`RemoteStorageError`], telling the missing objects and the transient failures apart from the others.
+//! Remote storages retry the transient failures themselves, see [`retry`].
+//!
+mod azure_blob;
+mod encryption;
+mod gcs_bucket;
 mod local_fs;
+mod retry;
 mod s3_bucket;
 
 use std::{
     borrow::Cow,
     collections::HashMap,
     ffi::OsStr,
-    num::{NonZeroU32, NonZeroUsize},
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
     path::{Path, PathBuf},
 };
 
-use anyhow::{bail, Context};
+use anyhow::{bail, ensure, Context};
 
 use tokio::io;
 use toml_edit::Item;
 use tracing::info;
 
 pub use self::{
+    azure_blob::{AzureBlob, AzureBlobName},
+    encryption::{EncryptedStorage, ENCRYPTION_KEY_ENV_VAR, ENCRYPTION_KEY_ID_METADATA_KEY},
+    gcs_bucket::{GcsBucket, GcsObjectName},
     local_fs::LocalFs,
+    retry::upload_with_retries,
     s3_bucket::{S3Bucket, S3ObjectKey},
 };
 
+/// An error of a [`RemoteStorage`] operation, classified by whether it makes sense to retry the operation.
+#[derive(Debug, thiserror::Error)]
+pub enum RemoteStorageError {
+    /// The object requested does not exist in the storage.
+    #[error("Remote storage object not found")]
+    NotFound,
+    /// The storage asked to reduce the request rate.
+    #[error("Remote storage request throttled: {0:#}")]
+    Throttled(anyhow::Error),
+    /// The failure may go away on its own: network errors, timeouts, storage server errors.
+    #[error("Transient remote storage error: {0:#}")]
+    Transient(anyhow::Error),
+    /// Any other failure, not going away on retries.
+    #[error(transparent)]
+    Permanent(#[from] anyhow::Error),
+}
+
+impl RemoteStorageError {
+    /// Whether the same operation may succeed, if retried later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Throttled(_) | Self::Transient(_))
+    }
+}
+
 /// How many different timelines can be processed simultaneously when synchronizing layers with the remote storage.
 /// During regular work, pageserver produces one layer file per timeline checkpoint, with bursts of concurrency
 /// during start (where local and remote timelines are compared and initial sync tasks are scheduled) and timeline attach.
 /// Both cases may trigger timeline download, that might download a lot of layers. This concurrency is limited by the clients internally, if needed.
 pub const DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_SYNCS: usize = 50;
 pub const DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS: u32 = 10;
+/// Every timeline index update is stored as a new version, the latest ones are kept to roll the timeline back to.
+/// Layers, removed from the timeline, stay in the remote storage until no kept version references them.
+pub const DEFAULT_REMOTE_STORAGE_MAX_INDEX_PART_VERSIONS: usize = 10;
 /// Currently, sync happens with AWS S3, that has two limits on requests per second:
 /// ~200 RPS for IAM services
 /// https://docs.aws.amazon.com/AmazonRDS/latest/AuroraUserGuide/UsingWithRDS.IAMDBAuth.html
 /// ~3500 PUT/COPY/POST/DELETE or 5500 GET/HEAD S3 requests
 /// https://aws.amazon.com/premiumsupport/knowledge-center/s3-request-limit-avoid-throttling/
 pub const DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT: usize = 100;
+/// Files larger than this are uploaded to S3 in parts, using the multipart upload API,
+/// so that a failed upload can be resumed from the parts already stored in S3.
+/// With the default checkpoint distance, this splits a regular layer file into a few parts.
+pub const DEFAULT_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE: usize = 16 * 1024 * 1024;
+/// S3 rejects multipart upload parts smaller than 5 MiB, except the last one.
+/// https://docs.aws.amazon.com/AmazonS3/latest/userguide/qfacts.html
+pub const MIN_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE: usize = 5 * 1024 * 1024;
+/// Every part being uploaded is kept in memory, so the part size multiplied by this number
+/// is the memory required to upload a single file.
+pub const DEFAULT_REMOTE_STORAGE_S3_MULTIPART_CONCURRENCY: usize = 4;
 
 /// Storage (potentially remote) API to manage its state.
 /// This storage tries to be unaware of any layered repository context,
@@ -55,7 +107,7 @@ pub trait RemoteStorage: Send + Sync {
     fn local_path(&self, remote_object_id: &Self::RemoteObjectId) -> anyhow::Result<PathBuf>;
 
     /// Lists all items the storage has right now.
-    async fn list(&self) -> anyhow::Result<Vec<Self::RemoteObjectId>>;
+    async fn list(&self) -> Result<Vec<Self::RemoteObjectId>, RemoteStorageError>;
 
     /// Streams the local file contents into remote into the remote storage entry.
     async fn upload(
@@ -66,7 +118,7 @@ pub trait RemoteStorage: Send + Sync {
         from_size_bytes: usize,
         to: &Self::RemoteObjectId,
         metadata: Option<StorageMetadata>,
-    ) -> anyhow::Result<()>;
+    ) -> Result<(), RemoteStorageError>;
 
     /// Streams the remote storage entry contents into the buffered writer given, returns the filled writer.
     /// Returns the metadata, if any was stored with the file previously.
@@ -74,7 +126,7 @@ pub trait RemoteStorage: Send + Sync {
         &self,
         from: &Self::RemoteObjectId,
         to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
-    ) -> anyhow::Result<Option<StorageMetadata>>;
+    ) -> Result<Option<StorageMetadata>, RemoteStorageError>;
 
     /// Streams a given byte range of the remote storage entry contents into the buffered writer given, returns the filled writer.
     /// Returns the metadata, if any was stored with the file previously.
@@ -84,16 +136,25 @@ pub trait RemoteStorage: Send + Sync {
         start_inclusive: u64,
         end_exclusive: Option<u64>,
         to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
-    ) -> anyhow::Result<Option<StorageMetadata>>;
+    ) -> Result<Option<StorageMetadata>, RemoteStorageError>;
 
-    async fn delete(&self, path: &Self::RemoteObjectId) -> anyhow::Result<()>;
+    async fn delete(&self, path: &Self::RemoteObjectId) -> Result<(), RemoteStorageError>;
+
+    /// Cleans up the leftovers of the uploads, interrupted before the storage was created, if the storage keeps any.
+    /// Supposed to be called on startup, before any uploads begin.
+    async fn abort_stale_uploads(&self) -> Result<(), RemoteStorageError> {
+        Ok(())
+    }
 }
 
 /// Every storage, currently supported.
 /// Serves as a simple way to pass around the [`RemoteStorage`] without dealing with generics.
+/// Every storage is wrapped with [`EncryptedStorage`], which passes the data through as is, if no encryption is configured.
 pub enum GenericRemoteStorage {
-    Local(LocalFs),
-    S3(S3Bucket),
+    Local(EncryptedStorage<LocalFs>),
+    S3(EncryptedStorage<S3Bucket>),
+    Azure(EncryptedStorage<AzureBlob>),
+    Gcs(EncryptedStorage<GcsBucket>),
 }
 
 impl GenericRemoteStorage {
@@ -101,15 +162,48 @@ impl GenericRemoteStorage {
         working_directory: PathBuf,
         storage_config: &RemoteStorageConfig,
     ) -> anyhow::Result<Self> {
+        let encryption = storage_config.encryption.as_ref();
+        if let Some(encryption) = encryption {
+            info!(
+                "Encrypting remote storage objects with master key '{}'",
+                encryption.key_id
+            );
+        }
         match &storage_config.storage {
             RemoteStorageKind::LocalFs(root) => {
                 info!("Using fs root '{}' as a remote storage", root.display());
-                LocalFs::new(root.clone(), working_directory).map(GenericRemoteStorage::Local)
+                LocalFs::new(root.clone(), working_directory)
+                    .and_then(|storage| EncryptedStorage::new(storage, encryption))
+                    .map(GenericRemoteStorage::Local)
             }
             RemoteStorageKind::AwsS3(s3_config) => {
                 info!("Using s3 bucket '{}' in region '{}' as a remote storage, prefix in bucket: '{:?}', bucket endpoint: '{:?}'",
                     s3_config.bucket_name, s3_config.bucket_region, s3_config.prefix_in_bucket, s3_config.endpoint);
-                S3Bucket::new(s3_config, working_directory).map(GenericRemoteStorage::S3)
+                S3Bucket::new(s3_config, working_directory)
+                    .map(|storage| {
+                        // Every upload attempt is encrypted with a new nonce, no parts of the previous attempt match
+                        if encryption.is_some() {
+                            storage.without_upload_resumption()
+                        } else {
+                            storage
+                        }
+                    })
+                    .and_then(|storage| EncryptedStorage::new(storage, encryption))
+                    .map(GenericRemoteStorage::S3)
+            }
+            RemoteStorageKind::AzureContainer(azure_config) => {
+                info!("Using azure container '{}' in storage account '{:?}' as a remote storage, prefix in container: '{:?}', endpoint: '{:?}'",
+                    azure_config.container_name, azure_config.storage_account, azure_config.prefix_in_container, azure_config.endpoint);
+                AzureBlob::new(azure_config, working_directory)
+                    .and_then(|storage| EncryptedStorage::new(storage, encryption))
+                    .map(GenericRemoteStorage::Azure)
+            }
+            RemoteStorageKind::Gcs(gcs_config) => {
+                info!("Using gcs bucket '{}' as a remote storage, prefix in bucket: '{:?}', endpoint: '{:?}', credentials file: '{:?}'",
+                    gcs_config.bucket_name, gcs_config.prefix_in_bucket, gcs_config.endpoint, gcs_config.credentials_file);
+                GcsBucket::new(gcs_config, working_directory)
+                    .and_then(|storage| EncryptedStorage::new(storage, encryption))
+                    .map(GenericRemoteStorage::Gcs)
             }
         }
     }
@@ -120,6 +214,17 @@ impl GenericRemoteStorage {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StorageMetadata(HashMap<String, String>);
 
+impl StorageMetadata {
+    pub fn new(metadata: HashMap<String, String>) -> Self {
+        Self(metadata)
+    }
+
+    /// Returns the value stored under the given key, if any.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+}
+
 fn strip_path_prefix<'a>(prefix: &'a Path, path: &'a Path) -> anyhow::Result<&'a Path> {
     if prefix == path {
         anyhow::bail!(
@@ -143,9 +248,30 @@ pub struct RemoteStorageConfig {
     /// Max allowed number of concurrent sync operations between the API user and the remote storage.
     pub max_concurrent_syncs: NonZeroUsize,
     /// Max allowed errors before the sync task is considered failed and evicted.
+    /// The retryable errors, see [`RemoteStorageError::is_retryable`], are not counted.
     pub max_sync_errors: NonZeroU32,
+    /// Max bytes per second to upload into the remote storage, summed over all concurrent syncs. Unlimited if not set.
+    pub max_upload_bytes_per_second: Option<NonZeroU64>,
+    /// Max bytes per second to download from the remote storage, summed over all concurrent syncs. Unlimited if not set.
+    pub max_download_bytes_per_second: Option<NonZeroU64>,
+    /// Max number of the latest timeline index versions to keep in the remote storage.
+    pub max_index_part_versions: NonZeroUsize,
     /// The storage connection configuration.
     pub storage: RemoteStorageKind,
+    /// Client-side encryption of the stored objects, disabled if not set.
+    pub encryption: Option<EncryptionConfig>,
+}
+
+/// Client-side encryption configuration.
+/// The master key itself is taken from the [`ENCRYPTION_KEY_ENV_VAR`] environment variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionConfig {
+    /// Id of the master key, stored with every object to tell the key it's encrypted with.
+    pub key_id: String,
+    /// Read the objects without the encryption header as plaintext, instead of failing.
+    /// Meant for the migration of the objects stored before the encryption was enabled only:
+    /// with it, anybody able to write to the storage can replace encrypted objects unnoticed.
+    pub allow_plaintext_reads: bool,
 }
 
 /// A kind of a remote storage to connect to, with its connection configuration.
@@ -157,6 +283,12 @@ pub enum RemoteStorageKind {
     /// AWS S3 based storage, storing all files in the S3 bucket
     /// specified by the config
     AwsS3(S3Config),
+    /// Azure Blob Storage based storage, storing all files in the container
+    /// specified by the config
+    AzureContainer(AzureConfig),
+    /// Google Cloud Storage based storage, storing all files in the bucket
+    /// specified by the config
+    Gcs(GcsConfig),
 }
 
 /// AWS S3 bucket coordinates and access credentials to manage the bucket contents (read and write).
@@ -178,6 +310,12 @@ pub struct S3Config {
     /// AWS S3 has various limits on its API calls, we need not to exceed those.
     /// See [`DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT`] for more details.
     pub concurrency_limit: NonZeroUsize,
+    /// Files larger than this are uploaded in parts of this size, resuming from the already uploaded parts on retries.
+    /// See [`DEFAULT_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE`] for more details.
+    pub multipart_part_size: usize,
+    /// How many parts of a single file are uploaded concurrently.
+    /// See [`DEFAULT_REMOTE_STORAGE_S3_MULTIPART_CONCURRENCY`] for more details.
+    pub multipart_concurrency: NonZeroUsize,
 }
 
 impl std::fmt::Debug for S3Config {
@@ -187,10 +325,54 @@ impl std::fmt::Debug for S3Config {
             .field("bucket_region", &self.bucket_region)
             .field("prefix_in_bucket", &self.prefix_in_bucket)
             .field("concurrency_limit", &self.concurrency_limit)
+            .field("multipart_part_size", &self.multipart_part_size)
+            .field("multipart_concurrency", &self.multipart_concurrency)
             .finish()
     }
 }
 
+/// Azure Blob Storage container coordinates to manage the container contents (read and write).
+/// The storage account access key is taken from the `AZURE_STORAGE_ACCESS_KEY` environment variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AzureConfig {
+    /// Name of the container to connect to.
+    pub container_name: String,
+    /// Storage account the container belongs to.
+    /// If not set, taken from the `AZURE_STORAGE_ACCOUNT` environment variable.
+    pub storage_account: Option<String>,
+    /// A "subfolder" in the container, to use the same container separately by multiple remote storage users at once.
+    pub prefix_in_container: Option<String>,
+    /// A base URL of the blob service to send requests to.
+    /// By default, `https://<storage_account>.blob.core.windows.net` is used.
+    /// Endpoint provides a way to use Azurite or other Azure compatible storages.
+    ///
+    /// Example: `http://127.0.0.1:10000/devstoreaccount1`
+    pub endpoint: Option<String>,
+    /// Azure has limits on the request rate per storage account, we need not to exceed those.
+    pub concurrency_limit: NonZeroUsize,
+}
+
+/// Google Cloud Storage bucket coordinates to manage the bucket contents (read and write).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcsConfig {
+    /// Name of the bucket to connect to.
+    pub bucket_name: String,
+    /// A "subfolder" in the bucket, to use the same bucket separately by multiple remote storage users at once.
+    pub prefix_in_bucket: Option<String>,
+    /// A base URL of the storage API to send requests to.
+    /// By default, `https://storage.googleapis.com` is used.
+    /// Endpoint provides a way to use fake GCS servers or other GCS compatible storages.
+    ///
+    /// Example: `http://127.0.0.1:4443`
+    pub endpoint: Option<String>,
+    /// Service account key file to get the access tokens with.
+    /// If not set, taken from the `GOOGLE_APPLICATION_CREDENTIALS` environment variable;
+    /// if that is not set either, the tokens are requested from the GCE metadata server.
+    pub credentials_file: Option<PathBuf>,
+    /// GCS throttles the buckets exceeding their request rate targets, we need not to exceed those.
+    pub concurrency_limit: NonZeroUsize,
+}
+
 pub fn path_with_suffix_extension(original_path: impl AsRef<Path>, suffix: &str) -> PathBuf {
     let new_extension = match original_path
         .as_ref()
@@ -210,6 +392,8 @@ impl RemoteStorageConfig {
         let local_path = toml.get("local_path");
         let bucket_name = toml.get("bucket_name");
         let bucket_region = toml.get("bucket_region");
+        let container_name = toml.get("container_name");
+        let gcs_bucket = toml.get("gcs_bucket");
 
         let max_concurrent_syncs = NonZeroUsize::new(
             parse_optional_integer("max_concurrent_syncs", toml)?
@@ -223,6 +407,17 @@ impl RemoteStorageConfig {
         )
         .context("Failed to parse 'max_sync_errors' as a positive integer")?;
 
+        let max_upload_bytes_per_second =
+            parse_optional_positive_integer("max_upload_bytes_per_second", toml)?;
+        let max_download_bytes_per_second =
+            parse_optional_positive_integer("max_download_bytes_per_second", toml)?;
+
+        let max_index_part_versions = NonZeroUsize::new(
+            parse_optional_integer("max_index_part_versions", toml)?
+                .unwrap_or(DEFAULT_REMOTE_STORAGE_MAX_INDEX_PART_VERSIONS),
+        )
+        .context("Failed to parse 'max_index_part_versions' as a positive integer")?;
+
         let concurrency_limit = NonZeroUsize::new(
             parse_optional_integer("concurrency_limit", toml)?
                 .unwrap_or(DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT),
@@ -230,7 +425,64 @@ impl RemoteStorageConfig {
         .context("Failed to parse 'concurrency_limit' as a positive integer")?;
 
         let storage = match (local_path, bucket_name, bucket_region) {
-            (None, None, None) => bail!("no 'local_path' nor 'bucket_name' option"),
+            _ if gcs_bucket.is_some() => {
+                if local_path.is_some()
+                    || bucket_name.is_some()
+                    || bucket_region.is_some()
+                    || container_name.is_some()
+                {
+                    bail!("gcs_bucket is mutually exclusive with local_path, bucket_name and container_name")
+                }
+                RemoteStorageKind::Gcs(GcsConfig {
+                    bucket_name: parse_toml_string("gcs_bucket", gcs_bucket.unwrap())?,
+                    prefix_in_bucket: toml
+                        .get("prefix_in_bucket")
+                        .map(|prefix_in_bucket| {
+                            parse_toml_string("prefix_in_bucket", prefix_in_bucket)
+                        })
+                        .transpose()?,
+                    endpoint: toml
+                        .get("endpoint")
+                        .map(|endpoint| parse_toml_string("endpoint", endpoint))
+                        .transpose()?,
+                    credentials_file: toml
+                        .get("credentials_file")
+                        .map(|credentials_file| {
+                            parse_toml_string("credentials_file", credentials_file)
+                                .map(PathBuf::from)
+                        })
+                        .transpose()?,
+                    concurrency_limit,
+                })
+            }
+            _ if container_name.is_some() => {
+                if local_path.is_some() || bucket_name.is_some() || bucket_region.is_some() {
+                    bail!("container_name is mutually exclusive with local_path and bucket_name")
+                }
+                RemoteStorageKind::AzureContainer(AzureConfig {
+                    container_name: parse_toml_string("container_name", container_name.unwrap())?,
+                    storage_account: toml
+                        .get("storage_account")
+                        .map(|storage_account| {
+                            parse_toml_string("storage_account", storage_account)
+                        })
+                        .transpose()?,
+                    prefix_in_container: toml
+                        .get("prefix_in_container")
+                        .map(|prefix_in_container| {
+                            parse_toml_string("prefix_in_container", prefix_in_container)
+                        })
+                        .transpose()?,
+                    endpoint: toml
+                        .get("endpoint")
+                        .map(|endpoint| parse_toml_string("endpoint", endpoint))
+                        .transpose()?,
+                    concurrency_limit,
+                })
+            }
+            (None, None, None) => {
+                bail!("no 'local_path' nor 'bucket_name' nor 'container_name' nor 'gcs_bucket' option")
+            }
             (_, Some(_), None) => {
                 bail!("'bucket_region' option is mandatory if 'bucket_name' is given ")
             }
@@ -249,6 +501,12 @@ impl RemoteStorageConfig {
                     .map(|endpoint| parse_toml_string("endpoint", endpoint))
                     .transpose()?,
                 concurrency_limit,
+                multipart_part_size: parse_multipart_part_size(toml)?,
+                multipart_concurrency: NonZeroUsize::new(
+                    parse_optional_integer("multipart_concurrency", toml)?
+                        .unwrap_or(DEFAULT_REMOTE_STORAGE_S3_MULTIPART_CONCURRENCY),
+                )
+                .context("Failed to parse 'multipart_concurrency' as a positive integer")?,
             }),
             (Some(local_path), None, None) => RemoteStorageKind::LocalFs(PathBuf::from(
                 parse_toml_string("local_path", local_path)?,
@@ -256,10 +514,36 @@ impl RemoteStorageConfig {
             (Some(_), Some(_), _) => bail!("local_path and bucket_name are mutually exclusive"),
         };
 
+        let allow_plaintext_reads = toml
+            .get("encryption_allow_plaintext_reads")
+            .map(|allow| {
+                allow
+                    .as_bool()
+                    .context("configure option encryption_allow_plaintext_reads is not a bool")
+            })
+            .transpose()?;
+        let encryption = match toml.get("encryption_key_id") {
+            Some(key_id) => Some(EncryptionConfig {
+                key_id: parse_toml_string("encryption_key_id", key_id)?,
+                allow_plaintext_reads: allow_plaintext_reads.unwrap_or(false),
+            }),
+            None => {
+                ensure!(
+                    allow_plaintext_reads.is_none(),
+                    "encryption_allow_plaintext_reads requires encryption_key_id"
+                );
+                None
+            }
+        };
+
         Ok(RemoteStorageConfig {
             max_concurrent_syncs,
             max_sync_errors,
+            max_upload_bytes_per_second,
+            max_download_bytes_per_second,
+            max_index_part_versions,
             storage,
+            encryption,
         })
     }
 }
@@ -282,6 +566,27 @@ where
         .with_context(|| format!("configure option {name} is too large"))
 }
 
+fn parse_optional_positive_integer(
+    name: &str,
+    item: &toml_edit::Item,
+) -> anyhow::Result<Option<NonZeroU64>> {
+    parse_optional_integer(name, item)?
+        .map(|value| {
+            NonZeroU64::new(value)
+                .with_context(|| format!("Failed to parse '{name}' as a positive integer"))
+        })
+        .transpose()
+}
+
+fn parse_multipart_part_size(item: &toml_edit::Item) -> anyhow::Result<usize> {
+    let part_size = parse_optional_integer("multipart_part_size", item)?
+        .unwrap_or(DEFAULT_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE);
+    if part_size < MIN_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE {
+        bail!("'multipart_part_size' should be at least {MIN_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE} bytes, got {part_size}")
+    }
+    Ok(part_size)
+}
+
 fn parse_toml_string(name: &str, item: &Item) -> anyhow::Result<String> {
     let s = item
         .as_str()
@@ -293,6 +598,119 @@ fn parse_toml_string(name: &str, item: &Item) -> anyhow::Result<String> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn parse_encryption_config() -> anyhow::Result<()> {
+        let toml = "local_path = '/some/path'".parse::<toml_edit::Document>()?;
+        assert_eq!(
+            RemoteStorageConfig::from_toml(toml.as_item())?.encryption,
+            None
+        );
+
+        let toml = "local_path = '/some/path'\nencryption_key_id = 'key-1'"
+            .parse::<toml_edit::Document>()?;
+        assert_eq!(
+            RemoteStorageConfig::from_toml(toml.as_item())?.encryption,
+            Some(EncryptionConfig {
+                key_id: "key-1".to_string(),
+                allow_plaintext_reads: false,
+            })
+        );
+
+        let toml = "local_path = '/some/path'\nencryption_key_id = 'key-1'\nencryption_allow_plaintext_reads = true"
+            .parse::<toml_edit::Document>()?;
+        assert_eq!(
+            RemoteStorageConfig::from_toml(toml.as_item())?.encryption,
+            Some(EncryptionConfig {
+                key_id: "key-1".to_string(),
+                allow_plaintext_reads: true,
+            })
+        );
+
+        let toml = "local_path = '/some/path'\nencryption_allow_plaintext_reads = true"
+            .parse::<toml_edit::Document>()?;
+        assert!(RemoteStorageConfig::from_toml(toml.as_item()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_s3_multipart_config() -> anyhow::Result<()> {
+        let s3_config = |toml: &str| -> anyhow::Result<S3Config> {
+            let toml = format!("bucket_name = 'bucket'\nbucket_region = 'eu-north-1'\n{toml}")
+                .parse::<toml_edit::Document>()?;
+            match RemoteStorageConfig::from_toml(toml.as_item())?.storage {
+                RemoteStorageKind::AwsS3(s3_config) => Ok(s3_config),
+                other => bail!("Expected S3 config, got {other:?}"),
+            }
+        };
+
+        let defaults = s3_config("")?;
+        assert_eq!(
+            defaults.multipart_part_size,
+            DEFAULT_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE
+        );
+        assert_eq!(
+            defaults.multipart_concurrency.get(),
+            DEFAULT_REMOTE_STORAGE_S3_MULTIPART_CONCURRENCY
+        );
+
+        let custom = s3_config("multipart_part_size = 8388608\nmultipart_concurrency = 2")?;
+        assert_eq!(custom.multipart_part_size, 8 * 1024 * 1024);
+        assert_eq!(custom.multipart_concurrency.get(), 2);
+
+        assert!(
+            s3_config("multipart_part_size = 1024").is_err(),
+            "Parts smaller than S3 allows should be rejected"
+        );
+        assert!(s3_config("multipart_concurrency = 0").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_bandwidth_limits() -> anyhow::Result<()> {
+        let config = |toml: &str| -> anyhow::Result<RemoteStorageConfig> {
+            let toml =
+                format!("local_path = '/some/path'\n{toml}").parse::<toml_edit::Document>()?;
+            RemoteStorageConfig::from_toml(toml.as_item())
+        };
+
+        let defaults = config("")?;
+        assert_eq!(defaults.max_upload_bytes_per_second, None);
+        assert_eq!(defaults.max_download_bytes_per_second, None);
+
+        let custom =
+            config("max_upload_bytes_per_second = 1048576\nmax_download_bytes_per_second = 2048")?;
+        assert_eq!(
+            custom.max_upload_bytes_per_second,
+            NonZeroU64::new(1024 * 1024)
+        );
+        assert_eq!(custom.max_download_bytes_per_second, NonZeroU64::new(2048));
+
+        assert!(config("max_upload_bytes_per_second = 0").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_max_index_part_versions() -> anyhow::Result<()> {
+        let config = |toml: &str| -> anyhow::Result<RemoteStorageConfig> {
+            let toml =
+                format!("local_path = '/some/path'\n{toml}").parse::<toml_edit::Document>()?;
+            RemoteStorageConfig::from_toml(toml.as_item())
+        };
+
+        assert_eq!(
+            config("")?.max_index_part_versions.get(),
+            DEFAULT_REMOTE_STORAGE_MAX_INDEX_PART_VERSIONS
+        );
+        assert_eq!(
+            config("max_index_part_versions = 1")?
+                .max_index_part_versions
+                .get(),
+            1
+        );
+        assert!(config("max_index_part_versions = 0").is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_path_with_suffix_extension() {
         let p = PathBuf::from("/foo/bar");
@@ -10,7 +10,7 @@ use std::{
     pin::Pin,
 };
 
-use anyhow::{bail, ensure, Context};
+use anyhow::{anyhow, bail, Context};
 use tokio::{
     fs,
     io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
@@ -19,7 +19,7 @@ use tracing::*;
 
 use crate::path_with_suffix_extension;
 
-use super::{strip_path_prefix, RemoteStorage, StorageMetadata};
+use super::{strip_path_prefix, RemoteStorage, RemoteStorageError, StorageMetadata};
 
 pub struct LocalFs {
     working_directory: PathBuf,
@@ -100,8 +100,8 @@ impl RemoteStorage for LocalFs {
         Ok(self.working_directory.join(relative_path))
     }
 
-    async fn list(&self) -> anyhow::Result<Vec<Self::RemoteObjectId>> {
-        get_all_files(&self.storage_root).await
+    async fn list(&self) -> Result<Vec<Self::RemoteObjectId>, RemoteStorageError> {
+        Ok(get_all_files(&self.storage_root).await?)
     }
 
     async fn upload(
@@ -110,7 +110,7 @@ impl RemoteStorage for LocalFs {
         from_size_bytes: usize,
         to: &Self::RemoteObjectId,
         metadata: Option<StorageMetadata>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RemoteStorageError> {
         let target_file_path = self.resolve_in_storage(to)?;
         create_target_directory(&target_file_path).await?;
         // We need this dance with sort of durable rename (without fsyncs)
@@ -144,18 +144,26 @@ impl RemoteStorage for LocalFs {
                 )
             })?;
 
-        ensure!(
-            bytes_read == from_size_bytes,
-            "Provided stream has actual size {} fthat is smaller than the given stream size {}",
-            bytes_read,
-            from_size_bytes
-        );
+        if bytes_read != from_size_bytes {
+            return Err(anyhow!(
+                "Provided stream has actual size {} fthat is smaller than the given stream size {}",
+                bytes_read,
+                from_size_bytes
+            )
+            .into());
+        }
 
-        ensure!(
-            buffer_to_read.read(&mut [0]).await? == 0,
-            "Provided stream has bigger size than the given stream size {}",
-            from_size_bytes
-        );
+        let extra_bytes_read = buffer_to_read
+            .read(&mut [0])
+            .await
+            .context("Failed to read the end of the provided stream")?;
+        if extra_bytes_read != 0 {
+            return Err(anyhow!(
+                "Provided stream has bigger size than the given stream size {}",
+                from_size_bytes
+            )
+            .into());
+        }
 
         destination.flush().await.with_context(|| {
             format!(
@@ -196,7 +204,7 @@ impl RemoteStorage for LocalFs {
         &self,
         from: &Self::RemoteObjectId,
         to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
-    ) -> anyhow::Result<Option<StorageMetadata>> {
+    ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
         let file_path = self.resolve_in_storage(from)?;
 
         if file_path.exists() && file_path.is_file() {
@@ -218,14 +226,14 @@ impl RemoteStorage for LocalFs {
                     file_path.display()
                 )
             })?;
-            source.flush().await?;
+            source
+                .flush()
+                .await
+                .context("Failed to flush the local storage file")?;
 
-            self.read_storage_metadata(&file_path).await
+            Ok(self.read_storage_metadata(&file_path).await?)
         } else {
-            bail!(
-                "File '{}' either does not exist or is not a file",
-                file_path.display()
-            )
+            Err(RemoteStorageError::NotFound)
         }
     }
 
@@ -235,14 +243,16 @@ impl RemoteStorage for LocalFs {
         start_inclusive: u64,
         end_exclusive: Option<u64>,
         to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
-    ) -> anyhow::Result<Option<StorageMetadata>> {
+    ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
         if let Some(end_exclusive) = end_exclusive {
-            ensure!(
-                end_exclusive > start_inclusive,
-                "Invalid range, start ({}) is bigger then end ({:?})",
-                start_inclusive,
-                end_exclusive
-            );
+            if end_exclusive <= start_inclusive {
+                return Err(anyhow!(
+                    "Invalid range, start ({}) is bigger then end ({:?})",
+                    start_inclusive,
+                    end_exclusive
+                )
+                .into());
+            }
             if start_inclusive == end_exclusive.saturating_sub(1) {
                 return Ok(None);
             }
@@ -279,24 +289,24 @@ impl RemoteStorage for LocalFs {
                 )
             })?;
 
-            self.read_storage_metadata(&file_path).await
+            Ok(self.read_storage_metadata(&file_path).await?)
         } else {
-            bail!(
-                "File '{}' either does not exist or is not a file",
-                file_path.display()
-            )
+            Err(RemoteStorageError::NotFound)
         }
     }
 
-    async fn delete(&self, path: &Self::RemoteObjectId) -> anyhow::Result<()> {
+    async fn delete(&self, path: &Self::RemoteObjectId) -> Result<(), RemoteStorageError> {
         let file_path = self.resolve_in_storage(path)?;
         if file_path.exists() && file_path.is_file() {
-            Ok(fs::remove_file(file_path).await?)
+            fs::remove_file(&file_path).await.with_context(|| {
+                format!(
+                    "Failed to remove file '{}' from the local storage",
+                    file_path.display()
+                )
+            })?;
+            Ok(())
         } else {
-            bail!(
-                "File '{}' either does not exist or is not a file",
-                file_path.display()
-            )
+            Err(RemoteStorageError::NotFound)
         }
     }
 }
@@ -586,11 +596,10 @@ mod fs_tests {
         let non_existing_path = PathBuf::from("somewhere").join("else");
         match storage.download(&non_existing_path, &mut io::sink()).await {
             Ok(_) => panic!("Should not allow downloading non-existing storage files"),
-            Err(e) => {
-                let error_string = e.to_string();
-                assert!(error_string.contains("does not exist"));
-                assert!(error_string.contains(&non_existing_path.display().to_string()));
-            }
+            Err(e) => assert!(
+                matches!(e, RemoteStorageError::NotFound),
+                "Expected a not found error, got: {e:?}"
+            ),
         }
         Ok(())
     }
@@ -718,11 +727,10 @@ mod fs_tests {
             .await
         {
             Ok(_) => panic!("Should not allow downloading non-existing storage file ranges"),
-            Err(e) => {
-                let error_string = e.to_string();
-                assert!(error_string.contains("does not exist"));
-                assert!(error_string.contains(&non_existing_path.display().to_string()));
-            }
+            Err(e) => assert!(
+                matches!(e, RemoteStorageError::NotFound),
+                "Expected a not found error, got: {e:?}"
+            ),
         }
         Ok(())
     }
@@ -740,11 +748,10 @@ mod fs_tests {
 
         match storage.delete(&upload_target).await {
             Ok(()) => panic!("Should not allow deleting non-existing storage files"),
-            Err(e) => {
-                let error_string = e.to_string();
-                assert!(error_string.contains("does not exist"));
-                assert!(error_string.contains(&upload_target.display().to_string()));
-            }
+            Err(e) => assert!(
+                matches!(e, RemoteStorageError::NotFound),
+                "Expected a not found error, got: {e:?}"
+            ),
         }
         Ok(())
     }
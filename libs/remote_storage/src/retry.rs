@@ -0,0 +1,418 @@
+//! Retries of the remote storage requests, failed with [`RemoteStorageError::Transient`] or [`RemoteStorageError::Throttled`] errors,
+//! along with the latency and error metrics of those requests, defined in [`metrics::remote_storage`].
+//!
+//! Only the requests that can be repeated as is get retried: the ones that stream a body read once
+//! (e.g. uploads from a reader) are measured with [`measured`] only. The storage users retry such uploads
+//! with [`upload_with_retries`], opening the body anew for every attempt.
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use metrics::remote_storage as request_metrics;
+use rand::Rng;
+use tokio::io;
+use tracing::warn;
+
+use crate::{RemoteStorage, RemoteStorageError, StorageMetadata};
+
+/// How many times a request is retried after a transient error, before the error is returned to the storage user.
+const MAX_RETRIES: u32 = 5;
+
+/// Delays between the request retries, growing exponentially with every retry.
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    base: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    const DEFAULT: Self = Self {
+        base: Duration::from_millis(100),
+        max: Duration::from_secs(10),
+    };
+
+    /// The storage asks to slow down when throttling, so throttled requests wait longer before the retry.
+    const THROTTLED_BASE_MULTIPLIER: u32 = 10;
+
+    /// The longest delay before the given retry, starting from 0.
+    fn max_delay(&self, retry: u32, throttled: bool) -> Duration {
+        let base = if throttled {
+            self.base * Self::THROTTLED_BASE_MULTIPLIER
+        } else {
+            self.base
+        };
+        base.saturating_mul(1 << retry.min(16)).min(self.max)
+    }
+
+    /// The actual delay is picked at random up to the [`Self::max_delay`], so that the requests
+    /// failed at the same time do not get retried at the same time too.
+    fn delay(&self, retry: u32, throttled: bool) -> Duration {
+        self.max_delay(retry, throttled)
+            .mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+/// Sends the request, created by `request`, repeating it after the jittered delays while it fails with the retryable errors,
+/// up to [`MAX_RETRIES`] times.
+pub(crate) async fn with_retries<T, F, Fut>(
+    storage: &'static str,
+    request_type: &'static str,
+    request: F,
+) -> Result<T, RemoteStorageError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RemoteStorageError>>,
+{
+    retry_with_backoff(storage, request_type, Backoff::DEFAULT, request).await
+}
+
+async fn retry_with_backoff<T, F, Fut>(
+    storage: &'static str,
+    request_type: &'static str,
+    backoff: Backoff,
+    mut request: F,
+) -> Result<T, RemoteStorageError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RemoteStorageError>>,
+{
+    retry_loop(
+        backoff,
+        |e, delay| {
+            warn!("{storage} {request_type} request failed, retrying in {delay:?}: {e}");
+            request_metrics::inc_request_retry(storage, request_type);
+        },
+        || measured(storage, request_type, request()),
+    )
+    .await
+}
+
+/// Uploads the object, retrying the retryable errors like [`with_retries`] does.
+/// The upload body is read once, so `open_body` is called to get a new one for every attempt.
+///
+/// Every upload attempt is measured by the storage itself, these retries are not counted in the request retry metrics.
+pub async fn upload_with_retries<S, F, Fut, R>(
+    storage: &S,
+    open_body: F,
+    from_size_bytes: usize,
+    to: &S::RemoteObjectId,
+    metadata: Option<StorageMetadata>,
+) -> Result<(), RemoteStorageError>
+where
+    S: RemoteStorage,
+    S::RemoteObjectId: std::fmt::Debug,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<R>>,
+    R: io::AsyncRead + Unpin + Send + Sync + 'static,
+{
+    upload_with_backoff(
+        storage,
+        Backoff::DEFAULT,
+        open_body,
+        from_size_bytes,
+        to,
+        metadata,
+    )
+    .await
+}
+
+async fn upload_with_backoff<S, F, Fut, R>(
+    storage: &S,
+    backoff: Backoff,
+    mut open_body: F,
+    from_size_bytes: usize,
+    to: &S::RemoteObjectId,
+    metadata: Option<StorageMetadata>,
+) -> Result<(), RemoteStorageError>
+where
+    S: RemoteStorage,
+    S::RemoteObjectId: std::fmt::Debug,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<R>>,
+    R: io::AsyncRead + Unpin + Send + Sync + 'static,
+{
+    retry_loop(
+        backoff,
+        |e, delay| warn!("Upload to {to:?} failed, retrying in {delay:?}: {e}"),
+        || {
+            let body = open_body();
+            let metadata = metadata.clone();
+            async move {
+                let body = body.await?;
+                storage.upload(body, from_size_bytes, to, metadata).await
+            }
+        },
+    )
+    .await
+}
+
+/// Repeats the request after the jittered delays while it fails with the retryable errors, up to [`MAX_RETRIES`] times.
+async fn retry_loop<T, F, Fut>(
+    backoff: Backoff,
+    mut on_retry: impl FnMut(&RemoteStorageError, Duration),
+    mut request: F,
+) -> Result<T, RemoteStorageError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RemoteStorageError>>,
+{
+    let mut retry = 0;
+    loop {
+        match request().await {
+            Err(e) if e.is_retryable() && retry < MAX_RETRIES => {
+                let delay = backoff.delay(retry, matches!(e, RemoteStorageError::Throttled(_)));
+                on_retry(&e, delay);
+                tokio::time::sleep(delay).await;
+                retry += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sends the request once, recording its metrics.
+pub(crate) async fn measured<T>(
+    storage: &'static str,
+    request_type: &'static str,
+    request: impl Future<Output = Result<T, RemoteStorageError>>,
+) -> Result<T, RemoteStorageError> {
+    let started_at = Instant::now();
+    let result = request.await;
+    request_metrics::observe_request(
+        storage,
+        request_type,
+        started_at.elapsed(),
+        result.as_ref().err().map(error_kind),
+    );
+    result
+}
+
+fn error_kind(error: &RemoteStorageError) -> &'static str {
+    match error {
+        RemoteStorageError::NotFound => "not_found",
+        RemoteStorageError::Throttled(_) => "throttled",
+        RemoteStorageError::Transient(_) => "transient",
+        RemoteStorageError::Permanent(_) => "permanent",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Mutex,
+        },
+    };
+
+    use anyhow::{anyhow, Context};
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    const TEST_BACKOFF: Backoff = Backoff {
+        base: Duration::from_millis(1),
+        max: Duration::from_millis(10),
+    };
+
+    #[test]
+    fn backoff_delays() {
+        let backoff = Backoff::DEFAULT;
+        assert_eq!(backoff.max_delay(0, false), Duration::from_millis(100));
+        assert_eq!(backoff.max_delay(1, false), Duration::from_millis(200));
+        assert_eq!(backoff.max_delay(3, false), Duration::from_millis(800));
+        assert_eq!(
+            backoff.max_delay(0, true),
+            Duration::from_secs(1),
+            "Throttled requests should wait longer"
+        );
+        assert_eq!(
+            backoff.max_delay(u32::MAX, false),
+            backoff.max,
+            "Delay should not grow past the maximum"
+        );
+
+        for retry in 0..10 {
+            for throttled in [false, true] {
+                assert!(backoff.delay(retry, throttled) <= backoff.max_delay(retry, throttled));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_retryable_errors_only() {
+        let attempts = AtomicU32::new(0);
+        let result = retry_with_backoff("test", "transient", TEST_BACKOFF, || async {
+            match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(RemoteStorageError::Transient(anyhow!("timeout"))),
+                1 => Err(RemoteStorageError::Throttled(anyhow!("slow down"))),
+                attempt => Ok(attempt),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 2, "Should succeed on the third attempt");
+
+        let non_retryable_errors: [fn() -> RemoteStorageError; 2] = [
+            || RemoteStorageError::NotFound,
+            || RemoteStorageError::Permanent(anyhow!("access denied")),
+        ];
+        for error in non_retryable_errors {
+            let attempts = AtomicU32::new(0);
+            let result = retry_with_backoff("test", "permanent", TEST_BACKOFF, || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(error())
+            })
+            .await;
+            assert!(!result.unwrap_err().is_retryable());
+            assert_eq!(
+                attempts.load(Ordering::Relaxed),
+                1,
+                "Non-retryable errors should be returned at once"
+            );
+        }
+    }
+
+    /// Fails the first uploads with the errors given, stores the bodies of the successful ones.
+    struct FlakyStorage {
+        upload_errors: Mutex<Vec<RemoteStorageError>>,
+        uploaded: Mutex<Vec<Vec<u8>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl RemoteStorage for FlakyStorage {
+        type RemoteObjectId = PathBuf;
+
+        fn remote_object_id(&self, local_path: &Path) -> anyhow::Result<PathBuf> {
+            Ok(local_path.to_path_buf())
+        }
+
+        fn local_path(&self, remote_object_id: &PathBuf) -> anyhow::Result<PathBuf> {
+            Ok(remote_object_id.clone())
+        }
+
+        async fn list(&self) -> Result<Vec<PathBuf>, RemoteStorageError> {
+            Err(anyhow!("Not supported by the test storage").into())
+        }
+
+        async fn upload(
+            &self,
+            mut from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+            from_size_bytes: usize,
+            _: &PathBuf,
+            _: Option<StorageMetadata>,
+        ) -> Result<(), RemoteStorageError> {
+            let mut body = Vec::new();
+            from.read_to_end(&mut body)
+                .await
+                .context("Failed to read the upload body")?;
+            assert_eq!(body.len(), from_size_bytes, "Upload body should be whole");
+            match self.upload_errors.lock().unwrap().pop() {
+                Some(e) => Err(e),
+                None => {
+                    self.uploaded.lock().unwrap().push(body);
+                    Ok(())
+                }
+            }
+        }
+
+        async fn download(
+            &self,
+            _: &PathBuf,
+            _: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+        ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
+            Err(anyhow!("Not supported by the test storage").into())
+        }
+
+        async fn download_byte_range(
+            &self,
+            _: &PathBuf,
+            _: u64,
+            _: Option<u64>,
+            _: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+        ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
+            Err(anyhow!("Not supported by the test storage").into())
+        }
+
+        async fn delete(&self, _: &PathBuf) -> Result<(), RemoteStorageError> {
+            Err(anyhow!("Not supported by the test storage").into())
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_retries_open_new_body() {
+        let storage = FlakyStorage {
+            upload_errors: Mutex::new(vec![
+                RemoteStorageError::Throttled(anyhow!("slow down")),
+                RemoteStorageError::Transient(anyhow!("connection reset")),
+            ]),
+            uploaded: Mutex::new(Vec::new()),
+        };
+        let bodies_opened = AtomicU32::new(0);
+        upload_with_backoff(
+            &storage,
+            TEST_BACKOFF,
+            || async {
+                bodies_opened.fetch_add(1, Ordering::Relaxed);
+                Ok(std::io::Cursor::new(b"layer contents".to_vec()))
+            },
+            14,
+            &PathBuf::from("layer"),
+            None,
+        )
+        .await
+        .expect("Upload should succeed on the third attempt");
+        assert_eq!(bodies_opened.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            storage.uploaded.lock().unwrap().as_slice(),
+            &[b"layer contents".to_vec()]
+        );
+
+        // Neither the permanent errors, nor the body opening errors are retried
+        storage
+            .upload_errors
+            .lock()
+            .unwrap()
+            .push(RemoteStorageError::Permanent(anyhow!("access denied")));
+        let bodies_opened = AtomicU32::new(0);
+        let result = upload_with_backoff(
+            &storage,
+            TEST_BACKOFF,
+            || async {
+                bodies_opened.fetch_add(1, Ordering::Relaxed);
+                Ok(std::io::Cursor::new(b"layer contents".to_vec()))
+            },
+            14,
+            &PathBuf::from("layer"),
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(RemoteStorageError::Permanent(_))));
+        assert_eq!(bodies_opened.load(Ordering::Relaxed), 1);
+
+        let result = upload_with_backoff(
+            &storage,
+            TEST_BACKOFF,
+            || async { Err::<std::io::Cursor<Vec<u8>>, _>(anyhow!("no such file")) },
+            14,
+            &PathBuf::from("layer"),
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(RemoteStorageError::Permanent(_))));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let attempts = AtomicU32::new(0);
+        let result = retry_with_backoff("test", "always_failing", TEST_BACKOFF, || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err::<(), _>(RemoteStorageError::Transient(anyhow!("connection reset")))
+        })
+        .await;
+        assert!(matches!(result, Err(RemoteStorageError::Transient(_))));
+        assert_eq!(attempts.load(Ordering::Relaxed), MAX_RETRIES + 1);
+    }
+}
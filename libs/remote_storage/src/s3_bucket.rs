@@ -3,28 +3,45 @@
 //! Respects `prefix_in_bucket` property from [`S3Config`],
 //! allowing multiple api users to independently work with the same S3 bucket, if
 //! their bucket prefixes are both specified and different.
+//!
+//! Files larger than [`S3Config::multipart_part_size`] are uploaded with the multipart upload API.
+//! The id of an unfinished upload is remembered, so a retried upload of the same key
+//! only sends the parts that are not yet stored in S3.
+//! The ids are also persisted in the [`MULTIPART_UPLOADS_FILE_NAME`] file of the working directory,
+//! so that the uploads, interrupted by a restart, get aborted without touching the uploads of the other
+//! nodes, sharing the bucket.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
-use std::path::{Path, PathBuf};
-
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use futures::stream::{FuturesUnordered, StreamExt};
 use rusoto_core::{
     credential::{InstanceMetadataProvider, StaticProvider},
-    HttpClient, Region,
+    HttpClient, Region, RusotoError,
 };
 use rusoto_s3::{
-    DeleteObjectRequest, GetObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client,
-    StreamingBody, S3,
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, DeleteObjectRequest, GetObjectError,
+    GetObjectRequest, ListObjectsV2Request, ListPartsRequest, PutObjectRequest, S3Client,
+    StreamingBody, UploadPartRequest, S3,
 };
-use tokio::{io, sync::Semaphore};
-use tokio_util::io::ReaderStream;
-use tracing::debug;
+use tokio::{
+    io::{self, AsyncReadExt},
+    sync::Semaphore,
+};
+use tracing::{debug, info, warn};
 
-use crate::{strip_path_prefix, RemoteStorage, S3Config};
+use crate::{retry::with_retries, strip_path_prefix, RemoteStorage, RemoteStorageError, S3Config};
 
 use super::StorageMetadata;
 
 pub(super) mod metrics {
-    use metrics::{register_int_counter_vec, IntCounterVec};
+    use metrics::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
     use once_cell::sync::Lazy;
 
     static S3_REQUESTS_COUNT: Lazy<IntCounterVec> = Lazy::new(|| {
@@ -86,9 +103,36 @@ pub(super) mod metrics {
             .with_label_values(&["list_objects"])
             .inc();
     }
+
+    /// Multipart upload requests: `create_multipart_upload`, `upload_part`, `list_parts`,
+    /// `complete_multipart_upload` and `abort_multipart_upload`.
+    pub fn inc_multipart_request(request_type: &str) {
+        S3_REQUESTS_COUNT.with_label_values(&[request_type]).inc();
+    }
+
+    pub fn inc_multipart_request_fail(request_type: &str) {
+        S3_REQUESTS_FAIL_COUNT
+            .with_label_values(&[request_type])
+            .inc();
+    }
+
+    static S3_MULTIPART_REUSED_PARTS_COUNT: Lazy<IntCounter> = Lazy::new(|| {
+        register_int_counter!(
+            "remote_storage_s3_multipart_reused_parts_count",
+            "Number of multipart upload parts, not sent again on upload retry since S3 has them already",
+        )
+        .expect("failed to define a metric")
+    });
+
+    pub fn inc_multipart_reused_part() {
+        S3_MULTIPART_REUSED_PARTS_COUNT.inc();
+    }
 }
 
+const S3_STORAGE: &str = "s3";
 const S3_PREFIX_SEPARATOR: char = '/';
+/// S3 allows no more than this number of parts in a single multipart upload.
+const S3_MAX_MULTIPART_PARTS: usize = 10_000;
 
 #[derive(Debug, Eq, PartialEq, PartialOrd, Ord, Hash)]
 pub struct S3ObjectKey(String);
@@ -127,6 +171,109 @@ pub struct S3Bucket {
     // Same goes to IAM, which is queried before every S3 request, if enabled. IAM has even lower RPS threshold.
     // The helps to ensure we don't exceed the thresholds.
     concurrency_limiter: Semaphore,
+    multipart_part_size: usize,
+    multipart_concurrency: usize,
+    /// Whether a retried multipart upload reuses the parts of the previous attempt, see [`S3Bucket::without_upload_resumption`].
+    resume_multipart_uploads: bool,
+    multipart_uploads: Mutex<MultipartUploads>,
+}
+
+/// Name of the file in the working directory, with the multipart uploads started by this node and not completed yet.
+pub const MULTIPART_UPLOADS_FILE_NAME: &str = "s3_multipart_uploads.json";
+
+/// Multipart uploads started by this node and not completed yet, persisted after every change.
+struct MultipartUploads {
+    file_path: PathBuf,
+    /// Ids of the multipart uploads started by this storage and not completed yet, by their object keys.
+    /// Used to resume the upload of the same key on retry.
+    unfinished: HashMap<String, String>,
+    /// Keys and ids of the uploads, left unfinished before the storage was created.
+    stale: Vec<(String, String)>,
+}
+
+impl MultipartUploads {
+    fn load(file_path: PathBuf) -> Self {
+        let stale = match fs::read(&file_path) {
+            Ok(contents) => match serde_json::from_slice::<Vec<(String, String)>>(&contents) {
+                Ok(stale) => stale,
+                Err(e) => {
+                    warn!(
+                        "Failed to parse the multipart uploads file '{}', ignoring it: {e}",
+                        file_path.display()
+                    );
+                    Vec::new()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                warn!(
+                    "Failed to read the multipart uploads file '{}', ignoring it: {e}",
+                    file_path.display()
+                );
+                Vec::new()
+            }
+        };
+        Self {
+            file_path,
+            unfinished: HashMap::new(),
+            stale,
+        }
+    }
+
+    /// Writes down the uploads, logging the failures: at worst, an upload interrupted later stays in S3 until
+    /// the bucket lifecycle rules remove it.
+    fn persist(&self) {
+        let uploads = self
+            .stale
+            .iter()
+            .map(|(key, upload_id)| (key.as_str(), upload_id.as_str()))
+            .chain(
+                self.unfinished
+                    .iter()
+                    .map(|(key, upload_id)| (key.as_str(), upload_id.as_str())),
+            )
+            .collect::<Vec<_>>();
+        let temp_path = self.file_path.with_extension("json.temp");
+        let persist_result = serde_json::to_vec(&uploads)
+            .context("Failed to serialize the multipart uploads")
+            .and_then(|contents| {
+                fs::write(&temp_path, contents).context("Failed to write the temporary file")
+            })
+            .and_then(|()| {
+                fs::rename(&temp_path, &self.file_path)
+                    .context("Failed to rename the temporary file")
+            });
+        if let Err(e) = persist_result {
+            warn!(
+                "Failed to persist the multipart uploads into '{}': {e:#}",
+                self.file_path.display()
+            );
+        }
+    }
+}
+
+/// A part of a multipart upload that S3 already stores.
+#[derive(Debug)]
+struct UploadedPart {
+    size: usize,
+    e_tag: String,
+}
+
+impl UploadedPart {
+    /// Checks whether the part stored in S3 has the same contents as the one about to be uploaded.
+    /// S3 uses the MD5 hash of the part as its ETag, unless the bucket is encrypted with a KMS key:
+    /// in that case the part never matches and gets uploaded again.
+    fn matches(&self, part: &[u8]) -> bool {
+        self.size == part.len()
+            && self.e_tag.trim_matches('"') == format!("{:x}", md5::compute(part))
+    }
+}
+
+/// Part size to split the object into, enlarged if the object would not fit into the
+/// [`S3_MAX_MULTIPART_PARTS`] of the configured size.
+fn multipart_part_size(object_size: usize, configured_part_size: usize) -> usize {
+    let min_part_size = (object_size + S3_MAX_MULTIPART_PARTS - 1) / S3_MAX_MULTIPART_PARTS;
+    configured_part_size.max(min_part_size)
 }
 
 impl S3Bucket {
@@ -181,11 +328,419 @@ impl S3Bucket {
 
         Ok(Self {
             client,
-            workdir,
             bucket_name: aws_config.bucket_name.clone(),
             prefix_in_bucket,
             concurrency_limiter: Semaphore::new(aws_config.concurrency_limit.get()),
+            multipart_part_size: aws_config.multipart_part_size,
+            multipart_concurrency: aws_config.multipart_concurrency.get(),
+            resume_multipart_uploads: true,
+            multipart_uploads: Mutex::new(MultipartUploads::load(
+                workdir.join(MULTIPART_UPLOADS_FILE_NAME),
+            )),
+            workdir,
+        })
+    }
+
+    /// Makes every multipart upload attempt start anew, aborting the previous attempt.
+    /// Needed when the uploaded contents differ between the attempts, e.g. encrypted with a new nonce every time,
+    /// since no part of the previous attempt can be reused then.
+    pub fn without_upload_resumption(mut self) -> Self {
+        self.resume_multipart_uploads = false;
+        self
+    }
+
+    /// Uploads the object with a single request, reading it into memory first, so the request can be retried.
+    /// Used for the objects, not larger than the multipart upload part.
+    async fn put_object(
+        &self,
+        from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        to: &S3ObjectKey,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<(), RemoteStorageError> {
+        let mut body = Vec::with_capacity(from_size_bytes);
+        from.take(from_size_bytes as u64 + 1)
+            .read_to_end(&mut body)
+            .await
+            .context("Failed to read the upload source")?;
+        if body.len() != from_size_bytes {
+            return Err(anyhow!(
+                "Upload source has {} bytes, expected {from_size_bytes}",
+                body.len()
+            )
+            .into());
+        }
+
+        with_retries(S3_STORAGE, "put_object", || async {
+            let _guard = self
+                .concurrency_limiter
+                .acquire()
+                .await
+                .context("Concurrency limiter semaphore got closed during S3 upload")?;
+
+            metrics::inc_put_object();
+            self.client
+                .put_object(PutObjectRequest {
+                    body: Some(StreamingBody::from(body.clone())),
+                    bucket: self.bucket_name.clone(),
+                    key: to.key().to_owned(),
+                    metadata: metadata.clone().map(|m| m.0),
+                    ..PutObjectRequest::default()
+                })
+                .await
+                .map(|_| ())
+                .map_err(|e| {
+                    metrics::inc_put_object_fail();
+                    classify_error(e)
+                })
         })
+        .await
+    }
+
+    /// Reads the source in parts of the same size, sends the parts to S3 concurrently and combines them into the object.
+    /// Parts that S3 already has from the previous attempt to upload the same key are not sent again.
+    async fn upload_multipart(
+        &self,
+        mut from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        to: &S3ObjectKey,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<(), RemoteStorageError> {
+        let part_size = multipart_part_size(from_size_bytes, self.multipart_part_size);
+        let (upload_id, uploaded_parts) =
+            self.start_or_resume_multipart_upload(to, metadata).await?;
+
+        let mut completed_parts = Vec::new();
+        let mut parts_in_progress = FuturesUnordered::new();
+        let mut part_number = 1;
+        let mut bytes_left = from_size_bytes;
+        while bytes_left > 0 {
+            let mut part = vec![0; part_size.min(bytes_left)];
+            from.read_exact(&mut part).await.with_context(|| {
+                format!(
+                    "Failed to read part {part_number} of the upload source, expected {from_size_bytes} bytes in total"
+                )
+            })?;
+            bytes_left -= part.len();
+
+            match uploaded_parts.get(&part_number) {
+                Some(uploaded_part) if uploaded_part.matches(&part) => {
+                    metrics::inc_multipart_reused_part();
+                    completed_parts.push(CompletedPart {
+                        e_tag: Some(uploaded_part.e_tag.clone()),
+                        part_number: Some(part_number),
+                    });
+                }
+                _ => {
+                    if parts_in_progress.len() >= self.multipart_concurrency {
+                        if let Some(completed_part) = parts_in_progress.next().await {
+                            completed_parts.push(completed_part?);
+                        }
+                    }
+                    parts_in_progress.push(self.upload_part(to, &upload_id, part_number, part));
+                }
+            }
+            part_number += 1;
+        }
+        while let Some(completed_part) = parts_in_progress.next().await {
+            completed_parts.push(completed_part?);
+        }
+        let extra_bytes_read = from
+            .read(&mut [0])
+            .await
+            .context("Failed to read the end of the upload source")?;
+        if extra_bytes_read != 0 {
+            return Err(anyhow!("Upload source has more than {from_size_bytes} bytes").into());
+        }
+        completed_parts.sort_by_key(|part| part.part_number);
+
+        with_retries(S3_STORAGE, "complete_multipart_upload", || async {
+            let _guard = self.concurrency_limiter.acquire().await.context(
+                "Concurrency limiter semaphore got closed during S3 multipart upload completion",
+            )?;
+            metrics::inc_multipart_request("complete_multipart_upload");
+            self.client
+                .complete_multipart_upload(CompleteMultipartUploadRequest {
+                    bucket: self.bucket_name.clone(),
+                    key: to.key().to_owned(),
+                    upload_id: upload_id.clone(),
+                    multipart_upload: Some(CompletedMultipartUpload {
+                        parts: Some(completed_parts.clone()),
+                    }),
+                    ..CompleteMultipartUploadRequest::default()
+                })
+                .await
+                .map_err(|e| {
+                    metrics::inc_multipart_request_fail("complete_multipart_upload");
+                    classify_error(e)
+                })
+        })
+        .await?;
+
+        {
+            let mut multipart_uploads = self.multipart_uploads.lock().unwrap();
+            multipart_uploads.unfinished.remove(to.key());
+            multipart_uploads.persist();
+        }
+        Ok(())
+    }
+
+    /// Returns the id of the unfinished upload of the same key along with its parts stored in S3, if there is any
+    /// and the uploads are resumed. Otherwise, starts a new multipart upload.
+    async fn start_or_resume_multipart_upload(
+        &self,
+        to: &S3ObjectKey,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<(String, HashMap<i64, UploadedPart>), RemoteStorageError> {
+        let unfinished_upload_id = self
+            .multipart_uploads
+            .lock()
+            .unwrap()
+            .unfinished
+            .get(to.key())
+            .cloned();
+        if let Some(upload_id) = unfinished_upload_id {
+            if !self.resume_multipart_uploads {
+                if let Err(e) = self.abort_multipart_upload(to.key(), &upload_id).await {
+                    warn!("Failed to abort the previous multipart upload attempt of {to:?}: {e:?}");
+                }
+            } else {
+                match self.list_uploaded_parts(to, &upload_id).await {
+                Ok(uploaded_parts) => {
+                    debug!(
+                        "Resuming multipart upload of {to:?} with {} parts already uploaded",
+                        uploaded_parts.len()
+                    );
+                    return Ok((upload_id, uploaded_parts));
+                }
+                Err(e) => warn!(
+                    "Failed to list parts of the unfinished multipart upload of {to:?}, starting the upload anew: {e:?}"
+                ),
+            }
+            }
+        }
+
+        let upload_id = with_retries(S3_STORAGE, "create_multipart_upload", || async {
+            let _guard = self.concurrency_limiter.acquire().await.context(
+                "Concurrency limiter semaphore got closed during S3 multipart upload start",
+            )?;
+            metrics::inc_multipart_request("create_multipart_upload");
+            self.client
+                .create_multipart_upload(CreateMultipartUploadRequest {
+                    bucket: self.bucket_name.clone(),
+                    key: to.key().to_owned(),
+                    metadata: metadata.clone().map(|m| m.0),
+                    ..CreateMultipartUploadRequest::default()
+                })
+                .await
+                .map_err(|e| {
+                    metrics::inc_multipart_request_fail("create_multipart_upload");
+                    classify_error(e)
+                })
+        })
+        .await?
+        .upload_id
+        .context("S3 returned no upload id for the new multipart upload")?;
+
+        {
+            let mut multipart_uploads = self.multipart_uploads.lock().unwrap();
+            multipart_uploads
+                .unfinished
+                .insert(to.key().to_owned(), upload_id.clone());
+            multipart_uploads.persist();
+        }
+        Ok((upload_id, HashMap::new()))
+    }
+
+    async fn list_uploaded_parts(
+        &self,
+        key: &S3ObjectKey,
+        upload_id: &str,
+    ) -> Result<HashMap<i64, UploadedPart>, RemoteStorageError> {
+        let mut uploaded_parts = HashMap::new();
+        let mut part_number_marker = None;
+        loop {
+            let response = with_retries(S3_STORAGE, "list_parts", || async {
+                let _guard =
+                    self.concurrency_limiter.acquire().await.context(
+                        "Concurrency limiter semaphore got closed during S3 parts listing",
+                    )?;
+                metrics::inc_multipart_request("list_parts");
+                self.client
+                    .list_parts(ListPartsRequest {
+                        bucket: self.bucket_name.clone(),
+                        key: key.key().to_owned(),
+                        upload_id: upload_id.to_owned(),
+                        part_number_marker,
+                        ..ListPartsRequest::default()
+                    })
+                    .await
+                    .map_err(|e| {
+                        metrics::inc_multipart_request_fail("list_parts");
+                        classify_error(e)
+                    })
+            })
+            .await?;
+
+            for part in response.parts.unwrap_or_default() {
+                if let (Some(part_number), Some(size), Some(e_tag)) =
+                    (part.part_number, part.size, part.e_tag)
+                {
+                    uploaded_parts.insert(
+                        part_number,
+                        UploadedPart {
+                            size: usize::try_from(size).unwrap_or_default(),
+                            e_tag,
+                        },
+                    );
+                }
+            }
+
+            if response.is_truncated != Some(true) {
+                break;
+            }
+            part_number_marker = response.next_part_number_marker;
+        }
+        Ok(uploaded_parts)
+    }
+
+    async fn upload_part(
+        &self,
+        to: &S3ObjectKey,
+        upload_id: &str,
+        part_number: i64,
+        part: Vec<u8>,
+    ) -> Result<CompletedPart, RemoteStorageError> {
+        let response = with_retries(S3_STORAGE, "upload_part", || async {
+            let _guard = self
+                .concurrency_limiter
+                .acquire()
+                .await
+                .context("Concurrency limiter semaphore got closed during S3 part upload")?;
+
+            metrics::inc_multipart_request("upload_part");
+            self.client
+                .upload_part(UploadPartRequest {
+                    body: Some(StreamingBody::from(part.clone())),
+                    bucket: self.bucket_name.clone(),
+                    key: to.key().to_owned(),
+                    upload_id: upload_id.to_owned(),
+                    part_number,
+                    content_length: Some(part.len() as i64),
+                    ..UploadPartRequest::default()
+                })
+                .await
+                .map_err(|e| {
+                    metrics::inc_multipart_request_fail("upload_part");
+                    classify_error(e)
+                })
+        })
+        .await?;
+
+        Ok(CompletedPart {
+            e_tag: response.e_tag,
+            part_number: Some(part_number),
+        })
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<(), RemoteStorageError> {
+        with_retries(S3_STORAGE, "abort_multipart_upload", || async {
+            let _guard = self.concurrency_limiter.acquire().await.context(
+                "Concurrency limiter semaphore got closed during S3 multipart upload abort",
+            )?;
+            metrics::inc_multipart_request("abort_multipart_upload");
+            self.client
+                .abort_multipart_upload(AbortMultipartUploadRequest {
+                    bucket: self.bucket_name.clone(),
+                    key: key.to_owned(),
+                    upload_id: upload_id.to_owned(),
+                    ..AbortMultipartUploadRequest::default()
+                })
+                .await
+                .map(|_| ())
+                .map_err(|e| {
+                    metrics::inc_multipart_request_fail("abort_multipart_upload");
+                    classify_error(e)
+                })
+        })
+        .await
+    }
+
+    /// Streams the object contents, or its byte range, into the writer given.
+    /// Only the request gets retried: if streaming of the contents fails, the error is returned as a transient one.
+    async fn get_object(
+        &self,
+        from: &S3ObjectKey,
+        range: Option<String>,
+        to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+    ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
+        let (_guard, object_output) = with_retries(S3_STORAGE, "get_object", || async {
+            let guard = self
+                .concurrency_limiter
+                .acquire()
+                .await
+                .context("Concurrency limiter semaphore got closed during S3 download")?;
+
+            metrics::inc_get_object();
+            self.client
+                .get_object(GetObjectRequest {
+                    bucket: self.bucket_name.clone(),
+                    key: from.key().to_owned(),
+                    range: range.clone(),
+                    ..GetObjectRequest::default()
+                })
+                .await
+                .map(|object_output| (guard, object_output))
+                .map_err(|e| {
+                    metrics::inc_get_object_fail();
+                    match e {
+                        RusotoError::Service(GetObjectError::NoSuchKey(_)) => {
+                            RemoteStorageError::NotFound
+                        }
+                        e => classify_error(e),
+                    }
+                })
+        })
+        .await?;
+
+        if let Some(body) = object_output.body {
+            let mut from = io::BufReader::new(body.into_async_read());
+            io::copy(&mut from, to)
+                .await
+                .context("Failed to download S3 object contents")
+                .map_err(RemoteStorageError::Transient)?;
+        }
+
+        Ok(object_output.metadata.map(StorageMetadata))
+    }
+}
+
+/// Tells the errors worth retrying (network failures, throttling and S3 server errors) from the rest.
+fn classify_error<E>(error: RusotoError<E>) -> RemoteStorageError
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let status = match &error {
+        RusotoError::Unknown(response) => Some(response.status.as_u16()),
+        _ => None,
+    };
+    match status {
+        Some(404) => RemoteStorageError::NotFound,
+        // S3 responds with 503 SlowDown when the request rate is too high
+        Some(429 | 503) => RemoteStorageError::Throttled(error.into()),
+        Some(408 | 500..=599) => RemoteStorageError::Transient(error.into()),
+        None if matches!(
+            error,
+            RusotoError::HttpDispatch(_) | RusotoError::Credentials(_)
+        ) =>
+        {
+            RemoteStorageError::Transient(error.into())
+        }
+        _ => RemoteStorageError::Permanent(error.into()),
     }
 }
 
@@ -207,32 +762,34 @@ impl RemoteStorage for S3Bucket {
         Ok(storage_path.download_destination(&self.workdir, self.prefix_in_bucket.as_deref()))
     }
 
-    async fn list(&self) -> anyhow::Result<Vec<Self::RemoteObjectId>> {
+    async fn list(&self) -> Result<Vec<Self::RemoteObjectId>, RemoteStorageError> {
         let mut document_keys = Vec::new();
 
         let mut continuation_token = None;
         loop {
-            let _guard = self
-                .concurrency_limiter
-                .acquire()
-                .await
-                .context("Concurrency limiter semaphore got closed during S3 list")?;
-
-            metrics::inc_list_objects();
-
-            let fetch_response = self
-                .client
-                .list_objects_v2(ListObjectsV2Request {
-                    bucket: self.bucket_name.clone(),
-                    prefix: self.prefix_in_bucket.clone(),
-                    continuation_token,
-                    ..ListObjectsV2Request::default()
-                })
-                .await
-                .map_err(|e| {
-                    metrics::inc_list_objects_fail();
-                    e
-                })?;
+            let fetch_response = with_retries(S3_STORAGE, "list_objects", || async {
+                let _guard = self
+                    .concurrency_limiter
+                    .acquire()
+                    .await
+                    .context("Concurrency limiter semaphore got closed during S3 list")?;
+
+                metrics::inc_list_objects();
+
+                self.client
+                    .list_objects_v2(ListObjectsV2Request {
+                        bucket: self.bucket_name.clone(),
+                        prefix: self.prefix_in_bucket.clone(),
+                        continuation_token: continuation_token.clone(),
+                        ..ListObjectsV2Request::default()
+                    })
+                    .await
+                    .map_err(|e| {
+                        metrics::inc_list_objects_fail();
+                        classify_error(e)
+                    })
+            })
+            .await?;
             document_keys.extend(
                 fetch_response
                     .contents
@@ -256,65 +813,21 @@ impl RemoteStorage for S3Bucket {
         from_size_bytes: usize,
         to: &Self::RemoteObjectId,
         metadata: Option<StorageMetadata>,
-    ) -> anyhow::Result<()> {
-        let _guard = self
-            .concurrency_limiter
-            .acquire()
-            .await
-            .context("Concurrency limiter semaphore got closed during S3 upload")?;
-
-        metrics::inc_put_object();
-        self.client
-            .put_object(PutObjectRequest {
-                body: Some(StreamingBody::new_with_size(
-                    ReaderStream::new(from),
-                    from_size_bytes,
-                )),
-                bucket: self.bucket_name.clone(),
-                key: to.key().to_owned(),
-                metadata: metadata.map(|m| m.0),
-                ..PutObjectRequest::default()
-            })
-            .await
-            .map_err(|e| {
-                metrics::inc_put_object_fail();
-                e
-            })?;
-        Ok(())
+    ) -> Result<(), RemoteStorageError> {
+        if from_size_bytes > self.multipart_part_size {
+            self.upload_multipart(from, from_size_bytes, to, metadata)
+                .await
+        } else {
+            self.put_object(from, from_size_bytes, to, metadata).await
+        }
     }
 
     async fn download(
         &self,
         from: &Self::RemoteObjectId,
         to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
-    ) -> anyhow::Result<Option<StorageMetadata>> {
-        let _guard = self
-            .concurrency_limiter
-            .acquire()
-            .await
-            .context("Concurrency limiter semaphore got closed during S3 download")?;
-
-        metrics::inc_get_object();
-
-        let object_output = self
-            .client
-            .get_object(GetObjectRequest {
-                bucket: self.bucket_name.clone(),
-                key: from.key().to_owned(),
-                ..GetObjectRequest::default()
-            })
-            .await
-            .map_err(|e| {
-                metrics::inc_get_object_fail();
-                e
-            })?;
-
-        if let Some(body) = object_output.body {
-            let mut from = io::BufReader::new(body.into_async_read());
-            io::copy(&mut from, to).await?;
-        }
-
-        Ok(object_output.metadata.map(StorageMetadata))
+    ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
+        self.get_object(from, None, to).await
     }
 
     async fn download_byte_range(
@@ -323,7 +836,7 @@ impl RemoteStorage for S3Bucket {
         start_inclusive: u64,
         end_exclusive: Option<u64>,
         to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
-    ) -> anyhow::Result<Option<StorageMetadata>> {
+    ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
         // S3 accepts ranges as https://www.w3.org/Protocols/rfc2616/rfc2616-sec14.html#sec14.35
         // and needs both ends to be exclusive
         let end_inclusive = end_exclusive.map(|end| end.saturating_sub(1));
@@ -331,62 +844,60 @@ impl RemoteStorage for S3Bucket {
             Some(end_inclusive) => format!("bytes={}-{}", start_inclusive, end_inclusive),
             None => format!("bytes={}-", start_inclusive),
         });
-        let _guard = self
-            .concurrency_limiter
-            .acquire()
-            .await
-            .context("Concurrency limiter semaphore got closed during S3 range download")?;
-
-        metrics::inc_get_object();
+        self.get_object(from, range, to).await
+    }
 
-        let object_output = self
-            .client
-            .get_object(GetObjectRequest {
-                bucket: self.bucket_name.clone(),
-                key: from.key().to_owned(),
-                range,
-                ..GetObjectRequest::default()
-            })
-            .await
-            .map_err(|e| {
-                metrics::inc_get_object_fail();
-                e
-            })?;
+    async fn delete(&self, path: &Self::RemoteObjectId) -> Result<(), RemoteStorageError> {
+        with_retries(S3_STORAGE, "delete_object", || async {
+            let _guard = self
+                .concurrency_limiter
+                .acquire()
+                .await
+                .context("Concurrency limiter semaphore got closed during S3 delete")?;
 
-        if let Some(body) = object_output.body {
-            let mut from = io::BufReader::new(body.into_async_read());
-            io::copy(&mut from, to).await?;
-        }
+            metrics::inc_delete_object();
 
-        Ok(object_output.metadata.map(StorageMetadata))
+            self.client
+                .delete_object(DeleteObjectRequest {
+                    bucket: self.bucket_name.clone(),
+                    key: path.key().to_owned(),
+                    ..DeleteObjectRequest::default()
+                })
+                .await
+                .map(|_| ())
+                .map_err(|e| {
+                    metrics::inc_delete_object_fail();
+                    classify_error(e)
+                })
+        })
+        .await
     }
 
-    async fn delete(&self, path: &Self::RemoteObjectId) -> anyhow::Result<()> {
-        let _guard = self
-            .concurrency_limiter
-            .acquire()
-            .await
-            .context("Concurrency limiter semaphore got closed during S3 delete")?;
-
-        metrics::inc_delete_object();
-
-        self.client
-            .delete_object(DeleteObjectRequest {
-                bucket: self.bucket_name.clone(),
-                key: path.key().to_owned(),
-                ..DeleteObjectRequest::default()
-            })
-            .await
-            .map_err(|e| {
-                metrics::inc_delete_object_fail();
-                e
-            })?;
+    async fn abort_stale_uploads(&self) -> Result<(), RemoteStorageError> {
+        // Only the uploads this node has started are aborted: the same bucket may be shared with other nodes,
+        // which have their own uploads in progress.
+        let stale_uploads = self.multipart_uploads.lock().unwrap().stale.clone();
+        for (key, upload_id) in stale_uploads {
+            info!("Aborting stale multipart upload {upload_id} of key {key}");
+            match self.abort_multipart_upload(&key, &upload_id).await {
+                Ok(()) | Err(RemoteStorageError::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+            let mut multipart_uploads = self.multipart_uploads.lock().unwrap();
+            multipart_uploads
+                .stale
+                .retain(|(stale_key, stale_upload_id)| {
+                    stale_key != &key || stale_upload_id != &upload_id
+                });
+            multipart_uploads.persist();
+        }
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use rusoto_core::request::{BufferedHttpResponse, HttpDispatchError};
     use tempfile::tempdir;
 
     use super::*;
@@ -541,13 +1052,138 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn multipart_part_size_fits_parts_limit() {
+        let part_size = crate::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE;
+        assert_eq!(multipart_part_size(0, part_size), part_size);
+        assert_eq!(multipart_part_size(part_size + 1, part_size), part_size);
+        assert_eq!(
+            multipart_part_size(part_size * S3_MAX_MULTIPART_PARTS, part_size),
+            part_size,
+            "Object fitting exactly into the parts limit should use the configured part size"
+        );
+
+        let huge_object_size = part_size * S3_MAX_MULTIPART_PARTS + 1;
+        let enlarged_part_size = multipart_part_size(huge_object_size, part_size);
+        assert!(enlarged_part_size > part_size);
+        assert!(
+            enlarged_part_size * S3_MAX_MULTIPART_PARTS >= huge_object_size,
+            "Enlarged parts should fit the whole object into the parts limit"
+        );
+    }
+
+    #[test]
+    fn uploaded_part_reuse() {
+        let part = b"some part contents".to_vec();
+        let uploaded_part = UploadedPart {
+            size: part.len(),
+            e_tag: format!("\"{:x}\"", md5::compute(&part)),
+        };
+        assert!(
+            uploaded_part.matches(&part),
+            "Part with the same contents should be reused"
+        );
+
+        let mut changed_part = part.clone();
+        changed_part[0] ^= 1;
+        assert!(
+            !uploaded_part.matches(&changed_part),
+            "Part with different contents should be uploaded again"
+        );
+
+        let mut longer_part = part.clone();
+        longer_part.push(0);
+        assert!(!uploaded_part.matches(&longer_part));
+
+        let kms_encrypted_part = UploadedPart {
+            size: part.len(),
+            e_tag: "\"not an md5 hash\"".to_string(),
+        };
+        assert!(!kms_encrypted_part.matches(&part));
+    }
+
+    #[test]
+    fn error_classification() {
+        let unknown_error = |status: u16| {
+            classify_error(RusotoError::<GetObjectError>::Unknown(
+                BufferedHttpResponse {
+                    status: hyper::StatusCode::from_u16(status).unwrap(),
+                    body: Default::default(),
+                    headers: Default::default(),
+                },
+            ))
+        };
+        assert!(matches!(unknown_error(404), RemoteStorageError::NotFound));
+        assert!(matches!(
+            unknown_error(503),
+            RemoteStorageError::Throttled(_)
+        ));
+        assert!(matches!(
+            unknown_error(500),
+            RemoteStorageError::Transient(_)
+        ));
+        assert!(matches!(
+            unknown_error(403),
+            RemoteStorageError::Permanent(_)
+        ));
+
+        assert!(matches!(
+            classify_error(RusotoError::<GetObjectError>::HttpDispatch(
+                HttpDispatchError::new("connection reset".to_string())
+            )),
+            RemoteStorageError::Transient(_)
+        ));
+        assert!(matches!(
+            classify_error(RusotoError::<GetObjectError>::Validation(
+                "invalid key".to_string()
+            )),
+            RemoteStorageError::Permanent(_)
+        ));
+    }
+
+    #[test]
+    fn stale_multipart_uploads() -> anyhow::Result<()> {
+        let workdir = tempdir()?;
+
+        let mut multipart_uploads =
+            MultipartUploads::load(workdir.path().join(MULTIPART_UPLOADS_FILE_NAME));
+        assert!(multipart_uploads.stale.is_empty());
+        multipart_uploads
+            .unfinished
+            .insert("key_1".to_string(), "upload_1".to_string());
+        multipart_uploads
+            .unfinished
+            .insert("key_2".to_string(), "upload_2".to_string());
+        multipart_uploads.unfinished.remove("key_2");
+        multipart_uploads.persist();
+
+        let restarted_uploads = dummy_storage(workdir.path().to_owned())
+            .multipart_uploads
+            .into_inner()
+            .unwrap();
+        assert_eq!(
+            restarted_uploads.stale,
+            vec![("key_1".to_string(), "upload_1".to_string())],
+            "Only the uploads left unfinished before the restart should be considered stale"
+        );
+        assert!(restarted_uploads.unfinished.is_empty());
+
+        Ok(())
+    }
+
     fn dummy_storage(workdir: PathBuf) -> S3Bucket {
         S3Bucket {
-            workdir,
             client: S3Client::new("us-east-1".parse().unwrap()),
             bucket_name: "dummy-bucket".to_string(),
             prefix_in_bucket: Some("dummy_prefix/".to_string()),
             concurrency_limiter: Semaphore::new(1),
+            multipart_part_size: crate::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE,
+            multipart_concurrency: 1,
+            resume_multipart_uploads: true,
+            multipart_uploads: Mutex::new(MultipartUploads::load(
+                workdir.join(MULTIPART_UPLOADS_FILE_NAME),
+            )),
+            workdir,
         }
     }
 
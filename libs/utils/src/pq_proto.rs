@@ -22,6 +22,10 @@ pub const INT8_OID: Oid = 20;
 pub const INT4_OID: Oid = 23;
 pub const TEXT_OID: Oid = 25;
 
+/// Application name of pageserver standby replication connections, which
+/// safekeepers serve with the WAL positions only, not the WAL.
+pub const STANDBY_APPLICATION_NAME: &str = "pageserver_standby";
+
 #[derive(Debug)]
 pub enum FeMessage {
     StartupPacket(FeStartupPacket),
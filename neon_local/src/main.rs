@@ -126,6 +126,12 @@ fn main() -> Result<()> {
         .takes_value(true)
         .required(false);
 
+    let replica_arg = Arg::new("replica")
+        .long("replica")
+        .help("Start a read-only hot standby, following the end of the timeline by streaming WAL from safekeepers")
+        .conflicts_with("lsn")
+        .required(false);
+
     let matches = App::new("Neon CLI")
         .setting(AppSettings::ArgRequiredElseHelp)
         .version(GIT_VERSION)
@@ -197,6 +203,9 @@ fn main() -> Result<()> {
                 .subcommand(App::new("stop").about("Stop local pageserver")
                             .arg(stop_mode_arg.clone()))
                 .subcommand(App::new("restart").about("Restart local pageserver").arg(pageserver_config_args.clone()))
+                .subcommand(App::new("bootstrap")
+                    .about("Download all timelines from the pageserver remote storage into the stopped local pageserver")
+                    .arg(pageserver_config_args.clone()))
         )
         .subcommand(
             App::new("safekeeper")
@@ -229,6 +238,7 @@ fn main() -> Result<()> {
                     .arg(tenant_id_arg.clone())
                     .arg(lsn_arg.clone())
                     .arg(port_arg.clone())
+                    .arg(replica_arg.clone())
                     .arg(
                         Arg::new("config-only")
                             .help("Don't do basebackup, create compute node with only config files")
@@ -242,7 +252,8 @@ fn main() -> Result<()> {
                     .arg(branch_name_arg.clone())
                     .arg(timeline_id_arg.clone())
                     .arg(lsn_arg.clone())
-                    .arg(port_arg.clone()))
+                    .arg(port_arg.clone())
+                    .arg(replica_arg.clone()))
                 .subcommand(
                     App::new("stop")
                     .arg(pg_node_arg.clone())
@@ -661,7 +672,7 @@ fn handle_timeline(timeline_match: &ArgMatches, env: &mut local_env::LocalEnv) -
             pageserver.timeline_import(tenant_id, timeline_id, base, pg_wal)?;
             println!("Creating node for imported timeline ...");
             env.register_branch_mapping(name.to_string(), tenant_id, timeline_id)?;
-            cplane.new_node(tenant_id, name, timeline_id, None, None)?;
+            cplane.new_node(tenant_id, name, timeline_id, None, None, false)?;
             println!("Done");
         }
         Some(("branch", branch_match)) => {
@@ -750,7 +761,7 @@ fn handle_pg(pg_match: &ArgMatches, env: &local_env::LocalEnv) -> Result<()> {
             {
                 let lsn_str = match node.lsn {
                     None => {
-                        // -> primary node
+                        // -> primary node or hot standby
                         // Use the LSN at the end of the timeline.
                         timeline_infos
                             .get(&node.timeline_id)
@@ -803,7 +814,8 @@ fn handle_pg(pg_match: &ArgMatches, env: &local_env::LocalEnv) -> Result<()> {
                 Some(p) => Some(p.parse()?),
                 None => None,
             };
-            cplane.new_node(tenant_id, &node_name, timeline_id, lsn, port)?;
+            let replica = sub_args.is_present("replica");
+            cplane.new_node(tenant_id, &node_name, timeline_id, lsn, port, replica)?;
         }
         "start" => {
             let port: Option<u16> = match sub_args.value_of("port") {
@@ -825,6 +837,16 @@ fn handle_pg(pg_match: &ArgMatches, env: &local_env::LocalEnv) -> Result<()> {
             };
 
             if let Some(node) = node {
+                if let Some(lsn) = sub_args.value_of("lsn") {
+                    let lsn = Lsn::from_str(lsn).context("Failed to parse Lsn from the request")?;
+                    if node.lsn != Some(lsn) {
+                        bail!(
+                            "postgres {} already exists and is not pinned at {}, create a new node to start it at another LSN",
+                            node_name,
+                            lsn
+                        );
+                    }
+                }
                 println!("Starting existing postgres {}...", node_name);
                 node.start(&auth_token)?;
             } else {
@@ -850,7 +872,9 @@ fn handle_pg(pg_match: &ArgMatches, env: &local_env::LocalEnv) -> Result<()> {
                     "Starting new postgres {} on timeline {} ...",
                     node_name, timeline_id
                 );
-                let node = cplane.new_node(tenant_id, node_name, timeline_id, lsn, port)?;
+                let replica = sub_args.is_present("replica");
+                let node =
+                    cplane.new_node(tenant_id, node_name, timeline_id, lsn, port, replica)?;
                 node.start(&auth_token)?;
             }
         }
@@ -905,6 +929,15 @@ fn handle_pageserver(sub_match: &ArgMatches, env: &local_env::LocalEnv) -> Resul
                 exit(1);
             }
         }
+
+        Some(("bootstrap", bootstrap_match)) => {
+            if let Err(e) =
+                pageserver.bootstrap_from_remote(&pageserver_config_overrides(bootstrap_match))
+            {
+                eprintln!("pageserver bootstrap failed: {:#}", e);
+                exit(1);
+            }
+        }
         Some((sub_name, _)) => bail!("Unexpected pageserver subcommand '{}'", sub_name),
         None => bail!("no pageserver subcommand provided"),
     }
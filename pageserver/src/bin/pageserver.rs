@@ -11,7 +11,7 @@ use daemonize::Daemonize;
 use fail::FailScenario;
 use pageserver::{
     config::{defaults::*, PageServerConf},
-    http, page_cache, page_service, profiling, tenant_mgr, thread_mgr,
+    http, page_cache, page_service, profiling, storage_sync, tenant_mgr, thread_mgr,
     thread_mgr::ThreadKind,
     timelines, virtual_file, LOG_FILE_NAME,
 };
@@ -54,6 +54,13 @@ fn main() -> anyhow::Result<()> {
                 .takes_value(false)
                 .help("Initialize pageserver service: creates an initial config, tenant and timeline, if specified"),
         )
+        .arg(
+            Arg::new("bootstrap-from-remote")
+                .long("bootstrap-from-remote")
+                .takes_value(false)
+                .conflicts_with_all(&["init", "daemonize"])
+                .help("Download all tenants' timelines found in the configured remote storage into the workdir and exit"),
+        )
         .arg(
             Arg::new("workdir")
                 .short('D')
@@ -111,6 +118,7 @@ fn main() -> anyhow::Result<()> {
     let cfg_file_path = workdir.join("pageserver.toml");
 
     let init = arg_matches.is_present("init");
+    let bootstrap_from_remote = arg_matches.is_present("bootstrap-from-remote");
     let create_tenant = arg_matches
         .value_of("create-tenant")
         .map(ZTenantId::from_str)
@@ -202,6 +210,10 @@ fn main() -> anyhow::Result<()> {
                 cfg_file_path.display()
             )
         })?;
+    } else if bootstrap_from_remote {
+        let _log_file = logging::init(LOG_FILE_NAME, false)?;
+        storage_sync::bootstrap::bootstrap_from_remote(conf)
+            .context("Failed to bootstrap pageserver from the remote storage")?;
     } else {
         start_pageserver(conf, daemonize).context("Failed to start pageserver")?;
     }
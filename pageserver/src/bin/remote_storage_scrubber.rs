@@ -0,0 +1,205 @@
+//! Main entry point for the remote_storage_scrubber executable
+//!
+//! Reports the remote storage files, not referenced by their timelines' index parts, and the index part entries
+//! with no files in the storage, optionally deleting the former ones after a grace period.
+//! See [`pageserver::storage_sync::scrubber`] for more details.
+use std::{fmt::Debug, path::Path, str::FromStr, time::SystemTime};
+
+use anyhow::{anyhow, Context};
+use clap::{App, Arg};
+use pageserver::{
+    config::PageServerConf,
+    storage_sync::scrubber::{self, IndexPartStatus, ScrubState},
+};
+use remote_storage::{GenericRemoteStorage, RemoteStorage};
+use utils::{
+    logging, project_git_version,
+    zid::{ZTenantId, ZTimelineId},
+};
+
+project_git_version!(GIT_VERSION);
+
+const SCRUB_STATE_FILE_NAME: &str = "remote_storage_scrubber_state.json";
+
+fn main() -> anyhow::Result<()> {
+    let arg_matches = App::new("Zenith remote storage scrubber")
+        .about("Find orphaned and missing layer files in the pageserver remote storage")
+        .version(GIT_VERSION)
+        .arg(
+            Arg::new("workdir")
+                .short('D')
+                .long("workdir")
+                .takes_value(true)
+                .help("Working directory of the pageserver, to read its remote storage configuration from"),
+        )
+        .arg(
+            Arg::new("tenant-id")
+                .long("tenant-id")
+                .takes_value(true)
+                .help("Scrub the given tenant only"),
+        )
+        .arg(
+            Arg::new("timeline-id")
+                .long("timeline-id")
+                .takes_value(true)
+                .help("Scrub the given timeline only")
+                .requires("tenant-id"),
+        )
+        .arg(
+            Arg::new("delete")
+                .long("delete")
+                .takes_value(false)
+                .help("Delete the files that stay orphaned for the grace period"),
+        )
+        .arg(
+            Arg::new("grace-period")
+                .long("grace-period")
+                .takes_value(true)
+                .default_value("1 day")
+                .help("How long a file has to stay orphaned across the scrubber runs before it gets deleted"),
+        )
+        .arg(
+            Arg::new("state-file")
+                .long("state-file")
+                .takes_value(true)
+                .help("File to keep the orphans seen between the runs in, defaults to a file in the workdir"),
+        )
+        .get_matches();
+
+    let workdir = Path::new(arg_matches.value_of("workdir").unwrap_or(".neon"));
+    let workdir = workdir
+        .canonicalize()
+        .with_context(|| format!("Error opening workdir '{}'", workdir.display()))?;
+    let tenant_id = arg_matches
+        .value_of("tenant-id")
+        .map(ZTenantId::from_str)
+        .transpose()
+        .context("Failed to parse tenant id from the arguments")?;
+    let timeline_id = arg_matches
+        .value_of("timeline-id")
+        .map(ZTimelineId::from_str)
+        .transpose()
+        .context("Failed to parse timeline id from the arguments")?;
+    let delete = arg_matches.is_present("delete");
+    let grace_period = humantime::parse_duration(arg_matches.value_of("grace-period").unwrap())
+        .context("Failed to parse grace period from the arguments")?;
+    let state_path = arg_matches
+        .value_of("state-file")
+        .map(|path| path.into())
+        .unwrap_or_else(|| workdir.join(SCRUB_STATE_FILE_NAME));
+
+    let cfg_file_path = workdir.join("pageserver.toml");
+    let cfg_file_contents = std::fs::read_to_string(&cfg_file_path)
+        .with_context(|| format!("No pageserver config at '{}'", cfg_file_path.display()))?;
+    let toml = cfg_file_contents
+        .parse::<toml_edit::Document>()
+        .with_context(|| {
+            format!(
+                "Failed to read '{}' as pageserver config",
+                cfg_file_path.display()
+            )
+        })?;
+    let conf = PageServerConf::parse_and_validate(&toml, &workdir)
+        .context("Failed to parse pageserver configuration")?;
+    let conf: &'static PageServerConf = Box::leak(Box::new(conf));
+
+    let _log_file = logging::init(workdir.join("remote_storage_scrubber.log"), false)?;
+
+    let storage_config = conf
+        .remote_storage_config
+        .as_ref()
+        .ok_or_else(|| anyhow!("No remote storage configured for the pageserver"))?;
+    let storage = GenericRemoteStorage::new(conf.workdir.clone(), storage_config)
+        .context("Failed to init the generic remote storage")?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to create the scrubber runtime")?;
+    let args = ScrubArgs {
+        tenant_id,
+        timeline_id,
+        delete,
+        grace_period,
+        state_path: &state_path,
+    };
+    match storage {
+        GenericRemoteStorage::Local(storage) => runtime.block_on(run(conf, &storage, args)),
+        GenericRemoteStorage::S3(storage) => runtime.block_on(run(conf, &storage, args)),
+        GenericRemoteStorage::Azure(storage) => runtime.block_on(run(conf, &storage, args)),
+        GenericRemoteStorage::Gcs(storage) => runtime.block_on(run(conf, &storage, args)),
+    }
+}
+
+struct ScrubArgs<'a> {
+    tenant_id: Option<ZTenantId>,
+    timeline_id: Option<ZTimelineId>,
+    delete: bool,
+    grace_period: std::time::Duration,
+    state_path: &'a Path,
+}
+
+async fn run<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    args: ScrubArgs<'_>,
+) -> anyhow::Result<()>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let report = scrubber::scrub(conf, storage, args.tenant_id, args.timeline_id).await?;
+
+    for (sync_id, timeline) in &report.timelines {
+        match &timeline.index_part {
+            IndexPartStatus::Valid => {}
+            IndexPartStatus::Missing => println!("{sync_id}: no index part"),
+            IndexPartStatus::Unreadable(e) => println!("{sync_id}: unreadable index part: {e}"),
+        }
+        for orphan in &timeline.orphaned_files {
+            println!("{sync_id}: orphaned file '{}'", orphan.display());
+        }
+        for missing in &timeline.missing_layers {
+            println!("{sync_id}: missing layer '{}'", missing.display());
+        }
+        for unindexed in &timeline.unindexed_files {
+            println!(
+                "{sync_id}: file '{}' not checked, the index part is missing or unreadable",
+                unindexed.display()
+            );
+        }
+    }
+    for unrecognized in &report.unrecognized_files {
+        println!("unrecognized file '{}'", unrecognized.display());
+    }
+    println!(
+        "Scrubbed {} timelines: {} orphaned files, {} missing layers, {} unchecked files, {} unrecognized files",
+        report.timelines.len(),
+        report.orphaned_files().count(),
+        report.missing_layers().count(),
+        report.unindexed_files().count(),
+        report.unrecognized_files.len(),
+    );
+
+    let mut state = ScrubState::load(args.state_path)?;
+    let expired_orphans = state.update(&report, SystemTime::now(), args.grace_period);
+    if args.delete {
+        let deleted = scrubber::delete_orphans(storage, &expired_orphans).await;
+        for orphan in &deleted {
+            state.forget(orphan);
+        }
+        println!(
+            "Deleted {} out of {} files orphaned for longer than {}",
+            deleted.len(),
+            expired_orphans.len(),
+            humantime::format_duration(args.grace_period),
+        );
+    } else if !expired_orphans.is_empty() {
+        println!(
+            "{} files are orphaned for longer than {}, rerun with --delete to remove them",
+            expired_orphans.len(),
+            humantime::format_duration(args.grace_period),
+        );
+    }
+    state.save(args.state_path)
+}
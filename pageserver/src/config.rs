@@ -495,6 +495,13 @@ impl PageServerConf {
         if let Some(max_lsn_wal_lag) = item.get("max_lsn_wal_lag") {
             t_conf.max_lsn_wal_lag = Some(parse_toml_from_str("max_lsn_wal_lag", max_lsn_wal_lag)?);
         }
+        if let Some(walreceiver_standby_connection) = item.get("walreceiver_standby_connection") {
+            t_conf.walreceiver_standby_connection = Some(
+                walreceiver_standby_connection
+                    .as_bool()
+                    .context("configure option walreceiver_standby_connection is not a bool")?,
+            );
+        }
 
         Ok(t_conf)
     }
@@ -596,7 +603,7 @@ mod tests {
         num::{NonZeroU32, NonZeroUsize},
     };
 
-    use remote_storage::{RemoteStorageKind, S3Config};
+    use remote_storage::{AzureConfig, GcsConfig, RemoteStorageKind, S3Config};
     use tempfile::{tempdir, TempDir};
 
     use super::*;
@@ -755,7 +762,13 @@ broker_endpoints = ['{broker_endpoint}']
                         .unwrap(),
                     max_sync_errors: NonZeroU32::new(remote_storage::DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS)
                         .unwrap(),
+                    max_upload_bytes_per_second: None,
+                    max_download_bytes_per_second: None,
+                    max_index_part_versions: NonZeroUsize::new(
+                        remote_storage::DEFAULT_REMOTE_STORAGE_MAX_INDEX_PART_VERSIONS
+                    ).unwrap(),
                     storage: RemoteStorageKind::LocalFs(local_storage_path.clone()),
+                    encryption: None,
                 },
                 "Remote storage config should correctly parse the local FS config and fill other storage defaults"
             );
@@ -775,6 +788,8 @@ broker_endpoints = ['{broker_endpoint}']
         let max_concurrent_syncs = NonZeroUsize::new(111).unwrap();
         let max_sync_errors = NonZeroU32::new(222).unwrap();
         let s3_concurrency_limit = NonZeroUsize::new(333).unwrap();
+        let multipart_part_size = 32 * 1024 * 1024;
+        let multipart_concurrency = NonZeroUsize::new(8).unwrap();
         let broker_endpoint = "http://127.0.0.1:7777";
 
         let identical_toml_declarations = &[
@@ -786,11 +801,14 @@ bucket_name = '{bucket_name}'
 bucket_region = '{bucket_region}'
 prefix_in_bucket = '{prefix_in_bucket}'
 endpoint = '{endpoint}'
-concurrency_limit = {s3_concurrency_limit}"#
+concurrency_limit = {s3_concurrency_limit}
+multipart_part_size = {multipart_part_size}
+multipart_concurrency = {multipart_concurrency}"#
             ),
             format!(
                 "remote_storage={{max_concurrent_syncs={max_concurrent_syncs}, max_sync_errors={max_sync_errors}, bucket_name='{bucket_name}',\
-                bucket_region='{bucket_region}', prefix_in_bucket='{prefix_in_bucket}', endpoint='{endpoint}', concurrency_limit={s3_concurrency_limit}}}",
+                bucket_region='{bucket_region}', prefix_in_bucket='{prefix_in_bucket}', endpoint='{endpoint}', concurrency_limit={s3_concurrency_limit},\
+                multipart_part_size={multipart_part_size}, multipart_concurrency={multipart_concurrency}}}",
             ),
         ];
 
@@ -818,13 +836,22 @@ broker_endpoints = ['{broker_endpoint}']
                 RemoteStorageConfig {
                     max_concurrent_syncs,
                     max_sync_errors,
+                    max_upload_bytes_per_second: None,
+                    max_download_bytes_per_second: None,
+                    max_index_part_versions: NonZeroUsize::new(
+                        remote_storage::DEFAULT_REMOTE_STORAGE_MAX_INDEX_PART_VERSIONS
+                    )
+                    .unwrap(),
                     storage: RemoteStorageKind::AwsS3(S3Config {
                         bucket_name: bucket_name.clone(),
                         bucket_region: bucket_region.clone(),
                         prefix_in_bucket: Some(prefix_in_bucket.clone()),
                         endpoint: Some(endpoint.clone()),
                         concurrency_limit: s3_concurrency_limit,
+                        multipart_part_size,
+                        multipart_concurrency,
                     }),
+                    encryption: None,
                 },
                 "Remote storage config should correctly parse the S3 config"
             );
@@ -832,6 +859,153 @@ broker_endpoints = ['{broker_endpoint}']
         Ok(())
     }
 
+    #[test]
+    fn parse_remote_azure_storage_config() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let (workdir, pg_distrib_dir) = prepare_fs(&tempdir)?;
+
+        let container_name = "some-sample-container".to_string();
+        let storage_account = "someaccount".to_string();
+        let prefix_in_container = "test_prefix".to_string();
+        let endpoint = "http://127.0.0.1:10000/devstoreaccount1".to_string();
+        let azure_concurrency_limit = NonZeroUsize::new(333).unwrap();
+        let broker_endpoint = "http://127.0.0.1:7777";
+
+        let config_string = format!(
+            r#"{ALL_BASE_VALUES_TOML}
+pg_distrib_dir='{}'
+broker_endpoints = ['{broker_endpoint}']
+
+[remote_storage]
+container_name = '{container_name}'
+storage_account = '{storage_account}'
+prefix_in_container = '{prefix_in_container}'
+endpoint = '{endpoint}'
+concurrency_limit = {azure_concurrency_limit}"#,
+            pg_distrib_dir.display(),
+        );
+
+        let toml = config_string.parse()?;
+
+        let parsed_remote_storage_config = PageServerConf::parse_and_validate(&toml, &workdir)
+            .unwrap_or_else(|e| panic!("Failed to parse config '{config_string}', reason: {e:?}"))
+            .remote_storage_config
+            .expect("Should have remote storage config for Azure");
+
+        assert_eq!(
+            parsed_remote_storage_config,
+            RemoteStorageConfig {
+                max_concurrent_syncs: NonZeroUsize::new(
+                    remote_storage::DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_SYNCS
+                )
+                .unwrap(),
+                max_sync_errors: NonZeroU32::new(
+                    remote_storage::DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS
+                )
+                .unwrap(),
+                max_upload_bytes_per_second: None,
+                max_download_bytes_per_second: None,
+                max_index_part_versions: NonZeroUsize::new(
+                    remote_storage::DEFAULT_REMOTE_STORAGE_MAX_INDEX_PART_VERSIONS
+                )
+                .unwrap(),
+                storage: RemoteStorageKind::AzureContainer(AzureConfig {
+                    container_name,
+                    storage_account: Some(storage_account),
+                    prefix_in_container: Some(prefix_in_container),
+                    endpoint: Some(endpoint),
+                    concurrency_limit: azure_concurrency_limit,
+                }),
+                encryption: None,
+            },
+            "Remote storage config should correctly parse the Azure config"
+        );
+
+        let mixed_config_string = config_string.replace(
+            "[remote_storage]",
+            "[remote_storage]\nbucket_name = 'some-sample-bucket'",
+        );
+        assert!(
+            PageServerConf::parse_and_validate(&mixed_config_string.parse()?, &workdir).is_err(),
+            "Azure and S3 options should not be allowed together"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_remote_gcs_storage_config() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let (workdir, pg_distrib_dir) = prepare_fs(&tempdir)?;
+
+        let bucket_name = "some-sample-bucket".to_string();
+        let prefix_in_bucket = "test_prefix".to_string();
+        let endpoint = "http://127.0.0.1:4443".to_string();
+        let credentials_file = PathBuf::from("/etc/neon/gcs-service-account.json");
+        let gcs_concurrency_limit = NonZeroUsize::new(444).unwrap();
+        let broker_endpoint = "http://127.0.0.1:7777";
+
+        let config_string = format!(
+            r#"{ALL_BASE_VALUES_TOML}
+pg_distrib_dir='{}'
+broker_endpoints = ['{broker_endpoint}']
+
+[remote_storage]
+gcs_bucket = '{bucket_name}'
+prefix_in_bucket = '{prefix_in_bucket}'
+endpoint = '{endpoint}'
+credentials_file = '{}'
+concurrency_limit = {gcs_concurrency_limit}"#,
+            pg_distrib_dir.display(),
+            credentials_file.display(),
+        );
+
+        let toml = config_string.parse()?;
+
+        let parsed_remote_storage_config = PageServerConf::parse_and_validate(&toml, &workdir)
+            .unwrap_or_else(|e| panic!("Failed to parse config '{config_string}', reason: {e:?}"))
+            .remote_storage_config
+            .expect("Should have remote storage config for GCS");
+
+        assert_eq!(
+            parsed_remote_storage_config,
+            RemoteStorageConfig {
+                max_concurrent_syncs: NonZeroUsize::new(
+                    remote_storage::DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_SYNCS
+                )
+                .unwrap(),
+                max_sync_errors: NonZeroU32::new(
+                    remote_storage::DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS
+                )
+                .unwrap(),
+                max_upload_bytes_per_second: None,
+                max_download_bytes_per_second: None,
+                max_index_part_versions: NonZeroUsize::new(
+                    remote_storage::DEFAULT_REMOTE_STORAGE_MAX_INDEX_PART_VERSIONS
+                )
+                .unwrap(),
+                storage: RemoteStorageKind::Gcs(GcsConfig {
+                    bucket_name,
+                    prefix_in_bucket: Some(prefix_in_bucket),
+                    endpoint: Some(endpoint),
+                    credentials_file: Some(credentials_file),
+                    concurrency_limit: gcs_concurrency_limit,
+                }),
+                encryption: None,
+            },
+            "Remote storage config should correctly parse the GCS config"
+        );
+
+        let mixed_config_string = config_string.replace(
+            "[remote_storage]",
+            "[remote_storage]\ncontainer_name = 'some-sample-container'",
+        );
+        assert!(
+            PageServerConf::parse_and_validate(&mixed_config_string.parse()?, &workdir).is_err(),
+            "GCS and Azure options should not be allowed together"
+        );
+        Ok(())
+    }
+
     fn prepare_fs(tempdir: &TempDir) -> anyhow::Result<(PathBuf, PathBuf)> {
         let tempdir_path = tempdir.path();
 
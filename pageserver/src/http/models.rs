@@ -7,6 +7,8 @@ use utils::{
     zid::{NodeId, ZTenantId, ZTimelineId},
 };
 
+use crate::storage_sync::index::IndexPartVersion;
+
 #[serde_as]
 #[derive(Serialize, Deserialize)]
 pub struct TimelineCreateRequest {
@@ -38,6 +40,7 @@ pub struct TenantCreateRequest {
     pub walreceiver_connect_timeout: Option<String>,
     pub lagging_wal_timeout: Option<String>,
     pub max_lsn_wal_lag: Option<NonZeroU64>,
+    pub walreceiver_standby_connection: Option<bool>,
 }
 
 #[serde_as]
@@ -76,6 +79,7 @@ pub struct TenantConfigRequest {
     pub walreceiver_connect_timeout: Option<String>,
     pub lagging_wal_timeout: Option<String>,
     pub max_lsn_wal_lag: Option<NonZeroU64>,
+    pub walreceiver_standby_connection: Option<bool>,
 }
 
 impl TenantConfigRequest {
@@ -93,6 +97,7 @@ impl TenantConfigRequest {
             walreceiver_connect_timeout: None,
             lagging_wal_timeout: None,
             max_lsn_wal_lag: None,
+            walreceiver_standby_connection: None,
         }
     }
 }
@@ -108,3 +113,48 @@ pub struct WalReceiverEntry {
     /// the timestamp (in microseconds) of the last received message
     pub last_received_msg_ts: Option<u128>,
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct FailpointConfig {
+    pub name: String,
+    pub actions: String,
+}
+
+pub type ConfigureFailpointsRequest = Vec<FailpointConfig>;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct TimelineGcRequest {
+    /// Overrides the tenant's gc_horizon for this GC run, if set.
+    pub gc_horizon: Option<u64>,
+}
+
+/// Result of the LSN lookup by timestamp. `lsn` is only set for the `present` kind.
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+pub struct LsnByTimestampResponse {
+    pub kind: LsnByTimestampKind,
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub lsn: Option<Lsn>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LsnByTimestampKind {
+    Present,
+    Future,
+    Past,
+}
+
+/// Index part versions of the timeline, kept in the remote storage, from the oldest to the current one.
+#[derive(Serialize, Deserialize)]
+pub struct RemoteIndexVersionsResponse {
+    pub current_version: u64,
+    pub versions: Vec<IndexPartVersion>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoteRollbackRequest {
+    /// One of the older index part versions kept.
+    pub version: u64,
+}
@@ -3,16 +3,20 @@ use std::sync::Arc;
 use anyhow::{Context, Result};
 use hyper::StatusCode;
 use hyper::{Body, Request, Response, Uri};
-use remote_storage::GenericRemoteStorage;
+use postgres_ffi::xlog_utils::to_pg_timestamp;
+use remote_storage::{GenericRemoteStorage, RemoteStorageError};
 use tracing::*;
 
 use super::models::{
-    StatusResponse, TenantConfigRequest, TenantCreateRequest, TenantCreateResponse,
-    TimelineCreateRequest,
+    ConfigureFailpointsRequest, LsnByTimestampKind, LsnByTimestampResponse,
+    RemoteIndexVersionsResponse, RemoteRollbackRequest, StatusResponse, TenantConfigRequest,
+    TenantCreateRequest, TenantCreateResponse, TimelineCreateRequest, TimelineGcRequest,
 };
+use crate::pgdatadir_mapping::LsnForTimestamp;
 use crate::repository::Repository;
 use crate::storage_sync;
 use crate::storage_sync::index::{RemoteIndex, RemoteTimeline};
+use crate::storage_sync::RemoteRollback;
 use crate::tenant_config::TenantConfOpt;
 use crate::timelines::{LocalTimelineInfo, RemoteTimelineInfo, TimelineInfo};
 use crate::{config::PageServerConf, tenant_mgr, timelines};
@@ -328,9 +332,30 @@ async fn try_download_index_part_data(
         Some(GenericRemoteStorage::S3(s3_storage)) => {
             storage_sync::download_index_part(state.conf, s3_storage, sync_id).await
         }
+        Some(GenericRemoteStorage::Azure(azure_storage)) => {
+            storage_sync::download_index_part(state.conf, azure_storage, sync_id).await
+        }
+        Some(GenericRemoteStorage::Gcs(gcs_storage)) => {
+            storage_sync::download_index_part(state.conf, gcs_storage, sync_id).await
+        }
         None => return Ok(None),
-    }
-    .with_context(|| format!("Failed to download index part for timeline {sync_id}"))?;
+    };
+    let index_part = match index_part {
+        Ok(index_part) => index_part,
+        Err(e)
+            if matches!(
+                e.downcast_ref::<RemoteStorageError>(),
+                Some(RemoteStorageError::NotFound)
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => {
+            return Err(e.context(format!(
+                "Failed to download index part for timeline {sync_id}"
+            )))
+        }
+    };
 
     let timeline_path = state
         .conf
@@ -342,6 +367,163 @@ async fn try_download_index_part_data(
         })
 }
 
+async fn remote_index_versions_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let sync_id = ZTenantTimelineId {
+        tenant_id,
+        timeline_id,
+    };
+    let state = get_state(&request);
+
+    let indexed_timeline = state
+        .remote_index
+        .read()
+        .await
+        .timeline_entry(&sync_id)
+        .cloned();
+    let remote_timeline = match indexed_timeline {
+        Some(remote_timeline) => remote_timeline,
+        None => try_download_index_part_data(state, sync_id)
+            .await?
+            .ok_or_else(|| ApiError::NotFound("Unknown remote timeline".to_string()))?,
+    };
+
+    json_response(
+        StatusCode::OK,
+        RemoteIndexVersionsResponse {
+            current_version: remote_timeline.index_version(),
+            versions: remote_timeline.index_history().to_vec(),
+        },
+    )
+}
+
+async fn remote_rollback_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let rollback_request: RemoteRollbackRequest = json_request(&mut request).await?;
+    let version = rollback_request.version;
+    info!("Handling timeline {timeline_id} remote rollback to index part version {version} for tenant: {tenant_id}");
+
+    let is_local = tokio::task::spawn_blocking(move || {
+        tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id).is_ok()
+    })
+    .await
+    .map_err(ApiError::from_err)?;
+    if is_local {
+        return Err(ApiError::Conflict(
+            "Timeline is present locally, detach it before the rollback".to_string(),
+        ));
+    }
+
+    let sync_id = ZTenantTimelineId {
+        tenant_id,
+        timeline_id,
+    };
+    let state = get_state(&request);
+    let remote_index = &state.remote_index;
+
+    let awaits_download = remote_index
+        .read()
+        .await
+        .timeline_entry(&sync_id)
+        .map(|remote_timeline| remote_timeline.awaits_download);
+    match awaits_download {
+        Some(true) => {
+            return Err(ApiError::Conflict(
+                "Timeline download is in progress".to_string(),
+            ))
+        }
+        Some(false) => {}
+        None => {
+            let new_timeline = try_download_index_part_data(state, sync_id)
+                .await?
+                .ok_or_else(|| ApiError::NotFound("Unknown remote timeline".to_string()))?;
+            let mut index_accessor = remote_index.write().await;
+            if index_accessor.timeline_entry(&sync_id).is_none() {
+                index_accessor.add_timeline_entry(sync_id, new_timeline);
+            }
+        }
+    }
+
+    let rollback = match state.remote_storage.as_ref() {
+        Some(GenericRemoteStorage::Local(local_storage)) => {
+            storage_sync::roll_back_remote_timeline(
+                state.conf,
+                local_storage,
+                remote_index,
+                sync_id,
+                version,
+            )
+            .await
+        }
+        Some(GenericRemoteStorage::S3(s3_storage)) => {
+            storage_sync::roll_back_remote_timeline(
+                state.conf,
+                s3_storage,
+                remote_index,
+                sync_id,
+                version,
+            )
+            .await
+        }
+        Some(GenericRemoteStorage::Azure(azure_storage)) => {
+            storage_sync::roll_back_remote_timeline(
+                state.conf,
+                azure_storage,
+                remote_index,
+                sync_id,
+                version,
+            )
+            .await
+        }
+        Some(GenericRemoteStorage::Gcs(gcs_storage)) => {
+            storage_sync::roll_back_remote_timeline(
+                state.conf,
+                gcs_storage,
+                remote_index,
+                sync_id,
+                version,
+            )
+            .await
+        }
+        None => {
+            return Err(ApiError::BadRequest(
+                "Remote storage is not configured".to_string(),
+            ))
+        }
+    }?;
+
+    match rollback {
+        RemoteRollback::RolledBack => {}
+        RemoteRollback::UnknownVersion => {
+            return Err(ApiError::NotFound(format!(
+                "No older index part version {version} is kept for the timeline"
+            )))
+        }
+        RemoteRollback::MissingLayers(missing_layers) => {
+            return Err(ApiError::Conflict(format!(
+                "Index part version {version} references layers missing in the remote storage: {missing_layers:?}"
+            )))
+        }
+    }
+
+    let remote_index_versions = remote_index
+        .read()
+        .await
+        .timeline_entry(&sync_id)
+        .map(|remote_timeline| RemoteIndexVersionsResponse {
+            current_version: remote_timeline.index_version(),
+            versions: remote_timeline.index_history().to_vec(),
+        })
+        .context("Timeline disappeared from the remote index after the rollback")?;
+    json_response(StatusCode::OK, remote_index_versions)
+}
+
 async fn timeline_detach_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
     let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
     check_permission(&request, Some(tenant_id))?;
@@ -407,6 +589,7 @@ async fn tenant_create_handler(mut request: Request<Body>) -> Result<Response<Bo
     if let Some(max_lsn_wal_lag) = request_data.max_lsn_wal_lag {
         tenant_conf.max_lsn_wal_lag = Some(max_lsn_wal_lag);
     }
+    tenant_conf.walreceiver_standby_connection = request_data.walreceiver_standby_connection;
 
     tenant_conf.checkpoint_distance = request_data.checkpoint_distance;
     tenant_conf.compaction_target_size = request_data.compaction_target_size;
@@ -467,6 +650,7 @@ async fn tenant_config_handler(mut request: Request<Body>) -> Result<Response<Bo
     if let Some(max_lsn_wal_lag) = request_data.max_lsn_wal_lag {
         tenant_conf.max_lsn_wal_lag = Some(max_lsn_wal_lag);
     }
+    tenant_conf.walreceiver_standby_connection = request_data.walreceiver_standby_connection;
 
     tenant_conf.checkpoint_distance = request_data.checkpoint_distance;
     tenant_conf.compaction_target_size = request_data.compaction_target_size;
@@ -488,6 +672,121 @@ async fn tenant_config_handler(mut request: Request<Body>) -> Result<Response<Bo
     json_response(StatusCode::OK, ())
 }
 
+async fn failpoints_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    // check for management permission
+    check_permission(&request, None)?;
+
+    let failpoints: ConfigureFailpointsRequest = json_request(&mut request).await?;
+    for fp in failpoints {
+        crate::apply_failpoint(&fp.name, &fp.actions)
+            .map_err(|e| ApiError::BadRequest(format!("{e:#}")))?;
+    }
+
+    json_response(StatusCode::OK, ())
+}
+
+// Run GC immediately on given timeline.
+async fn timeline_gc_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let gc_req: TimelineGcRequest = json_request(&mut request).await?;
+
+    let gc_result = tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("timeline_gc", tenant = %tenant_id, timeline = %timeline_id, gc_horizon = ?gc_req.gc_horizon).entered();
+        tenant_mgr::immediate_gc(tenant_id, timeline_id, gc_req.gc_horizon)
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, gc_result)
+}
+
+// Run compaction immediately on given timeline.
+async fn timeline_compact_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_compact", tenant = %tenant_id, timeline = %timeline_id).entered();
+        tenant_mgr::immediate_compact(tenant_id, timeline_id)
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, ())
+}
+
+// Run checkpoint (followed by compaction) immediately on given timeline.
+async fn timeline_checkpoint_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_checkpoint", tenant = %tenant_id, timeline = %timeline_id)
+                .entered();
+        tenant_mgr::immediate_checkpoint(tenant_id, timeline_id)
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, ())
+}
+
+// Locate LSN of last transaction with timestamp less or equal than specified
+async fn get_lsn_by_timestamp_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timestamp_raw = request
+        .uri()
+        .query()
+        .and_then(|v| {
+            url::form_urlencoded::parse(v.as_bytes())
+                .into_owned()
+                .find(|(param, _)| param == "timestamp")
+                .map(|(_, value)| value)
+        })
+        .ok_or_else(|| ApiError::BadRequest("Missing 'timestamp' query parameter".to_string()))?;
+    let timestamp = humantime::parse_rfc3339(&timestamp_raw)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid timestamp '{timestamp_raw}': {e}")))?;
+    let timestamp_pg = to_pg_timestamp(timestamp);
+
+    let result = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("get_lsn_by_timestamp", tenant = %tenant_id, timeline = %timeline_id)
+                .entered();
+        let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)
+            .context("Cannot load local timeline")?;
+        timeline.find_lsn_for_timestamp(timestamp_pg)
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    let response = match result {
+        LsnForTimestamp::Present(lsn) => LsnByTimestampResponse {
+            kind: LsnByTimestampKind::Present,
+            lsn: Some(lsn),
+        },
+        LsnForTimestamp::Future(_lsn) => LsnByTimestampResponse {
+            kind: LsnByTimestampKind::Future,
+            lsn: None,
+        },
+        LsnForTimestamp::Past(_lsn) => LsnByTimestampResponse {
+            kind: LsnByTimestampKind::Past,
+            lsn: None,
+        },
+    };
+
+    json_response(StatusCode::OK, response)
+}
+
 async fn handler_404(_: Request<Body>) -> Result<Response<Body>, ApiError> {
     json_response(
         StatusCode::NOT_FOUND,
@@ -521,6 +820,7 @@ pub fn make_router(
         .get("/v1/tenant", tenant_list_handler)
         .post("/v1/tenant", tenant_create_handler)
         .put("/v1/tenant/config", tenant_config_handler)
+        .put("/v1/failpoints", failpoints_handler)
         .get("/v1/tenant/:tenant_id/timeline", timeline_list_handler)
         .post("/v1/tenant/:tenant_id/timeline", timeline_create_handler)
         .get(
@@ -539,5 +839,29 @@ pub fn make_router(
             "/v1/tenant/:tenant_id/timeline/:timeline_id/detach",
             timeline_detach_handler,
         )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/remote_index_versions",
+            remote_index_versions_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/remote_rollback",
+            remote_rollback_handler,
+        )
+        .put(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/do_gc",
+            timeline_gc_handler,
+        )
+        .put(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/compact",
+            timeline_compact_handler,
+        )
+        .put(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/checkpoint",
+            timeline_checkpoint_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/get_lsn_by_timestamp",
+            get_lsn_by_timestamp_handler,
+        )
         .any(handler_404))
 }
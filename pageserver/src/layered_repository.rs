@@ -21,7 +21,7 @@ use tracing::*;
 use std::cmp::{max, min, Ordering};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::collections::{BTreeSet, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
@@ -579,6 +579,13 @@ impl LayeredRepository {
             .unwrap_or(self.conf.default_tenant_conf.max_lsn_wal_lag)
     }
 
+    pub fn get_walreceiver_standby_connection(&self) -> bool {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .walreceiver_standby_connection
+            .unwrap_or(self.conf.default_tenant_conf.walreceiver_standby_connection)
+    }
+
     pub fn update_tenant_config(&self, new_tenant_conf: TenantConfOpt) -> Result<()> {
         let mut tenant_conf = self.tenant_conf.write().unwrap();
 
@@ -1017,6 +1024,12 @@ struct GcInfo {
     /// explicit user-defined snapshot points.
     retain_lsns: Vec<Lsn>,
 
+    /// Temporary retain points, registered by read-only snapshot connections
+    /// for their lifetime, with the number of connections holding each one.
+    ///
+    /// Unlike 'retain_lsns', these are not recalculated on each GC iteration.
+    pinned_lsns: BTreeMap<Lsn, usize>,
+
     /// In addition to 'retain_lsns', keep everything newer than this
     /// point.
     ///
@@ -1135,6 +1148,26 @@ impl Timeline for LayeredTimeline {
         Ok(())
     }
 
+    fn pin_lsn(&self, lsn: Lsn) -> Result<()> {
+        // GC holds 'gc_info' for the whole iteration, so once the check passes,
+        // the GC horizon cannot move past 'lsn' before the pin is registered.
+        let mut gc_info = self.gc_info.write().unwrap();
+        self.check_lsn_is_in_scope(lsn, &self.get_latest_gc_cutoff_lsn())?;
+        *gc_info.pinned_lsns.entry(lsn).or_default() += 1;
+        Ok(())
+    }
+
+    fn unpin_lsn(&self, lsn: Lsn) {
+        let mut gc_info = self.gc_info.write().unwrap();
+        match gc_info.pinned_lsns.get_mut(&lsn) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                gc_info.pinned_lsns.remove(&lsn);
+            }
+            None => warn!("unpinning LSN {} that was not pinned", lsn),
+        }
+    }
+
     fn get_last_record_lsn(&self) -> Lsn {
         self.last_record_lsn.load().last
     }
@@ -1273,6 +1306,7 @@ impl LayeredTimeline {
 
             gc_info: RwLock::new(GcInfo {
                 retain_lsns: Vec::new(),
+                pinned_lsns: BTreeMap::new(),
                 cutoff: Lsn(0),
                 pitr: Duration::ZERO,
             }),
@@ -2185,6 +2219,7 @@ impl LayeredTimeline {
 
         let gc_info = self.gc_info.read().unwrap();
         let retain_lsns = &gc_info.retain_lsns;
+        let pinned_lsns = &gc_info.pinned_lsns;
         let cutoff = min(gc_info.cutoff, disk_consistent_lsn);
         let pitr = gc_info.pitr;
 
@@ -2240,6 +2275,7 @@ impl LayeredTimeline {
         info!("GC starting");
 
         debug!("retain_lsns: {:?}", retain_lsns);
+        debug!("pinned_lsns: {:?}", pinned_lsns);
 
         let mut layers_to_remove = Vec::new();
 
@@ -2248,7 +2284,7 @@ impl LayeredTimeline {
         // Garbage collect the layer if all conditions are satisfied:
         // 1. it is older than cutoff LSN;
         // 2. it is older than PITR interval;
-        // 3. it doesn't need to be retained for 'retain_lsns';
+        // 3. it doesn't need to be retained for 'retain_lsns' or 'pinned_lsns';
         // 4. newer on-disk image layers cover the layer's whole key range
         //
         let mut layers = self.layers.write().unwrap();
@@ -2306,6 +2342,19 @@ impl LayeredTimeline {
                 }
             }
 
+            // 3a. Is it needed by a read-only snapshot connection?
+            for pinned_lsn in pinned_lsns.keys() {
+                if &l.get_lsn_range().start <= pinned_lsn {
+                    debug!(
+                        "keeping {} because it's still might be read by a snapshot pinned at {}",
+                        l.filename().display(),
+                        pinned_lsn,
+                    );
+                    result.layers_needed_by_pins += 1;
+                    continue 'outer;
+                }
+            }
+
             // 4. Is there a later on-disk layer for this relation?
             //
             // The end-LSN is exclusive, while disk_consistent_lsn is
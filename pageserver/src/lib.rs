@@ -7,6 +7,7 @@ pub mod layered_repository;
 pub mod page_cache;
 pub mod page_service;
 pub mod pgdatadir_mapping;
+pub mod prefetch;
 pub mod profiling;
 pub mod reltag;
 pub mod repository;
@@ -73,6 +74,7 @@ pub fn shutdown_pageserver(exit_code: i32) {
 
     // Shut down any page service threads.
     thread_mgr::shutdown_threads(Some(ThreadKind::PageRequestHandler), None, None);
+    thread_mgr::shutdown_threads(Some(ThreadKind::Prefetcher), None, None);
 
     // Shut down all the tenants. This flushes everything to disk and kills
     // the checkpoint and GC threads.
@@ -94,3 +96,26 @@ pub fn shutdown_pageserver(exit_code: i32) {
     info!("Shut down successfully completed");
     std::process::exit(exit_code);
 }
+
+/// Configures a single failpoint, as accepted by both the libpq `failpoints` command
+/// and the `/v1/failpoints` HTTP endpoint.
+///
+/// We recognize one extra "action" that's not natively recognized
+/// by the failpoints crate: exit, to immediately kill the process
+pub fn apply_failpoint(name: &str, actions: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        fail::has_failpoints(),
+        "Cannot manage failpoints because pageserver was compiled without failpoints support"
+    );
+
+    info!("cfg failpoint: {} {}", name, actions);
+    if actions == "exit" {
+        fail::cfg_callback(name, || {
+            info!("Exit requested by failpoint");
+            std::process::exit(1);
+        })
+        .map_err(|e| anyhow::anyhow!(e))
+    } else {
+        fail::cfg(name, actions).map_err(|e| anyhow::anyhow!(e))
+    }
+}
@@ -15,6 +15,7 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use std::io::{self, Read};
 use std::net::TcpListener;
+use std::ops::Range;
 use std::str;
 use std::str::FromStr;
 use std::sync::{Arc, RwLockReadGuard};
@@ -23,7 +24,7 @@ use utils::{
     auth::{self, Claims, JwtAuth, Scope},
     lsn::Lsn,
     postgres_backend::{self, is_socket_read_timed_out, AuthType, PostgresBackend},
-    pq_proto::{BeMessage, FeMessage, RowDescriptor, SINGLE_COL_ROWDESC},
+    pq_proto::{BeMessage, FeMessage, FeStartupPacket, RowDescriptor, SINGLE_COL_ROWDESC},
     zid::{ZTenantId, ZTimelineId},
 };
 
@@ -32,14 +33,15 @@ use crate::config::{PageServerConf, ProfilingConfig};
 use crate::import_datadir::{import_basebackup_from_tar, import_wal_from_tar};
 use crate::layered_repository::LayeredRepository;
 use crate::pgdatadir_mapping::{DatadirTimeline, LsnForTimestamp};
+use crate::prefetch::{PrefetchRequest, Prefetcher, TimelineLsnPin};
 use crate::profiling::profpoint_start;
 use crate::reltag::RelTag;
-use crate::repository::Repository;
-use crate::repository::Timeline;
+use crate::repository::{LsnPin, Repository, Timeline};
 use crate::tenant_mgr;
 use crate::thread_mgr;
 use crate::thread_mgr::ThreadKind;
 use crate::CheckpointConfig;
+use crate::DatadirTimelineImpl;
 use metrics::{register_histogram_vec, HistogramVec};
 use postgres_ffi::xlog_utils::to_pg_timestamp;
 
@@ -51,6 +53,7 @@ enum PagestreamFeMessage {
     Nblocks(PagestreamNblocksRequest),
     GetPage(PagestreamGetPageRequest),
     DbSize(PagestreamDbSizeRequest),
+    Prefetch(PagestreamPrefetchRequest),
 }
 
 // Wrapped in libpq CopyData
@@ -91,6 +94,16 @@ struct PagestreamDbSizeRequest {
     dbnode: u32,
 }
 
+/// Fire-and-forget hint about the pages the compute is going to read soon.
+/// There is no response.
+#[derive(Debug, PartialEq)]
+struct PagestreamPrefetchRequest {
+    latest: bool,
+    lsn: Lsn,
+    rel: RelTag,
+    ranges: Vec<Range<u32>>,
+}
+
 #[derive(Debug)]
 struct PagestreamExistsResponse {
     exists: bool,
@@ -162,6 +175,35 @@ impl PagestreamFeMessage {
                 lsn: Lsn::from(body.get_u64()),
                 dbnode: body.get_u32(),
             })),
+            4 => {
+                let latest = body.get_u8() != 0;
+                let lsn = Lsn::from(body.get_u64());
+                let rel = RelTag {
+                    spcnode: body.get_u32(),
+                    dbnode: body.get_u32(),
+                    relnode: body.get_u32(),
+                    forknum: body.get_u8(),
+                };
+                let n_ranges = body.get_u32() as usize;
+                ensure!(
+                    body.remaining() >= n_ranges * 8,
+                    "prefetch message is too short for {} ranges",
+                    n_ranges
+                );
+                let ranges = (0..n_ranges)
+                    .map(|_| {
+                        let start = body.get_u32();
+                        let nblocks = body.get_u32();
+                        start..start.saturating_add(nblocks)
+                    })
+                    .collect();
+                Ok(PagestreamFeMessage::Prefetch(PagestreamPrefetchRequest {
+                    latest,
+                    lsn,
+                    rel,
+                    ranges,
+                }))
+            }
             _ => bail!("unknown smgr message tag: {},'{:?}'", msg_tag, body),
         }
     }
@@ -425,6 +467,10 @@ struct PageServerHandler {
     conf: &'static PageServerConf,
     auth: Option<Arc<JwtAuth>>,
     claims: Option<Claims>,
+    /// If set, the connection is a read-only snapshot of the timeline frozen at
+    /// this LSN. Passed as `snapshot_lsn` startup option, which the control
+    /// plane puts into the pageserver connection string of read-only nodes.
+    snapshot_lsn: Option<Lsn>,
 }
 
 const TIME_BUCKETS: &[f64] = &[
@@ -451,6 +497,7 @@ impl PageServerHandler {
             conf,
             auth,
             claims: None,
+            snapshot_lsn: None,
         }
     }
 
@@ -470,6 +517,25 @@ impl PageServerHandler {
         let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
             .context("Cannot load local timeline")?;
 
+        // In snapshot mode, keep GC from removing the page versions needed to
+        // read the timeline at the snapshot LSN, for as long as the connection lives.
+        // The prefetch requests hold a reference to the pin too, because the
+        // prefetch thread can outlive the connection.
+        let snapshot_pin = match self.snapshot_lsn {
+            Some(lsn) => {
+                info!("serving read-only snapshot at {}", lsn);
+                timeline.tline.wait_lsn(lsn)?;
+                Some(Arc::new(
+                    LsnPin::new(Arc::clone(&timeline.tline), lsn)
+                        .context("invalid snapshot lsn")?,
+                ))
+            }
+            None => None,
+        };
+
+        // Started on the first Prefetch message
+        let mut prefetcher: Option<Prefetcher> = None;
+
         /* switch client to COPYBOTH */
         pgb.write_message(&BeMessage::CopyBothResponse)?;
 
@@ -492,6 +558,20 @@ impl PageServerHandler {
                         let timeline_id = timelineid.to_string();
 
                         let response = match zenith_fe_msg {
+                            PagestreamFeMessage::Prefetch(req) => {
+                                if let Err(e) = self.handle_prefetch_request(
+                                    &timeline,
+                                    &mut prefetcher,
+                                    snapshot_pin.as_ref(),
+                                    tenantid,
+                                    timelineid,
+                                    req,
+                                ) {
+                                    warn!("failed to handle prefetch request: {:?}", e);
+                                }
+                                // Prefetch messages are not responded to
+                                continue;
+                            }
                             PagestreamFeMessage::Exists(req) => SMGR_QUERY_TIME
                                 .with_label_values(&["get_rel_exists", &tenant_id, &timeline_id])
                                 .observe_closure_duration(|| {
@@ -502,11 +582,20 @@ impl PageServerHandler {
                                 .observe_closure_duration(|| {
                                     self.handle_get_nblocks_request(timeline.as_ref(), &req)
                                 }),
-                            PagestreamFeMessage::GetPage(req) => SMGR_QUERY_TIME
-                                .with_label_values(&["get_page_at_lsn", &tenant_id, &timeline_id])
-                                .observe_closure_duration(|| {
-                                    self.handle_get_page_at_lsn_request(timeline.as_ref(), &req)
-                                }),
+                            PagestreamFeMessage::GetPage(req) => {
+                                if let Some(prefetcher) = &prefetcher {
+                                    prefetcher.page_requested(req.rel, req.blkno);
+                                }
+                                SMGR_QUERY_TIME
+                                    .with_label_values(&[
+                                        "get_page_at_lsn",
+                                        &tenant_id,
+                                        &timeline_id,
+                                    ])
+                                    .observe_closure_duration(|| {
+                                        self.handle_get_page_at_lsn_request(timeline.as_ref(), &req)
+                                    })
+                            }
                             PagestreamFeMessage::DbSize(req) => SMGR_QUERY_TIME
                                 .with_label_values(&["get_db_size", &tenant_id, &timeline_id])
                                 .observe_closure_duration(|| {
@@ -643,12 +732,20 @@ impl PageServerHandler {
     /// In either case, if the page server hasn't received the WAL up to the
     /// requested LSN yet, we will wait for it to arrive. The return value is
     /// the LSN that should be used to look up the page versions.
+    ///
+    /// Snapshot connections always read at their pinned LSN, whatever the request says.
     fn wait_or_get_last_lsn<R: Repository>(
         timeline: &DatadirTimeline<R>,
         mut lsn: Lsn,
         latest: bool,
         latest_gc_cutoff_lsn: &RwLockReadGuard<Lsn>,
+        snapshot_lsn: Option<Lsn>,
     ) -> Result<Lsn> {
+        if let Some(snapshot_lsn) = snapshot_lsn {
+            // The connection holds a pin on the snapshot LSN, so the page versions are
+            // there even if the GC horizon has moved past it.
+            return Ok(snapshot_lsn);
+        }
         if latest {
             // Latest page version was requested. If LSN is given, it is a hint
             // to the page server that there have been no modifications to the
@@ -699,7 +796,13 @@ impl PageServerHandler {
         let _enter = info_span!("get_rel_exists", rel = %req.rel, req_lsn = %req.lsn).entered();
 
         let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
-        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+        let lsn = Self::wait_or_get_last_lsn(
+            timeline,
+            req.lsn,
+            req.latest,
+            &latest_gc_cutoff_lsn,
+            self.snapshot_lsn,
+        )?;
 
         let exists = timeline.get_rel_exists(req.rel, lsn)?;
 
@@ -715,7 +818,13 @@ impl PageServerHandler {
     ) -> Result<PagestreamBeMessage> {
         let _enter = info_span!("get_nblocks", rel = %req.rel, req_lsn = %req.lsn).entered();
         let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
-        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+        let lsn = Self::wait_or_get_last_lsn(
+            timeline,
+            req.lsn,
+            req.latest,
+            &latest_gc_cutoff_lsn,
+            self.snapshot_lsn,
+        )?;
 
         let n_blocks = timeline.get_rel_size(req.rel, lsn)?;
 
@@ -731,7 +840,13 @@ impl PageServerHandler {
     ) -> Result<PagestreamBeMessage> {
         let _enter = info_span!("get_db_size", dbnode = %req.dbnode, req_lsn = %req.lsn).entered();
         let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
-        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+        let lsn = Self::wait_or_get_last_lsn(
+            timeline,
+            req.lsn,
+            req.latest,
+            &latest_gc_cutoff_lsn,
+            self.snapshot_lsn,
+        )?;
 
         let total_blocks =
             timeline.get_db_size(pg_constants::DEFAULTTABLESPACE_OID, req.dbnode, lsn)?;
@@ -751,7 +866,13 @@ impl PageServerHandler {
         let _enter = info_span!("get_page", rel = %req.rel, blkno = &req.blkno, req_lsn = %req.lsn)
             .entered();
         let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
-        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+        let lsn = Self::wait_or_get_last_lsn(
+            timeline,
+            req.lsn,
+            req.latest,
+            &latest_gc_cutoff_lsn,
+            self.snapshot_lsn,
+        )?;
         /*
         // Add a 1s delay to some requests. The delayed causes the requests to
         // hit the race condition from github issue #1047 more easily.
@@ -767,6 +888,43 @@ impl PageServerHandler {
         }))
     }
 
+    /// Queue the pages for background reconstruction, starting the connection's
+    /// prefetcher on first use. Doesn't wait for the WAL to arrive.
+    fn handle_prefetch_request(
+        &self,
+        timeline: &Arc<DatadirTimelineImpl>,
+        prefetcher: &mut Option<Prefetcher>,
+        snapshot_pin: Option<&Arc<TimelineLsnPin>>,
+        tenantid: ZTenantId,
+        timelineid: ZTimelineId,
+        req: PagestreamPrefetchRequest,
+    ) -> Result<()> {
+        let lsn = if let Some(snapshot_pin) = snapshot_pin {
+            snapshot_pin.lsn()
+        } else if req.latest {
+            std::cmp::max(req.lsn, timeline.get_last_record_lsn())
+        } else {
+            ensure!(req.lsn != Lsn(0), "invalid LSN(0) in prefetch request");
+            req.lsn
+        };
+
+        if prefetcher.is_none() {
+            *prefetcher = Some(Prefetcher::spawn(
+                Arc::clone(timeline),
+                tenantid,
+                timelineid,
+            )?);
+        }
+        let prefetcher = prefetcher.as_ref().unwrap();
+        prefetcher.request(PrefetchRequest {
+            rel: req.rel,
+            ranges: req.ranges,
+            lsn,
+            pin: snapshot_pin.cloned(),
+        });
+        Ok(())
+    }
+
     fn handle_basebackup_request(
         &self,
         pgb: &mut PostgresBackend,
@@ -782,12 +940,13 @@ impl PageServerHandler {
         // check that the timeline exists
         let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
             .context("Cannot load local timeline")?;
-        let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
-        if let Some(lsn) = lsn {
-            timeline
-                .check_lsn_is_in_scope(lsn, &latest_gc_cutoff_lsn)
-                .context("invalid basebackup lsn")?;
-        }
+
+        // Keep GC from removing the page versions needed for the backup while it's being sent.
+        let lsn = lsn.or(self.snapshot_lsn);
+        let _lsn_pin = lsn
+            .map(|lsn| LsnPin::new(Arc::clone(&timeline.tline), lsn))
+            .transpose()
+            .context("invalid basebackup lsn")?;
 
         // switch client to COPYOUT
         pgb.write_message(&BeMessage::CopyOutResponse)?;
@@ -858,6 +1017,15 @@ impl postgres_backend::Handler for PageServerHandler {
         thread_mgr::is_shutdown_requested()
     }
 
+    fn startup(&mut self, _pgb: &mut PostgresBackend, sm: &FeStartupPacket) -> anyhow::Result<()> {
+        if let FeStartupPacket::StartupMessage { params, .. } = sm {
+            if let Some(lsn) = params.get("snapshot_lsn") {
+                self.snapshot_lsn = Some(Lsn::from_str(lsn).context("invalid snapshot_lsn")?);
+            }
+        }
+        Ok(())
+    }
+
     fn process_query(
         &mut self,
         pgb: &mut PostgresBackend,
@@ -977,25 +1145,11 @@ impl postgres_backend::Handler for PageServerHandler {
             // on connect
             pgb.write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
         } else if query_string.starts_with("failpoints ") {
-            ensure!(fail::has_failpoints(), "Cannot manage failpoints because pageserver was compiled without failpoints support");
-
             let (_, failpoints) = query_string.split_at("failpoints ".len());
 
             for failpoint in failpoints.split(';') {
                 if let Some((name, actions)) = failpoint.split_once('=') {
-                    info!("cfg failpoint: {} {}", name, actions);
-
-                    // We recognize one extra "action" that's not natively recognized
-                    // by the failpoints crate: exit, to immediately kill the process
-                    if actions == "exit" {
-                        fail::cfg_callback(name, || {
-                            info!("Exit requested by failpoint");
-                            std::process::exit(1);
-                        })
-                        .unwrap();
-                    } else {
-                        fail::cfg(name, actions).unwrap();
-                    }
+                    crate::apply_failpoint(name, actions)?;
                 } else {
                     bail!("Invalid failpoints format");
                 }
@@ -1052,21 +1206,15 @@ impl postgres_backend::Handler for PageServerHandler {
             let tenantid = ZTenantId::from_str(caps.get(1).unwrap().as_str())?;
             let timelineid = ZTimelineId::from_str(caps.get(2).unwrap().as_str())?;
 
-            let repo = tenant_mgr::get_repository_for_tenant(tenantid)?;
-
-            let gc_horizon: u64 = caps
-                .get(4)
-                .map(|h| h.as_str().parse())
-                .unwrap_or_else(|| Ok(repo.get_gc_horizon()))?;
+            let gc_horizon: Option<u64> = caps.get(4).map(|h| h.as_str().parse()).transpose()?;
 
-            // Use tenant's pitr setting
-            let pitr = repo.get_pitr_interval();
-            let result = repo.gc_iteration(Some(timelineid), gc_horizon, pitr, true)?;
+            let result = tenant_mgr::immediate_gc(tenantid, timelineid, gc_horizon)?;
             pgb.write_message_noflush(&BeMessage::RowDescription(&[
                 RowDescriptor::int8_col(b"layers_total"),
                 RowDescriptor::int8_col(b"layers_needed_by_cutoff"),
                 RowDescriptor::int8_col(b"layers_needed_by_pitr"),
                 RowDescriptor::int8_col(b"layers_needed_by_branches"),
+                RowDescriptor::int8_col(b"layers_needed_by_pins"),
                 RowDescriptor::int8_col(b"layers_not_updated"),
                 RowDescriptor::int8_col(b"layers_removed"),
                 RowDescriptor::int8_col(b"elapsed"),
@@ -1076,6 +1224,7 @@ impl postgres_backend::Handler for PageServerHandler {
                 Some(result.layers_needed_by_cutoff.to_string().as_bytes()),
                 Some(result.layers_needed_by_pitr.to_string().as_bytes()),
                 Some(result.layers_needed_by_branches.to_string().as_bytes()),
+                Some(result.layers_needed_by_pins.to_string().as_bytes()),
                 Some(result.layers_not_updated.to_string().as_bytes()),
                 Some(result.layers_removed.to_string().as_bytes()),
                 Some(result.elapsed.as_millis().to_string().as_bytes()),
@@ -1095,9 +1244,7 @@ impl postgres_backend::Handler for PageServerHandler {
 
             let tenantid = ZTenantId::from_str(caps.get(1).unwrap().as_str())?;
             let timelineid = ZTimelineId::from_str(caps.get(2).unwrap().as_str())?;
-            let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
-                .context("Couldn't load timeline")?;
-            timeline.tline.compact()?;
+            tenant_mgr::immediate_compact(tenantid, timelineid)?;
 
             pgb.write_message_noflush(&SINGLE_COL_ROWDESC)?
                 .write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
@@ -1114,16 +1261,7 @@ impl postgres_backend::Handler for PageServerHandler {
             let tenantid = ZTenantId::from_str(caps.get(1).unwrap().as_str())?;
             let timelineid = ZTimelineId::from_str(caps.get(2).unwrap().as_str())?;
 
-            let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
-                .context("Cannot load local timeline")?;
-
-            timeline.tline.checkpoint(CheckpointConfig::Forced)?;
-
-            // Also compact it.
-            //
-            // FIXME: This probably shouldn't be part of a "checkpoint" command, but a
-            // separate operation. Update the tests if you change this.
-            timeline.tline.compact()?;
+            tenant_mgr::immediate_checkpoint(tenantid, timelineid)?;
 
             pgb.write_message_noflush(&SINGLE_COL_ROWDESC)?
                 .write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
@@ -1190,3 +1328,52 @@ impl<'a> io::Write for CopyDataSink<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Same layout as the compute sends, see PagestreamFeMessage::parse()
+    fn serialize_prefetch(req: &PagestreamPrefetchRequest) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(4);
+        bytes.put_u8(req.latest as u8);
+        bytes.put_u64(req.lsn.0);
+        bytes.put_u32(req.rel.spcnode);
+        bytes.put_u32(req.rel.dbnode);
+        bytes.put_u32(req.rel.relnode);
+        bytes.put_u8(req.rel.forknum);
+        bytes.put_u32(req.ranges.len() as u32);
+        for range in req.ranges.iter() {
+            bytes.put_u32(range.start);
+            bytes.put_u32(range.end - range.start);
+        }
+        bytes
+    }
+
+    #[test]
+    fn prefetch_message_roundtrip() -> Result<()> {
+        let req = PagestreamPrefetchRequest {
+            latest: true,
+            lsn: Lsn(0x16B9188),
+            rel: RelTag {
+                spcnode: 1663,
+                dbnode: 13010,
+                relnode: 16384,
+                forknum: 0,
+            },
+            ranges: vec![0..1, 10..42, 100..1100],
+        };
+        match PagestreamFeMessage::parse(serialize_prefetch(&req).freeze())? {
+            PagestreamFeMessage::Prefetch(parsed) => assert_eq!(parsed, req),
+            _ => panic!("prefetch message parsed as a different one"),
+        }
+
+        // The range count doesn't match the message length
+        let mut truncated = serialize_prefetch(&req);
+        truncated.truncate(truncated.len() - 4);
+        assert!(PagestreamFeMessage::parse(truncated.freeze()).is_err());
+
+        Ok(())
+    }
+}
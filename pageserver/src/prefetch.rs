@@ -0,0 +1,339 @@
+//!
+//! Background page prefetching for the page service connections.
+//!
+//! The compute can announce the pages it is going to read soon with a
+//! fire-and-forget `Prefetch` message. The pages are reconstructed by a
+//! per-connection background thread, which warms the page cache, so that the
+//! following GetPage requests don't wait for the layer files and WAL redo.
+//!
+//! Usefulness of the prefetching is tracked by counting the prefetched pages,
+//! that were requested by the same connection afterwards.
+//!
+use anyhow::{ensure, Context, Result};
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::mpsc::{sync_channel, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::*;
+
+use metrics::{register_int_counter_vec, IntCounter, IntCounterVec};
+use utils::{
+    lsn::Lsn,
+    zid::{ZTenantId, ZTimelineId},
+};
+
+use crate::reltag::RelTag;
+use crate::repository::{LsnPin, Repository, Timeline};
+use crate::thread_mgr::{self, ThreadKind};
+use crate::{DatadirTimelineImpl, RepositoryImpl};
+
+/// Prefetch requests waiting for the background thread. When the queue is
+/// full, new requests are dropped: the compute will fetch the pages anyway.
+const PREFETCH_QUEUE_SIZE: usize = 64;
+
+/// Upper bound for the number of pages in a single prefetch request.
+const MAX_PAGES_PER_REQUEST: u32 = 1024;
+
+/// Upper bound for the number of prefetched pages tracked for usefulness
+/// metrics. Pages prefetched after that are accounted as unused.
+const MAX_TRACKED_PAGES: usize = 16 * 1024;
+
+lazy_static! {
+    static ref PREFETCH_PAGES: IntCounterVec = register_int_counter_vec!(
+        "pageserver_prefetch_pages_total",
+        "Number of pages announced in prefetch requests, by outcome: every requested page is either dropped or prefetched, and every prefetched page is then either used or unused",
+        &["outcome", "tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+}
+
+pub type TimelineLsnPin = LsnPin<<RepositoryImpl as Repository>::Timeline>;
+
+/// Pages of a relation to reconstruct in the background.
+pub struct PrefetchRequest {
+    pub rel: RelTag,
+    pub ranges: Vec<Range<u32>>,
+    pub lsn: Lsn,
+    /// Pin of the snapshot connection, which keeps the LSN readable while the
+    /// request is processed, even if the connection is closed meanwhile.
+    /// No need to check such LSN against the GC horizon.
+    pub pin: Option<Arc<TimelineLsnPin>>,
+}
+
+impl PrefetchRequest {
+    fn num_pages(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|range| range.end.saturating_sub(range.start) as u64)
+            .sum()
+    }
+}
+
+struct PrefetchMetrics {
+    requested: IntCounter,
+    dropped: IntCounter,
+    prefetched: IntCounter,
+    used: IntCounter,
+    unused: IntCounter,
+}
+
+impl PrefetchMetrics {
+    fn new(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> Self {
+        let tenant_id = tenant_id.to_string();
+        let timeline_id = timeline_id.to_string();
+        let counter =
+            |outcome: &str| PREFETCH_PAGES.with_label_values(&[outcome, &tenant_id, &timeline_id]);
+        PrefetchMetrics {
+            requested: counter("requested"),
+            dropped: counter("dropped"),
+            prefetched: counter("prefetched"),
+            used: counter("used"),
+            unused: counter("unused"),
+        }
+    }
+}
+
+/// Prefetcher of a single page service connection.
+///
+/// The background thread exits when the prefetcher is dropped.
+pub struct Prefetcher {
+    tx: SyncSender<PrefetchRequest>,
+    /// Pages reconstructed in the background, but not requested by the compute yet.
+    prefetched: Arc<Mutex<HashSet<(RelTag, u32)>>>,
+    metrics: Arc<PrefetchMetrics>,
+}
+
+impl Prefetcher {
+    pub fn spawn(
+        timeline: Arc<DatadirTimelineImpl>,
+        tenant_id: ZTenantId,
+        timeline_id: ZTimelineId,
+    ) -> Result<Self> {
+        let (tx, rx) = sync_channel(PREFETCH_QUEUE_SIZE);
+        let prefetched = Arc::new(Mutex::new(HashSet::new()));
+        let metrics = Arc::new(PrefetchMetrics::new(tenant_id, timeline_id));
+
+        let thread_prefetched = Arc::clone(&prefetched);
+        let thread_metrics = Arc::clone(&metrics);
+        thread_mgr::spawn(
+            ThreadKind::Prefetcher,
+            Some(tenant_id),
+            Some(timeline_id),
+            "prefetch thread",
+            false,
+            move || {
+                let _enter =
+                    info_span!("prefetch", timeline = %timeline_id, tenant = %tenant_id).entered();
+                loop {
+                    let request = match rx.recv_timeout(Duration::from_secs(1)) {
+                        Ok(request) => request,
+                        Err(RecvTimeoutError::Timeout) => {
+                            if thread_mgr::is_shutdown_requested() {
+                                return Ok(());
+                            }
+                            continue;
+                        }
+                        // The connection is closed
+                        Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                    };
+                    if thread_mgr::is_shutdown_requested() {
+                        return Ok(());
+                    }
+
+                    let mut num_prefetched = 0;
+                    let result = prefetch_pages(
+                        &timeline,
+                        &request,
+                        &thread_prefetched,
+                        &mut num_prefetched,
+                    );
+                    thread_metrics.prefetched.inc_by(num_prefetched);
+                    // Pages beyond the end of the relation or left after an error
+                    thread_metrics
+                        .dropped
+                        .inc_by(request.num_pages() - num_prefetched);
+                    if let Err(e) = result {
+                        // Prefetching is best effort, the compute requests the pages anyway.
+                        debug!("failed to prefetch pages of {}: {:#}", request.rel, e);
+                    }
+                }
+            },
+        )
+        .context("failed to spawn prefetch thread")?;
+
+        Ok(Prefetcher {
+            tx,
+            prefetched,
+            metrics,
+        })
+    }
+
+    /// Queue the pages for prefetching. Never blocks.
+    pub fn request(&self, mut request: PrefetchRequest) {
+        let num_pages = request.num_pages();
+        self.metrics.requested.inc_by(num_pages);
+
+        // Cut the ranges to the per-request limit
+        let mut budget = MAX_PAGES_PER_REQUEST;
+        for range in request.ranges.iter_mut() {
+            range.end = range.end.min(range.start.saturating_add(budget));
+            budget -= range.end.saturating_sub(range.start);
+        }
+        request.ranges.retain(|range| range.start < range.end);
+        let accepted_pages = request.num_pages();
+        self.metrics.dropped.inc_by(num_pages - accepted_pages);
+
+        match self.tx.try_send(request) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.metrics.dropped.inc_by(accepted_pages);
+            }
+        }
+    }
+
+    /// Account a GetPage request for prefetching usefulness.
+    pub fn page_requested(&self, rel: RelTag, blkno: u32) {
+        if self.prefetched.lock().unwrap().remove(&(rel, blkno)) {
+            self.metrics.used.inc();
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        let unused = self.prefetched.lock().unwrap().len();
+        self.metrics.unused.inc_by(unused as u64);
+    }
+}
+
+fn prefetch_pages(
+    timeline: &DatadirTimelineImpl,
+    request: &PrefetchRequest,
+    prefetched: &Mutex<HashSet<(RelTag, u32)>>,
+    num_prefetched: &mut u64,
+) -> Result<()> {
+    timeline.tline.wait_lsn(request.lsn)?;
+    let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
+    ensure!(
+        request.pin.is_some() || request.lsn >= *latest_gc_cutoff_lsn,
+        "prefetch LSN {} is behind the GC horizon {}",
+        request.lsn,
+        *latest_gc_cutoff_lsn
+    );
+
+    let nblocks = timeline.get_rel_size(request.rel, request.lsn)?;
+    for range in request.ranges.iter() {
+        for blkno in range.start..range.end.min(nblocks) {
+            if thread_mgr::is_shutdown_requested() {
+                return Ok(());
+            }
+            timeline.get_rel_page_at_lsn(request.rel, blkno, request.lsn)?;
+            *num_prefetched += 1;
+
+            let mut prefetched = prefetched.lock().unwrap();
+            if prefetched.len() < MAX_TRACKED_PAGES {
+                prefetched.insert((request.rel, blkno));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page_cache;
+    use crate::pgdatadir_mapping::{create_test_timeline, rel_block_to_key};
+    use crate::repository::repo_harness::*;
+    use crate::walrecord::ZenithWalRecord;
+    use bytes::Bytes;
+    use std::time::Instant;
+
+    const TESTREL: RelTag = RelTag {
+        spcnode: 1663,
+        dbnode: 13010,
+        relnode: 1000,
+        forknum: 0,
+    };
+
+    #[test]
+    fn prefetch_fills_page_cache() -> Result<()> {
+        // Exclusive, so that the small test page cache isn't shared with other tests
+        let harness = RepoHarness::create_exclusive("prefetch_fills_page_cache")?;
+        let tline = create_test_timeline(harness.load(), TIMELINE_ID)?;
+
+        // Only the pages reconstructed with WAL redo are cached
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(TESTREL, 4)?;
+        for blkno in 0..4 {
+            m.put_rel_page_image(TESTREL, blkno, TEST_IMG(&format!("block {}", blkno)))?;
+        }
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x30));
+        for blkno in 0..4 {
+            m.put_rel_wal_record(
+                TESTREL,
+                blkno,
+                ZenithWalRecord::Postgres {
+                    will_init: false,
+                    rec: Bytes::from_static(b"test record"),
+                },
+            )?;
+        }
+        m.commit()?;
+
+        let metric = |outcome: &str| {
+            PREFETCH_PAGES
+                .with_label_values(&[
+                    outcome,
+                    &harness.tenant_id.to_string(),
+                    &TIMELINE_ID.to_string(),
+                ])
+                .get()
+        };
+
+        let prefetcher = Prefetcher::spawn(Arc::clone(&tline), harness.tenant_id, TIMELINE_ID)?;
+        // Block 2 is skipped, the second range goes beyond the end of the relation
+        prefetcher.request(PrefetchRequest {
+            rel: TESTREL,
+            ranges: vec![0..2, 3..10],
+            lsn: Lsn(0x30),
+            pin: None,
+        });
+        assert_eq!(metric("requested"), 9);
+
+        let started_at = Instant::now();
+        while metric("prefetched") + metric("dropped") < 9 {
+            assert!(
+                started_at.elapsed() < Duration::from_secs(10),
+                "prefetch request was not processed in time"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(metric("prefetched"), 3);
+        assert_eq!(metric("dropped"), 6);
+
+        for blkno in 0..4 {
+            let cached = page_cache::get().lookup_materialized_page(
+                harness.tenant_id,
+                TIMELINE_ID,
+                &rel_block_to_key(TESTREL, blkno),
+                Lsn(0x30),
+            );
+            assert_eq!(cached.is_some(), blkno != 2, "block {} cache state", blkno);
+        }
+
+        // Only the prefetched pages count as used, and only once
+        prefetcher.page_requested(TESTREL, 1);
+        prefetcher.page_requested(TESTREL, 1);
+        prefetcher.page_requested(TESTREL, 2);
+        assert_eq!(metric("used"), 1);
+
+        drop(prefetcher);
+        assert_eq!(metric("unused"), 2);
+
+        Ok(())
+    }
+}
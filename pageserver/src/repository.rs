@@ -303,24 +303,35 @@ impl<'a, T> From<&'a RepositoryTimeline<T>> for LocalTimelineState {
 ///
 /// Result of performing GC
 ///
-#[derive(Default)]
+#[derive(Default, Serialize)]
 pub struct GcResult {
     pub layers_total: u64,
     pub layers_needed_by_cutoff: u64,
     pub layers_needed_by_pitr: u64,
     pub layers_needed_by_branches: u64,
+    pub layers_needed_by_pins: u64,
     pub layers_not_updated: u64,
     pub layers_removed: u64, // # of layer files removed because they have been made obsolete by newer ondisk files.
 
+    #[serde(serialize_with = "serialize_duration_as_millis")]
     pub elapsed: Duration,
 }
 
+// Reported in milliseconds, same as the `elapsed` column of the libpq `do_gc` command.
+fn serialize_duration_as_millis<S>(d: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_u64(d.as_millis() as u64)
+}
+
 impl AddAssign for GcResult {
     fn add_assign(&mut self, other: Self) {
         self.layers_total += other.layers_total;
         self.layers_needed_by_pitr += other.layers_needed_by_pitr;
         self.layers_needed_by_cutoff += other.layers_needed_by_cutoff;
         self.layers_needed_by_branches += other.layers_needed_by_branches;
+        self.layers_needed_by_pins += other.layers_needed_by_pins;
         self.layers_not_updated += other.layers_not_updated;
         self.layers_removed += other.layers_removed;
 
@@ -396,6 +407,41 @@ pub trait Timeline: Send + Sync {
         lsn: Lsn,
         latest_gc_cutoff_lsn: &RwLockReadGuard<Lsn>,
     ) -> Result<()>;
+
+    ///
+    /// Register a temporary GC retain point, so that the timeline stays readable
+    /// at 'lsn' even after the GC horizon moves past it. Pins are reference
+    /// counted and not persisted, each one must be released with [`Timeline::unpin_lsn`].
+    ///
+    /// Fails if 'lsn' is already behind the GC horizon.
+    fn pin_lsn(&self, lsn: Lsn) -> Result<()>;
+
+    /// Release a retain point registered with [`Timeline::pin_lsn`].
+    fn unpin_lsn(&self, lsn: Lsn);
+}
+
+/// Temporary GC retain point of a timeline, registered with [`Timeline::pin_lsn`]
+/// and released when dropped.
+pub struct LsnPin<T: Timeline> {
+    timeline: Arc<T>,
+    lsn: Lsn,
+}
+
+impl<T: Timeline> LsnPin<T> {
+    pub fn new(timeline: Arc<T>, lsn: Lsn) -> Result<Self> {
+        timeline.pin_lsn(lsn)?;
+        Ok(LsnPin { timeline, lsn })
+    }
+
+    pub fn lsn(&self) -> Lsn {
+        self.lsn
+    }
+}
+
+impl<T: Timeline> Drop for LsnPin<T> {
+    fn drop(&mut self) {
+        self.timeline.unpin_lsn(self.lsn);
+    }
 }
 
 /// Various functions to mutate the timeline.
@@ -473,6 +519,7 @@ pub mod repo_harness {
                 walreceiver_connect_timeout: Some(tenant_conf.walreceiver_connect_timeout),
                 lagging_wal_timeout: Some(tenant_conf.lagging_wal_timeout),
                 max_lsn_wal_lag: Some(tenant_conf.max_lsn_wal_lag),
+                walreceiver_standby_connection: Some(tenant_conf.walreceiver_standby_connection),
             }
         }
     }
@@ -591,7 +638,10 @@ pub mod repo_harness {
             );
             println!("{}", s);
 
-            Ok(TEST_IMG(&s))
+            // Page-sized like the real WAL redo results, so that they get cached
+            let mut img = BytesMut::from(&TEST_IMG(&s)[..]);
+            img.resize(crate::page_cache::PAGE_SZ, 0);
+            Ok(img.freeze())
         }
     }
 }
@@ -829,6 +879,34 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_retain_data_for_pinned_lsn() -> Result<()> {
+        let repo = RepoHarness::create("test_retain_data_for_pinned_lsn")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        make_some_layers(tline.as_ref(), Lsn(0x20))?;
+
+        tline.pin_lsn(Lsn(0x25))?;
+        let result = repo.gc_iteration(Some(TIMELINE_ID), 0x10, Duration::ZERO, false)?;
+        assert!(result.layers_needed_by_pins > 0);
+        assert_eq!(result.layers_needed_by_branches, 0);
+        assert!(*tline.get_latest_gc_cutoff_lsn() > Lsn(0x25));
+        assert_eq!(
+            tline.get(*TEST_KEY, Lsn(0x25))?,
+            TEST_IMG(&format!("foo at {}", Lsn(0x20)))
+        );
+
+        // The GC horizon has moved past the pinned LSN, so it cannot be pinned anew
+        match tline.pin_lsn(Lsn(0x25)) {
+            Ok(_) => panic!("pinning should have failed"),
+            Err(err) => assert!(err
+                .to_string()
+                .contains("we might've already garbage collected needed data")),
+        }
+        tline.unpin_lsn(Lsn(0x25));
+
+        Ok(())
+    }
     #[test]
     fn test_parent_keeps_data_forever_after_branching() -> Result<()> {
         let repo = RepoHarness::create("test_parent_keeps_data_forever_after_branching")?.load();
@@ -6,6 +6,9 @@
 //! Synchronization internals are split into submodules
 //!     * [`storage_sync::index`] to keep track of remote tenant files, the metadata and their mappings to local files
 //!     * [`storage_sync::upload`] and [`storage_sync::download`] to manage archive creation and upload; download and extraction, respectively
+//!     * [`storage_sync::throttle`] to limit the bandwidth the layer uploads and downloads use
+//!     * [`storage_sync::scrubber`] to find the remote files, not referenced by any index part, and the index part entries with no remote files, used by a standalone binary only
+//!     * [`storage_sync::bootstrap`] to restore all timelines of a fresh pageserver workdir from the remote storage, used by the pageserver `--bootstrap-from-remote` mode only
 //!
 //! * public API via to interact with the external world:
 //!     * [`start_local_timeline_sync`] to launch a background async loop to handle the synchronization
@@ -78,6 +81,9 @@
 //! NOTES:
 //! * pageserver assumes it has exclusive write access to the remote storage. If supported, the way multiple pageservers can be separated in the same storage
 //! (i.e. using different directories in the local filesystem external storage), but totally up to the storage implementation and not covered with the trait API.
+//! To protect the timelines from a stale pageserver, still attached to them after the migration, every timeline restored from the remote index part
+//! gets the next attachment [`index::Generation`]: the layers are uploaded under the names with the generation suffix and index part updates are refused,
+//! once a newer generation is observed remotely.
 //!
 //! * the sync tasks may not processed immediately after the submission: if they error and get re-enqueued, their execution might be backed off to ensure error cap is not exceeded too fast.
 //! The sync queue processing also happens in batches, so the sync tasks can wait in the queue for some time.
@@ -97,6 +103,8 @@
 //! Deletion happens only after a successful upload only, otherwise the compaction output might make the timeline inconsistent until both tasks are fully processed without errors.
 //! Upload and download update the remote data (inmemory index and S3 json index part file) only after every layer is successfully synchronized, while the deletion task
 //! does otherwise: it requires to have the remote data updated first successfully: blob files will be invisible to pageserver this way.
+//! Every remote data update is uploaded as a new index part version, and the layers removed stay in the remote storage,
+//! until all kept versions referencing them expire: this way, the timeline can be rolled back to an older version, see [`roll_back_remote_timeline`].
 //!
 //! During the loop startup, an initial [`RemoteTimelineIndex`] state is constructed via downloading and merging the index data for all timelines,
 //! present locally.
@@ -120,6 +128,7 @@
 //! Index construction is currently the only place where the storage sync can return an [`Err`] to the user.
 //! New sync tasks are accepted via [`schedule_layer_upload`], [`schedule_layer_download`] and [`schedule_layer_delete`] functions,
 //! disregarding of the corresponding loop startup.
+//! The tasks are queued by their priority: on-demand downloads go before the uploads of freshly flushed layers, and both go before the background tasks.
 //! It's up to the caller to avoid synchronizations if the loop is disabled: otherwise, the sync tasks will be ignored.
 //! After the initial state is loaded into memory and the loop starts, any further [`Err`] results do not stop the loop, but rather
 //! reschedule the same task, with possibly less files to sync:
@@ -138,26 +147,34 @@
 //!
 //! After the whole timeline is downloaded, [`crate::tenant_mgr::apply_timeline_sync_status_updates`] function is used to update pageserver memory stage for the timeline processed.
 
+pub mod bootstrap;
 mod delete;
 mod download;
 pub mod index;
+pub mod scrubber;
+mod throttle;
 mod upload;
 
 use std::{
-    collections::{hash_map, HashMap, HashSet, VecDeque},
+    collections::{hash_map, BTreeMap, HashMap, HashSet, VecDeque},
     ffi::OsStr,
     fmt::Debug,
     num::{NonZeroU32, NonZeroUsize},
     ops::ControlFlow,
     path::{Path, PathBuf},
+    str::FromStr,
     sync::{Arc, Condvar, Mutex},
+    time::SystemTime,
 };
 
 use anyhow::{anyhow, bail, Context};
 use futures::stream::{FuturesUnordered, StreamExt};
 use lazy_static::lazy_static;
 use once_cell::sync::OnceCell;
-use remote_storage::{GenericRemoteStorage, RemoteStorage};
+use remote_storage::{
+    GenericRemoteStorage, RemoteStorage, RemoteStorageConfig, RemoteStorageError,
+    DEFAULT_REMOTE_STORAGE_MAX_INDEX_PART_VERSIONS,
+};
 use tokio::{
     fs,
     runtime::Runtime,
@@ -166,9 +183,10 @@ use tokio::{
 use tracing::*;
 
 use self::{
-    delete::delete_timeline_layers,
-    download::{download_timeline_layers, DownloadedTimeline},
-    index::{IndexPart, RemoteTimeline, RemoteTimelineIndex},
+    delete::{delete_index_part_versions, delete_timeline_layers},
+    download::{download_index_part_version, download_timeline_layers, DownloadedTimeline},
+    index::{Generation, IndexPart, RemoteTimeline, RemoteTimelineIndex},
+    throttle::BandwidthLimiter,
     upload::{upload_index_part, upload_timeline_layers, UploadedTimeline},
 };
 use crate::{
@@ -176,7 +194,7 @@ use crate::{
     layered_repository::{
         ephemeral_file::is_ephemeral_file,
         metadata::{metadata_path, TimelineMetadata, METADATA_FILE_NAME},
-        LayeredRepository,
+        LayeredRepository, TIMELINES_SEGMENT_NAME,
     },
     repository::TimelineSyncStatusUpdate,
     storage_sync::{self, index::RemoteIndex},
@@ -187,7 +205,7 @@ use crate::{
 
 use metrics::{
     register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
-    HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    register_int_gauge_vec, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
 };
 use utils::zid::{ZTenantId, ZTenantTimelineId, ZTimelineId};
 
@@ -200,6 +218,12 @@ lazy_static! {
         "Number of storage sync items left in the queue"
     )
     .expect("failed to register pageserver remote storage remaining sync items int gauge");
+    static ref SYNC_QUEUE_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "pageserver_remote_storage_sync_queue_depth",
+        "Number of storage sync tasks waiting in the queue, grouped by `priority` (on_demand|fresh|background)",
+        &["priority"]
+    )
+    .expect("failed to register pageserver remote storage sync queue depth int gauge vec");
     static ref FATAL_TASK_FAILURES: IntCounter = register_int_counter!(
         "pageserver_remote_storage_fatal_task_failures_total",
         "Number of critically failed tasks"
@@ -222,6 +246,8 @@ lazy_static! {
 }
 
 static SYNC_QUEUE: OnceCell<SyncQueue> = OnceCell::new();
+static UPLOAD_LIMITER: OnceCell<BandwidthLimiter> = OnceCell::new();
+static DOWNLOAD_LIMITER: OnceCell<BandwidthLimiter> = OnceCell::new();
 
 /// A timeline status to share with pageserver's sync counterpart,
 /// after comparing local and remote timeline state.
@@ -259,6 +285,7 @@ pub fn start_local_timeline_sync(
 
     match config.remote_storage_config.as_ref() {
         Some(storage_config) => {
+            init_bandwidth_limiters(storage_config);
             match GenericRemoteStorage::new(config.workdir.clone(), storage_config)
                 .context("Failed to init the generic remote storage")?
             {
@@ -280,6 +307,24 @@ pub fn start_local_timeline_sync(
                         storage_config.max_sync_errors,
                     )
                 }
+                GenericRemoteStorage::Azure(azure_blob_storage) => {
+                    storage_sync::spawn_storage_sync_thread(
+                        config,
+                        local_timeline_files,
+                        azure_blob_storage,
+                        storage_config.max_concurrent_syncs,
+                        storage_config.max_sync_errors,
+                    )
+                }
+                GenericRemoteStorage::Gcs(gcs_bucket_storage) => {
+                    storage_sync::spawn_storage_sync_thread(
+                        config,
+                        local_timeline_files,
+                        gcs_bucket_storage,
+                        storage_config.max_concurrent_syncs,
+                        storage_config.max_sync_errors,
+                    )
+                }
             }
             .context("Failed to spawn the storage sync thread")
         }
@@ -307,6 +352,18 @@ pub fn start_local_timeline_sync(
     }
 }
 
+/// Sets up the bandwidth limits for the layer uploads and downloads, if configured.
+fn init_bandwidth_limiters(storage_config: &RemoteStorageConfig) {
+    if let Some(bytes_per_second) = storage_config.max_upload_bytes_per_second {
+        info!("Limiting remote storage uploads to {bytes_per_second} bytes per second");
+        let _ = UPLOAD_LIMITER.set(BandwidthLimiter::new(bytes_per_second));
+    }
+    if let Some(bytes_per_second) = storage_config.max_download_bytes_per_second {
+        info!("Limiting remote storage downloads to {bytes_per_second} bytes per second");
+        let _ = DOWNLOAD_LIMITER.set(BandwidthLimiter::new(bytes_per_second));
+    }
+}
+
 fn local_tenant_timeline_files(
     config: &'static PageServerConf,
 ) -> anyhow::Result<HashMap<ZTenantTimelineId, (TimelineMetadata, HashSet<PathBuf>)>> {
@@ -390,6 +447,23 @@ fn collect_timelines_for_tenant(
     Ok(timelines)
 }
 
+/// Parses the `tenants/<tenant id>/timelines/<timeline id>/<file>` local path into the timeline id.
+fn parse_sync_id(conf: &PageServerConf, local_path: &Path) -> Option<ZTenantTimelineId> {
+    let mut segments = local_path
+        .strip_prefix(conf.tenants_path())
+        .ok()?
+        .iter()
+        .map(|segment| segment.to_str());
+    let tenant_id = ZTenantId::from_str(segments.next()??).ok()?;
+    if segments.next()? != Some(TIMELINES_SEGMENT_NAME) {
+        return None;
+    }
+    let timeline_id = ZTimelineId::from_str(segments.next()??).ok()?;
+    // the timeline directory itself is not a file in the storage
+    segments.next()?;
+    Some(ZTenantTimelineId::new(tenant_id, timeline_id))
+}
+
 // discover timeline files and extract timeline metadata
 //  NOTE: ephemeral files are excluded from the list
 fn collect_timeline_files(
@@ -457,21 +531,95 @@ fn collect_timeline_files(
     Ok((timeline_id, metadata, timeline_files))
 }
 
-/// Global queue of sync tasks.
+/// How urgent a sync task is: tasks of the higher priority are taken from the queue first.
+/// Retried tasks keep their priority, tasks merged into a batch get the highest one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum SyncPriority {
+    /// Backfill of the layers, not blocking anything: the initial sync tasks after the pageserver restart and the remote deletions.
+    Background,
+    /// Layers, freshly flushed or compacted by the pageserver, not backed up yet.
+    Fresh,
+    /// Downloads, requested by the users explicitly, e.g. on timeline attach.
+    OnDemand,
+}
+
+impl Default for SyncPriority {
+    fn default() -> Self {
+        Self::Background
+    }
+}
+
+impl SyncPriority {
+    const ALL: [Self; 3] = [Self::OnDemand, Self::Fresh, Self::Background];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Background => "background",
+            Self::Fresh => "fresh",
+            Self::OnDemand => "on_demand",
+        }
+    }
+}
+
+/// Global queue of sync tasks, a separate FIFO queue per task priority.
 ///
 /// 'queue' is protected by a mutex, and 'condvar' is used to wait for tasks to arrive.
 struct SyncQueue {
     max_timelines_per_batch: NonZeroUsize,
 
-    queue: Mutex<VecDeque<(ZTenantTimelineId, SyncTask)>>,
+    queue: Mutex<PriorityQueue>,
     condvar: Condvar,
 }
 
+#[derive(Default)]
+struct PriorityQueue {
+    tasks: BTreeMap<SyncPriority, VecDeque<(ZTenantTimelineId, SyncTask)>>,
+    len: usize,
+}
+
+impl PriorityQueue {
+    fn push_back(&mut self, sync_id: ZTenantTimelineId, task: SyncTask) {
+        self.tasks
+            .entry(task.priority())
+            .or_default()
+            .push_back((sync_id, task));
+        self.len += 1;
+    }
+
+    /// Pops the oldest task of the highest priority present.
+    fn pop_front(&mut self) -> Option<(ZTenantTimelineId, SyncTask)> {
+        let task = self
+            .tasks
+            .values_mut()
+            .rev()
+            .find_map(|tasks| tasks.pop_front())?;
+        self.len -= 1;
+        Some(task)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn update_metrics(&self) {
+        for priority in SyncPriority::ALL {
+            let depth = self.tasks.get(&priority).map_or(0, VecDeque::len);
+            SYNC_QUEUE_DEPTH
+                .with_label_values(&[priority.as_str()])
+                .set(depth as i64);
+        }
+    }
+}
+
 impl SyncQueue {
     fn new(max_timelines_per_batch: NonZeroUsize) -> Self {
         Self {
             max_timelines_per_batch,
-            queue: Mutex::new(VecDeque::new()),
+            queue: Mutex::new(PriorityQueue::default()),
             condvar: Condvar::new(),
         }
     }
@@ -480,7 +628,8 @@ impl SyncQueue {
     fn push(&self, sync_id: ZTenantTimelineId, new_task: SyncTask) {
         let mut q = self.queue.lock().unwrap();
 
-        q.push_back((sync_id, new_task));
+        q.push_back(sync_id, new_task);
+        q.update_metrics();
         if q.len() <= 1 {
             self.condvar.notify_one();
         }
@@ -490,6 +639,7 @@ impl SyncQueue {
     /// A timeline has to care to not to delete certain layers from the remote storage before the corresponding uploads happen.
     /// Other than that, due to "immutable" nature of the layers, the order of their deletion/uploading/downloading does not matter.
     /// Hence, we merge the layers together into single task per timeline and run those concurrently (with the deletion happening only after successful uploading).
+    /// The tasks of higher priorities are batched first, so only the lower priority ones are left in the queue, if the batch is full.
     fn next_task_batch(&self) -> (HashMap<ZTenantTimelineId, SyncTaskBatch>, usize) {
         // Wait for the first task in blocking fashion
         let mut q = self.queue.lock().unwrap();
@@ -537,8 +687,9 @@ impl SyncQueue {
             tasks_to_reenqueue.len()
         );
         for (id, task) in tasks_to_reenqueue {
-            q.push_back((id, task));
+            q.push_back(id, task);
         }
+        q.update_metrics();
 
         (batches, q.len())
     }
@@ -566,16 +717,61 @@ enum SyncTask {
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct SyncData<T> {
     retries: u32,
+    /// Failed attempts, caused by the retryable remote storage errors only.
+    /// They delay the next attempt as the retries do, but don't count against the `max_sync_errors`.
+    transient_errors: u32,
+    priority: SyncPriority,
     data: T,
 }
 
 impl<T> SyncData<T> {
     fn new(retries: u32, data: T) -> Self {
-        Self { retries, data }
+        Self {
+            retries,
+            transient_errors: 0,
+            priority: SyncPriority::default(),
+            data,
+        }
+    }
+
+    /// Accounts a failed attempt of the task, before rescheduling it.
+    fn register_failure(&mut self, retryable: bool) {
+        if retryable {
+            self.transient_errors += 1;
+        } else {
+            self.retries += 1;
+        }
     }
 }
 
+/// Whether the error is caused by a remote storage failure that may go away on its own,
+/// e.g. a network issue or the storage throttling.
+fn is_retryable_error(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<RemoteStorageError>()
+            .map_or(false, RemoteStorageError::is_retryable)
+    })
+}
+
 impl SyncTask {
+    fn priority(&self) -> SyncPriority {
+        match self {
+            Self::Download(data) => data.priority,
+            Self::Upload(data) => data.priority,
+            Self::Delete(data) => data.priority,
+        }
+    }
+
+    fn with_priority(mut self, priority: SyncPriority) -> Self {
+        match &mut self {
+            Self::Download(data) => data.priority = priority,
+            Self::Upload(data) => data.priority = priority,
+            Self::Delete(data) => data.priority = priority,
+        }
+        self
+    }
+
     fn download(download_task: LayersDownload) -> Self {
         Self::Download(SyncData::new(0, download_task))
     }
@@ -608,6 +804,10 @@ impl SyncTaskBatch {
             SyncTask::Download(new_download) => match &mut self.download {
                 Some(batch_download) => {
                     batch_download.retries = batch_download.retries.min(new_download.retries);
+                    batch_download.transient_errors = batch_download
+                        .transient_errors
+                        .min(new_download.transient_errors);
+                    batch_download.priority = batch_download.priority.max(new_download.priority);
                     batch_download
                         .data
                         .layers_to_skip
@@ -618,6 +818,10 @@ impl SyncTaskBatch {
             SyncTask::Upload(new_upload) => match &mut self.upload {
                 Some(batch_upload) => {
                     batch_upload.retries = batch_upload.retries.min(new_upload.retries);
+                    batch_upload.transient_errors = batch_upload
+                        .transient_errors
+                        .min(new_upload.transient_errors);
+                    batch_upload.priority = batch_upload.priority.max(new_upload.priority);
 
                     let batch_data = &mut batch_upload.data;
                     let new_data = new_upload.data;
@@ -644,6 +848,10 @@ impl SyncTaskBatch {
             SyncTask::Delete(new_delete) => match &mut self.delete {
                 Some(batch_delete) => {
                     batch_delete.retries = batch_delete.retries.min(new_delete.retries);
+                    batch_delete.transient_errors = batch_delete
+                        .transient_errors
+                        .min(new_delete.transient_errors);
+                    batch_delete.priority = batch_delete.priority.max(new_delete.priority);
                     // Need to reregister deletions, but it's ok to register already deleted files once again, they will be skipped.
                     batch_delete.data.deletion_registered = batch_delete
                         .data
@@ -678,6 +886,10 @@ impl SyncTaskBatch {
                         .data
                         .deleted_layers
                         .extend(new_delete.data.deleted_layers.into_iter());
+                    batch_delete
+                        .data
+                        .layer_generations
+                        .extend(new_delete.data.layer_generations.into_iter());
                 }
                 None => self.delete = Some(new_delete),
             },
@@ -717,6 +929,9 @@ struct LayersDeletion {
     /// the corresponding files on S3 won't exist for pageserver albeit being physically present on that remote storage still.
     /// Then all that's left is to remove the files from the remote storage, without concerns about consistency.
     deletion_registered: bool,
+    /// Generations of the layers to delete, for the layers the remote index has forgotten about already:
+    /// the ones expired with the older index part versions, see [`RemoteTimeline::new_index_version`].
+    layer_generations: HashMap<PathBuf, Generation>,
 }
 
 /// Adds the new checkpoint files as an upload sync task to the queue.
@@ -745,7 +960,8 @@ pub fn schedule_layer_upload(
             layers_to_upload,
             uploaded_layers: HashSet::new(),
             metadata,
-        }),
+        })
+        .with_priority(SyncPriority::Fresh),
     );
     debug!("Upload task for tenant {tenant_id}, timeline {timeline_id} sent")
 }
@@ -775,6 +991,7 @@ pub fn schedule_layer_delete(
             layers_to_delete,
             deleted_layers: HashSet::new(),
             deletion_registered: false,
+            layer_generations: HashMap::new(),
         }),
     );
     debug!("Deletion task for tenant {tenant_id}, timeline {timeline_id} sent")
@@ -803,7 +1020,8 @@ pub fn schedule_layer_download(tenant_id: ZTenantId, timeline_id: ZTimelineId) {
         },
         SyncTask::download(LayersDownload {
             layers_to_skip: HashSet::new(),
-        }),
+        })
+        .with_priority(SyncPriority::OnDemand),
     );
     debug!("Download task for tenant {tenant_id}, timeline {timeline_id} sent")
 }
@@ -835,6 +1053,10 @@ where
         .build()
         .context("Failed to create storage sync runtime")?;
 
+    if let Err(e) = runtime.block_on(storage.abort_stale_uploads()) {
+        warn!("Failed to abort stale uploads in the remote storage: {e:?}");
+    }
+
     let applicable_index_parts = runtime.block_on(try_fetch_index_parts(
         conf,
         &storage,
@@ -842,6 +1064,7 @@ where
     ));
 
     let remote_index = RemoteIndex::from_parts(conf, applicable_index_parts)?;
+    runtime.block_on(publish_generations(conf, &storage, &remote_index));
 
     let local_timeline_init_statuses = schedule_first_sync_tasks(
         &mut runtime.block_on(remote_index.write()),
@@ -1085,7 +1308,9 @@ where
     );
 
     if let Some(delete_data) = batch.delete {
-        if upload_result.is_some() {
+        // Layers are removed from the index only after the files replacing them got uploaded.
+        // Registered deletions are the expired layers, no index part version references anymore: nothing waits for them.
+        if delete_data.data.deletion_registered || upload_result.is_some() {
             match validate_task_retries(delete_data, max_sync_errors)
                 .instrument(info_span!("retries_validation"))
                 .await
@@ -1256,6 +1481,22 @@ async fn delete_timeline_data<P, S>(
     let timeline_delete = &mut new_delete_data.data;
 
     if !timeline_delete.deletion_registered {
+        // Layers, known to the remote index, are deleted later, when no index part version kept references them anymore,
+        // see `schedule_expired_layers_delete`. The rest of the layers are either never indexed or expired already.
+        let mut delayed_layers = HashSet::new();
+        if let Some(remote_timeline) = index.read().await.timeline_entry(&sync_id) {
+            for layer in timeline_delete
+                .layers_to_delete
+                .intersection(remote_timeline.stored_files())
+            {
+                delayed_layers.insert(layer.clone());
+            }
+            for layer in remote_timeline.pending_deletions() {
+                if timeline_delete.layers_to_delete.contains(layer) {
+                    delayed_layers.insert(layer.to_path_buf());
+                }
+            }
+        }
         if let Err(e) = update_remote_data(
             conf,
             storage,
@@ -1266,11 +1507,15 @@ async fn delete_timeline_data<P, S>(
         .await
         {
             error!("Failed to update remote timeline {sync_id}: {e:?}");
-            new_delete_data.retries += 1;
+            new_delete_data.register_failure(is_retryable_error(&e));
             sync_queue.push(sync_id, SyncTask::Delete(new_delete_data));
             register_sync_status(sync_id, sync_start, task_name, Some(false));
             return;
         }
+
+        timeline_delete
+            .layers_to_delete
+            .retain(|layer| !delayed_layers.contains(layer));
     }
     timeline_delete.deletion_registered = true;
 
@@ -1299,6 +1544,13 @@ async fn upload_timeline_data<P, S>(
     P: Debug + Send + Sync + 'static,
     S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
 {
+    if let Some(newer_generation) = current_remote_timeline.and_then(|timeline| timeline.fenced_by)
+    {
+        error!("Timeline {sync_id} is attached to a newer generation {newer_generation} elsewhere, dropping the upload task");
+        register_sync_status(sync_id, sync_start, task_name, Some(false));
+        return;
+    }
+
     let mut uploaded_data = match upload_timeline_layers(
         storage,
         sync_queue,
@@ -1332,7 +1584,7 @@ async fn upload_timeline_data<P, S>(
         }
         Err(e) => {
             error!("Failed to update remote timeline {sync_id}: {e:?}");
-            uploaded_data.retries += 1;
+            uploaded_data.register_failure(is_retryable_error(&e));
             sync_queue.push(sync_id, SyncTask::Upload(uploaded_data));
             register_sync_status(sync_id, sync_start, task_name, Some(false));
         }
@@ -1345,6 +1597,7 @@ enum RemoteDataUpdate<'a> {
         upload_failed: bool,
     },
     Delete(&'a HashSet<PathBuf>),
+    RollBack(RemoteTimeline),
 }
 
 async fn update_remote_data<P, S>(
@@ -1358,7 +1611,26 @@ where
     P: Debug + Send + Sync + 'static,
     S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
 {
-    let updated_remote_timeline = {
+    let generation = match index.read().await.timeline_entry(&sync_id) {
+        Some(remote_timeline) => {
+            if let Some(newer_generation) = remote_timeline.fenced_by {
+                bail!("Timeline {sync_id} is attached to a newer generation {newer_generation} elsewhere, not updating its remote data");
+            }
+            remote_timeline.generation
+        }
+        None => Generation::FIRST,
+    };
+    if let Some(newer_generation) = newer_remote_generation(conf, storage, sync_id, generation)
+        .await
+        .context("Failed to check the remote index part generation")?
+    {
+        if let Some(remote_timeline) = index.write().await.timeline_entry_mut(&sync_id) {
+            remote_timeline.fenced_by = Some(newer_generation);
+        }
+        bail!("Timeline {sync_id} remote index part has generation {newer_generation}, newer than the current {generation}, refusing to update it");
+    }
+
+    let (updated_remote_timeline, version_update) = {
         let mut index_accessor = index.write().await;
 
         match index_accessor.timeline_entry_mut(&sync_id) {
@@ -1387,8 +1659,13 @@ where
                     RemoteDataUpdate::Delete(layers_to_remove) => {
                         existing_entry.remove_layers(layers_to_remove)
                     }
+                    RemoteDataUpdate::RollBack(older_version) => {
+                        existing_entry.roll_back(older_version)
+                    }
                 }
-                existing_entry.clone()
+                let version_update = existing_entry
+                    .new_index_version(SystemTime::now(), max_index_part_versions(conf));
+                (existing_entry.clone(), version_update)
             }
             None => match update {
                 RemoteDataUpdate::Upload {
@@ -1407,14 +1684,19 @@ where
                         new_remote_timeline
                             .add_timeline_layers(uploaded_data.uploaded_layers.iter().cloned());
                     }
+                    let version_update = new_remote_timeline
+                        .new_index_version(SystemTime::now(), max_index_part_versions(conf));
 
                     index_accessor.add_timeline_entry(sync_id, new_remote_timeline.clone());
-                    new_remote_timeline
+                    (new_remote_timeline, version_update)
                 }
                 RemoteDataUpdate::Delete(_) => {
                     warn!("No remote index entry for timeline {sync_id}, skipping deletion");
                     return Ok(());
                 }
+                RemoteDataUpdate::RollBack(_) => {
+                    bail!("No remote index entry for timeline {sync_id}, cannot roll it back")
+                }
             },
         }
     };
@@ -1434,7 +1716,233 @@ where
 
     upload_index_part(conf, storage, sync_id, new_index_part)
         .await
-        .context("Failed to upload new index part")
+        .context("Failed to upload new index part")?;
+
+    delete_index_part_versions(conf, storage, sync_id, &version_update.expired_versions).await;
+    schedule_expired_layers_delete(sync_id, version_update.expired_layers);
+    Ok(())
+}
+
+/// Result of the remote timeline rollback attempt, see [`roll_back_remote_timeline`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum RemoteRollback {
+    /// The timeline's current index part now has the layers and metadata of the version requested.
+    RolledBack,
+    /// The version requested is not among the versions kept, older than the current one.
+    UnknownVersion,
+    /// Some layers of the version requested are not present in the remote storage anymore.
+    MissingLayers(Vec<PathBuf>),
+}
+
+/// How many bytes to read from the remote layer to ensure it exists. Layer files are never empty.
+const LAYER_PROBE_BYTES: u64 = 8;
+
+/// Rolls the timeline's remote state back to the older index part version, kept in the remote storage.
+/// The rollback is uploaded as a new index part version, so it can be rolled back too, while the versions kept.
+///
+/// The timeline has to be present in the remote index and not present locally: the pageserver does not sync
+/// its local timelines with the remote state rolled back.
+pub async fn roll_back_remote_timeline<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    index: &RemoteIndex,
+    sync_id: ZTenantTimelineId,
+    version: u64,
+) -> anyhow::Result<RemoteRollback>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let (current_version, is_kept) = match index.read().await.timeline_entry(&sync_id) {
+        Some(remote_timeline) => (
+            remote_timeline.index_version(),
+            remote_timeline
+                .index_history()
+                .iter()
+                .any(|kept_version| kept_version.version == version),
+        ),
+        None => bail!("No remote index entry for timeline {sync_id}"),
+    };
+    if version >= current_version || !is_kept {
+        return Ok(RemoteRollback::UnknownVersion);
+    }
+
+    let older_index_part = download_index_part_version(conf, storage, sync_id, version)
+        .await
+        .with_context(|| format!("Failed to download index part version {version}"))?;
+    let timeline_path = conf.timeline_path(&sync_id.timeline_id, &sync_id.tenant_id);
+    let older_version = RemoteTimeline::from_index_part(&timeline_path, older_index_part)
+        .with_context(|| {
+            format!("Failed to restore remote timeline from index part version {version}")
+        })?;
+
+    let mut missing_layers = Vec::new();
+    for layer in older_version.stored_files() {
+        let layer_storage_path = storage
+            .remote_object_id(&older_version.remote_layer_path(layer))
+            .with_context(|| {
+                format!(
+                    "Failed to get the layer storage path for local path '{}'",
+                    layer.display()
+                )
+            })?;
+        match storage
+            .download_byte_range(
+                &layer_storage_path,
+                0,
+                Some(LAYER_PROBE_BYTES),
+                &mut tokio::io::sink(),
+            )
+            .await
+        {
+            Ok(_) => {}
+            Err(RemoteStorageError::NotFound) => missing_layers.push(layer.clone()),
+            Err(e) => {
+                return Err(anyhow::Error::from(e).context(format!(
+                    "Failed to check the layer at {layer_storage_path:?}"
+                )))
+            }
+        }
+    }
+    if !missing_layers.is_empty() {
+        missing_layers.sort();
+        return Ok(RemoteRollback::MissingLayers(missing_layers));
+    }
+
+    update_remote_data(
+        conf,
+        storage,
+        index,
+        sync_id,
+        RemoteDataUpdate::RollBack(older_version),
+    )
+    .await
+    .with_context(|| {
+        format!("Failed to roll back timeline {sync_id} to index part version {version}")
+    })?;
+    info!("Rolled back timeline {sync_id} remote state from index part version {current_version} to {version}");
+    Ok(RemoteRollback::RolledBack)
+}
+
+/// Max number of the latest index part versions to keep in the remote storage for every timeline.
+fn max_index_part_versions(conf: &PageServerConf) -> NonZeroUsize {
+    conf.remote_storage_config
+        .as_ref()
+        .map(|storage_config| storage_config.max_index_part_versions)
+        .unwrap_or_else(|| {
+            NonZeroUsize::new(DEFAULT_REMOTE_STORAGE_MAX_INDEX_PART_VERSIONS)
+                .expect("Default index part versions number should be positive")
+        })
+}
+
+/// Schedules the physical deletion of the layers, not referenced by any index part version kept anymore.
+/// Such layers are already removed from the remote index, so the deletion is registered from the start.
+fn schedule_expired_layers_delete(
+    sync_id: ZTenantTimelineId,
+    expired_layers: HashMap<PathBuf, Generation>,
+) {
+    if expired_layers.is_empty() {
+        return;
+    }
+    let sync_queue = match SYNC_QUEUE.get() {
+        Some(queue) => queue,
+        None => {
+            warn!("Could not send deletion task for expired layers of timeline {sync_id}");
+            return;
+        }
+    };
+    debug!(
+        "Scheduling deletion of {} expired layers for timeline {sync_id}",
+        expired_layers.len()
+    );
+    sync_queue.push(
+        sync_id,
+        SyncTask::delete(LayersDeletion {
+            layers_to_delete: expired_layers.keys().cloned().collect(),
+            deleted_layers: HashSet::new(),
+            deletion_registered: true,
+            layer_generations: expired_layers,
+        }),
+    );
+}
+
+/// Checks the generation of the timeline's index part in the remote storage, returning it if it's newer than the given one.
+/// The check is not atomic with the following index part upload, so a stale pageserver might still update the index part once,
+/// if a newer pageserver attaches to the timeline in between: the newer pageserver overwrites such update with its own later,
+/// and the stale one stops on its next update attempt.
+async fn newer_remote_generation<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    sync_id: ZTenantTimelineId,
+    generation: Generation,
+) -> anyhow::Result<Option<Generation>>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    match download_index_part(conf, storage, sync_id).await {
+        Ok(remote_index_part) => Ok(Some(remote_index_part.generation())
+            .filter(|&remote_generation| remote_generation > generation)),
+        Err(e)
+            if matches!(
+                e.downcast_ref::<RemoteStorageError>(),
+                Some(RemoteStorageError::NotFound)
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Uploads the index parts of the timelines, restored from the remote storage on startup, with their new generations,
+/// so that the pageservers, attached to the same timelines before, stop updating them as soon as possible.
+async fn publish_generations<P, S>(conf: &'static PageServerConf, storage: &S, index: &RemoteIndex)
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let index_parts = {
+        let index_accessor = index.read().await;
+        index_accessor
+            .all_sync_ids()
+            .filter_map(|sync_id| {
+                let remote_timeline = index_accessor.timeline_entry(&sync_id)?.clone();
+                let timeline_path = conf.timeline_path(&sync_id.timeline_id, &sync_id.tenant_id);
+                match IndexPart::from_remote_timeline(&timeline_path, remote_timeline) {
+                    Ok(index_part) => Some((sync_id, index_part)),
+                    Err(e) => {
+                        warn!("Failed to create an index part for timeline {sync_id}: {e:?}");
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut part_uploads = index_parts
+        .into_iter()
+        .map(|(sync_id, index_part)| async move {
+            let generation = index_part.generation();
+            let result = match newer_remote_generation(conf, storage, sync_id, generation).await {
+                Ok(None) => upload_index_part(conf, storage, sync_id, index_part).await,
+                Ok(Some(newer_generation)) => Err(anyhow!(
+                    "remote index part has a newer generation {newer_generation}"
+                )),
+                Err(e) => Err(e),
+            };
+            (sync_id, generation, result)
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    while let Some((sync_id, generation, result)) = part_uploads.next().await {
+        match result {
+            Ok(()) => debug!("Published generation {generation} for timeline {sync_id}"),
+            Err(e) => {
+                warn!("Failed to publish generation {generation} for timeline {sync_id}: {e:#}")
+            }
+        }
+    }
 }
 
 async fn validate_task_retries<T>(
@@ -1450,8 +1958,10 @@ async fn validate_task_retries<T>(
         return ControlFlow::Break(sync_data);
     }
 
-    if current_attempt > 0 {
-        let seconds_to_wait = 2.0_f64.powf(current_attempt as f64 - 1.0).min(30.0);
+    // Transient errors are retried without a limit, but not more often than the other ones
+    let failed_attempts = current_attempt.saturating_add(sync_data.transient_errors);
+    if failed_attempts > 0 {
+        let seconds_to_wait = 2.0_f64.powf(failed_attempts as f64 - 1.0).min(30.0);
         info!("Waiting {seconds_to_wait} seconds before starting the task");
         tokio::time::sleep(Duration::from_secs_f64(seconds_to_wait)).await;
     }
@@ -1683,6 +2193,34 @@ mod tests {
         timeline_id: TIMELINE_ID,
     };
 
+    #[test]
+    fn retryable_errors() {
+        let storage_error = |e: RemoteStorageError| {
+            anyhow::Error::new(e)
+                .context("Failed to download a layer")
+                .context("Failed to download timeline")
+        };
+        assert!(is_retryable_error(&storage_error(
+            RemoteStorageError::Transient(anyhow::anyhow!("timeout"))
+        )));
+        assert!(is_retryable_error(&storage_error(
+            RemoteStorageError::Throttled(anyhow::anyhow!("slow down"))
+        )));
+        assert!(!is_retryable_error(&storage_error(
+            RemoteStorageError::NotFound
+        )));
+        assert!(!is_retryable_error(&storage_error(
+            RemoteStorageError::Permanent(anyhow::anyhow!("access denied"))
+        )));
+        assert!(!is_retryable_error(&anyhow::anyhow!("disk is full")));
+
+        let mut sync_data = SyncData::new(0, ());
+        sync_data.register_failure(true);
+        sync_data.register_failure(false);
+        sync_data.register_failure(true);
+        assert_eq!((sync_data.retries, sync_data.transient_errors), (1, 2));
+    }
+
     #[tokio::test]
     async fn separate_task_ids_batch() {
         let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
@@ -1712,6 +2250,7 @@ mod tests {
             layers_to_delete: HashSet::from([PathBuf::from("de")]),
             deleted_layers: HashSet::from([PathBuf::from("del")]),
             deletion_registered: false,
+            layer_generations: HashMap::new(),
         });
 
         sync_queue.push(TEST_SYNC_ID, download_task.clone());
@@ -1744,6 +2283,69 @@ mod tests {
         assert_eq!(sync_queue.len(), 0);
     }
 
+    #[tokio::test]
+    async fn higher_priority_tasks_batched_first() {
+        // the batch takes one timeline less than the limit
+        let sync_queue = SyncQueue::new(NonZeroUsize::new(3).unwrap());
+        let sync_id = |tenant_byte: u8| ZTenantTimelineId {
+            tenant_id: ZTenantId::from_array([tenant_byte; 16]),
+            timeline_id: TIMELINE_ID,
+        };
+        let download = || {
+            SyncTask::download(LayersDownload {
+                layers_to_skip: HashSet::new(),
+            })
+        };
+        let upload = || {
+            SyncTask::upload(LayersUpload {
+                layers_to_upload: HashSet::from([PathBuf::from("up")]),
+                uploaded_layers: HashSet::new(),
+                metadata: None,
+            })
+        };
+
+        sync_queue.push(sync_id(1), download());
+        sync_queue.push(sync_id(2), upload());
+        sync_queue.push(sync_id(3), upload().with_priority(SyncPriority::Fresh));
+        sync_queue.push(sync_id(4), download().with_priority(SyncPriority::OnDemand));
+        assert_eq!(sync_queue.len(), 4);
+
+        let (batch, remaining) = sync_queue.next_task_batch();
+        assert_eq!(
+            batch.keys().copied().collect::<HashSet<_>>(),
+            HashSet::from([sync_id(3), sync_id(4)]),
+            "On demand and fresh tasks should be batched before the background ones"
+        );
+        assert_eq!(remaining, 2);
+
+        let (batch, remaining) = sync_queue.next_task_batch();
+        assert_eq!(
+            batch.keys().copied().collect::<HashSet<_>>(),
+            HashSet::from([sync_id(1), sync_id(2)]),
+        );
+        assert_eq!(remaining, 0);
+    }
+
+    #[tokio::test]
+    async fn merged_tasks_keep_highest_priority() {
+        let mut batch = SyncTaskBatch::new(SyncTask::download(LayersDownload {
+            layers_to_skip: HashSet::new(),
+        }));
+        batch.add(
+            SyncTask::download(LayersDownload {
+                layers_to_skip: HashSet::new(),
+            })
+            .with_priority(SyncPriority::OnDemand),
+        );
+        batch.add(SyncTask::download(LayersDownload {
+            layers_to_skip: HashSet::new(),
+        }));
+        assert_eq!(
+            batch.download.map(|download| download.priority),
+            Some(SyncPriority::OnDemand)
+        );
+    }
+
     #[tokio::test]
     async fn same_task_id_separate_tasks_batch() {
         let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
@@ -1761,6 +2363,7 @@ mod tests {
             layers_to_delete: HashSet::from([PathBuf::from("de")]),
             deleted_layers: HashSet::from([PathBuf::from("del")]),
             deletion_registered: false,
+            layer_generations: HashMap::new(),
         };
 
         sync_queue.push(TEST_SYNC_ID, SyncTask::download(download.clone()));
@@ -1780,14 +2383,20 @@ mod tests {
             Some(SyncTaskBatch {
                 upload: Some(SyncData {
                     retries: 0,
+                    transient_errors: 0,
+                    priority: SyncPriority::Background,
                     data: upload
                 }),
                 download: Some(SyncData {
                     retries: 0,
+                    transient_errors: 0,
+                    priority: SyncPriority::Background,
                     data: download
                 }),
                 delete: Some(SyncData {
                     retries: 0,
+                    transient_errors: 0,
+                    priority: SyncPriority::Background,
                     data: delete
                 }),
             }),
@@ -1837,6 +2446,8 @@ mod tests {
             Some(SyncTaskBatch {
                 download: Some(SyncData {
                     retries: 0,
+                    transient_errors: 0,
+                    priority: SyncPriority::Background,
                     data: LayersDownload {
                         layers_to_skip: {
                             let mut set = HashSet::new();
@@ -1861,4 +2472,35 @@ mod tests {
             "Should have one task left out of the batch"
         );
     }
+
+    #[test]
+    fn sync_id_from_local_path() {
+        let conf =
+            PageServerConf::dummy_conf(PageServerConf::test_repo_dir("sync_id_from_local_path"));
+        let timeline_path = conf.timeline_path(&TEST_SYNC_ID.timeline_id, &TEST_SYNC_ID.tenant_id);
+
+        assert_eq!(
+            parse_sync_id(&conf, &timeline_path.join("some_layer")),
+            Some(TEST_SYNC_ID),
+            "Timeline files should be attributed to their timeline"
+        );
+        assert_eq!(
+            parse_sync_id(&conf, &timeline_path),
+            None,
+            "Timeline directory should not be treated as a timeline file"
+        );
+        assert_eq!(
+            parse_sync_id(
+                &conf,
+                &conf.tenant_path(&TEST_SYNC_ID.tenant_id).join("config")
+            ),
+            None,
+            "Tenant files should not belong to any timeline"
+        );
+        assert_eq!(
+            parse_sync_id(&conf, &conf.workdir.join("some_file")),
+            None,
+            "Files outside of the tenants directory should not belong to any timeline"
+        );
+    }
 }
@@ -0,0 +1,373 @@
+//! Restores all tenants' timelines of a pageserver from the remote storage alone, without knowing their ids beforehand.
+//!
+//! Used to recover a lost pageserver: the remote storage is listed to discover every timeline's [`IndexPart`],
+//! then the timelines get downloaded into the workdir in their ancestry order, so that every timeline's ancestor
+//! is complete locally before the timeline itself, and an interrupted bootstrap never leaves a child without its parent.
+//! The downloads skip the files that are present locally already, so the bootstrap can be rerun after a failure.
+//!
+//! The bootstrap only fills the workdir: the pageserver started on it afterwards loads the timelines as local ones and syncs them as usual.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Debug,
+    num::{NonZeroU32, NonZeroUsize},
+    ops::ControlFlow,
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context};
+use remote_storage::{GenericRemoteStorage, RemoteStorage};
+use tokio::fs;
+use tracing::{error, info, warn};
+
+use crate::config::PageServerConf;
+use utils::zid::{ZTenantTimelineId, ZTimelineId};
+
+use super::{
+    download::{download_timeline_layers, DownloadedTimeline},
+    index::{IndexPart, RemoteTimeline},
+    init_bandwidth_limiters, parse_sync_id, try_fetch_index_parts, update_local_metadata,
+    validate_task_retries, LayersDownload, SyncData, SyncQueue,
+};
+
+/// Downloads every timeline found in the configured remote storage into the pageserver workdir.
+/// Fails if any of the timelines could not be restored, after trying to restore all others.
+pub fn bootstrap_from_remote(conf: &'static PageServerConf) -> anyhow::Result<()> {
+    let storage_config = conf.remote_storage_config.as_ref().ok_or_else(|| {
+        anyhow!("Cannot bootstrap the pageserver without remote storage configured")
+    })?;
+    init_bandwidth_limiters(storage_config);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to create the bootstrap runtime")?;
+
+    match GenericRemoteStorage::new(conf.workdir.clone(), storage_config)
+        .context("Failed to init the generic remote storage")?
+    {
+        GenericRemoteStorage::Local(local_fs_storage) => runtime.block_on(bootstrap(
+            conf,
+            &local_fs_storage,
+            storage_config.max_sync_errors,
+        )),
+        GenericRemoteStorage::S3(s3_bucket_storage) => runtime.block_on(bootstrap(
+            conf,
+            &s3_bucket_storage,
+            storage_config.max_sync_errors,
+        )),
+        GenericRemoteStorage::Azure(azure_blob_storage) => runtime.block_on(bootstrap(
+            conf,
+            &azure_blob_storage,
+            storage_config.max_sync_errors,
+        )),
+        GenericRemoteStorage::Gcs(gcs_bucket_storage) => runtime.block_on(bootstrap(
+            conf,
+            &gcs_bucket_storage,
+            storage_config.max_sync_errors,
+        )),
+    }
+}
+
+async fn bootstrap<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    max_sync_errors: NonZeroU32,
+) -> anyhow::Result<()>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let sync_ids = discover_remote_timelines(conf, storage).await?;
+    let tenants = sync_ids
+        .iter()
+        .map(|sync_id| sync_id.tenant_id)
+        .collect::<HashSet<_>>();
+    info!(
+        "Discovered {} remote timelines of {} tenants",
+        sync_ids.len(),
+        tenants.len()
+    );
+
+    let mut index_parts = try_fetch_index_parts(conf, storage, sync_ids.clone()).await;
+    let mut failed = sync_ids
+        .iter()
+        .filter(|sync_id| !index_parts.contains_key(sync_id))
+        .copied()
+        .collect::<BTreeSet<_>>();
+
+    let mut remote_timelines = HashMap::with_capacity(index_parts.len());
+    for (sync_id, index_part) in index_parts.drain() {
+        let timeline_path = conf.timeline_path(&sync_id.timeline_id, &sync_id.tenant_id);
+        match RemoteTimeline::from_index_part(&timeline_path, index_part) {
+            Ok(remote_timeline) => {
+                remote_timelines.insert(sync_id, remote_timeline);
+            }
+            Err(e) => {
+                error!("Failed to restore timeline {sync_id} from its index part: {e:?}");
+                failed.insert(sync_id);
+            }
+        }
+    }
+
+    let ancestors = remote_timelines
+        .iter()
+        .map(|(&sync_id, remote_timeline)| (sync_id, remote_timeline.metadata.ancestor_timeline()))
+        .collect();
+    let (attach_order, orphans) = attach_order(&ancestors);
+    for orphan in &orphans {
+        error!("Timeline {orphan} has no ancestor timeline in the remote storage, skipping it");
+    }
+    failed.extend(orphans);
+
+    // Download tasks reschedule themselves into the queue on failures, the bootstrap retries them itself instead.
+    let sync_queue = SyncQueue::new(NonZeroUsize::new(1).unwrap());
+    let total = attach_order.len();
+    for (i, sync_id) in attach_order.into_iter().enumerate() {
+        if let Some(ancestor_id) = ancestors[&sync_id] {
+            let ancestor_sync_id = ZTenantTimelineId::new(sync_id.tenant_id, ancestor_id);
+            if failed.contains(&ancestor_sync_id) {
+                error!(
+                    "[{}/{total}] Skipping timeline {sync_id}, its ancestor failed to bootstrap",
+                    i + 1
+                );
+                failed.insert(sync_id);
+                continue;
+            }
+        }
+
+        let remote_timeline = remote_timelines
+            .get_mut(&sync_id)
+            .expect("Attach order contains the remote timelines only");
+        info!(
+            "[{}/{total}] Downloading timeline {sync_id} with {} layers",
+            i + 1,
+            remote_timeline.stored_files().len()
+        );
+        match download_timeline(
+            conf,
+            storage,
+            &sync_queue,
+            sync_id,
+            remote_timeline,
+            max_sync_errors,
+        )
+        .await
+        {
+            Ok(()) => info!("[{}/{total}] Downloaded timeline {sync_id}", i + 1),
+            Err(e) => {
+                error!(
+                    "[{}/{total}] Failed to download timeline {sync_id}: {e:?}",
+                    i + 1
+                );
+                failed.insert(sync_id);
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        info!("Bootstrapped {total} timelines from the remote storage");
+        Ok(())
+    } else {
+        bail!(
+            "Failed to bootstrap {} out of {} remote timelines: {failed:?}",
+            failed.len(),
+            sync_ids.len()
+        )
+    }
+}
+
+/// Lists the remote storage for the timeline index parts.
+async fn discover_remote_timelines<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+) -> anyhow::Result<HashSet<ZTenantTimelineId>>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let index_part_file_name =
+        Path::new(IndexPart::FILE_NAME).with_extension(IndexPart::FILE_EXTENSION);
+    let mut sync_ids = HashSet::new();
+    for storage_file in storage
+        .list()
+        .await
+        .context("Failed to list the remote storage files")?
+    {
+        let local_path = match storage.local_path(&storage_file) {
+            Ok(local_path) => local_path,
+            Err(e) => {
+                warn!("Skipping remote storage file {storage_file:?} with no local path: {e:#}");
+                continue;
+            }
+        };
+        if local_path.file_name() != Some(index_part_file_name.as_os_str()) {
+            continue;
+        }
+        match parse_sync_id(conf, &local_path) {
+            Some(sync_id) => {
+                sync_ids.insert(sync_id);
+            }
+            None => warn!(
+                "Skipping index part '{}' outside of the timeline directories",
+                local_path.display()
+            ),
+        }
+    }
+    Ok(sync_ids)
+}
+
+/// Orders the timelines so that every timeline goes after its ancestor.
+/// Returns the order and the timelines that cannot be ordered, since their ancestors (or the ancestors' ancestors) are absent.
+fn attach_order(
+    ancestors: &BTreeMap<ZTenantTimelineId, Option<ZTimelineId>>,
+) -> (Vec<ZTenantTimelineId>, BTreeSet<ZTenantTimelineId>) {
+    let mut order = Vec::with_capacity(ancestors.len());
+    let mut ordered = HashSet::with_capacity(ancestors.len());
+    let mut orphans = BTreeSet::new();
+
+    for &sync_id in ancestors.keys() {
+        // walk up to the first ordered ancestor or the root, then order the walked timelines from the top
+        let mut chain = Vec::new();
+        let mut current = sync_id;
+        let has_root = loop {
+            if ordered.contains(&current) {
+                break true;
+            }
+            if orphans.contains(&current) || chain.contains(&current) {
+                break false;
+            }
+            match ancestors.get(&current) {
+                Some(ancestor) => {
+                    chain.push(current);
+                    match ancestor {
+                        Some(ancestor_id) => {
+                            current = ZTenantTimelineId::new(current.tenant_id, *ancestor_id)
+                        }
+                        None => break true,
+                    }
+                }
+                None => break false,
+            }
+        };
+
+        if has_root {
+            for sync_id in chain.into_iter().rev() {
+                ordered.insert(sync_id);
+                order.push(sync_id);
+            }
+        } else {
+            orphans.extend(chain);
+        }
+    }
+
+    (order, orphans)
+}
+
+async fn download_timeline<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    sync_queue: &SyncQueue,
+    sync_id: ZTenantTimelineId,
+    remote_timeline: &mut RemoteTimeline,
+    max_sync_errors: NonZeroU32,
+) -> anyhow::Result<()>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let timeline_path = conf.timeline_path(&sync_id.timeline_id, &sync_id.tenant_id);
+    fs::create_dir_all(&timeline_path).await.with_context(|| {
+        format!(
+            "Failed to create timeline directory '{}'",
+            timeline_path.display()
+        )
+    })?;
+    remote_timeline.awaits_download = true;
+
+    let mut download_data = SyncData::new(
+        0,
+        LayersDownload {
+            layers_to_skip: HashSet::new(),
+        },
+    );
+    loop {
+        download_data = match validate_task_retries(download_data, max_sync_errors).await {
+            ControlFlow::Continue(download_data) => download_data,
+            ControlFlow::Break(_) => {
+                bail!("Timeline layers download failed {max_sync_errors} times")
+            }
+        };
+        match download_timeline_layers(
+            conf,
+            storage,
+            sync_queue,
+            Some(remote_timeline),
+            sync_id,
+            download_data,
+        )
+        .await
+        {
+            DownloadedTimeline::Successful(_) => break,
+            // the layers downloaded already are skipped on retry
+            DownloadedTimeline::FailedAndRescheduled => {
+                // take the task back from the queue, with its failure counted
+                let (mut batch, _) = sync_queue.next_task_batch();
+                download_data = batch
+                    .remove(&sync_id)
+                    .and_then(|batch| batch.download)
+                    .context("Failed download task was not rescheduled")?;
+            }
+            DownloadedTimeline::Abort => bail!("Timeline layers download aborted"),
+        }
+    }
+
+    update_local_metadata(conf, sync_id, Some(remote_timeline))
+        .await
+        .context("Failed to update local timeline metadata")
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+    use utils::zid::ZTenantId;
+
+    use super::*;
+
+    #[test]
+    fn ancestors_go_first() {
+        let tenant_id = ZTenantId::from_array(hex!("11223344556677881122334455667788"));
+        let other_tenant_id = ZTenantId::from_array(hex!("22223344556677881122334455667788"));
+        let timeline = |tenant_id, byte: u8| {
+            ZTenantTimelineId::new(tenant_id, ZTimelineId::from_array([byte; 16]))
+        };
+
+        let root = timeline(tenant_id, 1);
+        let child = timeline(tenant_id, 2);
+        let grandchild = timeline(tenant_id, 0);
+        let other_root = timeline(other_tenant_id, 3);
+        // a timeline with the same id as the root, but in another tenant, is not an ancestor
+        let orphan = timeline(other_tenant_id, 4);
+        let orphan_child = timeline(other_tenant_id, 5);
+
+        let ancestors = BTreeMap::from([
+            (grandchild, Some(child.timeline_id)),
+            (child, Some(root.timeline_id)),
+            (root, None),
+            (other_root, None),
+            (orphan, Some(root.timeline_id)),
+            (orphan_child, Some(orphan.timeline_id)),
+        ]);
+        let (order, orphans) = attach_order(&ancestors);
+
+        assert_eq!(orphans, BTreeSet::from([orphan, orphan_child]));
+        assert_eq!(
+            order.len(),
+            4,
+            "All timelines with ancestors present should be ordered"
+        );
+        let position = |sync_id| order.iter().position(|&id| id == sync_id).unwrap();
+        assert!(position(root) < position(child));
+        assert!(position(child) < position(grandchild));
+        assert!(order.contains(&other_root));
+    }
+}
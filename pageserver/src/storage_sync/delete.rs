@@ -2,13 +2,52 @@
 
 use anyhow::Context;
 use futures::stream::{FuturesUnordered, StreamExt};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
-use crate::storage_sync::{SyncQueue, SyncTask};
+use crate::{
+    config::PageServerConf,
+    layered_repository::metadata::metadata_path,
+    storage_sync::{SyncQueue, SyncTask},
+};
 use remote_storage::RemoteStorage;
 use utils::zid::ZTenantTimelineId;
 
-use super::{LayersDeletion, SyncData};
+use super::{
+    index::{versioned_index_part_path, Generation, IndexPart},
+    is_retryable_error, LayersDeletion, SyncData,
+};
+
+/// Removes the index part versions, not kept anymore, from the remote storage.
+/// Failures are only logged: the current index part does not reference such versions, so they are never read again.
+pub(super) async fn delete_index_part_versions<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    sync_id: ZTenantTimelineId,
+    versions: &[u64],
+) where
+    P: std::fmt::Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let index_part_path = metadata_path(conf, sync_id.timeline_id, sync_id.tenant_id)
+        .with_file_name(IndexPart::FILE_NAME)
+        .with_extension(IndexPart::FILE_EXTENSION);
+    for &version in versions {
+        let versioned_path = versioned_index_part_path(&index_part_path, version);
+        let deletion_result = match storage.remote_object_id(&versioned_path) {
+            Ok(storage_path) => storage
+                .delete(&storage_path)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        };
+        match deletion_result {
+            Ok(()) => debug!("Deleted index part version {version} for timeline {sync_id}"),
+            Err(e) => {
+                warn!("Failed to delete index part version {version} for timeline {sync_id}: {e:#}")
+            }
+        }
+    }
+}
 
 /// Attempts to remove the timleline layers from the remote storage.
 /// If the task had not adjusted the metadata before, the deletion will fail.
@@ -38,25 +77,33 @@ where
         .data
         .layers_to_delete
         .drain()
+        .map(|layer| {
+            let generation = delete_data
+                .data
+                .layer_generations
+                .get(&layer)
+                .copied()
+                .unwrap_or(Generation::NONE);
+            (layer, generation)
+        })
         .collect::<Vec<_>>();
     debug!("Layers to delete: {layers_to_delete:?}");
     info!("Deleting {} timeline layers", layers_to_delete.len());
 
     let mut delete_tasks = layers_to_delete
         .into_iter()
-        .map(|local_layer_path| async {
-            let storage_path =
-                match storage
-                    .remote_object_id(&local_layer_path)
-                    .with_context(|| {
-                        format!(
-                            "Failed to get the layer storage path for local path '{}'",
-                            local_layer_path.display()
-                        )
-                    }) {
-                    Ok(path) => path,
-                    Err(e) => return Err((e, local_layer_path)),
-                };
+        .map(|(local_layer_path, generation)| async move {
+            let storage_path = match storage
+                .remote_object_id(&generation.layer_path(&local_layer_path))
+                .with_context(|| {
+                    format!(
+                        "Failed to get the layer storage path for local path '{}'",
+                        local_layer_path.display()
+                    )
+                }) {
+                Ok(path) => path,
+                Err(e) => return Err((e, local_layer_path)),
+            };
 
             match storage.delete(&storage_path).await.with_context(|| {
                 format!(
@@ -71,6 +118,7 @@ where
         .collect::<FuturesUnordered<_>>();
 
     let mut errored = false;
+    let mut retryable_errors_only = true;
     while let Some(deletion_result) = delete_tasks.next().await {
         match deletion_result {
             Ok(local_layer_path) => {
@@ -82,6 +130,7 @@ where
             }
             Err((e, local_layer_path)) => {
                 errored = true;
+                retryable_errors_only &= is_retryable_error(&e);
                 error!(
                     "Failed to delete layer {} for timeline {sync_id}: {e:?}",
                     local_layer_path.display()
@@ -93,7 +142,7 @@ where
 
     if errored {
         debug!("Reenqueuing failed delete task for timeline {sync_id}");
-        delete_data.retries += 1;
+        delete_data.register_failure(retryable_errors_only);
         sync_queue.push(sync_id, SyncTask::Delete(delete_data));
     }
     errored
@@ -101,7 +150,10 @@ where
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashSet, num::NonZeroUsize};
+    use std::{
+        collections::{HashMap, HashSet},
+        num::NonZeroUsize,
+    };
 
     use itertools::Itertools;
     use tempfile::tempdir;
@@ -110,7 +162,10 @@ mod tests {
 
     use crate::{
         repository::repo_harness::{RepoHarness, TIMELINE_ID},
-        storage_sync::test_utils::{create_local_timeline, dummy_metadata},
+        storage_sync::{
+            test_utils::{create_local_timeline, dummy_metadata},
+            SyncPriority,
+        },
     };
     use remote_storage::LocalFs;
 
@@ -132,10 +187,13 @@ mod tests {
             sync_id,
             SyncData {
                 retries: 1,
+                transient_errors: 0,
+                priority: SyncPriority::Background,
                 data: LayersDeletion {
                     deleted_layers: HashSet::new(),
                     layers_to_delete: HashSet::new(),
                     deletion_registered: false,
+                    layer_generations: HashMap::new(),
                 },
             },
         )
@@ -189,6 +247,12 @@ mod tests {
                 .collect::<Vec<_>>(),
             "Expect to have all layer files remotely before deletion"
         );
+        let c_layer = local_timeline_path.join("c");
+        fs::rename(
+            storage.remote_object_id(&c_layer)?,
+            storage.remote_object_id(&Generation::FIRST.layer_path(&c_layer))?,
+        )
+        .await?;
 
         let deleted = delete_timeline_layers(
             &storage,
@@ -196,6 +260,8 @@ mod tests {
             sync_id,
             SyncData {
                 retries: current_retries,
+                transient_errors: 0,
+                priority: SyncPriority::Background,
                 data: LayersDeletion {
                     deleted_layers: HashSet::new(),
                     layers_to_delete: HashSet::from([
@@ -204,6 +270,7 @@ mod tests {
                         local_timeline_path.join("something_different"),
                     ]),
                     deletion_registered: true,
+                    layer_generations: HashMap::from([(c_layer, Generation::FIRST)]),
                 },
             },
         )
@@ -17,8 +17,10 @@ use crate::{
 use utils::zid::ZTenantTimelineId;
 
 use super::{
-    index::{IndexPart, RemoteTimeline},
-    LayersDownload, SyncData, SyncQueue,
+    index::{versioned_index_part_path, IndexPart, RemoteTimeline},
+    is_retryable_error,
+    throttle::ThrottledWriter,
+    LayersDownload, SyncData, SyncQueue, DOWNLOAD_LIMITER,
 };
 
 pub const TEMP_DOWNLOAD_EXTENSION: &str = "temp_download";
@@ -36,14 +38,52 @@ where
     let index_part_path = metadata_path(conf, sync_id.timeline_id, sync_id.tenant_id)
         .with_file_name(IndexPart::FILE_NAME)
         .with_extension(IndexPart::FILE_EXTENSION);
-    let part_storage_path = storage
-        .remote_object_id(&index_part_path)
-        .with_context(|| {
-            format!(
-                "Failed to get the index part storage path for local path '{}'",
-                index_part_path.display()
-            )
-        })?;
+    download_index_part_file(storage, sync_id, &index_part_path).await
+}
+
+/// Retrieves the given version of the index data from the remote storage for a given timeline.
+pub(super) async fn download_index_part_version<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    sync_id: ZTenantTimelineId,
+    version: u64,
+) -> anyhow::Result<IndexPart>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let index_part_path = metadata_path(conf, sync_id.timeline_id, sync_id.tenant_id)
+        .with_file_name(IndexPart::FILE_NAME)
+        .with_extension(IndexPart::FILE_EXTENSION);
+    let index_part = download_index_part_file(
+        storage,
+        sync_id,
+        &versioned_index_part_path(&index_part_path, version),
+    )
+    .await?;
+    anyhow::ensure!(
+        index_part.version() == version,
+        "Index part of version {version} for timeline {sync_id} has a different version {} stored",
+        index_part.version()
+    );
+    Ok(index_part)
+}
+
+async fn download_index_part_file<P, S>(
+    storage: &S,
+    sync_id: ZTenantTimelineId,
+    index_part_path: &Path,
+) -> anyhow::Result<IndexPart>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let part_storage_path = storage.remote_object_id(index_part_path).with_context(|| {
+        format!(
+            "Failed to get the index part storage path for local path '{}'",
+            index_part_path.display()
+        )
+    })?;
     let mut index_part_bytes = Vec::new();
     storage
         .download(&part_storage_path, &mut index_part_bytes)
@@ -81,7 +121,8 @@ pub(super) enum DownloadedTimeline {
 /// Timeline files that already exist locally are skipped during the download, but the local metadata file is
 /// updated in the end, if the remote one contains a newer disk_consistent_lsn.
 ///
-/// On an error, bumps the retries count and updates the files to skip with successful downloads, rescheduling the task.
+/// On an error, bumps the retries count, unless only the retryable storage errors happened, and updates the files to skip
+/// with successful downloads, rescheduling the task.
 pub(super) async fn download_timeline_layers<'a, P, S>(
     conf: &'static PageServerConf,
     storage: &'a S,
@@ -134,7 +175,7 @@ where
                 );
             } else {
                 let layer_storage_path = storage
-                    .remote_object_id(&layer_desination_path)
+                    .remote_object_id(&remote_timeline.remote_layer_path(&layer_desination_path))
                     .with_context(|| {
                         format!(
                             "Failed to get the layer storage path for local path '{}'",
@@ -155,13 +196,15 @@ where
                 let temp_file_path =
                     path_with_suffix_extension(&layer_desination_path, TEMP_DOWNLOAD_EXTENSION);
 
-                let mut destination_file =
+                let destination_file =
                     fs::File::create(&temp_file_path).await.with_context(|| {
                         format!(
                             "Failed to create a destination file for layer '{}'",
                             temp_file_path.display()
                         )
                     })?;
+                let mut destination_file =
+                    ThrottledWriter::new(destination_file, DOWNLOAD_LIMITER.get());
 
                 storage
                     .download(&layer_storage_path, &mut destination_file)
@@ -188,6 +231,7 @@ where
                 })?;
 
                 // not using sync_data because it can lose file size update
+                let destination_file = destination_file.into_inner();
                 destination_file.sync_all().await.with_context(|| {
                     format!(
                         "failed to fsync source file at {}",
@@ -214,6 +258,7 @@ where
         .collect::<FuturesUnordered<_>>();
 
     let mut errors_happened = false;
+    let mut retryable_errors_only = true;
     // keep files we've downloaded to remove them from layers_to_skip if directory fsync fails
     let mut undo = HashSet::new();
     while let Some(download_result) = download_tasks.next().await {
@@ -224,6 +269,7 @@ where
             }
             Err(e) => {
                 errors_happened = true;
+                retryable_errors_only &= is_retryable_error(&e);
                 error!("Failed to download a layer for timeline {sync_id}: {e:?}");
             }
         }
@@ -245,11 +291,12 @@ where
             download.layers_to_skip.remove(&item);
         }
         errors_happened = true;
+        retryable_errors_only = false;
     }
 
     if errors_happened {
         debug!("Reenqueuing failed download task for timeline {sync_id}");
-        download_data.retries += 1;
+        download_data.register_failure(retryable_errors_only);
         sync_queue.push(sync_id, SyncTask::Download(download_data));
         DownloadedTimeline::FailedAndRescheduled
     } else {
@@ -267,16 +314,17 @@ mod tests {
     use std::{
         collections::{BTreeSet, HashSet},
         num::NonZeroUsize,
+        path::PathBuf,
     };
 
-    use remote_storage::{LocalFs, RemoteStorage};
+    use remote_storage::{LocalFs, RemoteStorage, RemoteStorageError, StorageMetadata};
     use tempfile::tempdir;
     use utils::lsn::Lsn;
 
     use crate::{
         repository::repo_harness::{RepoHarness, TIMELINE_ID},
         storage_sync::{
-            index::RelativePath,
+            index::{Generation, RelativePath},
             test_utils::{create_local_timeline, dummy_metadata},
         },
     };
@@ -301,7 +349,8 @@ mod tests {
             create_local_timeline(&harness, TIMELINE_ID, &layer_files, metadata.clone()).await?;
 
         for local_path in timeline_upload.layers_to_upload {
-            let remote_path = storage.remote_object_id(&local_path)?;
+            let remote_path =
+                storage.remote_object_id(&Generation::FIRST.layer_path(&local_path))?;
             let remote_parent_dir = remote_path.parent().unwrap();
             if !remote_parent_dir.exists() {
                 fs::create_dir_all(&remote_parent_dir).await?;
@@ -438,6 +487,137 @@ mod tests {
         Ok(())
     }
 
+    /// Fails every download with the error given.
+    struct FailingDownloads {
+        inner: LocalFs,
+        error: fn() -> RemoteStorageError,
+    }
+
+    #[async_trait::async_trait]
+    impl RemoteStorage for FailingDownloads {
+        type RemoteObjectId = PathBuf;
+
+        fn remote_object_id(&self, local_path: &Path) -> anyhow::Result<PathBuf> {
+            self.inner.remote_object_id(local_path)
+        }
+
+        fn local_path(&self, remote_object_id: &PathBuf) -> anyhow::Result<PathBuf> {
+            self.inner.local_path(remote_object_id)
+        }
+
+        async fn list(&self) -> Result<Vec<PathBuf>, RemoteStorageError> {
+            self.inner.list().await
+        }
+
+        async fn upload(
+            &self,
+            from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+            from_size_bytes: usize,
+            to: &PathBuf,
+            metadata: Option<StorageMetadata>,
+        ) -> Result<(), RemoteStorageError> {
+            self.inner.upload(from, from_size_bytes, to, metadata).await
+        }
+
+        async fn download(
+            &self,
+            _: &PathBuf,
+            _: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+        ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
+            Err((self.error)())
+        }
+
+        async fn download_byte_range(
+            &self,
+            _: &PathBuf,
+            _: u64,
+            _: Option<u64>,
+            _: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+        ) -> Result<Option<StorageMetadata>, RemoteStorageError> {
+            Err((self.error)())
+        }
+
+        async fn delete(&self, path: &PathBuf) -> Result<(), RemoteStorageError> {
+            self.inner.delete(path).await
+        }
+    }
+
+    #[tokio::test]
+    async fn download_failures_classification() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("download_failures_classification")?;
+        let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+        let local_timeline_path = harness.timeline_path(&TIMELINE_ID);
+        fs::create_dir_all(&local_timeline_path).await?;
+
+        let mut remote_timeline = RemoteTimeline::new(dummy_metadata(Lsn(0x30)));
+        remote_timeline.awaits_download = true;
+        remote_timeline.add_timeline_layers([local_timeline_path.join("layer")]);
+
+        let current_retries = 3;
+        let failures: [(fn() -> RemoteStorageError, bool); 4] = [
+            (
+                || RemoteStorageError::Transient(anyhow::anyhow!("connection reset")),
+                true,
+            ),
+            (
+                || RemoteStorageError::Throttled(anyhow::anyhow!("slow down")),
+                true,
+            ),
+            (|| RemoteStorageError::NotFound, false),
+            (
+                || RemoteStorageError::Permanent(anyhow::anyhow!("access denied")),
+                false,
+            ),
+        ];
+        for (error, retryable) in failures {
+            let storage = FailingDownloads {
+                inner: LocalFs::new(tempdir()?.path().to_owned(), harness.conf.workdir.clone())?,
+                error,
+            };
+            let download = download_timeline_layers(
+                harness.conf,
+                &storage,
+                &sync_queue,
+                Some(&remote_timeline),
+                sync_id,
+                SyncData::new(
+                    current_retries,
+                    LayersDownload {
+                        layers_to_skip: HashSet::new(),
+                    },
+                ),
+            )
+            .await;
+            assert!(
+                matches!(download, DownloadedTimeline::FailedAndRescheduled),
+                "Expected the failed download to be rescheduled, but got: {download:?}"
+            );
+
+            let (mut batch, _) = sync_queue.next_task_batch();
+            let rescheduled = batch
+                .remove(&sync_id)
+                .and_then(|batch| batch.download)
+                .expect("Failed download should be rescheduled");
+            let error = error();
+            if retryable {
+                assert_eq!(
+                    (rescheduled.retries, rescheduled.transient_errors),
+                    (current_retries, 1),
+                    "Retryable error {error} should not count against the max sync errors"
+                );
+            } else {
+                assert_eq!(
+                    (rescheduled.retries, rescheduled.transient_errors),
+                    (current_retries + 1, 0),
+                    "Error {error} should count against the max sync errors"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_download_index_part() -> anyhow::Result<()> {
         let harness = RepoHarness::create("test_download_index_part")?;
@@ -1,11 +1,20 @@
 //! In-memory index to track the tenant files on the remote storage.
 //! Able to restore itself from the storage index parts, that are located in every timeline's remote directory and contain all data about
 //! remote timeline layers and its metadata.
+//!
+//! Every index part upload is versioned: besides overwriting the current index part, its copy is stored under the versioned name,
+//! see [`versioned_index_part_path`]. A few latest versions are kept, so that the timeline's remote state can be rolled back to one of them,
+//! if the latest updates turn out to be wrong (e.g. a buggy GC removes the layers still needed).
+//! For that, the layers removed from the timeline are not deleted from the remote storage until no kept version references them,
+//! see [`RemoteTimeline::new_index_version`].
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map, HashMap, HashSet},
+    fmt::Display,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
     sync::Arc,
+    time::SystemTime,
 };
 
 use anyhow::{anyhow, Context, Ok};
@@ -41,6 +50,49 @@ impl RelativePath {
     }
 }
 
+/// Attachment generation of a remote timeline, used to fence off the stale pageservers, attached to the same timeline.
+///
+/// Every pageserver restoring the timeline from its remote [`IndexPart`] takes the generation next to the one stored there,
+/// and refuses to update the index part, once a newer generation is observed in it.
+/// The layers get uploaded under the names suffixed with the generation of their uploader,
+/// so the layers of different pageservers never overwrite each other.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Generation(u32);
+
+impl Generation {
+    /// Generation of the timelines and layers, uploaded before the generations were introduced.
+    /// Such layers have no generation suffix in their remote names.
+    pub const NONE: Self = Self(0);
+    /// Generation of the timelines, created by the current pageserver and never uploaded before.
+    pub const FIRST: Self = Self(1);
+
+    pub fn next(self) -> Self {
+        Self(self.0 + 1)
+    }
+
+    /// Path of the remote counterpart of the local layer, uploaded in this generation.
+    pub fn layer_path(self, local_layer_path: &Path) -> PathBuf {
+        if self == Self::NONE {
+            return local_layer_path.to_path_buf();
+        }
+        let mut file_name = local_layer_path
+            .file_name()
+            .unwrap_or_default()
+            .to_os_string();
+        file_name.push(format!("-{:08x}", self.0));
+        local_layer_path.with_file_name(file_name)
+    }
+}
+
+impl Display for Generation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
 /// An index to track tenant files that exist on the remote storage.
 #[derive(Debug, Clone)]
 pub struct RemoteTimelineIndex {
@@ -124,9 +176,50 @@ impl RemoteTimelineIndex {
 pub struct RemoteTimeline {
     timeline_layers: HashSet<PathBuf>,
     missing_layers: HashSet<PathBuf>,
+    /// Generations the timeline layers were uploaded in, [`Generation::NONE`] layers are omitted.
+    layer_generations: HashMap<PathBuf, Generation>,
 
     pub metadata: TimelineMetadata,
     pub awaits_download: bool,
+    /// Generation this pageserver is attached to the timeline with, all new layers are uploaded in.
+    pub generation: Generation,
+    /// A newer generation observed in the remote index part, if any: no more remote updates are allowed for the timeline after that.
+    pub fenced_by: Option<Generation>,
+
+    /// Version of the latest index part uploaded, 0 if it was uploaded before the versioning was introduced.
+    index_version: u64,
+    /// Index part versions, kept in the remote storage, from the oldest to the latest one.
+    index_history: Vec<IndexPartVersion>,
+    /// Layers removed from the timeline, but still referenced by the older kept index part versions.
+    pending_deletions: HashMap<PathBuf, PendingDeletion>,
+}
+
+/// A timeline index part version, kept in the remote storage, to roll the timeline back to.
+#[serde_as]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexPartVersion {
+    pub version: u64,
+    #[serde(with = "humantime_serde")]
+    pub uploaded_at: SystemTime,
+    #[serde_as(as = "DisplayFromStr")]
+    pub disk_consistent_lsn: Lsn,
+}
+
+/// A layer, removed from the timeline in the given index part version, to delete from the remote storage
+/// once that version becomes the oldest one kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingDeletion {
+    generation: Generation,
+    removed_in_version: u64,
+}
+
+/// Changes in the kept index part versions, after a new version of the index part is created.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct IndexVersionUpdate {
+    /// Versions, not kept anymore, to delete from the remote storage.
+    pub expired_versions: Vec<u64>,
+    /// Layers, not referenced by any kept version anymore, to delete from the remote storage, with their generations.
+    pub expired_layers: HashMap<PathBuf, Generation>,
 }
 
 impl RemoteTimeline {
@@ -134,24 +227,74 @@ impl RemoteTimeline {
         Self {
             timeline_layers: HashSet::new(),
             missing_layers: HashSet::new(),
+            layer_generations: HashMap::new(),
             metadata,
             awaits_download: false,
+            generation: Generation::FIRST,
+            fenced_by: None,
+            index_version: 0,
+            index_history: Vec::new(),
+            pending_deletions: HashMap::new(),
         }
     }
 
+    /// Adds the layers, uploaded in the current timeline generation.
     pub fn add_timeline_layers(&mut self, new_layers: impl IntoIterator<Item = PathBuf>) {
-        self.timeline_layers.extend(new_layers.into_iter());
+        for new_layer in new_layers {
+            // the upload has overwritten the remote layer, pending deletion
+            if let hash_map::Entry::Occupied(pending_deletion) =
+                self.pending_deletions.entry(new_layer.clone())
+            {
+                if pending_deletion.get().generation == self.generation {
+                    pending_deletion.remove();
+                }
+            }
+            if self.generation == Generation::NONE {
+                self.layer_generations.remove(&new_layer);
+            } else {
+                self.layer_generations
+                    .insert(new_layer.clone(), self.generation);
+            }
+            self.timeline_layers.insert(new_layer);
+        }
     }
 
     pub fn add_upload_failures(&mut self, upload_failures: impl IntoIterator<Item = PathBuf>) {
         self.missing_layers.extend(upload_failures.into_iter());
     }
 
+    /// Removes the layers from the timeline, the ones uploaded are kept in the remote storage
+    /// until the older index part versions, referencing them, expire.
     pub fn remove_layers(&mut self, layers_to_remove: &HashSet<PathBuf>) {
+        for layer in layers_to_remove {
+            if self.timeline_layers.contains(layer) {
+                let pending_deletion = PendingDeletion {
+                    generation: self.layer_generation(layer),
+                    removed_in_version: self.index_version + 1,
+                };
+                self.pending_deletions
+                    .insert(layer.clone(), pending_deletion);
+            }
+        }
         self.timeline_layers
             .retain(|layer| !layers_to_remove.contains(layer));
         self.missing_layers
             .retain(|layer| !layers_to_remove.contains(layer));
+        self.layer_generations
+            .retain(|layer, _| !layers_to_remove.contains(layer));
+    }
+
+    /// Generation the given timeline layer was uploaded in.
+    pub fn layer_generation(&self, layer: &Path) -> Generation {
+        self.layer_generations
+            .get(layer)
+            .copied()
+            .unwrap_or(Generation::NONE)
+    }
+
+    /// Path of the remote counterpart of the given local timeline layer.
+    pub fn remote_layer_path(&self, layer: &Path) -> PathBuf {
+        self.layer_generation(layer).layer_path(layer)
     }
 
     /// Lists all layer files in the given remote timeline. Omits the metadata file.
@@ -159,13 +302,123 @@ impl RemoteTimeline {
         &self.timeline_layers
     }
 
+    /// Lists the layers removed from the timeline, but not deleted from the remote storage yet.
+    pub fn pending_deletions(&self) -> impl Iterator<Item = &Path> + '_ {
+        self.pending_deletions.keys().map(PathBuf::as_path)
+    }
+
+    /// Paths of the remote counterparts of the layers, pending deletion.
+    pub fn remote_pending_deletion_paths(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.pending_deletions
+            .iter()
+            .map(|(layer, pending_deletion)| pending_deletion.generation.layer_path(layer))
+    }
+
+    /// Version of the latest index part uploaded.
+    pub fn index_version(&self) -> u64 {
+        self.index_version
+    }
+
+    /// Index part versions, kept in the remote storage, from the oldest to the latest one.
+    pub fn index_history(&self) -> &[IndexPartVersion] {
+        &self.index_history
+    }
+
+    /// Registers a new index part version to upload, keeping at most the given number of the latest versions.
+    /// Returns the versions and the layers to delete from the remote storage, after the new version is uploaded.
+    pub fn new_index_version(
+        &mut self,
+        uploaded_at: SystemTime,
+        versions_to_keep: NonZeroUsize,
+    ) -> IndexVersionUpdate {
+        self.index_version += 1;
+        self.index_history.push(IndexPartVersion {
+            version: self.index_version,
+            uploaded_at,
+            disk_consistent_lsn: self.metadata.disk_consistent_lsn(),
+        });
+
+        let versions_to_expire = self
+            .index_history
+            .len()
+            .saturating_sub(versions_to_keep.get());
+        let expired_versions = self
+            .index_history
+            .drain(..versions_to_expire)
+            .map(|version| version.version)
+            .collect();
+
+        // layers, removed in the oldest version kept or earlier, are not referenced by any kept version
+        let oldest_kept_version = self.index_history[0].version;
+        let mut expired_layers = HashMap::new();
+        self.pending_deletions.retain(|layer, pending_deletion| {
+            if pending_deletion.removed_in_version <= oldest_kept_version {
+                expired_layers.insert(layer.clone(), pending_deletion.generation);
+                false
+            } else {
+                true
+            }
+        });
+
+        IndexVersionUpdate {
+            expired_versions,
+            expired_layers,
+        }
+    }
+
+    /// Replaces the timeline layers and metadata with the ones from an older index part version.
+    /// Layers, not present in that version, get removed the same way as with [`Self::remove_layers`],
+    /// the removed layers, present in that version, are not deleted anymore.
+    /// The version history, the generations and the download status stay intact.
+    pub fn roll_back(&mut self, older_version: RemoteTimeline) {
+        let layers_to_remove = self
+            .timeline_layers
+            .iter()
+            .filter(|layer| {
+                !older_version.timeline_layers.contains(*layer)
+                    || older_version.layer_generation(layer) != self.layer_generation(layer)
+            })
+            .cloned()
+            .collect();
+        self.remove_layers(&layers_to_remove);
+        for layer in &older_version.timeline_layers {
+            if let hash_map::Entry::Occupied(pending_deletion) =
+                self.pending_deletions.entry(layer.clone())
+            {
+                if pending_deletion.get().generation == older_version.layer_generation(layer) {
+                    pending_deletion.remove();
+                }
+            }
+        }
+
+        self.timeline_layers = older_version.timeline_layers;
+        self.missing_layers = older_version.missing_layers;
+        self.layer_generations = older_version.layer_generations;
+        self.metadata = older_version.metadata;
+    }
+
+    /// Restores the remote timeline from its index part, attaching it with the generation next to the index part's one.
     pub fn from_index_part(timeline_path: &Path, index_part: IndexPart) -> anyhow::Result<Self> {
         let metadata = TimelineMetadata::from_bytes(&index_part.metadata_bytes)?;
         Ok(Self {
             timeline_layers: to_local_paths(timeline_path, index_part.timeline_layers),
             missing_layers: to_local_paths(timeline_path, index_part.missing_layers),
+            layer_generations: index_part
+                .layer_generations
+                .into_iter()
+                .map(|(layer, generation)| (layer.as_path(timeline_path), generation))
+                .collect(),
             metadata,
             awaits_download: false,
+            generation: index_part.generation.next(),
+            fenced_by: None,
+            index_version: index_part.version,
+            index_history: index_part.history,
+            pending_deletions: index_part
+                .pending_deletions
+                .into_iter()
+                .map(|(layer, pending_deletion)| (layer.as_path(timeline_path), pending_deletion))
+                .collect(),
         })
     }
 }
@@ -185,6 +438,21 @@ pub struct IndexPart {
     #[serde_as(as = "DisplayFromStr")]
     disk_consistent_lsn: Lsn,
     metadata_bytes: Vec<u8>,
+    /// Generation of the pageserver that uploaded the index part, absent in the index parts uploaded before the generations were introduced.
+    #[serde(default)]
+    generation: Generation,
+    /// Generations of the timeline layers, uploaded with the generation suffix in their names.
+    #[serde(default)]
+    layer_generations: HashMap<RelativePath, Generation>,
+    /// Version of the index part, absent in the index parts uploaded before the versioning was introduced.
+    #[serde(default)]
+    version: u64,
+    /// Index part versions, kept in the remote storage, including the current one.
+    #[serde(default)]
+    history: Vec<IndexPartVersion>,
+    /// Layers not referenced by the current version, but still present in the remote storage for the older versions kept.
+    #[serde(default)]
+    pending_deletions: HashMap<RelativePath, PendingDeletion>,
 }
 
 impl IndexPart {
@@ -203,6 +471,11 @@ impl IndexPart {
             missing_layers,
             disk_consistent_lsn,
             metadata_bytes,
+            generation: Generation::NONE,
+            layer_generations: HashMap::new(),
+            version: 0,
+            history: Vec::new(),
+            pending_deletions: HashMap::new(),
         }
     }
 
@@ -210,6 +483,14 @@ impl IndexPart {
         &self.missing_layers
     }
 
+    pub fn generation(&self) -> Generation {
+        self.generation
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
     pub fn from_remote_timeline(
         timeline_path: &Path,
         remote_timeline: RemoteTimeline,
@@ -222,10 +503,34 @@ impl IndexPart {
                 .context("Failed to convert missing layers' paths to relative ones")?,
             disk_consistent_lsn: remote_timeline.metadata.disk_consistent_lsn(),
             metadata_bytes,
+            generation: remote_timeline.generation,
+            layer_generations: remote_timeline
+                .layer_generations
+                .into_iter()
+                .map(|(layer, generation)| {
+                    Ok((RelativePath::new(timeline_path, layer)?, generation))
+                })
+                .collect::<anyhow::Result<_>>()
+                .context("Failed to convert layer generations' paths to relative ones")?,
+            version: remote_timeline.index_version,
+            history: remote_timeline.index_history,
+            pending_deletions: remote_timeline
+                .pending_deletions
+                .into_iter()
+                .map(|(layer, pending_deletion)| {
+                    Ok((RelativePath::new(timeline_path, layer)?, pending_deletion))
+                })
+                .collect::<anyhow::Result<_>>()
+                .context("Failed to convert pending deletions' paths to relative ones")?,
         })
     }
 }
 
+/// Path of the index part copy of the given version, next to the current index part.
+pub fn versioned_index_part_path(index_part_path: &Path, version: u64) -> PathBuf {
+    index_part_path.with_extension(format!("{version}.{}", IndexPart::FILE_EXTENSION))
+}
+
 fn to_local_paths(
     timeline_path: &Path,
     paths: impl IntoIterator<Item = RelativePath>,
@@ -268,8 +573,14 @@ mod tests {
                 timeline_path.join("missing_1"),
                 timeline_path.join("missing_2"),
             ]),
+            layer_generations: HashMap::from([(timeline_path.join("layer_2"), Generation(3))]),
             metadata: metadata.clone(),
             awaits_download: false,
+            generation: Generation(4),
+            fenced_by: None,
+            index_version: 0,
+            index_history: Vec::new(),
+            pending_deletions: HashMap::new(),
         };
 
         let index_part = IndexPart::from_remote_timeline(&timeline_path, remote_timeline.clone())
@@ -369,6 +680,185 @@ mod tests {
                 .collect::<BTreeSet<_>>(),
             "remote timeline -> index part -> remote timeline conversion should not loose missing file data"
         );
+        assert_eq!(
+            restored_timeline.layer_generation(&timeline_path.join("layer_2")),
+            Generation(3),
+            "remote timeline -> index part -> remote timeline conversion should not loose layer generations"
+        );
+        assert_eq!(
+            restored_timeline.layer_generation(&timeline_path.join("layer_1")),
+            Generation::NONE,
+        );
+        assert_eq!(
+            restored_timeline.generation,
+            Generation(5),
+            "Restored remote timeline should be attached with the next generation"
+        );
+    }
+
+    #[test]
+    fn generation_layer_paths() {
+        let layer = Path::new("tenants/tenant/timelines/timeline/layer");
+        assert_eq!(Generation::NONE.layer_path(layer), layer);
+        assert_eq!(
+            Generation::FIRST.layer_path(layer),
+            Path::new("tenants/tenant/timelines/timeline/layer-00000001")
+        );
+        assert_eq!(
+            Generation(0x1f).next().layer_path(layer),
+            Path::new("tenants/tenant/timelines/timeline/layer-00000020")
+        );
+    }
+
+    #[test]
+    fn index_part_without_generations() {
+        let index_part: IndexPart = serde_json::from_str(
+            r#"{"timeline_layers":["layer_1"],"missing_layers":[],"disk_consistent_lsn":"0/16960E8","metadata_bytes":[]}"#,
+        )
+        .expect("Index part without generations should be deserializable");
+        assert_eq!(index_part.generation, Generation::NONE);
+        assert!(index_part.layer_generations.is_empty());
+        assert_eq!(index_part.version, 0);
+        assert!(index_part.history.is_empty());
+        assert!(index_part.pending_deletions.is_empty());
+    }
+
+    #[test]
+    fn versioned_index_part_paths() {
+        let index_part_path = Path::new("tenants/tenant/timelines/timeline")
+            .join(IndexPart::FILE_NAME)
+            .with_extension(IndexPart::FILE_EXTENSION);
+        assert_eq!(
+            versioned_index_part_path(&index_part_path, 42),
+            Path::new("tenants/tenant/timelines/timeline/index_part.42.json")
+        );
+    }
+
+    #[test]
+    fn removed_layers_expire_with_versions() {
+        let harness = RepoHarness::create("removed_layers_expire_with_versions").unwrap();
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let versions_to_keep = NonZeroUsize::new(2).unwrap();
+        let layer_1 = timeline_path.join("layer_1");
+        let layer_2 = timeline_path.join("layer_2");
+
+        let mut remote_timeline = RemoteTimeline::new(TimelineMetadata::new(
+            Lsn(5).align(),
+            Some(Lsn(4)),
+            None,
+            Lsn(3),
+            Lsn(2),
+            Lsn(1),
+        ));
+        remote_timeline.add_timeline_layers([layer_1.clone(), layer_2.clone()]);
+        assert_eq!(
+            remote_timeline.new_index_version(SystemTime::now(), versions_to_keep),
+            IndexVersionUpdate::default()
+        );
+
+        remote_timeline.remove_layers(&HashSet::from([layer_1.clone()]));
+        assert_eq!(
+            remote_timeline.new_index_version(SystemTime::now(), versions_to_keep),
+            IndexVersionUpdate::default(),
+            "Removed layer should be kept while the version before its removal is kept"
+        );
+        assert_eq!(
+            remote_timeline.pending_deletions().collect::<Vec<_>>(),
+            vec![layer_1.as_path()]
+        );
+
+        let version_update = remote_timeline.new_index_version(SystemTime::now(), versions_to_keep);
+        assert_eq!(version_update.expired_versions, vec![1]);
+        assert_eq!(
+            version_update.expired_layers,
+            HashMap::from([(layer_1, Generation::FIRST)]),
+            "Removed layer should expire with the last version referencing it"
+        );
+        assert_eq!(remote_timeline.pending_deletions().count(), 0);
+        assert_eq!(remote_timeline.index_version(), 3);
+        assert_eq!(
+            remote_timeline
+                .index_history()
+                .iter()
+                .map(|kept_version| kept_version.version)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+    }
+
+    #[test]
+    fn single_version_expires_removed_layers_at_once() {
+        let harness = RepoHarness::create("single_version_expires_removed_layers_at_once").unwrap();
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let versions_to_keep = NonZeroUsize::new(1).unwrap();
+        let layer = timeline_path.join("layer");
+
+        let mut remote_timeline = RemoteTimeline::new(TimelineMetadata::new(
+            Lsn(5).align(),
+            Some(Lsn(4)),
+            None,
+            Lsn(3),
+            Lsn(2),
+            Lsn(1),
+        ));
+        remote_timeline.add_timeline_layers([layer.clone()]);
+        remote_timeline.new_index_version(SystemTime::now(), versions_to_keep);
+
+        remote_timeline.remove_layers(&HashSet::from([layer.clone()]));
+        let version_update = remote_timeline.new_index_version(SystemTime::now(), versions_to_keep);
+        assert_eq!(version_update.expired_versions, vec![1]);
+        assert_eq!(
+            version_update.expired_layers,
+            HashMap::from([(layer, Generation::FIRST)])
+        );
+    }
+
+    #[test]
+    fn roll_back_to_older_version() {
+        let harness = RepoHarness::create("roll_back_to_older_version").unwrap();
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let versions_to_keep = NonZeroUsize::new(10).unwrap();
+        let layer_1 = timeline_path.join("layer_1");
+        let layer_2 = timeline_path.join("layer_2");
+        let layer_3 = timeline_path.join("layer_3");
+
+        let mut remote_timeline = RemoteTimeline::new(TimelineMetadata::new(
+            Lsn(5).align(),
+            Some(Lsn(4)),
+            None,
+            Lsn(3),
+            Lsn(2),
+            Lsn(1),
+        ));
+        remote_timeline.add_timeline_layers([layer_1.clone(), layer_2.clone()]);
+        remote_timeline.new_index_version(SystemTime::now(), versions_to_keep);
+        let older_version = remote_timeline.clone();
+
+        remote_timeline.remove_layers(&HashSet::from([layer_1.clone()]));
+        remote_timeline.add_timeline_layers([layer_3.clone()]);
+        remote_timeline.metadata =
+            TimelineMetadata::new(Lsn(10).align(), Some(Lsn(9)), None, Lsn(3), Lsn(2), Lsn(1));
+        remote_timeline.new_index_version(SystemTime::now(), versions_to_keep);
+
+        remote_timeline.roll_back(older_version);
+        assert_eq!(
+            remote_timeline.stored_files(),
+            &HashSet::from([layer_1.clone(), layer_2])
+        );
+        assert_eq!(
+            remote_timeline.metadata.disk_consistent_lsn(),
+            Lsn(5).align()
+        );
+        assert_eq!(
+            remote_timeline.pending_deletions().collect::<Vec<_>>(),
+            vec![layer_3.as_path()],
+            "Layers absent in the older version should be pending deletion, the restored ones should not"
+        );
+        assert_eq!(
+            remote_timeline.index_version(),
+            2,
+            "Rollback should not alter the version history"
+        );
     }
 
     #[test]
@@ -389,8 +879,14 @@ mod tests {
                     timeline_path.join("missing_1"),
                     timeline_path.join("missing_2"),
                 ]),
+                layer_generations: HashMap::new(),
                 metadata: metadata.clone(),
                 awaits_download: false,
+                generation: Generation::FIRST,
+                fenced_by: None,
+                index_version: 0,
+                index_history: Vec::new(),
+                pending_deletions: HashMap::new(),
             },
         );
         assert!(conversion_result.is_err(), "Should not be able to convert metadata with layer paths that are not in the timeline directory");
@@ -406,8 +902,14 @@ mod tests {
                     PathBuf::from("bad_path"),
                     timeline_path.join("missing_2"),
                 ]),
+                layer_generations: HashMap::new(),
                 metadata,
                 awaits_download: false,
+                generation: Generation::FIRST,
+                fenced_by: None,
+                index_version: 0,
+                index_history: Vec::new(),
+                pending_deletions: HashMap::new(),
             },
         );
         assert!(conversion_result.is_err(), "Should not be able to convert metadata with missing layer paths that are not in the timeline directory");
@@ -0,0 +1,474 @@
+//! Remote storage scrubber, reconciling the remote storage contents with the timelines' [`IndexPart`] files.
+//!
+//! The storage sync never lists the remote storage and relies on the index parts only (see [`crate::storage_sync`] for the reasons),
+//! so the files uploaded but never referenced (interrupted uploads, layers uploaded before the index part update failed,
+//! layers of the stale pageservers fenced off by a newer [`Generation`](super::index::Generation))
+//! stay in the storage forever, and the referenced files removed by external means get noticed on download only.
+//! The scrubber lists the storage, compares every timeline's files with its index part and reports both kinds of discrepancies.
+//!
+//! Orphaned files can be deleted, but only after they stay orphaned for a grace period: a running pageserver uploads
+//! the layers before the index part, so a freshly uploaded layer looks orphaned until its timeline upload finishes.
+//! The storage API does not expose the object modification times, so the time an orphan was first seen
+//! is kept in a local [`ScrubState`] file between the scrubber runs instead.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::Debug,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context};
+use remote_storage::{RemoteStorage, RemoteStorageError};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config::PageServerConf;
+use utils::zid::{ZTenantId, ZTenantTimelineId, ZTimelineId};
+
+use super::{
+    download::download_index_part,
+    index::{versioned_index_part_path, IndexPart, RemoteTimeline},
+    parse_sync_id,
+};
+
+/// Discrepancies between the remote storage files and the index parts, found in a certain part of the storage.
+#[derive(Debug)]
+pub struct ScrubReport {
+    /// Local counterpart of the storage directory scrubbed.
+    pub scope: PathBuf,
+    pub timelines: BTreeMap<ZTenantTimelineId, TimelineScrubReport>,
+    /// Storage files that do not belong to any timeline, reported but never deleted.
+    pub unrecognized_files: BTreeSet<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TimelineScrubReport {
+    pub index_part: IndexPartStatus,
+    /// Files present in the storage, but not referenced by the timeline index part.
+    pub orphaned_files: BTreeSet<PathBuf>,
+    /// Layers referenced by the timeline index part, but absent in the storage.
+    pub missing_layers: BTreeSet<PathBuf>,
+    /// Files of the timeline that cannot be checked, since its index part is missing or unreadable.
+    /// Reported, but never deleted: one lost index part should not wipe out the whole timeline.
+    pub unindexed_files: BTreeSet<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum IndexPartStatus {
+    /// The timeline files are compared against a valid index part.
+    Valid,
+    /// The timeline has no index part in the storage: no timeline files are considered orphaned, to avoid deleting the timeline.
+    Missing,
+    /// The index part cannot be read: no timeline files are considered orphaned, to avoid deleting the referenced ones.
+    Unreadable(String),
+}
+
+impl ScrubReport {
+    pub fn orphaned_files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.timelines
+            .values()
+            .flat_map(|timeline| timeline.orphaned_files.iter())
+    }
+
+    pub fn missing_layers(&self) -> impl Iterator<Item = &PathBuf> {
+        self.timelines
+            .values()
+            .flat_map(|timeline| timeline.missing_layers.iter())
+    }
+
+    pub fn unindexed_files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.timelines
+            .values()
+            .flat_map(|timeline| timeline.unindexed_files.iter())
+    }
+}
+
+/// Lists the remote storage files of all tenants, the given tenant or the given tenant's timeline,
+/// and compares them with the corresponding timelines' index parts.
+pub async fn scrub<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    tenant_id: Option<ZTenantId>,
+    timeline_id: Option<ZTimelineId>,
+) -> anyhow::Result<ScrubReport>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let scope = match (tenant_id, timeline_id) {
+        (None, None) => conf.tenants_path(),
+        (Some(tenant_id), None) => conf.tenant_path(&tenant_id),
+        (Some(tenant_id), Some(timeline_id)) => conf.timeline_path(&timeline_id, &tenant_id),
+        (None, Some(timeline_id)) => {
+            bail!("Cannot scrub timeline {timeline_id} without its tenant id")
+        }
+    };
+
+    let storage_files = storage
+        .list()
+        .await
+        .context("Failed to list the remote storage files")?;
+    info!(
+        "Listed {} remote storage files, scrubbing the ones under '{}'",
+        storage_files.len(),
+        scope.display()
+    );
+
+    let mut timeline_files = BTreeMap::<ZTenantTimelineId, HashSet<PathBuf>>::new();
+    let mut unrecognized_files = BTreeSet::new();
+    for storage_file in storage_files {
+        let local_path = match storage.local_path(&storage_file) {
+            Ok(local_path) => local_path,
+            Err(e) => {
+                warn!("Skipping remote storage file {storage_file:?} with no local path: {e:#}");
+                continue;
+            }
+        };
+        if !local_path.starts_with(&scope) {
+            continue;
+        }
+        match parse_sync_id(conf, &local_path) {
+            Some(sync_id) => {
+                timeline_files
+                    .entry(sync_id)
+                    .or_default()
+                    .insert(local_path);
+            }
+            None => {
+                unrecognized_files.insert(local_path);
+            }
+        }
+    }
+
+    let mut timelines = BTreeMap::new();
+    for (sync_id, files) in timeline_files {
+        let timeline_report = scrub_timeline(conf, storage, sync_id, files).await;
+        timelines.insert(sync_id, timeline_report);
+    }
+
+    Ok(ScrubReport {
+        scope,
+        timelines,
+        unrecognized_files,
+    })
+}
+
+async fn scrub_timeline<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    sync_id: ZTenantTimelineId,
+    mut files: HashSet<PathBuf>,
+) -> TimelineScrubReport
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let timeline_path = conf.timeline_path(&sync_id.timeline_id, &sync_id.tenant_id);
+    let index_part_path = timeline_path
+        .join(IndexPart::FILE_NAME)
+        .with_extension(IndexPart::FILE_EXTENSION);
+
+    if !files.remove(&index_part_path) {
+        return TimelineScrubReport {
+            index_part: IndexPartStatus::Missing,
+            orphaned_files: BTreeSet::new(),
+            missing_layers: BTreeSet::new(),
+            unindexed_files: files.into_iter().collect(),
+        };
+    }
+
+    let remote_timeline = match download_index_part(conf, storage, sync_id)
+        .await
+        .and_then(|index_part| RemoteTimeline::from_index_part(&timeline_path, index_part))
+    {
+        Ok(remote_timeline) => remote_timeline,
+        Err(e) => {
+            warn!("Failed to read the index part of timeline {sync_id}: {e:?}");
+            return TimelineScrubReport {
+                index_part: IndexPartStatus::Unreadable(format!("{e:#}")),
+                orphaned_files: BTreeSet::new(),
+                missing_layers: BTreeSet::new(),
+                unindexed_files: files.into_iter().collect(),
+            };
+        }
+    };
+
+    let referenced_layers = remote_timeline
+        .stored_files()
+        .iter()
+        .map(|layer| remote_timeline.remote_layer_path(layer))
+        .collect::<HashSet<_>>();
+    // the layers pending deletion and the index part versions kept are still needed to roll the timeline back
+    let mut referenced_files = referenced_layers.clone();
+    referenced_files.extend(remote_timeline.remote_pending_deletion_paths());
+    referenced_files.extend(
+        remote_timeline
+            .index_history()
+            .iter()
+            .map(|kept_version| versioned_index_part_path(&index_part_path, kept_version.version)),
+    );
+    TimelineScrubReport {
+        index_part: IndexPartStatus::Valid,
+        orphaned_files: files.difference(&referenced_files).cloned().collect(),
+        missing_layers: referenced_layers.difference(&files).cloned().collect(),
+        unindexed_files: BTreeSet::new(),
+    }
+}
+
+/// Orphaned files, seen by the previous scrubber runs, along with the time each of them was first seen.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ScrubState {
+    orphans_first_seen: BTreeMap<PathBuf, SystemTime>,
+}
+
+impl ScrubState {
+    /// Loads the state from the file given, or creates an empty one, if there's no such file yet.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).with_context(|| {
+                format!("Failed to deserialize scrub state at '{}'", path.display())
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e)
+                .with_context(|| format!("Failed to read scrub state at '{}'", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(self).context("Failed to serialize scrub state")?;
+        std::fs::write(path, bytes)
+            .with_context(|| format!("Failed to write scrub state at '{}'", path.display()))
+    }
+
+    /// Remembers the orphans from the report and forgets the ones that are not orphaned anymore,
+    /// returning the orphans that stay such for the grace period or longer.
+    pub fn update(
+        &mut self,
+        report: &ScrubReport,
+        now: SystemTime,
+        grace_period: Duration,
+    ) -> Vec<PathBuf> {
+        let orphans = report.orphaned_files().collect::<HashSet<_>>();
+        self.orphans_first_seen
+            .retain(|path, _| !path.starts_with(&report.scope) || orphans.contains(&path));
+
+        let mut expired_orphans = Vec::new();
+        for orphan in orphans {
+            let first_seen = *self.orphans_first_seen.entry(orphan.clone()).or_insert(now);
+            // clock going backwards is treated as the orphan being just seen
+            if now.duration_since(first_seen).unwrap_or_default() >= grace_period {
+                expired_orphans.push(orphan.clone());
+            }
+        }
+        expired_orphans.sort();
+        expired_orphans
+    }
+
+    pub fn forget(&mut self, path: &Path) {
+        self.orphans_first_seen.remove(path);
+    }
+}
+
+/// Deletes the files from the remote storage, one by one, returning the ones deleted.
+/// A file that is already absent is considered deleted, other failures are logged and skipped.
+pub async fn delete_orphans<P, S>(storage: &S, orphans: &[PathBuf]) -> Vec<PathBuf>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let mut deleted = Vec::with_capacity(orphans.len());
+    for orphan in orphans {
+        let storage_path = match storage.remote_object_id(orphan) {
+            Ok(storage_path) => storage_path,
+            Err(e) => {
+                warn!(
+                    "Failed to get the storage path for orphan '{}': {e:#}",
+                    orphan.display()
+                );
+                continue;
+            }
+        };
+        match storage.delete(&storage_path).await {
+            Ok(()) | Err(RemoteStorageError::NotFound) => {
+                info!("Deleted orphan '{}'", orphan.display());
+                deleted.push(orphan.clone());
+            }
+            Err(e) => warn!("Failed to delete orphan {storage_path:?}: {e:#}"),
+        }
+    }
+    deleted
+}
+
+#[cfg(test)]
+mod tests {
+    use remote_storage::LocalFs;
+    use tempfile::tempdir;
+    use utils::lsn::Lsn;
+
+    use crate::{
+        repository::repo_harness::{RepoHarness, TIMELINE_ID},
+        storage_sync::{
+            index::Generation,
+            test_utils::{create_local_timeline, dummy_metadata},
+            upload::upload_index_part,
+        },
+    };
+
+    use super::*;
+
+    async fn upload_file(
+        storage: &LocalFs,
+        local_path: &Path,
+        generation: Generation,
+    ) -> anyhow::Result<()> {
+        let contents = tokio::fs::read(local_path).await?;
+        let size = contents.len();
+        storage
+            .upload(
+                std::io::Cursor::new(contents),
+                size,
+                &storage.remote_object_id(&generation.layer_path(local_path))?,
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn orphaned_and_missing_files() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("orphaned_and_missing_files")?;
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+        let storage = LocalFs::new(
+            tempdir()?.path().to_path_buf(),
+            harness.conf.workdir.clone(),
+        )?;
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+
+        let metadata = dummy_metadata(Lsn(0x30));
+        create_local_timeline(
+            &harness,
+            TIMELINE_ID,
+            &["a", "b", "orphan"],
+            metadata.clone(),
+        )
+        .await?;
+        upload_file(&storage, &timeline_path.join("a"), Generation::FIRST).await?;
+        upload_file(&storage, &timeline_path.join("orphan"), Generation::NONE).await?;
+        let mut remote_timeline = RemoteTimeline::new(metadata);
+        remote_timeline.add_timeline_layers([timeline_path.join("a"), timeline_path.join("b")]);
+        upload_index_part(
+            harness.conf,
+            &storage,
+            sync_id,
+            IndexPart::from_remote_timeline(&timeline_path, remote_timeline)?,
+        )
+        .await?;
+
+        let report = scrub(harness.conf, &storage, Some(harness.tenant_id), None).await?;
+        assert!(report.unrecognized_files.is_empty());
+        assert_eq!(
+            report.timelines,
+            BTreeMap::from([(
+                sync_id,
+                TimelineScrubReport {
+                    index_part: IndexPartStatus::Valid,
+                    orphaned_files: BTreeSet::from([timeline_path.join("orphan")]),
+                    missing_layers: BTreeSet::from([
+                        Generation::FIRST.layer_path(&timeline_path.join("b"))
+                    ]),
+                    unindexed_files: BTreeSet::new(),
+                }
+            )]),
+            "Unreferenced files should be orphaned and absent referenced layers should be missing"
+        );
+
+        let other_timeline_id = ZTimelineId::generate();
+        let other_timeline_path = harness.timeline_path(&other_timeline_id);
+        create_local_timeline(
+            &harness,
+            other_timeline_id,
+            &["c"],
+            dummy_metadata(Lsn(0x30)),
+        )
+        .await?;
+        upload_file(&storage, &other_timeline_path.join("c"), Generation::NONE).await?;
+
+        let timeline_report = scrub(
+            harness.conf,
+            &storage,
+            Some(harness.tenant_id),
+            Some(TIMELINE_ID),
+        )
+        .await?;
+        assert_eq!(
+            timeline_report.timelines.keys().collect::<Vec<_>>(),
+            vec![&sync_id],
+            "Timeline scrub should not touch other timelines"
+        );
+
+        let tenant_report = scrub(harness.conf, &storage, Some(harness.tenant_id), None).await?;
+        assert_eq!(
+            tenant_report.timelines[&ZTenantTimelineId::new(harness.tenant_id, other_timeline_id)],
+            TimelineScrubReport {
+                index_part: IndexPartStatus::Missing,
+                orphaned_files: BTreeSet::new(),
+                missing_layers: BTreeSet::new(),
+                unindexed_files: BTreeSet::from([other_timeline_path.join("c")]),
+            },
+            "Files of a timeline without index part should be reported, but not orphaned"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn orphans_deleted_after_grace_period() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("orphans_deleted_after_grace_period")?;
+        let storage = LocalFs::new(
+            tempdir()?.path().to_path_buf(),
+            harness.conf.workdir.clone(),
+        )?;
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        create_local_timeline(
+            &harness,
+            TIMELINE_ID,
+            &["orphan"],
+            dummy_metadata(Lsn(0x30)),
+        )
+        .await?;
+        let orphan = timeline_path.join("orphan");
+        upload_file(&storage, &orphan, Generation::NONE).await?;
+
+        let grace_period = Duration::from_secs(3600);
+        let first_run = SystemTime::now();
+        let mut state = ScrubState::default();
+
+        let report = scrub(harness.conf, &storage, None, None).await?;
+        assert!(
+            state.update(&report, first_run, grace_period).is_empty(),
+            "Freshly found orphan should not be deleted"
+        );
+        assert!(state
+            .update(&report, first_run + grace_period / 2, grace_period)
+            .is_empty());
+
+        let state_path = harness.conf.workdir.join("scrub_state.json");
+        state.save(&state_path)?;
+        let mut state = ScrubState::load(&state_path)?;
+
+        let expired = state.update(&report, first_run + grace_period, grace_period);
+        assert_eq!(expired, vec![orphan.clone()]);
+        assert_eq!(delete_orphans(&storage, &expired).await, expired);
+        for deleted in &expired {
+            state.forget(deleted);
+        }
+        assert!(storage.list().await?.is_empty());
+
+        let report = scrub(harness.conf, &storage, None, None).await?;
+        assert!(report.timelines.is_empty());
+        assert!(state.orphans_first_seen.is_empty());
+
+        Ok(())
+    }
+}
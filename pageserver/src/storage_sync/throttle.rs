@@ -0,0 +1,207 @@
+//! Bandwidth limits for the layer transfers between the pageserver and the remote storage.
+//!
+//! Every transfer direction has a single [`BandwidthLimiter`], shared by all timelines synchronized concurrently.
+//! The limiter is consulted after every chunk of bytes read from (for uploads) or written into (for downloads) a layer file,
+//! delaying the next chunk until the bytes transferred fit the configured rate again.
+//! A short burst is allowed, so that small transfers (index parts, metadata files) go without delays.
+
+use std::{
+    future::Future,
+    io,
+    num::NonZeroU64,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// How many seconds worth of bytes can be transferred without a delay after an idle period.
+const BURST: Duration = Duration::from_secs(1);
+
+/// A token bucket, shared by the concurrent transfers in a single direction.
+#[derive(Debug)]
+pub(super) struct BandwidthLimiter {
+    bytes_per_second: NonZeroU64,
+    /// The moment when all bytes, transferred so far, fit into the configured rate.
+    paid_until: Mutex<Instant>,
+}
+
+impl BandwidthLimiter {
+    pub(super) fn new(bytes_per_second: NonZeroU64) -> Self {
+        Self {
+            bytes_per_second,
+            paid_until: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Registers the bytes transferred, returning how long to wait before transferring more.
+    fn consume(&self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let mut paid_until = self.paid_until.lock().unwrap();
+        let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_second.get() as f64);
+        *paid_until = (*paid_until).max(now) + cost;
+        paid_until.saturating_duration_since(now + BURST)
+    }
+}
+
+/// Delays the reads from the inner reader, if the limiter given runs out of bandwidth.
+/// Passes everything through as is, if there's no limiter.
+pub(super) struct ThrottledReader<R> {
+    inner: R,
+    limiter: Option<&'static BandwidthLimiter>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> ThrottledReader<R> {
+    pub(super) fn new(inner: R, limiter: Option<&'static BandwidthLimiter>) -> Self {
+        Self {
+            inner,
+            limiter,
+            delay: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(delay) = self.delay.as_mut() {
+            futures::ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+
+        let filled_before = buf.filled().len();
+        futures::ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let bytes_read = buf.filled().len() - filled_before;
+        self.delay = throttle(self.limiter, bytes_read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Delays the writes into the inner writer, if the limiter given runs out of bandwidth.
+/// Passes everything through as is, if there's no limiter.
+pub(super) struct ThrottledWriter<W> {
+    inner: W,
+    limiter: Option<&'static BandwidthLimiter>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> ThrottledWriter<W> {
+    pub(super) fn new(inner: W, limiter: Option<&'static BandwidthLimiter>) -> Self {
+        Self {
+            inner,
+            limiter,
+            delay: None,
+        }
+    }
+
+    pub(super) fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ThrottledWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Some(delay) = self.delay.as_mut() {
+            futures::ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+
+        let bytes_written = futures::ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.delay = throttle(self.limiter, bytes_written);
+        Poll::Ready(Ok(bytes_written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Registers the bytes transferred in the limiter, if any, returning the delay to wait before the next transfer.
+fn throttle(limiter: Option<&BandwidthLimiter>, bytes: usize) -> Option<Pin<Box<Sleep>>> {
+    let wait = limiter?.consume(bytes);
+    if wait.is_zero() {
+        None
+    } else {
+        Some(Box::pin(tokio::time::sleep(wait)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn limiter_allows_burst() {
+        let limiter = BandwidthLimiter::new(NonZeroU64::new(1000).unwrap());
+        assert_eq!(
+            limiter.consume(500),
+            Duration::ZERO,
+            "Transfers within the burst should not wait"
+        );
+        let wait = limiter.consume(1500);
+        assert!(
+            wait > Duration::from_millis(900) && wait <= Duration::from_secs(1),
+            "Transfers over the burst should wait for the excess bytes, but waited for {wait:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn transfers_are_throttled() -> anyhow::Result<()> {
+        let limiter: &'static BandwidthLimiter = Box::leak(Box::new(BandwidthLimiter::new(
+            NonZeroU64::new(32 * 1024).unwrap(),
+        )));
+        let contents = vec![7; 32 * 1024];
+
+        let start = Instant::now();
+        let mut reader = ThrottledReader::new(contents.as_slice(), Some(limiter));
+        let mut writer = ThrottledWriter::new(Vec::new(), Some(limiter));
+        let mut buf = [0; 4096];
+        loop {
+            let bytes_read = reader.read(&mut buf).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            writer.write_all(&buf[..bytes_read]).await?;
+        }
+        writer.flush().await?;
+
+        assert_eq!(writer.into_inner(), contents);
+        // both reader and writer share the limiter: 64 KiB transferred in total, with 32 KiB of them fitting the burst
+        // and the delay after the last chunk never awaited
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(500),
+            "Transfer should be throttled, but took {elapsed:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_limiter_passes_through() -> anyhow::Result<()> {
+        let contents = vec![7; 4096];
+        let mut reader = ThrottledReader::new(contents.as_slice(), None);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).await?;
+        assert_eq!(read, contents);
+        Ok(())
+    }
+}
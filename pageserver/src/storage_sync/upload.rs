@@ -1,19 +1,24 @@
 //! Timeline synchronization logic to compress and upload to the remote storage all new timeline files from the checkpoints.
 
-use std::{fmt::Debug, path::PathBuf};
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 use futures::stream::{FuturesUnordered, StreamExt};
 use lazy_static::lazy_static;
-use remote_storage::RemoteStorage;
+use remote_storage::{upload_with_retries, RemoteStorage};
 use tokio::fs;
 use tracing::{debug, error, info, warn};
 
 use utils::zid::ZTenantTimelineId;
 
 use super::{
-    index::{IndexPart, RemoteTimeline},
-    LayersUpload, SyncData, SyncQueue,
+    index::{versioned_index_part_path, Generation, IndexPart, RemoteTimeline},
+    is_retryable_error,
+    throttle::ThrottledReader,
+    LayersUpload, SyncData, SyncQueue, UPLOAD_LIMITER,
 };
 use crate::{
     config::PageServerConf, layered_repository::metadata::metadata_path, storage_sync::SyncTask,
@@ -30,6 +35,7 @@ lazy_static! {
 }
 
 /// Serializes and uploads the given index part data to the remote storage.
+/// A copy of the index part is uploaded under its versioned name first, so the current index part never lists the version missing.
 pub(super) async fn upload_index_part<P, S>(
     conf: &'static PageServerConf,
     storage: &S,
@@ -42,33 +48,52 @@ where
 {
     let index_part_bytes = serde_json::to_vec(&index_part)
         .context("Failed to serialize index part file into bytes")?;
-    let index_part_size = index_part_bytes.len();
-    let index_part_bytes = tokio::io::BufReader::new(std::io::Cursor::new(index_part_bytes));
 
     let index_part_path = metadata_path(conf, sync_id.timeline_id, sync_id.tenant_id)
         .with_file_name(IndexPart::FILE_NAME)
         .with_extension(IndexPart::FILE_EXTENSION);
-    let index_part_storage_path =
-        storage
-            .remote_object_id(&index_part_path)
-            .with_context(|| {
-                format!(
-                    "Failed to get the index part storage path for local path '{}'",
-                    index_part_path.display()
-                )
-            })?;
+    if index_part.version() > 0 {
+        let versioned_path = versioned_index_part_path(&index_part_path, index_part.version());
+        upload_index_part_bytes(storage, &versioned_path, index_part_bytes.clone())
+            .await
+            .context("Failed to upload versioned index part")?;
+    }
+    upload_index_part_bytes(storage, &index_part_path, index_part_bytes).await
+}
 
-    storage
-        .upload(
-            index_part_bytes,
-            index_part_size,
-            &index_part_storage_path,
-            None,
+async fn upload_index_part_bytes<P, S>(
+    storage: &S,
+    index_part_path: &Path,
+    index_part_bytes: Vec<u8>,
+) -> anyhow::Result<()>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let index_part_size = index_part_bytes.len();
+
+    let index_part_storage_path = storage.remote_object_id(index_part_path).with_context(|| {
+        format!(
+            "Failed to get the index part storage path for local path '{}'",
+            index_part_path.display()
         )
-        .await
-        .with_context(|| {
-            format!("Failed to upload index part to the storage path '{index_part_storage_path:?}'")
-        })
+    })?;
+
+    upload_with_retries(
+        storage,
+        || async {
+            Ok(tokio::io::BufReader::new(std::io::Cursor::new(
+                index_part_bytes.clone(),
+            )))
+        },
+        index_part_size,
+        &index_part_storage_path,
+        None,
+    )
+    .await
+    .with_context(|| {
+        format!("Failed to upload index part to the storage path '{index_part_storage_path:?}'")
+    })
 }
 
 /// Timeline upload result, with extra data, needed for uploading.
@@ -82,8 +107,9 @@ pub(super) enum UploadedTimeline {
 
 /// Attempts to upload given layer files.
 /// No extra checks for overlapping files is made and any files that are already present remotely will be overwritten, if submitted during the upload.
+/// The layers are uploaded in the remote timeline's generation, or in the [`Generation::FIRST`] for the timelines never uploaded before.
 ///
-/// On an error, bumps the retries count and reschedules the entire task.
+/// On an error, bumps the retries count, unless only the retryable storage errors happened, and reschedules the entire task.
 pub(super) async fn upload_timeline_layers<'a, P, S>(
     storage: &'a S,
     sync_queue: &SyncQueue,
@@ -101,6 +127,9 @@ where
         .as_ref()
         .map(|meta| meta.disk_consistent_lsn());
 
+    let generation = remote_timeline
+        .map(|timeline| timeline.generation)
+        .unwrap_or(Generation::FIRST);
     let already_uploaded_layers = remote_timeline
         .map(|timeline| timeline.stored_files())
         .cloned()
@@ -133,7 +162,7 @@ where
         .into_iter()
         .map(|source_path| async move {
             let storage_path = storage
-                .remote_object_id(&source_path)
+                .remote_object_id(&generation.layer_path(&source_path))
                 .with_context(|| {
                     format!(
                         "Failed to get the layer storage path for local path '{}'",
@@ -142,30 +171,27 @@ where
                 })
                 .map_err(UploadError::Other)?;
 
-            let source_file = match fs::File::open(&source_path).await.with_context(|| {
+            let source_size = match fs::metadata(&source_path).await.with_context(|| {
                 format!(
-                    "Failed to upen a source file for layer '{}'",
+                    "Failed to get the source file metadata for layer '{}'",
                     source_path.display()
                 )
             }) {
-                Ok(file) => file,
+                Ok(metadata) => metadata.len() as usize,
                 Err(e) => return Err(UploadError::MissingLocalFile(source_path, e)),
             };
 
-            let source_size = source_file
-                .metadata()
-                .await
-                .with_context(|| {
+            // The source file is opened anew for every upload attempt
+            let open_source_file = || async {
+                let source_file = fs::File::open(&source_path).await.with_context(|| {
                     format!(
-                        "Failed to get the source file metadata for layer '{}'",
+                        "Failed to open a source file for layer '{}'",
                         source_path.display()
                     )
-                })
-                .map_err(UploadError::Other)?
-                .len() as usize;
-
-            match storage
-                .upload(source_file, source_size, &storage_path, None)
+                })?;
+                Ok::<_, anyhow::Error>(ThrottledReader::new(source_file, UPLOAD_LIMITER.get()))
+            };
+            match upload_with_retries(storage, open_source_file, source_size, &storage_path, None)
                 .await
                 .with_context(|| {
                     format!(
@@ -180,6 +206,7 @@ where
         .collect::<FuturesUnordered<_>>();
 
     let mut errors_happened = false;
+    let mut retryable_errors_only = true;
     while let Some(upload_result) = upload_tasks.next().await {
         match upload_result {
             Ok(uploaded_path) => {
@@ -189,11 +216,13 @@ where
             Err(e) => match e {
                 UploadError::Other(e) => {
                     errors_happened = true;
+                    retryable_errors_only = false;
                     error!("Failed to upload a layer for timeline {sync_id}: {e:?}");
                 }
                 UploadError::MissingLocalFile(source_path, e) => {
                     if source_path.exists() {
                         errors_happened = true;
+                        retryable_errors_only &= is_retryable_error(&e);
                         error!("Failed to upload a layer for timeline {sync_id}: {e:?}");
                     } else {
                         // We have run the upload sync task, but the file we wanted to upload is gone.
@@ -219,7 +248,8 @@ where
 
     if errors_happened {
         debug!("Reenqueuing failed upload task for timeline {sync_id}");
-        upload_data.retries += 1;
+        // Unless the storage is encrypted, S3 resumes the partially uploaded layers on retry, sending only the parts it does not have yet.
+        upload_data.register_failure(retryable_errors_only);
         sync_queue.push(sync_id, SyncTask::Upload(upload_data));
         UploadedTimeline::FailedAndRescheduled
     } else {
@@ -332,9 +362,9 @@ mod tests {
                 .collect::<anyhow::Result<BTreeSet<_>>>()?,
             layer_files
                 .into_iter()
-                .map(|file| local_timeline_path.join(file))
+                .map(|file| Generation::FIRST.layer_path(&local_timeline_path.join(file)))
                 .collect(),
-            "Uploaded files should match with the local ones"
+            "Uploaded files should match with the local ones, suffixed with the first generation"
         );
 
         Ok(())
@@ -424,9 +454,9 @@ mod tests {
                 .collect::<anyhow::Result<BTreeSet<_>>>()?,
             layer_files
                 .into_iter()
-                .map(|file| local_timeline_path.join(file))
+                .map(|file| Generation::FIRST.layer_path(&local_timeline_path.join(file)))
                 .collect(),
-            "Uploaded files should match with the local ones"
+            "Uploaded files should match with the local ones, suffixed with the first generation"
         );
 
         Ok(())
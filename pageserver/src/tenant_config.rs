@@ -38,6 +38,7 @@ pub mod defaults {
     pub const DEFAULT_WALRECEIVER_CONNECT_TIMEOUT: &str = "2 seconds";
     pub const DEFAULT_WALRECEIVER_LAGGING_WAL_TIMEOUT: &str = "10 seconds";
     pub const DEFAULT_MAX_WALRECEIVER_LSN_WAL_LAG: u64 = 10_000;
+    pub const DEFAULT_WALRECEIVER_STANDBY_CONNECTION: bool = false;
 }
 
 /// Per-tenant configuration options
@@ -83,6 +84,9 @@ pub struct TenantConf {
     /// A lagging safekeeper will be changed after `lagging_wal_timeout` time elapses since the last WAL update,
     /// to avoid eager reconnects.
     pub max_lsn_wal_lag: NonZeroU64,
+    /// Keep a standby connection to a second safekeeper, that only streams its commit position,
+    /// so that the WAL source can be switched to it without waiting for broker updates.
+    pub walreceiver_standby_connection: bool,
 }
 
 /// Same as TenantConf, but this struct preserves the information about
@@ -105,6 +109,7 @@ pub struct TenantConfOpt {
     #[serde(with = "humantime_serde")]
     pub lagging_wal_timeout: Option<Duration>,
     pub max_lsn_wal_lag: Option<NonZeroU64>,
+    pub walreceiver_standby_connection: Option<bool>,
 }
 
 impl TenantConfOpt {
@@ -135,6 +140,9 @@ impl TenantConfOpt {
                 .lagging_wal_timeout
                 .unwrap_or(global_conf.lagging_wal_timeout),
             max_lsn_wal_lag: self.max_lsn_wal_lag.unwrap_or(global_conf.max_lsn_wal_lag),
+            walreceiver_standby_connection: self
+                .walreceiver_standby_connection
+                .unwrap_or(global_conf.walreceiver_standby_connection),
         }
     }
 
@@ -172,6 +180,9 @@ impl TenantConfOpt {
         if let Some(max_lsn_wal_lag) = other.max_lsn_wal_lag {
             self.max_lsn_wal_lag = Some(max_lsn_wal_lag);
         }
+        if let Some(walreceiver_standby_connection) = other.walreceiver_standby_connection {
+            self.walreceiver_standby_connection = Some(walreceiver_standby_connection);
+        }
     }
 }
 
@@ -199,6 +210,7 @@ impl TenantConf {
                 .expect("cannot parse default walreceiver lagging wal timeout"),
             max_lsn_wal_lag: NonZeroU64::new(DEFAULT_MAX_WALRECEIVER_LSN_WAL_LAG)
                 .expect("cannot parse default max walreceiver Lsn wal lag"),
+            walreceiver_standby_connection: DEFAULT_WALRECEIVER_STANDBY_CONNECTION,
         }
     }
 
@@ -229,6 +241,7 @@ impl TenantConf {
             .unwrap(),
             max_lsn_wal_lag: NonZeroU64::new(defaults::DEFAULT_MAX_WALRECEIVER_LSN_WAL_LAG)
                 .unwrap(),
+            walreceiver_standby_connection: defaults::DEFAULT_WALRECEIVER_STANDBY_CONNECTION,
         }
     }
 }
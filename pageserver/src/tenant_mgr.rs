@@ -4,7 +4,7 @@
 use crate::config::PageServerConf;
 use crate::layered_repository::{load_metadata, LayeredRepository};
 use crate::pgdatadir_mapping::DatadirTimeline;
-use crate::repository::{Repository, TimelineSyncStatusUpdate};
+use crate::repository::{GcResult, Repository, Timeline, TimelineSyncStatusUpdate};
 use crate::storage_sync::index::RemoteIndex;
 use crate::storage_sync::{self, LocalTimelineInitStatus, SyncStartupData};
 use crate::tenant_config::TenantConfOpt;
@@ -12,7 +12,7 @@ use crate::thread_mgr::ThreadKind;
 use crate::timelines::CreateRepo;
 use crate::walredo::PostgresRedoManager;
 use crate::{thread_mgr, timelines, walreceiver};
-use crate::{DatadirTimelineImpl, RepositoryImpl};
+use crate::{CheckpointConfig, DatadirTimelineImpl, RepositoryImpl};
 use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
@@ -419,6 +419,41 @@ pub fn get_local_timeline_with_load(
     }
 }
 
+/// Runs GC immediately on the given timeline, using the tenant's gc_horizon
+/// unless a different one is given, and the tenant's pitr setting.
+pub fn immediate_gc(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    gc_horizon: Option<u64>,
+) -> anyhow::Result<GcResult> {
+    let repo = get_repository_for_tenant(tenant_id)?;
+    let gc_horizon = gc_horizon.unwrap_or_else(|| repo.get_gc_horizon());
+    let pitr = repo.get_pitr_interval();
+    repo.gc_iteration(Some(timeline_id), gc_horizon, pitr, true)
+}
+
+/// Runs compaction immediately on the given timeline.
+pub fn immediate_compact(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> anyhow::Result<()> {
+    let timeline =
+        get_local_timeline_with_load(tenant_id, timeline_id).context("Couldn't load timeline")?;
+    timeline.tline.compact()
+}
+
+/// Flushes all in-memory data of the given timeline to disk, reconstructing
+/// page images, and compacts it afterwards.
+pub fn immediate_checkpoint(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> anyhow::Result<()> {
+    let timeline = get_local_timeline_with_load(tenant_id, timeline_id)
+        .context("Cannot load local timeline")?;
+
+    timeline.tline.checkpoint(CheckpointConfig::Forced)?;
+
+    // Also compact it.
+    //
+    // FIXME: This probably shouldn't be part of a "checkpoint" command, but a
+    // separate operation. Update the tests if you change this.
+    timeline.tline.compact()
+}
+
 pub fn detach_timeline(
     conf: &'static PageServerConf,
     tenant_id: ZTenantId,
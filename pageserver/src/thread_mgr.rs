@@ -91,6 +91,10 @@ pub enum ThreadKind {
     // associated with one later, after receiving a command from the client.
     PageRequestHandler,
 
+    // Thread that reconstructs the pages announced by Prefetch messages of
+    // a page service connection, warming the page cache.
+    Prefetcher,
+
     // Main walreceiver manager thread that ensures that every timeline spawns a connection to safekeeper, to fetch WAL.
     WalReceiverManager,
 
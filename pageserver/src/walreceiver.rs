@@ -284,14 +284,18 @@ async fn wal_receiver_main_thread_loop_step<'a>(
                             hash_map::Entry::Vacant(v) => v,
                         };
 
-                    let (wal_connect_timeout, lagging_wal_timeout, max_lsn_wal_lag) =
-                        match fetch_tenant_settings(new_id.tenant_id).await {
-                            Ok(settings) => settings,
-                            Err(e) => {
-                                error!("Failed to fetch tenant settings for id {new_id}: {e:#}");
-                                return;
-                            }
-                        };
+                    let (
+                        wal_connect_timeout,
+                        lagging_wal_timeout,
+                        max_lsn_wal_lag,
+                        standby_connection_enabled,
+                    ) = match fetch_tenant_settings(new_id.tenant_id).await {
+                        Ok(settings) => settings,
+                        Err(e) => {
+                            error!("Failed to fetch tenant settings for id {new_id}: {e:#}");
+                            return;
+                        }
+                    };
 
                     {
                         WAL_RECEIVER_ENTRIES.write().await.insert(
@@ -313,6 +317,7 @@ async fn wal_receiver_main_thread_loop_step<'a>(
                             wal_connect_timeout,
                             lagging_wal_timeout,
                             max_lsn_wal_lag,
+                            standby_connection_enabled,
                         ),
                     );
                 }
@@ -327,7 +332,7 @@ async fn wal_receiver_main_thread_loop_step<'a>(
 
 async fn fetch_tenant_settings(
     tenant_id: ZTenantId,
-) -> anyhow::Result<(Duration, Duration, NonZeroU64)> {
+) -> anyhow::Result<(Duration, Duration, NonZeroU64, bool)> {
     tokio::task::spawn_blocking(move || {
         let repo = tenant_mgr::get_repository_for_tenant(tenant_id)
             .with_context(|| format!("no repository found for tenant {tenant_id}"))?;
@@ -335,6 +340,7 @@ async fn fetch_tenant_settings(
             repo.get_wal_receiver_connect_timeout(),
             repo.get_lagging_wal_timeout(),
             repo.get_max_lsn_wal_lag(),
+            repo.get_walreceiver_standby_connection(),
         ))
     })
     .await
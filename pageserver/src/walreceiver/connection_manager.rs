@@ -13,7 +13,7 @@ use std::{
     collections::{hash_map, HashMap},
     num::NonZeroU64,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
@@ -22,6 +22,8 @@ use etcd_broker::{
     subscription_key::SubscriptionKey, subscription_value::SkTimelineInfo, BrokerSubscription,
     BrokerUpdate, Client,
 };
+use lazy_static::lazy_static;
+use metrics::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
 use tokio::select;
 use tracing::*;
 
@@ -34,6 +36,21 @@ use utils::{
 
 use super::{TaskEvent, TaskHandle};
 
+lazy_static! {
+    static ref WAL_CONNECTION_SWITCHES: IntCounterVec = register_int_counter_vec!(
+        "pageserver_walreceiver_connection_switches_total",
+        "Number of WAL streaming connection switches, by the reason of the switch",
+        &["reason", "from_standby"]
+    )
+    .expect("failed to define a metric");
+    static ref NO_WAL_SOURCE_TIME: HistogramVec = register_histogram_vec!(
+        "pageserver_walreceiver_no_wal_source_seconds",
+        "Time spent without an active WAL streaming connection before a new one was started",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+}
+
 /// Spawns the loop to take care of the timeline's WAL streaming connection.
 pub(super) fn spawn_connection_manager_task(
     id: ZTenantTimelineId,
@@ -43,6 +60,7 @@ pub(super) fn spawn_connection_manager_task(
     wal_connect_timeout: Duration,
     lagging_wal_timeout: Duration,
     max_lsn_wal_lag: NonZeroU64,
+    standby_connection_enabled: bool,
 ) -> TaskHandle<()> {
     TaskHandle::spawn(move |_, mut cancellation| {
         async move {
@@ -53,6 +71,7 @@ pub(super) fn spawn_connection_manager_task(
                 wal_connect_timeout,
                 lagging_wal_timeout,
                 max_lsn_wal_lag,
+                standby_connection_enabled,
             );
             loop {
                 select! {
@@ -62,6 +81,10 @@ pub(super) fn spawn_connection_manager_task(
                         {
                             wal_connection.connection_task.shutdown().await;
                         }
+                        if let Some(standby_connection) = walreceiver_state.standby_connection.take()
+                        {
+                            standby_connection.connection_task.shutdown().await;
+                        }
                         return Ok(());
                     },
 
@@ -129,6 +152,7 @@ async fn connection_manager_loop_step(
                             },
                         };
                         walreceiver_state.wal_connection = None;
+                        walreceiver_state.no_wal_source_since = Some(Instant::now());
                         (None, should_reset_connection_attempts)
                     },
                 };
@@ -150,6 +174,36 @@ async fn connection_manager_loop_step(
                 }
             },
 
+            Some(standby_connection_update) = async {
+                match walreceiver_state.standby_connection.as_mut() {
+                    Some(standby_connection) => {
+                        let receiver = &mut standby_connection.connection_task.events_receiver;
+                        Some(match receiver.changed().await {
+                            Ok(()) => receiver.borrow().clone(),
+                            Err(_cancellation_error) => TaskEvent::End(Ok(())),
+                        })
+                    }
+                    None => None,
+                }
+            } => {
+                match standby_connection_update {
+                    TaskEvent::Started => {},
+                    TaskEvent::NewEvent(wal_end) => {
+                        if let Some(standby_connection) = walreceiver_state.standby_connection.as_mut() {
+                            standby_connection.latest_wal_end = Some(wal_end);
+                            standby_connection.latest_connection_update = Utc::now().naive_utc();
+                        }
+                    },
+                    TaskEvent::End(end_result) => {
+                        match end_result {
+                            Ok(()) => debug!("Standby connection task finished"),
+                            Err(e) => warn!("Standby connection task failed: {e}"),
+                        }
+                        walreceiver_state.standby_connection = None;
+                    },
+                }
+            },
+
             broker_update = broker_subscription.value_updates.recv() => {
                 match broker_update {
                     Some(broker_update) => walreceiver_state.register_timeline_update(broker_update),
@@ -188,9 +242,12 @@ async fn connection_manager_loop_step(
                 .change_connection(
                     new_candidate.safekeeper_id,
                     new_candidate.wal_producer_connstr,
+                    new_candidate.reason,
                 )
                 .await
         }
+
+        walreceiver_state.maintain_standby_connection().await;
     }
 }
 
@@ -274,6 +331,12 @@ struct WalreceiverState {
     max_lsn_wal_lag: NonZeroU64,
     /// Current connection to safekeeper for WAL streaming.
     wal_connection: Option<WalConnection>,
+    /// Time since which there's no WAL streaming connection, if any.
+    no_wal_source_since: Option<Instant>,
+    /// Whether to keep a standby connection to another safekeeper, to switch to it without waiting for broker updates.
+    standby_connection_enabled: bool,
+    /// Current standby connection, if enabled.
+    standby_connection: Option<StandbyConnection>,
     wal_connection_attempts: HashMap<NodeId, u32>,
     /// Data about all timelines, available for connection, fetched from etcd, grouped by their corresponding safekeeper node id.
     wal_stream_candidates: HashMap<NodeId, EtcdSkTimeline>,
@@ -290,6 +353,22 @@ struct WalConnection {
     connection_task: TaskHandle<ReplicationFeedback>,
 }
 
+/// A connection to a safekeeper that streams its latest WAL positions only, to know whether it's safe to switch to the safekeeper
+/// without waiting for the broker updates and connection backoffs.
+#[derive(Debug)]
+struct StandbyConnection {
+    /// Safekeeper the standby connection is opened to.
+    sk_id: NodeId,
+    /// Connection string used to open the standby connection.
+    wal_producer_connstr: String,
+    /// Latest WAL position streamed by the safekeeper over the standby connection.
+    latest_wal_end: Option<Lsn>,
+    /// Connection task start time or the timestamp of a latest WAL position received.
+    latest_connection_update: NaiveDateTime,
+    /// Standby connection task handle.
+    connection_task: TaskHandle<Lsn>,
+}
+
 /// Data about the timeline to connect to, received from etcd.
 #[derive(Debug)]
 struct EtcdSkTimeline {
@@ -307,6 +386,7 @@ impl WalreceiverState {
         wal_connect_timeout: Duration,
         lagging_wal_timeout: Duration,
         max_lsn_wal_lag: NonZeroU64,
+        standby_connection_enabled: bool,
     ) -> Self {
         Self {
             id,
@@ -315,24 +395,52 @@ impl WalreceiverState {
             lagging_wal_timeout,
             max_lsn_wal_lag,
             wal_connection: None,
+            no_wal_source_since: Some(Instant::now()),
+            standby_connection_enabled,
+            standby_connection: None,
             wal_stream_candidates: HashMap::new(),
             wal_connection_attempts: HashMap::new(),
         }
     }
 
     /// Shuts down the current connection (if any) and immediately starts another one with the given connection string.
-    async fn change_connection(&mut self, new_sk_id: NodeId, new_wal_producer_connstr: String) {
+    /// If there's a standby connection to the same safekeeper, it is stopped and the new connection is opened without a backoff:
+    /// the standby connection has just proven the safekeeper to be alive.
+    async fn change_connection(
+        &mut self,
+        new_sk_id: NodeId,
+        new_wal_producer_connstr: String,
+        reason: ReconnectReason,
+    ) {
         if let Some(old_connection) = self.wal_connection.take() {
-            old_connection.connection_task.shutdown().await
+            old_connection.connection_task.shutdown().await;
+            self.no_wal_source_since = Some(Instant::now());
+        }
+
+        let from_standby = self.stop_standby_connection(new_sk_id).await;
+        WAL_CONNECTION_SWITCHES
+            .with_label_values(&[reason.name(), if from_standby { "true" } else { "false" }])
+            .inc();
+        if let Some(no_wal_source_since) = self.no_wal_source_since.take() {
+            NO_WAL_SOURCE_TIME
+                .with_label_values(&[
+                    &self.id.tenant_id.to_string(),
+                    &self.id.timeline_id.to_string(),
+                ])
+                .observe(no_wal_source_since.elapsed().as_secs_f64());
         }
 
         let id = self.id;
         let connect_timeout = self.wal_connect_timeout;
-        let connection_attempt = self
-            .wal_connection_attempts
-            .get(&new_sk_id)
-            .copied()
-            .unwrap_or(0);
+        // The standby connection is known to be alive, no need to back off.
+        let connection_attempt = if from_standby {
+            0
+        } else {
+            self.wal_connection_attempts
+                .get(&new_sk_id)
+                .copied()
+                .unwrap_or(0)
+        };
         let connection_handle = TaskHandle::spawn(move |events_sender, cancellation| {
             async move {
                 exponential_backoff(
@@ -361,6 +469,99 @@ impl WalreceiverState {
         });
     }
 
+    /// Stops the standby connection, if it's opened to the given safekeeper, returning whether it was.
+    /// Any other standby connection is left as is.
+    async fn stop_standby_connection(&mut self, sk_id: NodeId) -> bool {
+        match self.standby_connection.take() {
+            Some(standby_connection) if standby_connection.sk_id == sk_id => {
+                info!("Switching WAL streaming to the standby safekeeper {sk_id}");
+                standby_connection.connection_task.shutdown().await;
+                true
+            }
+            other_connection => {
+                self.standby_connection = other_connection;
+                false
+            }
+        }
+    }
+
+    /// Ensures there's a standby connection to the best safekeeper other than the one WAL is streamed from, if standby connections are enabled.
+    /// An existing standby connection is kept while its safekeeper stays applicable for connection.
+    async fn maintain_standby_connection(&mut self) {
+        if !self.standby_connection_enabled {
+            return;
+        }
+        let streaming_sk_id = match &self.wal_connection {
+            Some(wal_connection) => wal_connection.sk_id,
+            // Nothing to stand by for, the next connection candidate check will choose a streaming connection.
+            None => return,
+        };
+
+        let new_standby = self.next_standby_candidate(streaming_sk_id);
+        if let Some(standby_connection) = &self.standby_connection {
+            let standby_is_stale = (Utc::now().naive_utc()
+                - standby_connection.latest_connection_update)
+                .to_std()
+                .map(|since_latest_update| since_latest_update > self.lagging_wal_timeout)
+                .unwrap_or(false);
+            let keep_standby = standby_connection.sk_id != streaming_sk_id
+                && !standby_is_stale
+                && self
+                    .applicable_connection_candidates()
+                    .any(|(sk_id, _, _)| sk_id == standby_connection.sk_id);
+            if keep_standby {
+                return;
+            }
+        }
+
+        if let Some(old_standby) = self.standby_connection.take() {
+            debug!(
+                "Stopping standby connection to safekeeper {}",
+                old_standby.sk_id
+            );
+            old_standby.connection_task.shutdown().await;
+        }
+
+        let (new_sk_id, new_wal_producer_connstr) = match new_standby {
+            Some(new_standby) => new_standby,
+            None => return,
+        };
+        info!("Opening standby connection to safekeeper {new_sk_id}");
+
+        let connect_timeout = self.wal_connect_timeout;
+        let task_connstr = new_wal_producer_connstr.clone();
+        let id = self.id;
+        let connection_task = TaskHandle::spawn(move |events_sender, cancellation| {
+            async move {
+                super::walreceiver_connection::handle_standby_connection(
+                    &task_connstr,
+                    events_sender.as_ref(),
+                    cancellation,
+                    connect_timeout,
+                )
+                .await
+                .map_err(|e| format!("walreceiver standby connection handling failure: {e:#}"))
+            }
+            .instrument(info_span!("walreceiver_standby_connection", id = %id))
+        });
+
+        self.standby_connection = Some(StandbyConnection {
+            sk_id: new_sk_id,
+            wal_producer_connstr: new_wal_producer_connstr,
+            latest_wal_end: None,
+            latest_connection_update: Utc::now().naive_utc(),
+            connection_task,
+        });
+    }
+
+    /// Picks a safekeeper with the biggest `commit_lsn` among the applicable ones, except the one WAL is currently streamed from.
+    fn next_standby_candidate(&self, streaming_sk_id: NodeId) -> Option<(NodeId, String)> {
+        self.applicable_connection_candidates()
+            .filter(|&(sk_id, _, _)| sk_id != streaming_sk_id)
+            .max_by_key(|(_, info, _)| info.commit_lsn)
+            .map(|(sk_id, _, connstr)| (sk_id, connstr))
+    }
+
     /// Adds another etcd timeline into the state, if its more recent than the one already added there for the same key.
     fn register_timeline_update(&mut self, timeline_update: BrokerUpdate<SkTimelineInfo>) {
         match self
@@ -462,9 +663,30 @@ impl WalreceiverState {
                 }
             }
             None => {
-                let (new_sk_id, _, new_wal_producer_connstr) = self
+                let (mut new_sk_id, new_safekeeper_etcd_data, mut new_wal_producer_connstr) = self
                     .applicable_connection_candidates()
                     .max_by_key(|(_, info, _)| info.commit_lsn)?;
+
+                // Prefer the standby safekeeper, if it's not lagging behind the best one too much: its liveness and position are known first-hand.
+                if let Some(standby_connection) = &self.standby_connection {
+                    let standby_commit_lsn = self
+                        .wal_stream_candidates
+                        .get(&standby_connection.sk_id)
+                        .and_then(|etcd_info| etcd_info.timeline.commit_lsn)
+                        .or(standby_connection.latest_wal_end);
+                    let best_commit_lsn = new_safekeeper_etcd_data.commit_lsn.unwrap_or(Lsn(0));
+                    if let Some(standby_commit_lsn) = standby_commit_lsn {
+                        let standby_lag = best_commit_lsn.0.saturating_sub(standby_commit_lsn.0);
+                        if standby_commit_lsn > self.local_timeline.get_last_record_lsn()
+                            && standby_lag < self.max_lsn_wal_lag.get()
+                        {
+                            new_sk_id = standby_connection.sk_id;
+                            new_wal_producer_connstr =
+                                standby_connection.wal_producer_connstr.clone();
+                        }
+                    }
+                }
+
                 return Some(NewWalConnectionCandidate {
                     safekeeper_id: new_sk_id,
                     wal_producer_connstr: new_wal_producer_connstr,
@@ -536,6 +758,20 @@ enum ReconnectReason {
     },
 }
 
+impl ReconnectReason {
+    /// Short name of the reason, used as a metric label.
+    fn name(&self) -> &'static str {
+        match self {
+            ReconnectReason::NoExistingConnection => "no_existing_connection",
+            ReconnectReason::NoEtcdDataForExistingConnection => {
+                "no_etcd_data_for_existing_connection"
+            }
+            ReconnectReason::LaggingWal { .. } => "lagging_wal",
+            ReconnectReason::NoWalTimeout { .. } => "no_wal_timeout",
+        }
+    }
+}
+
 fn wal_stream_connection_string(
     ZTenantTimelineId {
         tenant_id,
@@ -581,6 +817,7 @@ mod tests {
                 NodeId(0),
                 EtcdSkTimeline {
                     timeline: SkTimelineInfo {
+                        term: None,
                         last_log_term: None,
                         flush_lsn: None,
                         commit_lsn: Some(Lsn(1)),
@@ -597,6 +834,7 @@ mod tests {
                 NodeId(1),
                 EtcdSkTimeline {
                     timeline: SkTimelineInfo {
+                        term: None,
                         last_log_term: None,
                         flush_lsn: None,
                         commit_lsn: None,
@@ -613,6 +851,7 @@ mod tests {
                 NodeId(2),
                 EtcdSkTimeline {
                     timeline: SkTimelineInfo {
+                        term: None,
                         last_log_term: None,
                         flush_lsn: None,
                         commit_lsn: None,
@@ -629,6 +868,7 @@ mod tests {
                 NodeId(3),
                 EtcdSkTimeline {
                     timeline: SkTimelineInfo {
+                        term: None,
                         last_log_term: None,
                         flush_lsn: None,
                         commit_lsn: Some(Lsn(1 + state.max_lsn_wal_lag.get())),
@@ -683,6 +923,7 @@ mod tests {
                 connected_sk_id,
                 EtcdSkTimeline {
                     timeline: SkTimelineInfo {
+                        term: None,
                         last_log_term: None,
                         flush_lsn: None,
                         commit_lsn: Some(Lsn(current_lsn + state.max_lsn_wal_lag.get() * 2)),
@@ -699,6 +940,7 @@ mod tests {
                 NodeId(1),
                 EtcdSkTimeline {
                     timeline: SkTimelineInfo {
+                        term: None,
                         last_log_term: None,
                         flush_lsn: None,
                         commit_lsn: Some(Lsn(current_lsn)),
@@ -715,6 +957,7 @@ mod tests {
                 NodeId(2),
                 EtcdSkTimeline {
                     timeline: SkTimelineInfo {
+                        term: None,
                         last_log_term: None,
                         flush_lsn: None,
                         commit_lsn: Some(Lsn(current_lsn + state.max_lsn_wal_lag.get() / 2)),
@@ -749,6 +992,7 @@ mod tests {
             NodeId(0),
             EtcdSkTimeline {
                 timeline: SkTimelineInfo {
+                    term: None,
                     last_log_term: None,
                     flush_lsn: None,
                     commit_lsn: Some(Lsn(1 + state.max_lsn_wal_lag.get())),
@@ -781,6 +1025,7 @@ mod tests {
                 NodeId(0),
                 EtcdSkTimeline {
                     timeline: SkTimelineInfo {
+                        term: None,
                         last_log_term: None,
                         flush_lsn: None,
                         commit_lsn: Some(Lsn(selected_lsn - 100)),
@@ -797,6 +1042,7 @@ mod tests {
                 NodeId(1),
                 EtcdSkTimeline {
                     timeline: SkTimelineInfo {
+                        term: None,
                         last_log_term: None,
                         flush_lsn: None,
                         commit_lsn: Some(Lsn(selected_lsn)),
@@ -813,6 +1059,7 @@ mod tests {
                 NodeId(2),
                 EtcdSkTimeline {
                     timeline: SkTimelineInfo {
+                        term: None,
                         last_log_term: None,
                         flush_lsn: None,
                         commit_lsn: Some(Lsn(selected_lsn + 100)),
@@ -843,6 +1090,241 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn no_connection_standby_candidate() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("no_connection_standby_candidate")?;
+        let mut state = dummy_state(&harness);
+        let now = Utc::now().naive_utc();
+
+        let standby_sk_id = NodeId(0);
+        let best_lsn = 100_000;
+        state.max_lsn_wal_lag = NonZeroU64::new(100).unwrap();
+        state.wal_connection = None;
+        state.standby_connection = Some(StandbyConnection {
+            sk_id: standby_sk_id,
+            wal_producer_connstr: "standby connstr".to_string(),
+            latest_wal_end: None,
+            latest_connection_update: now,
+            connection_task: TaskHandle::spawn(move |_, _| async move { Ok(()) }),
+        });
+        state.wal_stream_candidates = HashMap::from([
+            (
+                standby_sk_id,
+                EtcdSkTimeline {
+                    timeline: SkTimelineInfo {
+                        term: None,
+                        last_log_term: None,
+                        flush_lsn: None,
+                        commit_lsn: Some(Lsn(best_lsn - state.max_lsn_wal_lag.get() / 2)),
+                        backup_lsn: None,
+                        remote_consistent_lsn: None,
+                        peer_horizon_lsn: None,
+                        safekeeper_connstr: Some(DUMMY_SAFEKEEPER_CONNSTR.to_string()),
+                    },
+                    etcd_version: 0,
+                    latest_update: now,
+                },
+            ),
+            (
+                NodeId(1),
+                EtcdSkTimeline {
+                    timeline: SkTimelineInfo {
+                        term: None,
+                        last_log_term: None,
+                        flush_lsn: None,
+                        commit_lsn: Some(Lsn(best_lsn)),
+                        backup_lsn: None,
+                        remote_consistent_lsn: None,
+                        peer_horizon_lsn: None,
+                        safekeeper_connstr: Some("biggest commit_lsn".to_string()),
+                    },
+                    etcd_version: 0,
+                    latest_update: now,
+                },
+            ),
+        ]);
+
+        let standby_candidate = state
+            .next_connection_candidate()
+            .expect("Expected the standby candidate selected, but got none");
+        assert_eq!(
+            standby_candidate.safekeeper_id, standby_sk_id,
+            "Should prefer the standby safekeeper if its WAL is not lagging behind too much"
+        );
+        assert_eq!(
+            standby_candidate.reason,
+            ReconnectReason::NoExistingConnection
+        );
+        assert_eq!(standby_candidate.wal_producer_connstr, "standby connstr");
+
+        state
+            .wal_stream_candidates
+            .get_mut(&standby_sk_id)
+            .unwrap()
+            .timeline
+            .commit_lsn = Some(Lsn(best_lsn - state.max_lsn_wal_lag.get() * 2));
+        let biggest_wal_candidate = state
+            .next_connection_candidate()
+            .expect("Expected the biggest WAL candidate selected, but got none");
+        assert_eq!(
+            biggest_wal_candidate.safekeeper_id,
+            NodeId(1),
+            "Should not prefer the standby safekeeper if its WAL is lagging behind too much"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn standby_candidate_failover() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("standby_candidate_failover")?;
+        let mut state = dummy_state(&harness);
+        let now = Utc::now().naive_utc();
+
+        let streaming_sk_id = NodeId(0);
+        let best_lsn = 100_000;
+        state.standby_connection_enabled = true;
+        state.max_lsn_wal_lag = NonZeroU64::new(1_000).unwrap();
+        // long enough for the broker data not to go stale during the test
+        state.lagging_wal_timeout = Duration::from_secs(60);
+        state.wal_connection = Some(WalConnection {
+            sk_id: streaming_sk_id,
+            latest_connection_update: now,
+            connection_task: TaskHandle::spawn(move |_, _| async move { Ok(()) }),
+        });
+        state.wal_stream_candidates = HashMap::from([
+            (
+                streaming_sk_id,
+                EtcdSkTimeline {
+                    timeline: SkTimelineInfo {
+                        term: None,
+                        last_log_term: None,
+                        flush_lsn: None,
+                        commit_lsn: Some(Lsn(best_lsn)),
+                        backup_lsn: None,
+                        remote_consistent_lsn: None,
+                        peer_horizon_lsn: None,
+                        safekeeper_connstr: Some("streaming_sk".to_string()),
+                    },
+                    etcd_version: 0,
+                    latest_update: now,
+                },
+            ),
+            (
+                NodeId(1),
+                EtcdSkTimeline {
+                    timeline: SkTimelineInfo {
+                        term: None,
+                        last_log_term: None,
+                        flush_lsn: None,
+                        commit_lsn: Some(Lsn(best_lsn - 100)),
+                        backup_lsn: None,
+                        remote_consistent_lsn: None,
+                        peer_horizon_lsn: None,
+                        safekeeper_connstr: Some("first_standby_sk".to_string()),
+                    },
+                    etcd_version: 0,
+                    latest_update: now,
+                },
+            ),
+            (
+                NodeId(2),
+                EtcdSkTimeline {
+                    timeline: SkTimelineInfo {
+                        term: None,
+                        last_log_term: None,
+                        flush_lsn: None,
+                        commit_lsn: Some(Lsn(best_lsn - 200)),
+                        backup_lsn: None,
+                        remote_consistent_lsn: None,
+                        peer_horizon_lsn: None,
+                        safekeeper_connstr: Some("second_standby_sk".to_string()),
+                    },
+                    etcd_version: 0,
+                    latest_update: now,
+                },
+            ),
+        ]);
+
+        assert_eq!(
+            state.next_standby_candidate(streaming_sk_id).map(|(sk_id, _)| sk_id),
+            Some(NodeId(1)),
+            "Should stand by for the safekeeper with the biggest commit_lsn, except the streaming one"
+        );
+        state.maintain_standby_connection().await;
+        assert_eq!(
+            state.standby_connection.as_ref().map(|c| c.sk_id),
+            Some(NodeId(1))
+        );
+
+        // The standby safekeeper is gone from the broker, another one should take its place.
+        state.wal_stream_candidates.remove(&NodeId(1));
+        state.maintain_standby_connection().await;
+        let standby_connection = state
+            .standby_connection
+            .as_ref()
+            .expect("Expected a standby connection to the remaining safekeeper, but got none");
+        assert_eq!(standby_connection.sk_id, NodeId(2));
+        assert!(standby_connection
+            .wal_producer_connstr
+            .contains("second_standby_sk"));
+
+        // The streaming connection is lost, WAL streaming should fail over to the standby safekeeper.
+        let switches_from_standby =
+            WAL_CONNECTION_SWITCHES.with_label_values(&["no_existing_connection", "true"]);
+        let switches_before = switches_from_standby.get();
+        let no_wal_source_time = NO_WAL_SOURCE_TIME.with_label_values(&[
+            &state.id.tenant_id.to_string(),
+            &state.id.timeline_id.to_string(),
+        ]);
+        let no_wal_source_before = no_wal_source_time.get_sample_count();
+        if let Some(wal_connection) = state.wal_connection.take() {
+            wal_connection.connection_task.shutdown().await;
+        }
+        state.no_wal_source_since = Some(Instant::now());
+
+        let failover_candidate = state
+            .next_connection_candidate()
+            .expect("Expected the standby candidate selected, but got none");
+        assert_eq!(failover_candidate.safekeeper_id, NodeId(2));
+        state
+            .change_connection(
+                failover_candidate.safekeeper_id,
+                failover_candidate.wal_producer_connstr,
+                failover_candidate.reason,
+            )
+            .await;
+        assert_eq!(
+            state.wal_connection.as_ref().map(|c| c.sk_id),
+            Some(NodeId(2))
+        );
+        assert!(
+            state.standby_connection.is_none(),
+            "Standby connection should be stopped once WAL is streamed from its safekeeper"
+        );
+        assert_eq!(switches_from_standby.get(), switches_before + 1);
+        assert_eq!(
+            no_wal_source_time.get_sample_count(),
+            no_wal_source_before + 1
+        );
+        assert!(state.no_wal_source_since.is_none());
+
+        // And the standby connection moves to the safekeeper left.
+        state.maintain_standby_connection().await;
+        assert_eq!(
+            state.standby_connection.as_ref().map(|c| c.sk_id),
+            Some(streaming_sk_id)
+        );
+
+        if let Some(wal_connection) = state.wal_connection.take() {
+            wal_connection.connection_task.shutdown().await;
+        }
+        if let Some(standby_connection) = state.standby_connection.take() {
+            standby_connection.connection_task.shutdown().await;
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn connection_no_etcd_data_candidate() -> anyhow::Result<()> {
         let harness = RepoHarness::create("connection_no_etcd_data_candidate")?;
@@ -873,6 +1355,7 @@ mod tests {
             other_sk_id,
             EtcdSkTimeline {
                 timeline: SkTimelineInfo {
+                    term: None,
                     last_log_term: None,
                     flush_lsn: None,
                     commit_lsn: Some(Lsn(1 + state.max_lsn_wal_lag.get())),
@@ -933,6 +1416,7 @@ mod tests {
                 connected_sk_id,
                 EtcdSkTimeline {
                     timeline: SkTimelineInfo {
+                        term: None,
                         last_log_term: None,
                         flush_lsn: None,
                         commit_lsn: Some(current_lsn),
@@ -949,6 +1433,7 @@ mod tests {
                 NodeId(1),
                 EtcdSkTimeline {
                     timeline: SkTimelineInfo {
+                        term: None,
                         last_log_term: None,
                         flush_lsn: None,
                         commit_lsn: Some(new_lsn),
@@ -1015,6 +1500,7 @@ mod tests {
             NodeId(0),
             EtcdSkTimeline {
                 timeline: SkTimelineInfo {
+                    term: None,
                     last_log_term: None,
                     flush_lsn: None,
                     commit_lsn: Some(current_lsn),
@@ -1071,6 +1557,7 @@ mod tests {
             NodeId(0),
             EtcdSkTimeline {
                 timeline: SkTimelineInfo {
+                    term: None,
                     last_log_term: None,
                     flush_lsn: None,
                     commit_lsn: Some(current_lsn),
@@ -1126,6 +1613,9 @@ mod tests {
             lagging_wal_timeout: Duration::from_secs(1),
             max_lsn_wal_lag: NonZeroU64::new(1).unwrap(),
             wal_connection: None,
+            no_wal_source_since: None,
+            standby_connection_enabled: false,
+            standby_connection: None,
             wal_stream_candidates: HashMap::new(),
             wal_connection_attempts: HashMap::new(),
         }
@@ -25,22 +25,26 @@ use crate::{
     walingest::WalIngest,
 };
 use postgres_ffi::waldecoder::WalStreamDecoder;
-use utils::{lsn::Lsn, pq_proto::ReplicationFeedback, zid::ZTenantTimelineId};
+use utils::{
+    lsn::Lsn,
+    pq_proto::{ReplicationFeedback, STANDBY_APPLICATION_NAME},
+    zid::ZTenantTimelineId,
+};
 
-/// Opens a conneciton to the given wal producer and streams the WAL, sending progress messages during streaming.
-pub async fn handle_walreceiver_connection(
-    id: ZTenantTimelineId,
+/// Opens a replication connection to the given wal producer, without starting any streaming.
+/// The connection is closed when the `cancellation` channel fires or the returned client is dropped.
+async fn connect_to_wal_producer(
     wal_producer_connstr: &str,
-    events_sender: &watch::Sender<TaskEvent<ReplicationFeedback>>,
-    mut cancellation: watch::Receiver<()>,
+    application_name: &str,
     connect_timeout: Duration,
-) -> anyhow::Result<()> {
+    cancellation: &watch::Receiver<()>,
+) -> anyhow::Result<Client> {
     // Connect to the database in replication mode.
     info!("connecting to {wal_producer_connstr}");
     let connect_cfg =
-        format!("{wal_producer_connstr} application_name=pageserver replication=true");
+        format!("{wal_producer_connstr} application_name={application_name} replication=true");
 
-    let (mut replication_client, connection) = time::timeout(
+    let (replication_client, connection) = time::timeout(
         connect_timeout,
         tokio_postgres::connect(&connect_cfg, postgres::NoTls),
     )
@@ -71,6 +75,25 @@ pub async fn handle_walreceiver_connection(
         .instrument(info_span!("safekeeper_handle_db")),
     );
 
+    Ok(replication_client)
+}
+
+/// Opens a conneciton to the given wal producer and streams the WAL, sending progress messages during streaming.
+pub async fn handle_walreceiver_connection(
+    id: ZTenantTimelineId,
+    wal_producer_connstr: &str,
+    events_sender: &watch::Sender<TaskEvent<ReplicationFeedback>>,
+    mut cancellation: watch::Receiver<()>,
+    connect_timeout: Duration,
+) -> anyhow::Result<()> {
+    let mut replication_client = connect_to_wal_producer(
+        wal_producer_connstr,
+        "pageserver",
+        connect_timeout,
+        &cancellation,
+    )
+    .await?;
+
     // Immediately increment the gauge, then create a job to decrement it on task exit.
     // One of the pros of `defer!` is that this will *most probably*
     // get called, even in presence of panics.
@@ -268,6 +291,72 @@ pub async fn handle_walreceiver_connection(
     Ok(())
 }
 
+/// Keeps a replication connection to the given wal producer, that streams the producer's latest WAL positions instead of the WAL,
+/// sending the positions received as progress messages.
+///
+/// The connection uses the [`STANDBY_APPLICATION_NAME`], which makes the safekeeper send a keepalive message
+/// every time its `commit_lsn` advances (and periodically otherwise), omitting the WAL itself.
+pub async fn handle_standby_connection(
+    wal_producer_connstr: &str,
+    events_sender: &watch::Sender<TaskEvent<Lsn>>,
+    mut cancellation: watch::Receiver<()>,
+    connect_timeout: Duration,
+) -> anyhow::Result<()> {
+    let mut replication_client = connect_to_wal_producer(
+        wal_producer_connstr,
+        STANDBY_APPLICATION_NAME,
+        connect_timeout,
+        &cancellation,
+    )
+    .await?;
+
+    let gauge = crate::LIVE_CONNECTIONS_COUNT.with_label_values(&["wal_receiver_standby"]);
+    gauge.inc();
+    scopeguard::defer! {
+        gauge.dec();
+    }
+
+    let identify = identify_system(&mut replication_client).await?;
+    info!("{identify:?}");
+    let mut wal_end = Lsn::from(u64::from(identify.xlogpos));
+    if let Err(e) = events_sender.send(TaskEvent::NewEvent(wal_end)) {
+        warn!("Standby connection event listener dropped, aborting the connection: {e}");
+        return Ok(());
+    }
+
+    let query = format!("START_REPLICATION PHYSICAL {wal_end}");
+    let copy_stream = replication_client.copy_both_simple(&query).await?;
+    let positions_stream = ReplicationStream::new(copy_stream);
+    pin!(positions_stream);
+
+    while let Some(replication_message) = {
+        select! {
+            _ = cancellation.changed() => {
+                info!("standby connection interrupted");
+                None
+            }
+            replication_message = positions_stream.next() => replication_message,
+        }
+    } {
+        let reported_wal_end = match replication_message? {
+            ReplicationMessage::PrimaryKeepAlive(keepalive) => Lsn::from(keepalive.wal_end()),
+            ReplicationMessage::XLogData(xlog_data) => Lsn::from(xlog_data.wal_end()),
+            _ => continue,
+        };
+        // Keepalives sent before the producer streamed anything report a zero position, do not step back on them.
+        wal_end = wal_end.max(reported_wal_end);
+        trace!("standby wal producer is at {wal_end}");
+
+        // Every message is sent, even if the position did not advance: the manager uses them to check the connection liveness.
+        if let Err(e) = events_sender.send(TaskEvent::NewEvent(wal_end)) {
+            warn!("Standby connection event listener dropped, aborting the connection: {e}");
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
 /// Data returned from the postgres `IDENTIFY_SYSTEM` command
 ///
 /// See the [postgres docs] for more details.
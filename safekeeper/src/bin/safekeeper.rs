@@ -24,6 +24,7 @@ use safekeeper::defaults::{
 };
 use safekeeper::http;
 use safekeeper::remove_wal;
+use safekeeper::scrub;
 use safekeeper::timeline::GlobalTimelines;
 use safekeeper::wal_backup;
 use safekeeper::wal_service;
@@ -134,6 +135,34 @@ fn main() -> anyhow::Result<()> {
                 .default_missing_value("true")
                 .help("Enable/disable WAL backup to s3. When disabled, safekeeper removes WAL ignoring WAL backup horizon."),
         )
+        .arg(
+            Arg::new("wal-backup-compression")
+                .long("wal-backup-compression")
+                .takes_value(true)
+                .default_value("false")
+                .default_missing_value("true")
+                .help("Enable/disable zstd compression of offloaded WAL segments. Compressed and plain segments are read back alike."),
+        )
+        .arg(
+            Arg::new("max-tenant-wal-size")
+                .long("max-tenant-wal-size")
+                .takes_value(true)
+                .help("Reject new WAL of a tenant once safekeeper retains more than this many bytes of its WAL. Unlimited if not set; can be overridden per tenant via HTTP API."),
+        )
+        .arg(
+            Arg::new("wal-scrub-interval")
+                .long("wal-scrub-interval")
+                .takes_value(true)
+                .help("Check page headers and record CRCs of WAL stored locally with this period, e.g. '1h'. Disabled if not set; scrub can also be requested via HTTP API."),
+        )
+        .arg(
+            Arg::new("enable-peer-recovery")
+                .long("enable-peer-recovery")
+                .takes_value(true)
+                .default_value("true")
+                .default_missing_value("true")
+                .help("Enable/disable fetching missing committed WAL from peer safekeepers when no compute is connected."),
+        )
         .arg(
             Arg::new("auth-validation-public-key-path")
                 .long("auth-validation-public-key-path")
@@ -211,10 +240,35 @@ fn main() -> anyhow::Result<()> {
         .unwrap()
         .parse()
         .context("failed to parse bool enable-s3-offload bool")?;
+    conf.wal_backup_compression = arg_matches
+        .value_of("wal-backup-compression")
+        .unwrap()
+        .parse()
+        .context("failed to parse bool wal-backup-compression")?;
+    conf.peer_recovery_enabled = arg_matches
+        .value_of("enable-peer-recovery")
+        .unwrap()
+        .parse()
+        .context("failed to parse bool enable-peer-recovery")?;
+    if let Some(max_tenant_wal_size) = arg_matches.value_of("max-tenant-wal-size") {
+        conf.max_tenant_wal_bytes = Some(
+            max_tenant_wal_size
+                .parse()
+                .context("failed to parse max-tenant-wal-size")?,
+        );
+    }
+    if let Some(scrub_interval) = arg_matches.value_of("wal-scrub-interval") {
+        conf.wal_scrub_interval = Some(
+            humantime::parse_duration(scrub_interval)
+                .context("failed to parse wal-scrub-interval")?,
+        );
+    }
 
     conf.auth_validation_public_key_path = arg_matches
         .value_of("auth-validation-public-key-path")
         .map(PathBuf::from);
+    // not an argument, to keep it out of the process list
+    conf.auth_token = std::env::var("SAFEKEEPER_AUTH_TOKEN").ok();
 
     start_safekeeper(conf, given_id, arg_matches.is_present("init"))
 }
@@ -352,6 +406,17 @@ fn start_safekeeper(mut conf: SafeKeeperConf, given_id: Option<NodeId>, init: bo
             })?,
     );
 
+    if let Some(scrub_interval) = conf.wal_scrub_interval {
+        let conf_ = conf.clone();
+        threads.push(
+            thread::Builder::new()
+                .name("WAL scrub thread".into())
+                .spawn(move || {
+                    scrub::thread_main(conf_, scrub_interval);
+                })?,
+        );
+    }
+
     let conf_ = conf.clone();
     threads.push(
         thread::Builder::new()
@@ -12,7 +12,7 @@ use tokio::{runtime, time::sleep};
 use tracing::*;
 use url::Url;
 
-use crate::{timeline::GlobalTimelines, SafeKeeperConf};
+use crate::{timeline::GlobalTimelines, wal_restore, SafeKeeperConf};
 use etcd_broker::{
     subscription_key::{OperationKind, SkOperationKind, SubscriptionKey},
     Client, PutOptions,
@@ -224,9 +224,18 @@ async fn pull_loop(conf: SafeKeeperConf) -> Result<()> {
         match subscription.value_updates.recv().await {
             Some(new_info) => {
                 // note: there are blocking operations below, but it's considered fine for now
-                if let Ok(tli) = GlobalTimelines::get(&conf, new_info.key.id, false) {
-                    tli.record_safekeeper_info(&new_info.value, new_info.key.node_id)
-                        .await?
+                match GlobalTimelines::get(&conf, new_info.key.id, false) {
+                    Ok(tli) => {
+                        tli.record_safekeeper_info(&conf, &new_info.value, new_info.key.node_id)
+                            .await?
+                    }
+                    // peer has the timeline, we might need to restore it
+                    Err(_) => wal_restore::maybe_start_restore(
+                        &conf,
+                        new_info.key.id,
+                        &new_info.value,
+                        new_info.key.node_id,
+                    ),
                 }
             }
             None => {
@@ -21,7 +21,7 @@ use crate::SafeKeeperConf;
 use std::convert::TryInto;
 
 // contains persistent metadata for safekeeper
-const CONTROL_FILE_NAME: &str = "safekeeper.control";
+pub const CONTROL_FILE_NAME: &str = "safekeeper.control";
 // needed to atomically update the state using `rename`
 const CONTROL_FILE_NAME_PARTIAL: &str = "safekeeper.control.partial";
 pub const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>();
@@ -145,6 +145,17 @@ impl FileStorage {
             .read_to_end(&mut buf)
             .context("failed to read control file")?;
 
+        FileStorage::deserialize_state(&buf).with_context(|| {
+            format!(
+                "while reading control file {}",
+                control_file_path.as_ref().display(),
+            )
+        })
+    }
+
+    /// Parse control file contents, verifying the checksum.
+    pub fn deserialize_state(buf: &[u8]) -> Result<SafeKeeperState> {
+        ensure!(buf.len() > CHECKSUM_SIZE, "control file is too short");
         let calculated_checksum = crc32c::crc32c(&buf[..buf.len() - CHECKSUM_SIZE]);
 
         let expected_checksum_bytes: &[u8; CHECKSUM_SIZE] =
@@ -159,14 +170,44 @@ impl FileStorage {
             )
         );
 
-        let state = FileStorage::deser_sk_state(&mut &buf[..buf.len() - CHECKSUM_SIZE])
-            .with_context(|| {
-                format!(
-                    "while reading control file {}",
-                    control_file_path.as_ref().display(),
-                )
+        FileStorage::deser_sk_state(&mut &buf[..buf.len() - CHECKSUM_SIZE])
+    }
+
+    /// Form control file contents: the state prefixed with magic and version
+    /// and followed by the checksum.
+    pub fn serialize_state(s: &SafeKeeperState) -> Result<Vec<u8>> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<LittleEndian>(SK_MAGIC)?;
+        buf.write_u32::<LittleEndian>(SK_FORMAT_VERSION)?;
+        s.ser_into(&mut buf)?;
+
+        // calculate checksum before resize
+        let checksum = crc32c::crc32c(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        Ok(buf)
+    }
+
+    /// Write control file into a timeline directory prepared out of place,
+    /// e.g. restored from remote storage; the timeline is not loaded yet.
+    pub fn create_control_file(
+        conf: &SafeKeeperConf,
+        timeline_dir: &Path,
+        s: &SafeKeeperState,
+    ) -> Result<()> {
+        let control_path = timeline_dir.join(CONTROL_FILE_NAME);
+        let mut control_file = File::create(&control_path).with_context(|| {
+            format!(
+                "failed to create control file at {}",
+                control_path.display()
+            )
+        })?;
+        control_file.write_all(&FileStorage::serialize_state(s)?)?;
+        if !conf.no_sync {
+            control_file.sync_all().with_context(|| {
+                format!("failed to sync control file at {}", control_path.display())
             })?;
-        Ok(state)
+        }
+        Ok(())
     }
 }
 
@@ -192,14 +233,7 @@ impl Storage for FileStorage {
                 &control_partial_path.display()
             )
         })?;
-        let mut buf: Vec<u8> = Vec::new();
-        buf.write_u32::<LittleEndian>(SK_MAGIC)?;
-        buf.write_u32::<LittleEndian>(SK_FORMAT_VERSION)?;
-        s.ser_into(&mut buf)?;
-
-        // calculate checksum before resize
-        let checksum = crc32c::crc32c(&buf);
-        buf.extend_from_slice(&checksum.to_le_bytes());
+        let buf = FileStorage::serialize_state(s)?;
 
         control_partial.write_all(&buf).with_context(|| {
             format!(
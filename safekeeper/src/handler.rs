@@ -34,23 +34,56 @@ pub struct SafekeeperPostgresHandler {
 /// Parsed Postgres command.
 enum SafekeeperPostgresCommand {
     StartWalPush,
-    StartReplication { start_lsn: Lsn },
+    StartReplication {
+        start_lsn: Lsn,
+        slot: Option<String>,
+    },
     IdentifySystem,
-    JSONCtrl { cmd: AppendLogicalMessage },
+    CreateReplicationSlot {
+        name: String,
+    },
+    DropReplicationSlot {
+        name: String,
+    },
+    JSONCtrl {
+        cmd: AppendLogicalMessage,
+    },
 }
 
 fn parse_cmd(cmd: &str) -> Result<SafekeeperPostgresCommand> {
     if cmd.starts_with("START_WAL_PUSH") {
         Ok(SafekeeperPostgresCommand::StartWalPush)
     } else if cmd.starts_with("START_REPLICATION") {
-        let re =
-            Regex::new(r"START_REPLICATION(?: PHYSICAL)? ([[:xdigit:]]+/[[:xdigit:]]+)").unwrap();
-        let mut caps = re.captures_iter(cmd);
-        let start_lsn = caps
-            .next()
-            .map(|cap| cap[1].parse::<Lsn>())
-            .context("failed to parse start LSN from START_REPLICATION command")??;
-        Ok(SafekeeperPostgresCommand::StartReplication { start_lsn })
+        let re = Regex::new(
+            r#"START_REPLICATION(?: SLOT "?([^"\s]+)"?)?(?: PHYSICAL)? ([[:xdigit:]]+/[[:xdigit:]]+)"#,
+        )
+        .unwrap();
+        let caps = re
+            .captures(cmd)
+            .context("failed to parse start LSN from START_REPLICATION command")?;
+        let start_lsn = caps[2].parse::<Lsn>()?;
+        let slot = caps.get(1).map(|m| m.as_str().to_owned());
+        Ok(SafekeeperPostgresCommand::StartReplication { start_lsn, slot })
+    } else if cmd.starts_with("CREATE_REPLICATION_SLOT") {
+        // RESERVE_WAL is implied: WAL is always kept since slot creation.
+        let re = Regex::new(r#"CREATE_REPLICATION_SLOT "?([^"\s]+)"?( TEMPORARY)? (\w+)"#).unwrap();
+        let caps = re
+            .captures(cmd)
+            .context("failed to parse CREATE_REPLICATION_SLOT command")?;
+        if caps.get(2).is_some() || &caps[3] != "PHYSICAL" {
+            bail!("only permanent physical replication slots are supported");
+        }
+        Ok(SafekeeperPostgresCommand::CreateReplicationSlot {
+            name: caps[1].to_owned(),
+        })
+    } else if cmd.starts_with("DROP_REPLICATION_SLOT") {
+        let re = Regex::new(r#"DROP_REPLICATION_SLOT "?([^"\s]+)"?"#).unwrap();
+        let caps = re
+            .captures(cmd)
+            .context("failed to parse DROP_REPLICATION_SLOT command")?;
+        Ok(SafekeeperPostgresCommand::DropReplicationSlot {
+            name: caps[1].to_owned(),
+        })
     } else if cmd.starts_with("IDENTIFY_SYSTEM") {
         Ok(SafekeeperPostgresCommand::IdentifySystem)
     } else if cmd.starts_with("JSON_CTRL") {
@@ -93,7 +126,9 @@ impl postgres_backend::Handler for SafekeeperPostgresHandler {
         info!("got query {:?}", query_string);
 
         let create = !(matches!(cmd, SafekeeperPostgresCommand::StartReplication { .. })
-            || matches!(cmd, SafekeeperPostgresCommand::IdentifySystem));
+            || matches!(cmd, SafekeeperPostgresCommand::IdentifySystem)
+            || matches!(cmd, SafekeeperPostgresCommand::CreateReplicationSlot { .. })
+            || matches!(cmd, SafekeeperPostgresCommand::DropReplicationSlot { .. }));
 
         let tenantid = self.ztenantid.context("tenantid is required")?;
         let timelineid = self.ztimelineid.context("timelineid is required")?;
@@ -111,14 +146,21 @@ impl postgres_backend::Handler for SafekeeperPostgresHandler {
                     .run(self)
                     .context("failed to run ReceiveWalConn")?;
             }
-            SafekeeperPostgresCommand::StartReplication { start_lsn } => {
+            SafekeeperPostgresCommand::StartReplication { start_lsn, slot } => {
                 ReplicationConn::new(pgb)
-                    .run(self, pgb, start_lsn)
+                    .run(self, pgb, start_lsn, slot)
                     .context("failed to run ReplicationConn")?;
             }
             SafekeeperPostgresCommand::IdentifySystem => {
                 self.handle_identify_system(pgb)?;
             }
+            SafekeeperPostgresCommand::CreateReplicationSlot { ref name } => {
+                self.handle_create_replication_slot(pgb, name)?;
+            }
+            SafekeeperPostgresCommand::DropReplicationSlot { ref name } => {
+                self.timeline.get().drop_replication_slot(name)?;
+                pgb.write_message(&BeMessage::CommandComplete(b"DROP_REPLICATION_SLOT"))?;
+            }
             SafekeeperPostgresCommand::JSONCtrl { ref cmd } => {
                 handle_json_ctrl(self, pgb, cmd)?;
             }
@@ -203,4 +245,51 @@ impl SafekeeperPostgresHandler {
         .write_message(&BeMessage::CommandComplete(b"IDENTIFY_SYSTEM"))?;
         Ok(())
     }
+
+    ///
+    /// Handle CREATE_REPLICATION_SLOT replication command
+    ///
+    fn handle_create_replication_slot(
+        &mut self,
+        pgb: &mut PostgresBackend,
+        name: &str,
+    ) -> Result<()> {
+        let consistent_point = self.timeline.get().create_replication_slot(name)?;
+        let consistent_point = consistent_point.to_string();
+
+        pgb.write_message_noflush(&BeMessage::RowDescription(&[
+            RowDescriptor {
+                name: b"slot_name",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+            RowDescriptor {
+                name: b"consistent_point",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+            RowDescriptor {
+                name: b"snapshot_name",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+            RowDescriptor {
+                name: b"output_plugin",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+        ]))?
+        .write_message_noflush(&BeMessage::DataRow(&[
+            Some(name.as_bytes()),
+            Some(consistent_point.as_bytes()),
+            None,
+            None,
+        ]))?
+        .write_message(&BeMessage::CommandComplete(b"CREATE_REPLICATION_SLOT"))?;
+        Ok(())
+    }
 }
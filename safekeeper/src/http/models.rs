@@ -1,5 +1,10 @@
 use serde::{Deserialize, Serialize};
-use utils::zid::{NodeId, ZTenantId, ZTimelineId};
+use utils::{
+    lsn::Lsn,
+    zid::{NodeId, ZTenantId, ZTimelineId},
+};
+
+use crate::safekeeper::{Configuration, SafeKeeperState, Term};
 
 #[derive(Serialize, Deserialize)]
 pub struct TimelineCreateRequest {
@@ -7,3 +12,43 @@ pub struct TimelineCreateRequest {
     pub timeline_id: ZTimelineId,
     pub peer_ids: Vec<NodeId>,
 }
+
+/// Request to switch the timeline membership configuration: the joint one to
+/// start a change, switching to the new members completes on its own once
+/// the majority of each member set has the WAL up to the switch.
+#[derive(Serialize, Deserialize)]
+pub struct MembershipChangeRequest {
+    /// Term of the current proposer, safekeeper's term if not set.
+    pub term: Option<Term>,
+    /// LSN the switch is effective since; the same LSN should be passed to
+    /// all members for them to switch at the same point.
+    pub lsn: Lsn,
+    pub configuration: Configuration,
+}
+
+/// Request to copy the timeline from another safekeeper.
+#[derive(Serialize, Deserialize)]
+pub struct TimelinePullRequest {
+    /// HTTP API address of the source safekeeper, e.g. `127.0.0.1:7676`.
+    pub source_http_addr: String,
+}
+
+/// Request to replace WAL segments found corrupted by scrub.
+#[derive(Serialize, Deserialize)]
+pub struct ScrubRepairRequest {
+    /// HTTP API address of safekeeper to fetch segments from; remote storage
+    /// is used if not set.
+    pub source_http_addr: Option<String>,
+}
+
+/// Timeline state along with local WAL it describes, given out to copy the
+/// timeline to another safekeeper.
+#[derive(Serialize, Deserialize)]
+pub struct TimelineSnapshot {
+    pub state: SafeKeeperState,
+    /// WAL beyond this point is not covered by the state and is not copied.
+    pub flush_lsn: Lsn,
+    /// Names of WAL segments holding WAL up to `flush_lsn`, oldest first,
+    /// without `.partial` suffix.
+    pub segments: Vec<String>,
+}
@@ -6,12 +6,23 @@ use serde::Serializer;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 use std::sync::Arc;
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
 
-use crate::safekeeper::Term;
-use crate::safekeeper::TermHistory;
-use crate::timeline::{GlobalTimelines, TimelineDeleteForceResult};
+use crate::control_file::CONTROL_FILE_NAME;
+use crate::pull_timeline;
+use crate::quota::{self, TenantWalQuota};
+use crate::safekeeper;
+use crate::safekeeper::{
+    AcceptorProposerMessage, ConfigHistory, Configuration, ProposerAcceptorMessage,
+    RetentionPolicy, SafeKeeperState, Term, TermHistory,
+};
+use crate::scrub;
+use crate::timeline::{GlobalTimelines, Timeline, TimelineDeleteForceResult};
+use crate::wal_restore;
 use crate::SafeKeeperConf;
 use etcd_broker::subscription_value::SkTimelineInfo;
+use postgres_ffi::xlog_utils::IsXLogFileName;
 use utils::{
     auth::JwtAuth,
     http::{
@@ -25,7 +36,9 @@ use utils::{
     zid::{NodeId, ZTenantId, ZTenantTimelineId, ZTimelineId},
 };
 
-use super::models::TimelineCreateRequest;
+use super::models::{
+    MembershipChangeRequest, ScrubRepairRequest, TimelineCreateRequest, TimelinePullRequest,
+};
 
 #[derive(Debug, Serialize)]
 struct SafekeeperStatus {
@@ -88,6 +101,33 @@ struct TimelineStatus {
     remote_consistent_lsn: Lsn,
 }
 
+impl TimelineStatus {
+    fn new(zttid: ZTenantTimelineId, tli: &Timeline) -> Self {
+        let (inmem, state) = tli.get_state();
+        let flush_lsn = tli.get_end_of_wal();
+
+        let acc_state = AcceptorStateStatus {
+            term: state.acceptor_state.term,
+            epoch: state.acceptor_state.get_epoch(flush_lsn),
+            term_history: state.acceptor_state.term_history,
+        };
+
+        // Note: we report in memory values which can be lost.
+        TimelineStatus {
+            tenant_id: zttid.tenant_id,
+            timeline_id: zttid.timeline_id,
+            acceptor_state: acc_state,
+            flush_lsn,
+            timeline_start_lsn: state.timeline_start_lsn,
+            local_start_lsn: state.local_start_lsn,
+            commit_lsn: inmem.commit_lsn,
+            backup_lsn: inmem.backup_lsn,
+            peer_horizon_lsn: inmem.peer_horizon_lsn,
+            remote_consistent_lsn: inmem.remote_consistent_lsn,
+        }
+    }
+}
+
 /// Report info about timeline.
 async fn timeline_status_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
     let zttid = ZTenantTimelineId::new(
@@ -97,28 +137,179 @@ async fn timeline_status_handler(request: Request<Body>) -> Result<Response<Body
     check_permission(&request, Some(zttid.tenant_id))?;
 
     let tli = GlobalTimelines::get(get_conf(&request), zttid, false).map_err(ApiError::from_err)?;
-    let (inmem, state) = tli.get_state();
-    let flush_lsn = tli.get_end_of_wal();
+    json_response(StatusCode::OK, TimelineStatus::new(zttid, &tli))
+}
 
-    let acc_state = AcceptorStateStatus {
-        term: state.acceptor_state.term,
-        epoch: state.acceptor_state.get_epoch(flush_lsn),
-        term_history: state.acceptor_state.term_history,
-    };
+/// Timeline membership configuration and its switches.
+#[derive(Debug, Serialize)]
+struct MembershipStatus {
+    configuration: Configuration,
+    /// Whether this safekeeper is a member.
+    is_member: bool,
+    config_history: ConfigHistory,
+}
 
-    // Note: we report in memory values which can be lost.
-    let status = TimelineStatus {
-        tenant_id: zttid.tenant_id,
-        timeline_id: zttid.timeline_id,
-        acceptor_state: acc_state,
-        flush_lsn,
-        timeline_start_lsn: state.timeline_start_lsn,
-        local_start_lsn: state.local_start_lsn,
-        commit_lsn: inmem.commit_lsn,
-        backup_lsn: inmem.backup_lsn,
-        peer_horizon_lsn: inmem.peer_horizon_lsn,
-        remote_consistent_lsn: inmem.remote_consistent_lsn,
-    };
+impl MembershipStatus {
+    fn new(state: SafeKeeperState, node_id: NodeId) -> Self {
+        let configuration = state.configuration();
+        MembershipStatus {
+            is_member: configuration.is_member(node_id),
+            configuration,
+            config_history: state.config_history,
+        }
+    }
+}
+
+/// Report timeline membership configuration, e.g. to observe a change.
+async fn timeline_membership_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+
+    let conf = get_conf(&request);
+    let tli = GlobalTimelines::get(conf, zttid, false).map_err(ApiError::from_err)?;
+    let (_, state) = tli.get_state();
+    json_response(StatusCode::OK, MembershipStatus::new(state, conf.my_id))
+}
+
+/// Switch timeline membership configuration, the same way the proposer does.
+/// The caller is expected to switch all members at the same LSN; commits past
+/// it require the majority of each member set of the joint configuration
+/// anyway, so a single safekeeper can't complete the change on its own.
+async fn timeline_membership_change_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    // changes the set of nodes storing the tenant data
+    check_permission(&request, None)?;
+    let request_data: MembershipChangeRequest = json_request(&mut request).await?;
+
+    let conf = get_conf(&request);
+    let tli = GlobalTimelines::get(conf, zttid, false).map_err(ApiError::from_err)?;
+    let (_, state) = tli.get_state();
+    let term = request_data.term.unwrap_or(state.acceptor_state.term);
+    if term > state.acceptor_state.term {
+        return Err(ApiError::BadRequest(format!(
+            "term {} is ahead of safekeeper's term {}",
+            term, state.acceptor_state.term
+        )));
+    }
+    let msg = ProposerAcceptorMessage::MembershipChange(safekeeper::MembershipChangeRequest {
+        term,
+        lsn: request_data.lsn,
+        config: request_data.configuration,
+    });
+    match tli.process_msg(&msg).map_err(ApiError::from_err)? {
+        Some(AcceptorProposerMessage::MembershipChangeResponse(resp)) if resp.accepted != 0 => {}
+        Some(AcceptorProposerMessage::MembershipChangeResponse(resp)) => {
+            return Err(ApiError::Conflict(format!(
+                "membership change refused, safekeeper term is {}, configuration generation is {}",
+                resp.term, resp.generation
+            )))
+        }
+        resp => {
+            return Err(ApiError::from_err(anyhow::anyhow!(
+                "unexpected response to membership change: {:?}",
+                resp
+            )))
+        }
+    }
+
+    let (_, state) = tli.get_state();
+    json_response(StatusCode::OK, MembershipStatus::new(state, conf.my_id))
+}
+
+/// Report policy of keeping WAL of the timeline locally.
+async fn timeline_retention_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+
+    let tli = GlobalTimelines::get(get_conf(&request), zttid, false).map_err(ApiError::from_err)?;
+    let (_, state) = tli.get_state();
+    json_response(StatusCode::OK, state.retention_policy)
+}
+
+/// Set policy of keeping WAL of the timeline locally, beyond what consumers
+/// and backup need.
+async fn timeline_retention_change_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+    let policy: RetentionPolicy = json_request(&mut request).await?;
+
+    let tli = GlobalTimelines::get(get_conf(&request), zttid, false).map_err(ApiError::from_err)?;
+    tli.set_retention_policy(policy)
+        .map_err(ApiError::from_err)?;
+    json_response(StatusCode::OK, policy)
+}
+
+/// Report outcome of the last check of WAL kept locally.
+async fn timeline_scrub_status_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+
+    GlobalTimelines::get(get_conf(&request), zttid, false).map_err(ApiError::from_err)?;
+    match scrub::get_status(&zttid) {
+        Some(status) => json_response(StatusCode::OK, status),
+        None => Err(ApiError::NotFound(format!(
+            "timeline {} was not scrubbed yet",
+            zttid
+        ))),
+    }
+}
+
+/// Check WAL kept locally right away.
+async fn timeline_scrub_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+    ensure_no_body(&mut request).await?;
+
+    let conf = get_conf(&request).clone();
+    let tli = GlobalTimelines::get(&conf, zttid, false).map_err(ApiError::from_err)?;
+    // reads the whole WAL, keep it off the executor
+    let status = tokio::task::spawn_blocking(move || scrub::scrub_timeline(&conf, &tli))
+        .await
+        .map_err(ApiError::from_err)?
+        .map_err(ApiError::from_err)?;
+    json_response(StatusCode::OK, status)
+}
+
+/// Replace WAL segments found corrupted by the last scrub with copies from a
+/// peer or remote storage.
+async fn timeline_scrub_repair_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    // makes requests to arbitrary hosts on our behalf
+    check_permission(&request, None)?;
+    let request_data: ScrubRepairRequest = json_request(&mut request).await?;
+
+    let conf = get_conf(&request);
+    let tli = GlobalTimelines::get(conf, zttid, false).map_err(ApiError::from_err)?;
+    let status = scrub::repair_timeline(conf, &tli, request_data.source_http_addr.as_deref())
+        .await
+        .map_err(ApiError::from_err)?;
     json_response(StatusCode::OK, status)
 }
 
@@ -136,6 +327,101 @@ async fn timeline_create_handler(mut request: Request<Body>) -> Result<Response<
     json_response(StatusCode::CREATED, ())
 }
 
+/// Restore timeline which safekeeper doesn't have (e.g. lost with the disk)
+/// from the WAL backup in remote storage.
+async fn timeline_restore_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+    ensure_no_body(&mut request).await?;
+
+    let conf = get_conf(&request);
+    if GlobalTimelines::get_loaded(zttid).is_some()
+        || conf.timeline_dir(&zttid).join(CONTROL_FILE_NAME).exists()
+    {
+        return Err(ApiError::Conflict(format!(
+            "timeline {} already exists",
+            zttid
+        )));
+    }
+    let tli = wal_restore::restore(conf, zttid)
+        .await
+        .map_err(ApiError::from_err)?;
+    json_response(StatusCode::CREATED, TimelineStatus::new(zttid, &tli))
+}
+
+/// Give out timeline state and list of WAL segments to copy the timeline to
+/// another safekeeper, see `pull_timeline`.
+async fn timeline_snapshot_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+
+    let conf = get_conf(&request);
+    let tli = GlobalTimelines::get(conf, zttid, false).map_err(ApiError::from_err)?;
+    let snapshot = pull_timeline::timeline_snapshot(conf, &tli).map_err(ApiError::from_err)?;
+    json_response(StatusCode::OK, snapshot)
+}
+
+/// Stream WAL segment of the timeline, partial one included.
+async fn timeline_file_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+    let file_name: String = parse_request_param(&request, "file_name")?;
+    if !IsXLogFileName(&file_name) {
+        return Err(ApiError::BadRequest(format!(
+            "{} is not a WAL segment name",
+            file_name
+        )));
+    }
+
+    let timeline_dir = get_conf(&request).timeline_dir(&zttid);
+    let file = match File::open(timeline_dir.join(format!("{}.partial", file_name))).await {
+        Ok(file) => file,
+        Err(_) => File::open(timeline_dir.join(&file_name))
+            .await
+            .map_err(|_| ApiError::NotFound(format!("segment {} not found", file_name)))?,
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+        .body(Body::wrap_stream(ReaderStream::new(file)))
+        .map_err(ApiError::from_err)
+}
+
+/// Copy the timeline which safekeeper doesn't have from another safekeeper,
+/// e.g. to move it to a less loaded node.
+async fn timeline_pull_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    // makes requests to arbitrary hosts on our behalf
+    check_permission(&request, None)?;
+    let request_data: TimelinePullRequest = json_request(&mut request).await?;
+
+    let conf = get_conf(&request);
+    if GlobalTimelines::get_loaded(zttid).is_some()
+        || conf.timeline_dir(&zttid).join(CONTROL_FILE_NAME).exists()
+    {
+        return Err(ApiError::Conflict(format!(
+            "timeline {} already exists",
+            zttid
+        )));
+    }
+    let tli = pull_timeline::pull_timeline(conf, zttid, &request_data.source_http_addr)
+        .await
+        .map_err(ApiError::from_err)?;
+    json_response(StatusCode::CREATED, TimelineStatus::new(zttid, &tli))
+}
+
 /// Deactivates the timeline and removes its data directory.
 ///
 /// It does not try to stop any processing of the timeline; there is no such code at the time of writing.
@@ -160,6 +446,61 @@ async fn timeline_delete_force_handler(
     )
 }
 
+#[derive(Debug, Serialize)]
+struct TenantWalQuotaStatus {
+    /// Limit in effect, None if unlimited.
+    max_wal_bytes: Option<u64>,
+    retained_wal_bytes: u64,
+}
+
+fn tenant_wal_quota_status(
+    conf: &SafeKeeperConf,
+    tenant_id: &ZTenantId,
+) -> Result<TenantWalQuotaStatus, ApiError> {
+    let quota = quota::get_quota(conf, tenant_id).map_err(ApiError::from_err)?;
+    Ok(TenantWalQuotaStatus {
+        max_wal_bytes: quota.max_wal_bytes,
+        retained_wal_bytes: quota::get_retained_wal(tenant_id),
+    })
+}
+
+/// Report limit on WAL retained by the tenant and its current usage.
+async fn tenant_wal_quota_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+    json_response(
+        StatusCode::OK,
+        tenant_wal_quota_status(get_conf(&request), &tenant_id)?,
+    )
+}
+
+/// Override limit on WAL retained by the tenant.
+async fn tenant_wal_quota_set_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    // not something the tenant decides itself
+    check_permission(&request, None)?;
+    let quota: TenantWalQuota = json_request(&mut request).await?;
+
+    let conf = get_conf(&request);
+    quota::set_quota(conf, &tenant_id, Some(quota)).map_err(ApiError::from_err)?;
+    json_response(StatusCode::OK, tenant_wal_quota_status(conf, &tenant_id)?)
+}
+
+/// Get back to the default limit on WAL retained by the tenant.
+async fn tenant_wal_quota_reset_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, None)?;
+    ensure_no_body(&mut request).await?;
+
+    let conf = get_conf(&request);
+    quota::set_quota(conf, &tenant_id, None).map_err(ApiError::from_err)?;
+    json_response(StatusCode::OK, tenant_wal_quota_status(conf, &tenant_id)?)
+}
+
 /// Deactivates all timelines for the tenant and removes its data directory.
 /// See `timeline_delete_force_handler`.
 async fn tenant_delete_force_handler(
@@ -188,8 +529,9 @@ async fn record_safekeeper_info(mut request: Request<Body>) -> Result<Response<B
     check_permission(&request, Some(zttid.tenant_id))?;
     let safekeeper_info: SkTimelineInfo = json_request(&mut request).await?;
 
-    let tli = GlobalTimelines::get(get_conf(&request), zttid, false).map_err(ApiError::from_err)?;
-    tli.record_safekeeper_info(&safekeeper_info, NodeId(1))
+    let conf = get_conf(&request);
+    let tli = GlobalTimelines::get(conf, zttid, false).map_err(ApiError::from_err)?;
+    tli.record_safekeeper_info(conf, &safekeeper_info, NodeId(1))
         .await?;
 
     json_response(StatusCode::OK, ())
@@ -222,13 +564,66 @@ pub fn make_router(
             "/v1/timeline/:tenant_id/:timeline_id",
             timeline_status_handler,
         )
+        .get(
+            "/v1/timeline/:tenant_id/:timeline_id/membership",
+            timeline_membership_handler,
+        )
+        .post(
+            "/v1/timeline/:tenant_id/:timeline_id/membership",
+            timeline_membership_change_handler,
+        )
+        .get(
+            "/v1/timeline/:tenant_id/:timeline_id/retention",
+            timeline_retention_handler,
+        )
+        .post(
+            "/v1/timeline/:tenant_id/:timeline_id/retention",
+            timeline_retention_change_handler,
+        )
+        .get(
+            "/v1/timeline/:tenant_id/:timeline_id/scrub",
+            timeline_scrub_status_handler,
+        )
+        .post(
+            "/v1/timeline/:tenant_id/:timeline_id/scrub",
+            timeline_scrub_handler,
+        )
+        .post(
+            "/v1/timeline/:tenant_id/:timeline_id/scrub/repair",
+            timeline_scrub_repair_handler,
+        )
+        .post(
+            "/v1/timeline/:tenant_id/:timeline_id/restore",
+            timeline_restore_handler,
+        )
         // Will be used in the future instead of implicit timeline creation
         .post("/v1/timeline", timeline_create_handler)
         .delete(
             "/v1/tenant/:tenant_id/timeline/:timeline_id",
             timeline_delete_force_handler,
         )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/snapshot",
+            timeline_snapshot_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/file/:file_name",
+            timeline_file_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/pull",
+            timeline_pull_handler,
+        )
         .delete("/v1/tenant/:tenant_id", tenant_delete_force_handler)
+        .get("/v1/tenant/:tenant_id/wal_quota", tenant_wal_quota_handler)
+        .post(
+            "/v1/tenant/:tenant_id/wal_quota",
+            tenant_wal_quota_set_handler,
+        )
+        .delete(
+            "/v1/tenant/:tenant_id/wal_quota",
+            tenant_wal_quota_reset_handler,
+        )
         // for tests
         .post(
             "/v1/record_safekeeper_info/:tenant_id/:timeline_id",
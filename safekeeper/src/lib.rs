@@ -14,12 +14,17 @@ pub mod handler;
 pub mod http;
 pub mod json_ctrl;
 pub mod metrics;
+pub mod pull_timeline;
+pub mod quota;
 pub mod receive_wal;
+pub mod recovery;
 pub mod remove_wal;
 pub mod safekeeper;
+pub mod scrub;
 pub mod send_wal;
 pub mod timeline;
 pub mod wal_backup;
+pub mod wal_restore;
 pub mod wal_service;
 pub mod wal_storage;
 
@@ -54,10 +59,20 @@ pub struct SafeKeeperConf {
     pub remote_storage: Option<RemoteStorageConfig>,
     pub backup_runtime_threads: usize,
     pub wal_backup_enabled: bool,
+    /// Compress offloaded WAL segments with zstd.
+    pub wal_backup_compression: bool,
+    pub peer_recovery_enabled: bool,
+    /// Default limit on WAL retained by a tenant, see `quota`.
+    pub max_tenant_wal_bytes: Option<u64>,
+    /// Period of checking WAL kept locally, see `scrub`; disabled if None.
+    pub wal_scrub_interval: Option<Duration>,
     pub my_id: NodeId,
     pub broker_endpoints: Vec<Url>,
     pub broker_etcd_prefix: String,
     pub auth_validation_public_key_path: Option<PathBuf>,
+    /// JWT token to present to the HTTP API of peers, e.g. when pulling a
+    /// timeline from them.
+    pub auth_token: Option<String>,
 }
 
 impl SafeKeeperConf {
@@ -89,7 +104,12 @@ impl Default for SafeKeeperConf {
             broker_etcd_prefix: etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string(),
             backup_runtime_threads: DEFAULT_WAL_BACKUP_RUNTIME_THREADS,
             wal_backup_enabled: true,
+            wal_backup_compression: false,
+            peer_recovery_enabled: true,
+            max_tenant_wal_bytes: None,
+            wal_scrub_interval: None,
             auth_validation_public_key_path: None,
+            auth_token: None,
         }
     }
 }
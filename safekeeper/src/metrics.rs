@@ -43,6 +43,7 @@ pub struct TimelineCollector {
     timeline_active: GenericGaugeVec<AtomicU64>,
     wal_backup_active: GenericGaugeVec<AtomicU64>,
     connected_computes: IntGaugeVec,
+    connected_standbys: IntGaugeVec,
     disk_usage: GenericGaugeVec<AtomicU64>,
     acceptor_term: GenericGaugeVec<AtomicU64>,
     collect_timeline_metrics: Gauge,
@@ -168,6 +169,16 @@ impl TimelineCollector {
         .unwrap();
         descs.extend(connected_computes.desc().into_iter().cloned());
 
+        let connected_standbys = IntGaugeVec::new(
+            Opts::new(
+                "safekeeper_connected_standbys",
+                "Number of read-only hot standby computes streaming WAL",
+            ),
+            &["tenant_id", "timeline_id"],
+        )
+        .unwrap();
+        descs.extend(connected_standbys.desc().into_iter().cloned());
+
         let disk_usage = GenericGaugeVec::new(
             Opts::new(
                 "safekeeper_disk_usage_bytes",
@@ -205,6 +216,7 @@ impl TimelineCollector {
             timeline_active,
             wal_backup_active,
             connected_computes,
+            connected_standbys,
             disk_usage,
             acceptor_term,
             collect_timeline_metrics,
@@ -232,6 +244,7 @@ impl Collector for TimelineCollector {
         self.timeline_active.reset();
         self.wal_backup_active.reset();
         self.connected_computes.reset();
+        self.connected_standbys.reset();
         self.disk_usage.reset();
         self.acceptor_term.reset();
 
@@ -243,7 +256,11 @@ impl Collector for TimelineCollector {
             let labels = &[tenant_id.as_str(), timeline_id.as_str()];
 
             let mut most_advanced: Option<utils::pq_proto::ReplicationFeedback> = None;
+            let mut num_standbys = 0;
             for replica in tli.replicas.iter() {
+                if replica.standby_reply.is_some() {
+                    num_standbys += 1;
+                }
                 if let Some(replica_feedback) = replica.pageserver_feedback {
                     if let Some(current) = most_advanced {
                         if current.ps_writelsn < replica_feedback.ps_writelsn {
@@ -282,6 +299,9 @@ impl Collector for TimelineCollector {
             self.connected_computes
                 .with_label_values(labels)
                 .set(tli.num_computes as i64);
+            self.connected_standbys
+                .with_label_values(labels)
+                .set(num_standbys);
             self.acceptor_term
                 .with_label_values(labels)
                 .set(tli.persisted_state.acceptor_state.term as u64);
@@ -323,6 +343,7 @@ impl Collector for TimelineCollector {
         mfs.extend(self.timeline_active.collect());
         mfs.extend(self.wal_backup_active.collect());
         mfs.extend(self.connected_computes.collect());
+        mfs.extend(self.connected_standbys.collect());
         mfs.extend(self.disk_usage.collect());
         mfs.extend(self.acceptor_term.collect());
 
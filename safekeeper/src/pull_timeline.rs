@@ -0,0 +1,208 @@
+//! Copying a timeline from another safekeeper, e.g. to move its replica to a
+//! less loaded node. The source gives out a snapshot of the timeline state
+//! along with the end of WAL it describes (see `TimelineSnapshot`), the
+//! target downloads WAL segments up to that point via HTTP, and the copy is
+//! installed the same way as a timeline restored from remote storage. WAL
+//! written after the snapshot is fetched from peers as usual.
+//!
+//! Source might be elected in another term meanwhile, and the WAL copied
+//! then might not match the snapshot; so the snapshot is fetched once again
+//! after the copy, and the pull fails if the term has changed.
+//!
+//! Requests to the source carry safekeeper's own token, `auth_token` of
+//! `SafeKeeperConf`, never the one of the pull request.
+
+use anyhow::{bail, ensure, Context, Result};
+use std::cmp::{max, min};
+use std::fs;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tracing::*;
+
+use postgres_ffi::xlog_utils::{
+    IsPartialXLogFileName, IsXLogFileName, XLogFromFileName, XLogSegNoOffsetToRecPtr,
+};
+use utils::{lsn::Lsn, zid::ZTenantTimelineId};
+
+use crate::control_file::FileStorage;
+use crate::http::models::TimelineSnapshot;
+use crate::safekeeper::AcceptorState;
+use crate::timeline::{GlobalTimelines, Timeline};
+use crate::wal_restore::RestoreGuard;
+use crate::SafeKeeperConf;
+
+/// Snapshot of the timeline for copying it elsewhere.
+pub fn timeline_snapshot(conf: &SafeKeeperConf, tli: &Timeline) -> Result<TimelineSnapshot> {
+    let (state, flush_lsn) = tli.get_snapshot();
+    let wal_seg_size = state.server.wal_seg_size as usize;
+    if wal_seg_size == 0 {
+        bail!("timeline {} has no WAL yet", tli.zttid);
+    }
+
+    // Segments past flush_lsn might appear meanwhile; the one flush_lsn
+    // points to is needed only if it has some WAL.
+    let flush_segno = flush_lsn.segment_number(wal_seg_size);
+    let flush_offset = flush_lsn.segment_offset(wal_seg_size);
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(conf.timeline_dir(&tli.zttid))? {
+        let file_name = entry?.file_name();
+        let file_name = match file_name.to_str() {
+            Some(name) if IsXLogFileName(name) => name.to_owned(),
+            Some(name) if IsPartialXLogFileName(name) => {
+                name.trim_end_matches(".partial").to_owned()
+            }
+            _ => continue,
+        };
+        let segno = XLogFromFileName(&file_name, wal_seg_size).0;
+        if segno < flush_segno || (segno == flush_segno && flush_offset > 0) {
+            segments.push(file_name);
+        }
+    }
+    segments.sort();
+    segments.dedup();
+
+    Ok(TimelineSnapshot {
+        state,
+        flush_lsn,
+        segments,
+    })
+}
+
+/// Copy the timeline from safekeeper with HTTP API at `source_http_addr` and
+/// load it.
+pub async fn pull_timeline(
+    conf: &SafeKeeperConf,
+    zttid: ZTenantTimelineId,
+    source_http_addr: &str,
+) -> Result<Arc<Timeline>> {
+    let _guard = match RestoreGuard::new(zttid, true) {
+        Some(guard) => guard,
+        None => bail!("timeline {} is already being restored", zttid),
+    };
+
+    let client = reqwest::Client::new();
+    let base_url = format!(
+        "http://{}/v1/tenant/{}/timeline/{}",
+        source_http_addr, zttid.tenant_id, zttid.timeline_id
+    );
+    let get = |url: String| {
+        let request = client.get(url);
+        match &conf.auth_token {
+            Some(auth_token) => request.bearer_auth(auth_token),
+            None => request,
+        }
+    };
+    let get_snapshot = || async {
+        get(format!("{}/snapshot", base_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<TimelineSnapshot>()
+            .await
+            .context("failed to get timeline snapshot")
+    };
+
+    let snapshot = get_snapshot().await?;
+    let mut state = snapshot.state;
+    let flush_lsn = snapshot.flush_lsn;
+    let wal_seg_size = state.server.wal_seg_size as usize;
+    ensure!(
+        state.tenant_id == zttid.tenant_id && state.timeline_id == zttid.timeline_id,
+        "source gave out state of another timeline"
+    );
+    if snapshot.segments.is_empty() {
+        bail!("source has no WAL of timeline {}", zttid);
+    }
+    info!(
+        "pulling segments {:?} up to {} from {}",
+        snapshot.segments, flush_lsn, source_http_addr
+    );
+
+    let pull_dir = conf.timeline_dir(&zttid).with_extension("pull");
+    // leftovers of interrupted pull
+    if pull_dir.exists() {
+        fs::remove_dir_all(&pull_dir)?;
+    }
+    fs::create_dir_all(&pull_dir)?;
+
+    let flush_segno = flush_lsn.segment_number(wal_seg_size);
+    for segment_name in &snapshot.segments {
+        let segno = XLogFromFileName(segment_name, wal_seg_size).0;
+        // Source writes into the last segment meanwhile, WAL past flush_lsn
+        // might be of a term the state doesn't know about: cut it off.
+        let valid_size = if segno == flush_segno {
+            Some(flush_lsn.segment_offset(wal_seg_size))
+        } else {
+            None
+        };
+        let mut response = get(format!("{}/file/{}", base_url, segment_name))
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("failed to download segment {}", segment_name))?;
+        let local_name = match valid_size {
+            Some(_) => format!("{}.partial", segment_name),
+            None => segment_name.clone(),
+        };
+        let mut file = File::create(pull_dir.join(&local_name)).await?;
+        let mut size = 0;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+            size += chunk.len();
+        }
+        ensure!(
+            size == wal_seg_size,
+            "downloaded segment {} has size {}, expected {}",
+            segment_name,
+            size,
+            wal_seg_size
+        );
+        if let Some(valid_size) = valid_size {
+            file.set_len(valid_size as u64).await?;
+            file.set_len(wal_seg_size as u64).await?;
+        }
+        if !conf.no_sync {
+            file.sync_all().await?;
+        }
+    }
+
+    // WAL up to flush_lsn is the one the snapshot describes only if the
+    // source hasn't switched the term meanwhile, possibly truncating it.
+    let after_copy = get_snapshot().await?.state.acceptor_state;
+    if !same_term(&state.acceptor_state, &after_copy) {
+        bail!(
+            "source term changed from {} to {} while pulling timeline {}, retry",
+            state.acceptor_state.term,
+            after_copy.term,
+            zttid
+        );
+    }
+
+    let first_segno = XLogFromFileName(&snapshot.segments[0], wal_seg_size).0;
+    state.local_start_lsn = max(
+        Lsn(XLogSegNoOffsetToRecPtr(first_segno, 0, wal_seg_size)),
+        state.timeline_start_lsn,
+    );
+    state.commit_lsn = min(state.commit_lsn, flush_lsn);
+    // don't let WAL removal get ahead of what we have
+    state.peer_horizon_lsn = min(state.peer_horizon_lsn, flush_lsn);
+    state.remote_consistent_lsn = min(state.remote_consistent_lsn, flush_lsn);
+    FileStorage::create_control_file(conf, &pull_dir, &state)?;
+
+    let tli = GlobalTimelines::install_restored(conf, zttid, &pull_dir)?;
+    tli.activate().await?;
+    info!("pulled timeline up to {}", tli.get_end_of_wal());
+    Ok(tli)
+}
+
+fn same_term(before: &AcceptorState, after: &AcceptorState) -> bool {
+    before.term == after.term
+        && before.term_history.0.len() == after.term_history.0.len()
+        && before
+            .term_history
+            .0
+            .iter()
+            .zip(&after.term_history.0)
+            .all(|(a, b)| a.term == b.term && a.lsn == b.lsn)
+}
@@ -0,0 +1,169 @@
+//! Per-tenant limits on WAL retained by safekeeper. WAL is held until
+//! pageservers, peers and backup consume it, so a tenant with e.g. a stuck
+//! pageserver makes safekeeper keep its WAL forever. Once the tenant retains
+//! more than allowed, new WAL of its timelines is rejected; the proposer gets
+//! the error and reconnects, and writes resume when the WAL gets consumed.
+//!
+//! The limit is `SafeKeeperConf::max_tenant_wal_bytes` unless overridden for
+//! the tenant via HTTP API by management token only; overrides are kept in
+//! the tenant directory. Usage is counted from WAL segments on disk and
+//! refreshed by the WAL removal thread, see `update_retained_wal`.
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use metrics::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::*;
+
+use utils::zid::ZTenantId;
+
+use crate::SafeKeeperConf;
+
+/// Name of the file with limit override in the tenant directory.
+const QUOTA_FILE_NAME: &str = "wal_quota.json";
+
+/// Limit on WAL retained by the tenant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantWalQuota {
+    /// None means no limit.
+    pub max_wal_bytes: Option<u64>,
+}
+
+lazy_static! {
+    /// Overrides of the limit, loaded from disk on first use; None if the
+    /// tenant has none.
+    static ref QUOTA_OVERRIDES: Mutex<HashMap<ZTenantId, Option<TenantWalQuota>>> =
+        Mutex::new(HashMap::new());
+    /// WAL retained by the tenant as of the last WAL removal round.
+    static ref RETAINED_WAL: Mutex<HashMap<ZTenantId, u64>> = Mutex::new(HashMap::new());
+    static ref RETAINED_WAL_BYTES: IntGaugeVec = register_int_gauge_vec!(
+        "safekeeper_tenant_retained_wal_bytes",
+        "WAL retained by safekeeper, grouped by tenant",
+        &["tenant_id"]
+    )
+    .expect("Failed to register safekeeper_tenant_retained_wal_bytes gauge vec");
+    static ref QUOTA_REJECTED_APPENDS: IntCounterVec = register_int_counter_vec!(
+        "safekeeper_tenant_quota_rejected_appends_total",
+        "Number of WAL appends rejected because tenant exceeded WAL quota, grouped by tenant",
+        &["tenant_id"]
+    )
+    .expect("Failed to register safekeeper_tenant_quota_rejected_appends_total counter vec");
+}
+
+fn quota_file_path(conf: &SafeKeeperConf, tenant_id: &ZTenantId) -> PathBuf {
+    conf.tenant_dir(tenant_id).join(QUOTA_FILE_NAME)
+}
+
+fn load_override(conf: &SafeKeeperConf, tenant_id: &ZTenantId) -> Result<Option<TenantWalQuota>> {
+    let path = quota_file_path(conf, tenant_id);
+    match fs::read(&path) {
+        Ok(buf) => Ok(Some(serde_json::from_slice(&buf).with_context(|| {
+            format!("failed to parse WAL quota file {}", path.display())
+        })?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+/// Limit in effect for the tenant.
+pub fn get_quota(conf: &SafeKeeperConf, tenant_id: &ZTenantId) -> Result<TenantWalQuota> {
+    let mut overrides = QUOTA_OVERRIDES.lock().unwrap();
+    let quota_override = match overrides.get(tenant_id) {
+        Some(quota_override) => *quota_override,
+        None => {
+            let quota_override = load_override(conf, tenant_id)?;
+            overrides.insert(*tenant_id, quota_override);
+            quota_override
+        }
+    };
+    Ok(quota_override.unwrap_or(TenantWalQuota {
+        max_wal_bytes: conf.max_tenant_wal_bytes,
+    }))
+}
+
+/// Override the limit for the tenant, or get back to the default one if
+/// `quota` is None.
+pub fn set_quota(
+    conf: &SafeKeeperConf,
+    tenant_id: &ZTenantId,
+    quota: Option<TenantWalQuota>,
+) -> Result<()> {
+    let mut overrides = QUOTA_OVERRIDES.lock().unwrap();
+    let path = quota_file_path(conf, tenant_id);
+    match quota {
+        Some(quota) => {
+            fs::create_dir_all(conf.tenant_dir(tenant_id))?;
+            let tmp_path = path.with_extension("partial");
+            fs::write(&tmp_path, serde_json::to_vec(&quota)?)?;
+            fs::rename(&tmp_path, &path)?;
+        }
+        None => {
+            if let Err(e) = fs::remove_file(&path) {
+                if e.kind() != ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+    overrides.insert(*tenant_id, quota);
+    info!("set WAL quota of tenant {} to {:?}", tenant_id, quota);
+    Ok(())
+}
+
+/// Drop cached override of the deleted tenant.
+pub fn forget_tenant(tenant_id: &ZTenantId) {
+    QUOTA_OVERRIDES.lock().unwrap().remove(tenant_id);
+}
+
+/// WAL retained by the tenant as of the last WAL removal round.
+pub fn get_retained_wal(tenant_id: &ZTenantId) -> u64 {
+    RETAINED_WAL
+        .lock()
+        .unwrap()
+        .get(tenant_id)
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Record WAL retained by each tenant having timelines loaded.
+pub fn update_retained_wal(retained: HashMap<ZTenantId, u64>) {
+    let mut retained_wal = RETAINED_WAL.lock().unwrap();
+    for tenant_id in retained_wal.keys() {
+        if !retained.contains_key(tenant_id) {
+            let _ = RETAINED_WAL_BYTES.remove_label_values(&[&tenant_id.to_string()]);
+        }
+    }
+    for (tenant_id, bytes) in &retained {
+        RETAINED_WAL_BYTES
+            .with_label_values(&[&tenant_id.to_string()])
+            .set(*bytes as i64);
+    }
+    *retained_wal = retained;
+}
+
+/// Check whether the tenant is allowed to write more WAL.
+pub fn check_append(conf: &SafeKeeperConf, tenant_id: &ZTenantId) -> Result<()> {
+    let max_wal_bytes = match get_quota(conf, tenant_id)?.max_wal_bytes {
+        Some(max_wal_bytes) => max_wal_bytes,
+        None => return Ok(()),
+    };
+    let retained = get_retained_wal(tenant_id);
+    if retained > max_wal_bytes {
+        QUOTA_REJECTED_APPENDS
+            .with_label_values(&[&tenant_id.to_string()])
+            .inc();
+        bail!(
+            "tenant {} exceeded WAL quota: safekeeper retains {} bytes of its WAL, limit is {} bytes; \
+             new WAL is accepted once pageserver and backup catch up",
+            tenant_id,
+            retained,
+            max_wal_bytes
+        );
+    }
+    Ok(())
+}
@@ -7,6 +7,7 @@ use anyhow::{anyhow, bail, Result};
 use bytes::BytesMut;
 use tracing::*;
 
+use crate::quota;
 use crate::timeline::Timeline;
 
 use std::net::SocketAddr;
@@ -82,7 +83,20 @@ impl<'pg> ReceiveWalConn<'pg> {
         let mut first_time_through = true;
         let mut _guard: Option<ComputeConnectionGuard> = None;
         loop {
-            if matches!(next_msg, Some(ProposerAcceptorMessage::AppendRequest(_))) {
+            if let Some(ProposerAcceptorMessage::AppendRequest(ref append_request)) = next_msg {
+                // Heartbeats are fine, new WAL is admitted if the tenant
+                // doesn't retain too much of it already.
+                if !append_request.wal_data.is_empty() {
+                    let tenant_id = spg.timeline.get().zttid.tenant_id;
+                    if let Err(e) = quota::check_append(&spg.conf, &tenant_id) {
+                        // Send the reason right away: only top level context
+                        // of the returned error reaches the proposer.
+                        self.pg_backend
+                            .write_message(&BeMessage::ErrorResponse(&e.to_string()))?;
+                        return Err(e);
+                    }
+                }
+
                 // poll AppendRequest's without blocking and write WAL to disk without flushing,
                 // while it's readily available
                 while let Some(ProposerAcceptorMessage::AppendRequest(append_request)) = next_msg {
@@ -0,0 +1,138 @@
+//! Peer recovery: a safekeeper lagging behind its peers (e.g. after being
+//! down for a while) fetches the committed WAL it misses directly from a peer
+//! via START_REPLICATION, instead of waiting for a compute to resend it.
+//!
+//! Peers are learnt from the broker, see `Timeline::record_safekeeper_info`.
+//! The fetched WAL is safe to adopt as long as the peer's last term is our
+//! epoch (then its WAL continues ours) and it is committed (then no proposer
+//! will ever truncate it).
+
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use postgres_protocol::message::backend::ReplicationMessage;
+use std::cmp::min;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_postgres::replication::ReplicationStream;
+use tracing::*;
+
+use utils::{
+    connstring::connection_host_port,
+    lsn::Lsn,
+    zid::{NodeId, ZTenantTimelineId},
+};
+
+use crate::safekeeper::Term;
+use crate::timeline::{GlobalTimelines, Timeline};
+
+/// Application name of recovery connections, mostly for logs of the donor.
+const RECOVERY_APPNAME: &str = "safekeeper_recovery";
+
+/// Give up if donor hasn't sent anything for so long; next broker message
+/// from a peer will restart recovery.
+const RECOVERY_RECV_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peer safekeeper to fetch WAL from.
+#[derive(Debug, Clone)]
+pub struct Donor {
+    pub sk_id: NodeId,
+    /// Postgres protocol address of the donor, as advertised in the broker.
+    pub pg_connstr: String,
+    /// Last log term of the donor, must be our epoch.
+    pub term: Term,
+    /// WAL is fetched up to this LSN: it is known to be committed.
+    pub commit_lsn: Lsn,
+}
+
+/// Allows to start recovery again once the task is done, even if it panicked.
+struct RecoveryGuard {
+    timeline: Arc<Timeline>,
+}
+
+impl Drop for RecoveryGuard {
+    fn drop(&mut self) {
+        self.timeline.finish_recovery();
+    }
+}
+
+/// Fetch WAL of a single timeline from the donor.
+pub async fn recovery_main(zttid: ZTenantTimelineId, donor: Donor) {
+    let timeline = if let Some(tli) = GlobalTimelines::get_loaded(zttid) {
+        tli
+    } else {
+        /* Timeline could get deleted while task was starting, just exit then. */
+        info!("no timeline, exiting");
+        return;
+    };
+    let guard = RecoveryGuard { timeline };
+
+    info!(
+        "recovering WAL from safekeeper {} at {} up to {}",
+        donor.sk_id, donor.pg_connstr, donor.commit_lsn
+    );
+    match recover(&guard.timeline, &donor).await {
+        Ok(end_lsn) => info!("recovered WAL up to {}", end_lsn),
+        Err(e) => warn!("recovery from safekeeper {} failed: {:#}", donor.sk_id, e),
+    }
+}
+
+/// Connection string to the donor for streaming WAL of the timeline.
+fn donor_connection_string(zttid: ZTenantTimelineId, pg_connstr: &str) -> Result<String> {
+    let sk_connstr = format!("postgresql://no_user@{pg_connstr}/no_db");
+    let config = sk_connstr
+        .parse::<postgres::config::Config>()
+        .with_context(|| format!("failed to parse safekeeper address '{pg_connstr}'"))?;
+    let (host, port) = connection_host_port(&config);
+    Ok(format!(
+        "host={host} port={port} options='-c ztimelineid={} ztenantid={}' application_name={RECOVERY_APPNAME} replication=true",
+        zttid.timeline_id, zttid.tenant_id
+    ))
+}
+
+/// Stream WAL from the donor till its commit_lsn, returns where we stopped.
+async fn recover(timeline: &Arc<Timeline>, donor: &Donor) -> Result<Lsn> {
+    let mut pos = timeline.start_recovery()?;
+
+    let connstr = donor_connection_string(timeline.zttid, &donor.pg_connstr)?;
+    let (client, connection) = tokio_postgres::connect(&connstr, postgres::NoTls)
+        .await
+        .context("failed to connect to donor")?;
+    // Connection is closed once the client is dropped.
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            info!("connection to donor closed: {}", e);
+        }
+    });
+
+    let query = format!("START_REPLICATION PHYSICAL {pos}");
+    let copy_stream = client.copy_both_simple(&query).await?;
+    let physical_stream = ReplicationStream::new(copy_stream);
+    tokio::pin!(physical_stream);
+
+    while pos < donor.commit_lsn {
+        let msg = timeout(RECOVERY_RECV_TIMEOUT, physical_stream.next())
+            .await
+            .with_context(|| format!("no WAL from donor at {pos}"))?;
+        let xlog_data = match msg {
+            Some(msg) => match msg? {
+                ReplicationMessage::XLogData(xlog_data) => xlog_data,
+                _ => continue, // keepalives
+            },
+            None => bail!("donor closed the stream at {}", pos),
+        };
+
+        let start_lsn = Lsn::from(xlog_data.wal_start());
+        let end_lsn = min(start_lsn + xlog_data.data().len() as u64, donor.commit_lsn);
+        let data = xlog_data.data().slice(..(end_lsn.0 - start_lsn.0) as usize);
+        trace!("received WAL between {} and {}", start_lsn, end_lsn);
+
+        // Writing WAL blocks, don't hold up the runtime.
+        let tli = Arc::clone(timeline);
+        let term = donor.term;
+        tokio::task::spawn_blocking(move || tli.write_recovered_wal(term, start_lsn, &data))
+            .await??;
+        pos = end_lsn;
+    }
+    Ok(pos)
+}
@@ -4,7 +4,7 @@ use std::{thread, time::Duration};
 
 use tracing::*;
 
-use crate::{timeline::GlobalTimelines, SafeKeeperConf};
+use crate::{quota, timeline::GlobalTimelines, SafeKeeperConf};
 
 pub fn thread_main(conf: SafeKeeperConf) {
     let wal_removal_interval = Duration::from_millis(5000);
@@ -12,7 +12,7 @@ pub fn thread_main(conf: SafeKeeperConf) {
         let active_tlis = GlobalTimelines::get_active_timelines();
         for zttid in &active_tlis {
             if let Ok(tli) = GlobalTimelines::get(&conf, *zttid, false) {
-                if let Err(e) = tli.remove_old_wal(conf.wal_backup_enabled) {
+                if let Err(e) = tli.remove_old_wal(&conf) {
                     warn!(
                         "failed to remove WAL for tenant {} timeline {}: {}",
                         tli.zttid.tenant_id, tli.zttid.timeline_id, e
@@ -20,6 +20,7 @@ pub fn thread_main(conf: SafeKeeperConf) {
                 }
             }
         }
+        quota::update_retained_wal(GlobalTimelines::retained_wal_by_tenant(&conf));
         thread::sleep(wal_removal_interval)
     }
 }
@@ -11,6 +11,7 @@ use postgres_ffi::xlog_utils::XLogSegNo;
 use serde::{Deserialize, Serialize};
 use std::cmp::max;
 use std::cmp::min;
+use std::collections::HashMap;
 use std::fmt;
 use std::io::Read;
 use tracing::*;
@@ -28,7 +29,7 @@ use utils::{
 };
 
 pub const SK_MAGIC: u32 = 0xcafeceefu32;
-pub const SK_FORMAT_VERSION: u32 = 6;
+pub const SK_FORMAT_VERSION: u32 = 9;
 const SK_PROTOCOL_VERSION: u32 = 2;
 const UNKNOWN_SERVER_VERSION: u32 = 0;
 
@@ -100,6 +101,196 @@ impl fmt::Debug for TermHistory {
     }
 }
 
+/// Set of safekeepers storing the timeline WAL.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberSet(pub Vec<NodeId>);
+
+impl MemberSet {
+    pub fn contains(&self, node_id: NodeId) -> bool {
+        self.0.contains(&node_id)
+    }
+
+    /// Whether both sets have the same members, in any order.
+    fn same_members(&self, other: &MemberSet) -> bool {
+        let mut ours = self.0.clone();
+        let mut theirs = other.0.clone();
+        ours.sort();
+        theirs.sort();
+        ours == theirs
+    }
+
+    /// Highest LSN flushed by the majority of the members.
+    pub fn quorum_lsn(&self, flush_lsn: impl Fn(NodeId) -> Lsn) -> Lsn {
+        let mut lsns: Vec<Lsn> = self.0.iter().map(|id| flush_lsn(*id)).collect();
+        lsns.sort_unstable_by(|a, b| b.cmp(a));
+        lsns.get(lsns.len() / 2).copied().unwrap_or(Lsn(0))
+    }
+}
+
+/// Membership configuration of the timeline safekeepers.
+///
+/// Members are changed in two steps, as in Raft joint consensus: first the
+/// joint configuration with both old and new member sets is switched to, in
+/// which elections and commits require a majority of each set; once it is
+/// committed, the new member set is switched to alone. Thus neither set can
+/// make decisions on its own during the change.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Configuration {
+    /// Increases with every switch; 0 means the configuration comes from
+    /// `peers` given on timeline creation and was never switched.
+    pub generation: u64,
+    pub members: MemberSet,
+    /// Present while the configuration is joint.
+    pub new_members: Option<MemberSet>,
+}
+
+impl Configuration {
+    pub fn is_joint(&self) -> bool {
+        self.new_members.is_some()
+    }
+
+    /// Whether the node is a member. Any node is until the members are
+    /// configured.
+    pub fn is_member(&self, node_id: NodeId) -> bool {
+        self.generation == 0 || self.contains(node_id)
+    }
+
+    /// Whether the node is in either member set.
+    pub fn contains(&self, node_id: NodeId) -> bool {
+        self.members.contains(node_id)
+            || self
+                .new_members
+                .as_ref()
+                .map_or(false, |new_members| new_members.contains(node_id))
+    }
+
+    /// Highest LSN acknowledged by the quorum of the configuration; in the
+    /// joint one, it requires the majority of each member set.
+    pub fn quorum_lsn(&self, flush_lsn: impl Fn(NodeId) -> Lsn) -> Lsn {
+        let lsn = self.members.quorum_lsn(&flush_lsn);
+        match &self.new_members {
+            Some(new_members) => min(lsn, new_members.quorum_lsn(&flush_lsn)),
+            None => lsn,
+        }
+    }
+
+    /// Check that switching from this configuration to the given one is
+    /// allowed: members change only through the joint configuration, one
+    /// change at a time. Generation gaps mean the acceptor missed some
+    /// switches, e.g. it was down or just added, then it adopts the
+    /// configuration as is.
+    fn check_switch(&self, new: &Configuration) -> Result<()> {
+        if new.generation <= self.generation {
+            bail!(
+                "generation {} is not newer than current {}",
+                new.generation,
+                self.generation
+            );
+        }
+        if new.members.0.is_empty()
+            || new
+                .new_members
+                .as_ref()
+                .map_or(false, |new_members| new_members.0.is_empty())
+        {
+            bail!("member set is empty");
+        }
+        if self.generation == 0 || new.generation > self.generation + 1 {
+            return Ok(());
+        }
+        if self.is_joint() {
+            bail!("membership change to {:?} is in progress", self.new_members);
+        }
+        if !new.is_joint() {
+            bail!("members can be changed through the joint configuration only");
+        }
+        if !new.members.same_members(&self.members) {
+            bail!(
+                "joint configuration members {:?} differ from current {:?}",
+                new.members,
+                self.members
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Switch to the membership configuration, effective since the LSN; like
+/// term switches, configuration switches beyond the WAL which is truncated
+/// on election are not committed and get dropped with it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigSwitchEntry {
+    pub lsn: Lsn,
+    pub config: Configuration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigHistory(pub Vec<ConfigSwitchEntry>);
+
+impl ConfigHistory {
+    pub fn empty() -> ConfigHistory {
+        ConfigHistory(Vec::new())
+    }
+
+    /// Return copy of self with switches happening strictly after up_to
+    /// truncated.
+    pub fn up_to(&self, up_to: Lsn) -> ConfigHistory {
+        ConfigHistory(
+            self.0
+                .iter()
+                .take_while(|e| e.lsn <= up_to)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// How much WAL to keep locally beyond what peers, pageserver and backup
+/// need, e.g. for logical decoding or slow consumers. WAL is kept while any
+/// of the limits requires it; zero disables the limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Keep at least this many bytes of WAL before flush_lsn.
+    #[serde(default)]
+    pub min_bytes: u64,
+    /// Keep segments written less than this many seconds ago.
+    #[serde(default)]
+    pub min_age_secs: u64,
+}
+
+/// Physical replication slot of an external WAL consumer, e.g.
+/// pg_receivewal: WAL it hasn't flushed yet is not removed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicationSlot {
+    pub name: String,
+    /// Oldest LSN the consumer still needs: the flush position it reported,
+    /// or end of WAL at slot creation.
+    pub restart_lsn: Lsn,
+}
+
+/// Max length of replication slot name, as in Postgres (NAMEDATALEN - 1).
+const MAX_SLOT_NAME_LEN: usize = 63;
+
+/// Slot names are restricted the same way Postgres does it.
+fn check_slot_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_SLOT_NAME_LEN {
+        bail!(
+            "replication slot name must be 1 to {} characters long",
+            MAX_SLOT_NAME_LEN
+        );
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        bail!(
+            "replication slot name {:?} contains invalid character, only lower case letters, numbers and underscore are allowed",
+            name
+        );
+    }
+    Ok(())
+}
+
 /// Unique id of proposer. Not needed for correctness, used for monitoring.
 pub type PgUuid = [u8; 16];
 
@@ -208,6 +399,13 @@ pub struct SafeKeeperState {
     // obviously can be stale. (Currently not saved at all, but let's provision
     // place to have less file version upgrades).
     pub peers: Peers,
+    /// Membership configuration switches, the last one is current.
+    /// Empty if members were never changed, then `peers` are the members.
+    pub config_history: ConfigHistory,
+    /// Extra WAL kept locally, see `RetentionPolicy`.
+    pub retention_policy: RetentionPolicy,
+    /// Replication slots of external WAL consumers.
+    pub replication_slots: Vec<ReplicationSlot>,
 }
 
 #[derive(Debug, Clone)]
@@ -219,6 +417,7 @@ pub struct SafekeeperMemState {
     pub peer_horizon_lsn: Lsn,
     pub remote_consistent_lsn: Lsn,
     pub proposer_uuid: PgUuid,
+    pub replication_slots: Vec<ReplicationSlot>,
 }
 
 impl SafeKeeperState {
@@ -243,6 +442,21 @@ impl SafeKeeperState {
             peer_horizon_lsn: Lsn(0),
             remote_consistent_lsn: Lsn(0),
             peers: Peers(peers.iter().map(|p| (*p, PeerInfo::new())).collect()),
+            config_history: ConfigHistory::empty(),
+            retention_policy: RetentionPolicy::default(),
+            replication_slots: Vec::new(),
+        }
+    }
+
+    /// Current membership configuration.
+    pub fn configuration(&self) -> Configuration {
+        match self.config_history.0.last() {
+            Some(e) => e.config.clone(),
+            None => Configuration {
+                generation: 0,
+                members: MemberSet(self.peers.0.iter().map(|(id, _)| *id).collect()),
+                new_members: None,
+            },
         }
     }
 
@@ -351,6 +565,26 @@ pub struct AppendResponse {
     pub pageserver_feedback: ReplicationFeedback,
 }
 
+/// Proposer -> Acceptor request to switch the membership configuration,
+/// effective since the given LSN.
+#[derive(Debug)]
+pub struct MembershipChangeRequest {
+    pub term: Term,
+    pub lsn: Lsn,
+    pub config: Configuration,
+}
+
+/// Acceptor -> Proposer reply to MembershipChangeRequest.
+#[derive(Debug, Serialize)]
+pub struct MembershipChangeResponse {
+    // Current term of the safekeeper; if it is higher than proposer's, the
+    // compute is out of date.
+    pub term: Term,
+    pub accepted: u64, // u64 due to padding
+    /// Generation of the current configuration, after the switch if accepted.
+    pub generation: u64,
+}
+
 impl AppendResponse {
     fn term_only(term: Term) -> AppendResponse {
         AppendResponse {
@@ -372,6 +606,7 @@ pub enum ProposerAcceptorMessage {
     AppendRequest(AppendRequest),
     NoFlushAppendRequest(AppendRequest),
     FlushWAL,
+    MembershipChange(MembershipChangeRequest),
 }
 
 impl ProposerAcceptorMessage {
@@ -432,17 +667,60 @@ impl ProposerAcceptorMessage {
 
                 Ok(ProposerAcceptorMessage::AppendRequest(msg))
             }
+            'm' => {
+                let mut msg_bytes = stream.into_inner();
+                if msg_bytes.remaining() < 24 {
+                    bail!("MembershipChangeRequest message is not complete");
+                }
+                let term = msg_bytes.get_u64_le();
+                let lsn = msg_bytes.get_u64_le().into();
+                let generation = msg_bytes.get_u64_le();
+                let members = parse_member_set(&mut msg_bytes)?;
+                let new_members = parse_member_set(&mut msg_bytes)?;
+                let msg = MembershipChangeRequest {
+                    term,
+                    lsn,
+                    config: Configuration {
+                        generation,
+                        members,
+                        // joint configuration always has new members
+                        new_members: if new_members.0.is_empty() {
+                            None
+                        } else {
+                            Some(new_members)
+                        },
+                    },
+                };
+                Ok(ProposerAcceptorMessage::MembershipChange(msg))
+            }
             _ => bail!("unknown proposer-acceptor message tag: {}", tag,),
         }
     }
 }
 
+// Parse MemberSet as n_members followed by node ids
+fn parse_member_set(bytes: &mut Bytes) -> Result<MemberSet> {
+    if bytes.remaining() < 4 {
+        bail!("MemberSet misses len");
+    }
+    let n_members = bytes.get_u32_le();
+    let mut res = Vec::with_capacity(n_members as usize);
+    for _ in 0..n_members {
+        if bytes.remaining() < 8 {
+            bail!("MemberSet is incomplete");
+        }
+        res.push(NodeId(bytes.get_u64_le()));
+    }
+    Ok(MemberSet(res))
+}
+
 /// Acceptor -> Proposer messages
 #[derive(Debug)]
 pub enum AcceptorProposerMessage {
     Greeting(AcceptorGreeting),
     VoteResponse(VoteResponse),
     AppendResponse(AppendResponse),
+    MembershipChangeResponse(MembershipChangeResponse),
 }
 
 impl AcceptorProposerMessage {
@@ -478,6 +756,12 @@ impl AcceptorProposerMessage {
 
                 msg.pageserver_feedback.serialize(buf)?
             }
+            AcceptorProposerMessage::MembershipChangeResponse(msg) => {
+                buf.put_u64_le('m' as u64);
+                buf.put_u64_le(msg.term);
+                buf.put_u64_le(msg.accepted);
+                buf.put_u64_le(msg.generation);
+            }
         }
 
         Ok(())
@@ -501,6 +785,9 @@ pub struct SafeKeeper<CTRL: control_file::Storage, WAL: wal_storage::Storage> {
     pub wal_store: WAL,
 
     node_id: NodeId, // safekeeper's node id
+    /// Last log term and flush_lsn of the peers, as reported by the broker;
+    /// used to count the joint configuration quorum.
+    peer_flush_lsns: HashMap<NodeId, (Term, Lsn)>,
 }
 
 impl<CTRL, WAL> SafeKeeper<CTRL, WAL>
@@ -531,10 +818,12 @@ where
                 peer_horizon_lsn: state.peer_horizon_lsn,
                 remote_consistent_lsn: state.remote_consistent_lsn,
                 proposer_uuid: state.proposer_uuid,
+                replication_slots: state.replication_slots.clone(),
             },
             state,
             wal_store,
             node_id,
+            peer_flush_lsns: HashMap::new(),
         })
     }
 
@@ -551,7 +840,7 @@ where
     }
 
     /// wal_store wrapper avoiding commit_lsn <= flush_lsn violation when we don't have WAL yet.
-    fn flush_lsn(&self) -> Lsn {
+    pub fn flush_lsn(&self) -> Lsn {
         max(self.wal_store.flush_lsn(), self.state.timeline_start_lsn)
     }
 
@@ -570,9 +859,15 @@ where
                 self.handle_append_request(msg, false)
             }
             ProposerAcceptorMessage::FlushWAL => self.handle_flush(),
+            ProposerAcceptorMessage::MembershipChange(msg) => self.handle_membership_change(msg),
         }
     }
 
+    /// Whether the safekeeper is a member of the timeline.
+    pub fn is_member(&self) -> bool {
+        self.state.configuration().is_member(self.node_id)
+    }
+
     /// Handle initial message from proposer: check its sanity and send my
     /// current term.
     fn handle_greeting(
@@ -646,7 +941,9 @@ where
             term_history: self.get_term_history(),
             timeline_start_lsn: self.state.timeline_start_lsn,
         };
-        if self.state.acceptor_state.term < msg.term {
+        // Safekeepers removed from the members don't vote, not to disrupt
+        // elections among the remaining ones.
+        if self.state.acceptor_state.term < msg.term && self.is_member() {
             let mut state = self.state.clone();
             state.acceptor_state.term = msg.term;
             // persist vote before sending it out
@@ -729,6 +1026,8 @@ where
             self.inmem.backup_lsn = max(self.inmem.backup_lsn, state.timeline_start_lsn);
 
             state.acceptor_state.term_history = msg.term_history.clone();
+            // configuration switches in the truncated WAL were not committed
+            state.config_history = state.config_history.up_to(msg.start_streaming_at);
             self.persist_control_file(state)?;
         }
 
@@ -739,7 +1038,16 @@ where
 
     /// Advance commit_lsn taking into account what we have locally
     pub fn update_commit_lsn(&mut self) -> Result<()> {
-        let commit_lsn = min(self.global_commit_lsn, self.flush_lsn());
+        let mut commit_lsn = min(self.global_commit_lsn, self.flush_lsn());
+        // Past the joint configuration switch, WAL is committed once the
+        // majority of each member set has it, while the proposer might count
+        // acknowledgements of the old members only.
+        if let Some(e) = self.state.config_history.0.last() {
+            if e.config.is_joint() && commit_lsn > e.lsn {
+                let quorum_lsn = max(e.lsn, self.quorum_flush_lsn(&e.config));
+                commit_lsn = max(self.inmem.commit_lsn, min(commit_lsn, quorum_lsn));
+            }
+        }
         assert!(commit_lsn >= self.inmem.commit_lsn);
 
         self.inmem.commit_lsn = commit_lsn;
@@ -755,6 +1063,221 @@ where
             self.persist_control_file(self.state.clone())?;
         }
 
+        self.complete_membership_change()
+    }
+
+    /// Highest LSN flushed by the quorum of the configuration, as far as we
+    /// know: only peers with the same last log term are counted.
+    fn quorum_flush_lsn(&self, config: &Configuration) -> Lsn {
+        let epoch = self.get_epoch();
+        config.quorum_lsn(|id| {
+            if id == self.node_id {
+                return self.flush_lsn();
+            }
+            match self.peer_flush_lsns.get(&id) {
+                Some((term, flush_lsn)) if *term == epoch => *flush_lsn,
+                _ => Lsn(0),
+            }
+        })
+    }
+
+    /// Switch from the joint configuration to the new members alone, once the
+    /// joint one is committed and the new members have the WAL up to it. All
+    /// safekeepers switch at the LSN of the joint configuration, so this needs
+    /// no proposer round trip and writes continue.
+    fn complete_membership_change(&mut self) -> Result<()> {
+        let (lsn, joint_config) = match self.state.config_history.0.last() {
+            Some(e)
+                if e.config.is_joint()
+                    && e.lsn <= self.inmem.commit_lsn
+                    && e.lsn <= self.quorum_flush_lsn(&e.config) =>
+            {
+                (e.lsn, e.config.clone())
+            }
+            _ => return Ok(()),
+        };
+        let new_members = joint_config.new_members.unwrap();
+
+        let mut state = self.state.clone();
+        // keep what we know about the remaining peers
+        state.peers = Peers(
+            new_members
+                .0
+                .iter()
+                .map(|id| {
+                    let info = self
+                        .state
+                        .peers
+                        .0
+                        .iter()
+                        .find(|(peer_id, _)| peer_id == id)
+                        .map_or_else(PeerInfo::new, |(_, info)| info.clone());
+                    (*id, info)
+                })
+                .collect(),
+        );
+        let config = Configuration {
+            generation: joint_config.generation + 1,
+            members: new_members,
+            new_members: None,
+        };
+        if !config.contains(self.node_id) {
+            info!(
+                "safekeeper {} is removed from the timeline members {:?}",
+                self.node_id, config.members
+            );
+        }
+        info!(
+            "completed membership change, switching to {:?} at {}",
+            config, lsn
+        );
+        state
+            .config_history
+            .0
+            .push(ConfigSwitchEntry { lsn, config });
+        self.persist_control_file(state)
+    }
+
+    /// Handle request to switch the membership configuration.
+    fn handle_membership_change(
+        &mut self,
+        msg: &MembershipChangeRequest,
+    ) -> Result<Option<AcceptorProposerMessage>> {
+        if self.state.acceptor_state.term < msg.term {
+            bail!("got MembershipChangeRequest before ProposerElected");
+        }
+
+        // initialize with refusal
+        let current = self.state.configuration();
+        let mut resp = MembershipChangeResponse {
+            term: self.state.acceptor_state.term,
+            accepted: false as u64,
+            generation: current.generation,
+        };
+        // If our term is higher, immediately refuse the message.
+        if self.state.acceptor_state.term > msg.term {
+            return Ok(Some(AcceptorProposerMessage::MembershipChangeResponse(
+                resp,
+            )));
+        }
+
+        // the request might be repeated, e.g. after reconnection
+        if msg.config == current {
+            resp.accepted = true as u64;
+            return Ok(Some(AcceptorProposerMessage::MembershipChangeResponse(
+                resp,
+            )));
+        }
+        if let Err(e) = current.check_switch(&msg.config) {
+            info!(
+                "refusing membership change from {:?} to {:?}: {}",
+                current, msg.config, e
+            );
+            return Ok(Some(AcceptorProposerMessage::MembershipChangeResponse(
+                resp,
+            )));
+        }
+        if let Some(last) = self.state.config_history.0.last() {
+            if msg.lsn < last.lsn {
+                info!(
+                    "refusing membership change to {:?} at {}, previous switch is at {}",
+                    msg.config, msg.lsn, last.lsn
+                );
+                return Ok(Some(AcceptorProposerMessage::MembershipChangeResponse(
+                    resp,
+                )));
+            }
+        }
+
+        let mut state = self.state.clone();
+        state.config_history.0.push(ConfigSwitchEntry {
+            lsn: msg.lsn,
+            config: msg.config.clone(),
+        });
+        self.persist_control_file(state)?;
+        info!(
+            "switched to membership configuration {:?} at {}",
+            msg.config, msg.lsn
+        );
+        // the switch might be committed already
+        self.complete_membership_change()?;
+
+        resp.accepted = true as u64;
+        resp.generation = self.state.configuration().generation;
+        Ok(Some(AcceptorProposerMessage::MembershipChangeResponse(
+            resp,
+        )))
+    }
+
+    /// Set policy of keeping WAL locally; it takes effect on the next WAL
+    /// removal round.
+    pub fn set_retention_policy(&mut self, policy: RetentionPolicy) -> Result<()> {
+        let mut state = self.state.clone();
+        state.retention_policy = policy;
+        self.persist_control_file(state)?;
+        info!("set WAL retention policy {:?}", policy);
+        Ok(())
+    }
+
+    /// Create replication slot keeping WAL since the current end of it,
+    /// returns that LSN.
+    pub fn create_replication_slot(&mut self, name: &str) -> Result<Lsn> {
+        check_slot_name(name)?;
+        if self.inmem.replication_slots.iter().any(|s| s.name == name) {
+            bail!("replication slot {:?} already exists", name);
+        }
+        let restart_lsn = self.flush_lsn();
+        self.inmem.replication_slots.push(ReplicationSlot {
+            name: name.to_owned(),
+            restart_lsn,
+        });
+        self.persist_control_file(self.state.clone())?;
+        info!("created replication slot {:?} at {}", name, restart_lsn);
+        Ok(restart_lsn)
+    }
+
+    pub fn drop_replication_slot(&mut self, name: &str) -> Result<()> {
+        let pos = match self
+            .inmem
+            .replication_slots
+            .iter()
+            .position(|s| s.name == name)
+        {
+            Some(pos) => pos,
+            None => bail!("replication slot {:?} does not exist", name),
+        };
+        self.inmem.replication_slots.remove(pos);
+        self.persist_control_file(self.state.clone())?;
+        info!("dropped replication slot {:?}", name);
+        Ok(())
+    }
+
+    /// Move the slot forward to the position the consumer flushed. Like other
+    /// horizons, it is synced to disk only once it moves far enough.
+    pub fn advance_replication_slot(&mut self, name: &str, flush_lsn: Lsn) -> Result<()> {
+        let slot = match self
+            .inmem
+            .replication_slots
+            .iter_mut()
+            .find(|s| s.name == name)
+        {
+            Some(slot) => slot,
+            None => bail!("replication slot {:?} does not exist", name),
+        };
+        // don't let consumer make us remove WAL it hasn't seen
+        let new_restart_lsn = max(slot.restart_lsn, min(flush_lsn, self.wal_store.flush_lsn()));
+        slot.restart_lsn = new_restart_lsn;
+        let persisted_lsn = self
+            .state
+            .replication_slots
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.restart_lsn);
+        if let Some(persisted_lsn) = persisted_lsn {
+            if persisted_lsn + (self.state.server.wal_seg_size as u64) < new_restart_lsn {
+                self.persist_control_file(self.state.clone())?;
+            }
+        }
         Ok(())
     }
 
@@ -765,6 +1288,7 @@ where
         state.peer_horizon_lsn = self.inmem.peer_horizon_lsn;
         state.remote_consistent_lsn = self.inmem.remote_consistent_lsn;
         state.proposer_uuid = self.inmem.proposer_uuid;
+        state.replication_slots = self.inmem.replication_slots.clone();
         self.state.persist(&state)
     }
 
@@ -850,8 +1374,21 @@ where
     }
 
     /// Update timeline state with peer safekeeper data.
-    pub fn record_safekeeper_info(&mut self, sk_info: &SkTimelineInfo) -> Result<()> {
+    pub fn record_safekeeper_info(
+        &mut self,
+        sk_id: NodeId,
+        sk_info: &SkTimelineInfo,
+    ) -> Result<()> {
         let mut sync_control_file = false;
+        if let (Some(flush_lsn), Some(last_log_term)) = (sk_info.flush_lsn, sk_info.last_log_term) {
+            let peer_flush_lsn = self
+                .peer_flush_lsns
+                .entry(sk_id)
+                .or_insert((last_log_term, flush_lsn));
+            if *peer_flush_lsn < (last_log_term, flush_lsn) {
+                *peer_flush_lsn = (last_log_term, flush_lsn);
+            }
+        }
         if let (Some(commit_lsn), Some(last_log_term)) = (sk_info.commit_lsn, sk_info.last_log_term)
         {
             // Note: the check is too restrictive, generally we can update local
@@ -889,9 +1426,47 @@ where
         Ok(())
     }
 
+    /// Prepare to append WAL fetched from a peer safekeeper: cut off the
+    /// incomplete record at the end of WAL, if any, and return the LSN to
+    /// fetch WAL from.
+    pub fn start_recovery(&mut self) -> Result<Lsn> {
+        let flush_lsn = self.flush_lsn();
+        self.wal_store.truncate_wal(flush_lsn)?;
+        Ok(flush_lsn)
+    }
+
+    /// Append WAL fetched from a peer safekeeper instead of the proposer. Peer
+    /// WAL continues ours only if its last term is our epoch, so the caller
+    /// passes the term the peer advertised and we refuse the WAL if our epoch
+    /// changed meanwhile. Only WAL known to be committed is accepted: it is
+    /// never truncated, so no proposer will ever disagree with it.
+    pub fn write_recovered_wal(&mut self, term: Term, start_lsn: Lsn, buf: &[u8]) -> Result<()> {
+        let epoch = self.get_epoch();
+        if epoch != term {
+            bail!(
+                "epoch changed to {} while recovering WAL of term {}",
+                epoch,
+                term
+            );
+        }
+        let end_lsn = start_lsn + buf.len() as u64;
+        if end_lsn > self.global_commit_lsn {
+            bail!(
+                "recovered WAL up to {} is beyond known commit_lsn {}",
+                end_lsn,
+                self.global_commit_lsn
+            );
+        }
+
+        self.wal_store.write_wal(start_lsn, buf)?;
+        self.wal_store.flush_wal()?;
+        self.update_commit_lsn()
+    }
+
     /// Get oldest segno we still need to keep. We hold WAL till it is consumed
     /// by all of 1) pageserver (remote_consistent_lsn) 2) peers 3) s3
-    /// offloading.
+    /// offloading 4) consumers with replication slots, and retention policy
+    /// allows to remove it.
     /// While it is safe to use inmem values for determining horizon,
     /// we use persistent to make possible normal states less surprising.
     pub fn get_horizon_segno(&self, wal_backup_enabled: bool) -> XLogSegNo {
@@ -902,6 +1477,19 @@ where
         if wal_backup_enabled {
             horizon_lsn = min(horizon_lsn, self.state.backup_lsn);
         }
+        for slot in &self.state.replication_slots {
+            horizon_lsn = min(horizon_lsn, slot.restart_lsn);
+        }
+        // age limit is applied by the caller, it needs to look at the files
+        let min_bytes = self.state.retention_policy.min_bytes;
+        if min_bytes > 0 {
+            let retained_lsn = self
+                .wal_store
+                .flush_lsn()
+                .checked_sub(min_bytes)
+                .unwrap_or(Lsn(0));
+            horizon_lsn = min(horizon_lsn, retained_lsn);
+        }
         horizon_lsn.segment_number(self.state.server.wal_seg_size as usize)
     }
 }
@@ -998,6 +1586,91 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_retention_min_bytes() {
+        const SEG_SIZE: u64 = 16 * 1024 * 1024;
+        let mut state = SafeKeeperState::empty();
+        state.server.wal_seg_size = SEG_SIZE as u32;
+        state.remote_consistent_lsn = Lsn(10 * SEG_SIZE);
+        state.peer_horizon_lsn = Lsn(10 * SEG_SIZE);
+        let storage = InMemoryState {
+            persisted_state: state,
+        };
+        let wal_store = DummyWalStore {
+            lsn: Lsn(12 * SEG_SIZE),
+        };
+        let ztli = ZTimelineId::from([0u8; 16]);
+
+        let mut sk = SafeKeeper::new(ztli, storage, wal_store, NodeId(0)).unwrap();
+        assert_eq!(sk.get_horizon_segno(false), 10);
+
+        // keeps more than consumers need
+        let policy = RetentionPolicy {
+            min_bytes: 4 * SEG_SIZE,
+            min_age_secs: 0,
+        };
+        sk.set_retention_policy(policy).unwrap();
+        assert_eq!(sk.state.retention_policy, policy);
+        assert_eq!(sk.get_horizon_segno(false), 8);
+
+        // but doesn't let remove what they need
+        sk.set_retention_policy(RetentionPolicy {
+            min_bytes: SEG_SIZE,
+            min_age_secs: 0,
+        })
+        .unwrap();
+        assert_eq!(sk.get_horizon_segno(false), 10);
+    }
+
+    #[test]
+    fn test_replication_slots() {
+        const SEG_SIZE: u64 = 16 * 1024 * 1024;
+        let mut state = SafeKeeperState::empty();
+        state.server.wal_seg_size = SEG_SIZE as u32;
+        state.remote_consistent_lsn = Lsn(10 * SEG_SIZE);
+        state.peer_horizon_lsn = Lsn(10 * SEG_SIZE);
+        let storage = InMemoryState {
+            persisted_state: state,
+        };
+        let wal_store = DummyWalStore {
+            lsn: Lsn(5 * SEG_SIZE),
+        };
+        let ztli = ZTimelineId::from([0u8; 16]);
+
+        let mut sk = SafeKeeper::new(ztli, storage, wal_store, NodeId(0)).unwrap();
+        assert!(sk.create_replication_slot("Bad-Name").is_err());
+        assert_eq!(
+            sk.create_replication_slot("slot").unwrap(),
+            Lsn(5 * SEG_SIZE)
+        );
+        assert!(sk.create_replication_slot("slot").is_err());
+        assert_eq!(sk.state.replication_slots.len(), 1);
+        assert_eq!(sk.get_horizon_segno(false), 5);
+
+        sk.wal_store.lsn = Lsn(12 * SEG_SIZE);
+        // small moves are not synced, horizon follows the persisted slot
+        sk.advance_replication_slot("slot", Lsn(5 * SEG_SIZE + 100))
+            .unwrap();
+        assert_eq!(sk.state.replication_slots[0].restart_lsn, Lsn(5 * SEG_SIZE));
+        sk.advance_replication_slot("slot", Lsn(7 * SEG_SIZE))
+            .unwrap();
+        assert_eq!(sk.state.replication_slots[0].restart_lsn, Lsn(7 * SEG_SIZE));
+        assert_eq!(sk.get_horizon_segno(false), 7);
+        // slot never moves back or beyond our WAL
+        sk.advance_replication_slot("slot", Lsn(SEG_SIZE)).unwrap();
+        sk.advance_replication_slot("slot", Lsn(20 * SEG_SIZE))
+            .unwrap();
+        assert_eq!(
+            sk.inmem.replication_slots[0].restart_lsn,
+            Lsn(12 * SEG_SIZE)
+        );
+
+        assert!(sk.drop_replication_slot("other").is_err());
+        sk.drop_replication_slot("slot").unwrap();
+        assert!(sk.state.replication_slots.is_empty());
+        assert_eq!(sk.get_horizon_segno(false), 10);
+    }
+
     #[test]
     fn test_epoch_switch() {
         let storage = InMemoryState {
@@ -1051,4 +1724,281 @@ mod tests {
         sk.wal_store.truncate_wal(Lsn(3)).unwrap(); // imitate the complete record at 3 %)
         assert_eq!(sk.get_epoch(), 1);
     }
+
+    fn elected_safekeeper(
+        node_id: NodeId,
+        peers: Vec<NodeId>,
+    ) -> SafeKeeper<InMemoryState, DummyWalStore> {
+        let storage = InMemoryState {
+            persisted_state: SafeKeeperState::new(&ZTenantTimelineId::empty(), peers),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let ztli = ZTimelineId::from([0u8; 16]);
+        let mut sk = SafeKeeper::new(ztli, storage, wal_store, node_id).unwrap();
+
+        let pem = ProposerElected {
+            term: 1,
+            start_streaming_at: Lsn(1),
+            term_history: TermHistory(vec![TermSwitchEntry {
+                term: 1,
+                lsn: Lsn(1),
+            }]),
+            timeline_start_lsn: Lsn(1),
+        };
+        sk.process_msg(&ProposerAcceptorMessage::Elected(pem))
+            .unwrap();
+        sk
+    }
+
+    fn append(sk: &mut SafeKeeper<InMemoryState, DummyWalStore>, end_lsn: Lsn, commit_lsn: Lsn) {
+        let append_request = AppendRequest {
+            h: AppendRequestHeader {
+                term: 1,
+                epoch_start_lsn: Lsn(1),
+                begin_lsn: sk.flush_lsn(),
+                end_lsn,
+                commit_lsn,
+                truncate_lsn: Lsn(0),
+                proposer_uuid: [0; 16],
+            },
+            wal_data: Bytes::from(vec![0; (end_lsn.0 - sk.flush_lsn().0) as usize]),
+        };
+        sk.process_msg(&ProposerAcceptorMessage::AppendRequest(append_request))
+            .unwrap();
+    }
+
+    fn change_membership(
+        sk: &mut SafeKeeper<InMemoryState, DummyWalStore>,
+        lsn: Lsn,
+        config: Configuration,
+    ) -> MembershipChangeResponse {
+        let msg = MembershipChangeRequest {
+            term: 1,
+            lsn,
+            config,
+        };
+        match sk.process_msg(&ProposerAcceptorMessage::MembershipChange(msg)) {
+            Ok(Some(AcceptorProposerMessage::MembershipChangeResponse(resp))) => resp,
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+
+    fn members(ids: &[u64]) -> MemberSet {
+        MemberSet(ids.iter().map(|id| NodeId(*id)).collect())
+    }
+
+    fn joint(generation: u64, old: &[u64], new: &[u64]) -> Configuration {
+        Configuration {
+            generation,
+            members: members(old),
+            new_members: Some(members(new)),
+        }
+    }
+
+    #[test]
+    fn test_membership_change() {
+        let mut sk = elected_safekeeper(NodeId(1), vec![NodeId(1), NodeId(2), NodeId(3)]);
+        append(&mut sk, Lsn(10), Lsn(5));
+        assert_eq!(sk.state.configuration().members, members(&[1, 2, 3]));
+
+        let resp = change_membership(&mut sk, Lsn(10), joint(1, &[3, 2, 1], &[1, 2, 4]));
+        assert_eq!(resp.accepted, true as u64);
+        assert_eq!(resp.generation, 1);
+        assert!(sk.state.configuration().is_joint());
+
+        // repeated request is fine, but the next change waits for this one
+        let resp = change_membership(&mut sk, Lsn(10), joint(1, &[3, 2, 1], &[1, 2, 4]));
+        assert_eq!(resp.accepted, true as u64);
+        let resp = change_membership(&mut sk, Lsn(10), joint(2, &[1, 2, 4], &[1, 2, 5]));
+        assert_eq!(resp.accepted, false as u64);
+
+        // writes continue, and once the joint configuration is committed by
+        // both member sets, the new members are switched to
+        append(&mut sk, Lsn(20), Lsn(9));
+        assert!(sk.state.configuration().is_joint());
+        append(&mut sk, Lsn(30), Lsn(20));
+        assert!(sk.state.configuration().is_joint());
+        assert_eq!(sk.inmem.commit_lsn, Lsn(10));
+        sk.record_safekeeper_info(NodeId(2), &peer_info(1, Lsn(20)))
+            .unwrap();
+        assert_eq!(sk.inmem.commit_lsn, Lsn(20));
+        let config = sk.state.configuration();
+        assert_eq!(
+            config,
+            Configuration {
+                generation: 2,
+                members: members(&[1, 2, 4]),
+                new_members: None,
+            }
+        );
+        assert_eq!(sk.state.config_history.0.last().unwrap().lsn, Lsn(10));
+        assert_eq!(
+            sk.state
+                .peers
+                .0
+                .iter()
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>(),
+            members(&[1, 2, 4]).0
+        );
+
+        // members are not changed without the joint configuration
+        let resp = change_membership(
+            &mut sk,
+            Lsn(30),
+            Configuration {
+                generation: 3,
+                members: members(&[1, 2, 5]),
+                new_members: None,
+            },
+        );
+        assert_eq!(resp.accepted, false as u64);
+        assert_eq!(resp.generation, 2);
+    }
+
+    #[test]
+    fn test_joint_quorum_commit() {
+        let config = joint(1, &[1, 2, 3], &[1, 4, 5]);
+        let flush_lsns = HashMap::from([(NodeId(1), Lsn(30)), (NodeId(2), Lsn(30))]);
+        let flush_lsn = |id: NodeId| flush_lsns.get(&id).copied().unwrap_or(Lsn(0));
+        assert_eq!(config.members.quorum_lsn(flush_lsn), Lsn(30));
+        assert_eq!(config.quorum_lsn(flush_lsn), Lsn(0));
+
+        let mut sk = elected_safekeeper(NodeId(1), vec![NodeId(1), NodeId(2), NodeId(3)]);
+        append(&mut sk, Lsn(10), Lsn(5));
+        change_membership(&mut sk, Lsn(10), config);
+
+        // the old members alone don't commit WAL past the switch
+        append(&mut sk, Lsn(30), Lsn(10));
+        sk.record_safekeeper_info(NodeId(2), &peer_info(1, Lsn(30)))
+            .unwrap();
+        assert_eq!(sk.inmem.commit_lsn, Lsn(10));
+        assert!(sk.state.configuration().is_joint());
+
+        let mut new_member_info = peer_info(1, Lsn(25));
+        new_member_info.commit_lsn = Some(Lsn(10));
+        sk.record_safekeeper_info(NodeId(4), &new_member_info)
+            .unwrap();
+        assert_eq!(sk.inmem.commit_lsn, Lsn(25));
+        assert_eq!(sk.state.configuration().members, members(&[1, 4, 5]));
+    }
+
+    #[test]
+    fn test_new_member_adopts_configuration() {
+        let mut sk = elected_safekeeper(NodeId(4), vec![]);
+        assert!(sk.is_member());
+
+        let resp = change_membership(&mut sk, Lsn(10), joint(5, &[1, 2, 3], &[1, 2, 4]));
+        assert_eq!(resp.accepted, true as u64);
+        assert_eq!(resp.generation, 5);
+        assert!(sk.is_member());
+    }
+
+    #[test]
+    fn test_removed_member_refuses_votes() {
+        let mut sk = elected_safekeeper(NodeId(3), vec![NodeId(1), NodeId(2), NodeId(3)]);
+        append(&mut sk, Lsn(10), Lsn(10));
+        for peer in [NodeId(1), NodeId(2)] {
+            sk.record_safekeeper_info(peer, &peer_info(1, Lsn(10)))
+                .unwrap();
+        }
+        change_membership(&mut sk, Lsn(10), joint(1, &[1, 2, 3], &[1, 2, 4]));
+        // committed joint configuration is completed right away
+        assert_eq!(sk.state.configuration().generation, 2);
+        assert!(!sk.is_member());
+
+        let vote_request = ProposerAcceptorMessage::VoteRequest(VoteRequest { term: 2 });
+        match sk.process_msg(&vote_request).unwrap() {
+            Some(AcceptorProposerMessage::VoteResponse(resp)) => {
+                assert_eq!(resp.vote_given, false as u64);
+                assert_eq!(resp.term, 1);
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_uncommitted_membership_change_truncated() {
+        let mut sk = elected_safekeeper(NodeId(1), vec![NodeId(1), NodeId(2), NodeId(3)]);
+        append(&mut sk, Lsn(20), Lsn(5));
+        change_membership(&mut sk, Lsn(20), joint(1, &[1, 2, 3], &[1, 2, 4]));
+
+        // new proposer doesn't have the WAL with the switch
+        let pem = ProposerElected {
+            term: 2,
+            start_streaming_at: Lsn(10),
+            term_history: TermHistory(vec![
+                TermSwitchEntry {
+                    term: 1,
+                    lsn: Lsn(1),
+                },
+                TermSwitchEntry {
+                    term: 2,
+                    lsn: Lsn(10),
+                },
+            ]),
+            timeline_start_lsn: Lsn(1),
+        };
+        sk.process_msg(&ProposerAcceptorMessage::Elected(pem))
+            .unwrap();
+        assert_eq!(sk.state.configuration().generation, 0);
+        assert_eq!(sk.state.configuration().members, members(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn test_parse_membership_change() {
+        let mut buf = BytesMut::new();
+        buf.put_u64_le('m' as u64);
+        buf.put_u64_le(3); // term
+        buf.put_u64_le(42); // lsn
+        buf.put_u64_le(7); // generation
+        buf.put_u32_le(2);
+        buf.put_u64_le(1);
+        buf.put_u64_le(2);
+        buf.put_u32_le(1);
+        buf.put_u64_le(3);
+
+        match ProposerAcceptorMessage::parse(buf.freeze()).unwrap() {
+            ProposerAcceptorMessage::MembershipChange(msg) => {
+                assert_eq!(msg.term, 3);
+                assert_eq!(msg.lsn, Lsn(42));
+                assert_eq!(msg.config, joint(7, &[1, 2], &[3]));
+            }
+            r => panic!("unexpected message: {:?}", r),
+        }
+    }
+
+    fn peer_info(last_log_term: Term, commit_lsn: Lsn) -> SkTimelineInfo {
+        SkTimelineInfo {
+            term: Some(last_log_term),
+            last_log_term: Some(last_log_term),
+            flush_lsn: Some(commit_lsn),
+            commit_lsn: Some(commit_lsn),
+            backup_lsn: None,
+            remote_consistent_lsn: None,
+            peer_horizon_lsn: None,
+            safekeeper_connstr: None,
+        }
+    }
+
+    #[test]
+    fn test_recovered_wal() {
+        let mut sk = elected_safekeeper(NodeId(1), vec![NodeId(1), NodeId(2), NodeId(3)]);
+        append(&mut sk, Lsn(10), Lsn(5));
+        sk.record_safekeeper_info(NodeId(2), &peer_info(1, Lsn(20)))
+            .unwrap();
+        assert_eq!(sk.inmem.commit_lsn, Lsn(10));
+
+        let start_lsn = sk.start_recovery().unwrap();
+        assert_eq!(start_lsn, Lsn(10));
+
+        // WAL of another term doesn't continue ours
+        assert!(sk.write_recovered_wal(2, start_lsn, &[0; 10]).is_err());
+        // and uncommitted WAL might be truncated later
+        assert!(sk.write_recovered_wal(1, start_lsn, &[0; 15]).is_err());
+
+        sk.write_recovered_wal(1, start_lsn, &[0; 10]).unwrap();
+        assert_eq!(sk.flush_lsn(), Lsn(20));
+        assert_eq!(sk.inmem.commit_lsn, Lsn(20));
+    }
 }
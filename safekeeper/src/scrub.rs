@@ -0,0 +1,420 @@
+//! Checking WAL stored locally for corruption. Segments are written once and
+//! trusted when read back, so a torn write or a bit flip on disk would be
+//! noticed only by the pageserver failing to decode WAL sent to it, or, worse,
+//! while recovering from the only copy left. Scrub decodes committed WAL of the
+//! timeline with `WalStreamDecoder`, which validates page headers and record
+//! CRCs, and remembers segments where decoding failed. They are reported via
+//! HTTP API and metrics, and can be replaced with a copy fetched from a peer or
+//! from remote storage.
+//!
+//! Only WAL up to commit_lsn is checked: it never changes, while WAL past it
+//! might be truncated meanwhile. Scrub runs periodically if
+//! `SafeKeeperConf::wal_scrub_interval` is set, or on request via HTTP API.
+
+use anyhow::{bail, ensure, Context, Result};
+use lazy_static::lazy_static;
+use metrics::{register_int_counter, register_int_gauge_vec, IntCounter, IntGauge, IntGaugeVec};
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{thread, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::*;
+
+use postgres_ffi::waldecoder::WalStreamDecoder;
+use postgres_ffi::xlog_utils::{
+    IsPartialXLogFileName, IsXLogFileName, XLogFileName, XLogFromFileName, XLogSegNo,
+    XLogSegNoOffsetToRecPtr, PG_TLI, XLOG_BLCKSZ, XLOG_SIZE_OF_XLOG_LONG_PHD,
+    XLOG_SIZE_OF_XLOG_SHORT_PHD, XLP_FIRST_IS_CONTRECORD,
+};
+use postgres_ffi::{XLogLongPageHeaderData, XLogPageHeaderData};
+use utils::{lsn::Lsn, zid::ZTenantTimelineId};
+
+use crate::timeline::{GlobalTimelines, Timeline};
+use crate::wal_backup;
+use crate::SafeKeeperConf;
+
+/// Segment where WAL failed to decode.
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct CorruptedSegment {
+    /// Segment name, without `.partial` suffix.
+    pub segment: String,
+    #[serde_as(as = "DisplayFromStr")]
+    pub lsn: Lsn,
+    pub error: String,
+}
+
+/// Outcome of the last scrub of the timeline.
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct ScrubStatus {
+    /// Range of WAL checked.
+    #[serde_as(as = "DisplayFromStr")]
+    pub start_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub end_lsn: Lsn,
+    /// Number of records decoded successfully.
+    pub records: u64,
+    pub corrupted_segments: Vec<CorruptedSegment>,
+}
+
+impl ScrubStatus {
+    fn add_corrupted(&mut self, segment_name: &str, lsn: Lsn, error: String) {
+        warn!("WAL segment {} is corrupted: {}", segment_name, error);
+        self.corrupted_segments.push(CorruptedSegment {
+            segment: segment_name.to_owned(),
+            lsn,
+            error,
+        });
+    }
+}
+
+lazy_static! {
+    static ref SCRUB_STATUS: Mutex<HashMap<ZTenantTimelineId, ScrubStatus>> =
+        Mutex::new(HashMap::new());
+    static ref CORRUPTED_SEGMENTS: IntGaugeVec = register_int_gauge_vec!(
+        "safekeeper_wal_scrub_corrupted_segments",
+        "Number of WAL segments found corrupted by the last scrub, grouped by timeline",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("Failed to register safekeeper_wal_scrub_corrupted_segments gauge vec");
+    static ref SCRUBBED_BYTES: IntCounter = register_int_counter!(
+        "safekeeper_wal_scrub_bytes_total",
+        "Bytes of WAL checked by scrub"
+    )
+    .expect("Failed to register safekeeper_wal_scrub_bytes_total counter");
+    static ref REPAIRED_SEGMENTS: IntCounter = register_int_counter!(
+        "safekeeper_wal_scrub_repaired_segments_total",
+        "Number of corrupted WAL segments replaced with a copy from elsewhere"
+    )
+    .expect("Failed to register safekeeper_wal_scrub_repaired_segments_total counter");
+}
+
+fn corrupted_segments_gauge(zttid: &ZTenantTimelineId) -> IntGauge {
+    CORRUPTED_SEGMENTS
+        .with_label_values(&[&zttid.tenant_id.to_string(), &zttid.timeline_id.to_string()])
+}
+
+fn set_status(zttid: ZTenantTimelineId, status: ScrubStatus) {
+    corrupted_segments_gauge(&zttid).set(status.corrupted_segments.len() as i64);
+    SCRUB_STATUS.lock().unwrap().insert(zttid, status);
+}
+
+/// Outcome of the last scrub of the timeline, None if it wasn't scrubbed.
+pub fn get_status(zttid: &ZTenantTimelineId) -> Option<ScrubStatus> {
+    SCRUB_STATUS.lock().unwrap().get(zttid).cloned()
+}
+
+/// Drop status of the deleted timeline.
+pub fn forget_timeline(zttid: &ZTenantTimelineId) {
+    if SCRUB_STATUS.lock().unwrap().remove(zttid).is_some() {
+        let _ = CORRUPTED_SEGMENTS
+            .remove_label_values(&[&zttid.tenant_id.to_string(), &zttid.timeline_id.to_string()]);
+    }
+}
+
+pub fn thread_main(conf: SafeKeeperConf, scrub_interval: Duration) {
+    loop {
+        thread::sleep(scrub_interval);
+        let active_tlis = GlobalTimelines::get_active_timelines();
+        for zttid in &active_tlis {
+            if let Ok(tli) = GlobalTimelines::get(&conf, *zttid, false) {
+                if let Err(e) = scrub_timeline(&conf, &tli) {
+                    warn!(
+                        "failed to scrub WAL for tenant {} timeline {}: {:#}",
+                        zttid.tenant_id, zttid.timeline_id, e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Check committed WAL of the timeline kept locally and remember the outcome.
+pub fn scrub_timeline(conf: &SafeKeeperConf, tli: &Timeline) -> Result<ScrubStatus> {
+    let (inmem, state) = tli.get_state();
+    let wal_seg_size = state.server.wal_seg_size as usize;
+    let end_lsn = inmem.commit_lsn;
+    let mut status = ScrubStatus {
+        start_lsn: end_lsn,
+        end_lsn,
+        records: 0,
+        corrupted_segments: Vec::new(),
+    };
+    if wal_seg_size == 0 {
+        set_status(tli.zttid, status.clone());
+        return Ok(status);
+    }
+
+    let timeline_dir = conf.timeline_dir(&tli.zttid);
+    let mut decoder: Option<WalStreamDecoder> = None;
+    for segno in local_segments(&timeline_dir, wal_seg_size)? {
+        let seg_start = Lsn(XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size));
+        let valid_end = min(seg_start + wal_seg_size as u64, end_lsn);
+        if seg_start >= end_lsn {
+            break;
+        }
+        let segment_name = XLogFileName(PG_TLI, segno, wal_seg_size);
+
+        let buf = match read_segment(&timeline_dir, &segment_name) {
+            Ok(buf) => buf,
+            // removed meanwhile
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                decoder = None;
+                continue;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read segment {}", segment_name))
+            }
+        };
+        if buf.len() != wal_seg_size {
+            let error = format!("segment has size {}, expected {}", buf.len(), wal_seg_size);
+            status.add_corrupted(&segment_name, seg_start + buf.len() as u64, error);
+            decoder = None;
+            continue;
+        }
+
+        // Continue decoding from the previous segment, if there was one.
+        if !matches!(&decoder, Some(decoder) if decoder.available() == seg_start) {
+            decoder = None;
+            // WAL before local_start_lsn is zeroes.
+            let start_lsn = if state.local_start_lsn > seg_start {
+                state.local_start_lsn
+            } else {
+                match first_record_offset(&buf, seg_start) {
+                    Ok(offset) => seg_start + offset as u64,
+                    Err(e) => {
+                        status.add_corrupted(&segment_name, seg_start, format!("{:#}", e));
+                        continue;
+                    }
+                }
+            };
+            if start_lsn >= valid_end {
+                continue;
+            }
+            status.start_lsn = min(status.start_lsn, start_lsn);
+            decoder = Some(WalStreamDecoder::new(start_lsn));
+        }
+        let decoder_ref = decoder.as_mut().unwrap();
+
+        let feed_from = (decoder_ref.available().0 - seg_start.0) as usize;
+        let feed_to = (valid_end.0 - seg_start.0) as usize;
+        decoder_ref.feed_bytes(&buf[feed_from..feed_to]);
+        SCRUBBED_BYTES.inc_by((feed_to - feed_from) as u64);
+        loop {
+            match decoder_ref.poll_decode() {
+                Ok(Some(_)) => status.records += 1,
+                Ok(None) => break,
+                Err(e) => {
+                    status.add_corrupted(&segment_name, e.lsn(), e.to_string());
+                    // resume from the next segment
+                    decoder = None;
+                    break;
+                }
+            }
+        }
+    }
+
+    if status.corrupted_segments.is_empty() {
+        debug!(
+            "scrubbed WAL {}..{} of timeline {}, {} records",
+            status.start_lsn, status.end_lsn, tli.zttid, status.records
+        );
+    }
+    set_status(tli.zttid, status.clone());
+    Ok(status)
+}
+
+/// Numbers of WAL segments in `timeline_dir`, in order.
+fn local_segments(timeline_dir: &Path, wal_seg_size: usize) -> Result<Vec<XLogSegNo>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(timeline_dir)? {
+        let file_name = entry?.file_name();
+        if let Some(file_name) = file_name.to_str() {
+            if IsXLogFileName(file_name) || IsPartialXLogFileName(file_name) {
+                segments.push(XLogFromFileName(file_name, wal_seg_size).0);
+            }
+        }
+    }
+    segments.sort_unstable();
+    segments.dedup();
+    Ok(segments)
+}
+
+/// Read the whole segment, partial or complete one.
+fn read_segment(timeline_dir: &Path, segment_name: &str) -> std::io::Result<Vec<u8>> {
+    match fs::read(timeline_dir.join(format!("{}.partial", segment_name))) {
+        Err(e) if e.kind() == ErrorKind::NotFound => fs::read(timeline_dir.join(segment_name)),
+        res => res,
+    }
+}
+
+/// Find where the first record starting in the segment is: its beginning is
+/// likely the tail of a record from the previous segment, which might span
+/// several pages.
+fn first_record_offset(segment: &[u8], seg_start: Lsn) -> Result<usize> {
+    let mut page_offset = 0;
+    while page_offset < segment.len() {
+        let page_lsn = seg_start + page_offset as u64;
+        let mut page = &segment[page_offset..];
+        let (hdr, hdr_size) = if page_offset == 0 {
+            (
+                XLogLongPageHeaderData::from_bytes(&mut page)?.std,
+                XLOG_SIZE_OF_XLOG_LONG_PHD,
+            )
+        } else {
+            (
+                XLogPageHeaderData::from_bytes(&mut page)?,
+                XLOG_SIZE_OF_XLOG_SHORT_PHD,
+            )
+        };
+        ensure!(
+            hdr.xlp_pageaddr == page_lsn.0,
+            "invalid xlp_pageaddr {} of page at {}",
+            Lsn(hdr.xlp_pageaddr),
+            page_lsn
+        );
+        if hdr.xlp_info & XLP_FIRST_IS_CONTRECORD == 0 {
+            return Ok(page_offset + hdr_size);
+        }
+        // records are MAXALIGNed
+        let rem_len = (hdr.xlp_rem_len as usize + 7) & !7;
+        if rem_len < XLOG_BLCKSZ - hdr_size {
+            return Ok(page_offset + hdr_size + rem_len);
+        }
+        page_offset += XLOG_BLCKSZ;
+    }
+    bail!("no record starts in the segment")
+}
+
+/// Check segment copy fetched for repair, from its first record to the end.
+fn check_segment(segment: &[u8], seg_start: Lsn, local_start_lsn: Lsn) -> Result<()> {
+    // WAL before local_start_lsn is zeroes.
+    let start_offset = if local_start_lsn > seg_start {
+        min(local_start_lsn.0 - seg_start.0, segment.len() as u64) as usize
+    } else {
+        first_record_offset(segment, seg_start)?
+    };
+    let mut decoder = WalStreamDecoder::new(seg_start + start_offset as u64);
+    decoder.feed_bytes(&segment[start_offset..]);
+    while decoder.poll_decode()?.is_some() {}
+    Ok(())
+}
+
+/// Replace segments found corrupted by the last scrub with copies fetched
+/// from safekeeper with HTTP API at `source_http_addr`, or from remote storage
+/// if it is not given. The source is given `auth_token` of `SafeKeeperConf`.
+/// Returns the status with repaired segments removed.
+pub async fn repair_timeline(
+    conf: &SafeKeeperConf,
+    tli: &Timeline,
+    source_http_addr: Option<&str>,
+) -> Result<ScrubStatus> {
+    let mut status = match get_status(&tli.zttid) {
+        Some(status) => status,
+        None => bail!("timeline {} was not scrubbed yet", tli.zttid),
+    };
+    let wal_seg_size = tli.get_wal_seg_size();
+    let timeline_dir = conf.timeline_dir(&tli.zttid);
+
+    let mut segment_names: Vec<String> = status
+        .corrupted_segments
+        .iter()
+        .map(|c| c.segment.clone())
+        .collect();
+    segment_names.dedup();
+    for segment_name in segment_names {
+        let segno = XLogFromFileName(&segment_name, wal_seg_size).0;
+        let seg_start = Lsn(XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size));
+        let seg_end = seg_start + wal_seg_size as u64;
+        let (inmem, state) = tli.get_state();
+        // Partial segment is being written into, the copy would get stale.
+        ensure!(
+            seg_end <= inmem.commit_lsn,
+            "segment {} is not complete yet, can't repair it",
+            segment_name
+        );
+        let segment_path = timeline_dir.join(&segment_name);
+
+        let segment = match source_http_addr {
+            Some(source_http_addr) => {
+                fetch_from_peer(conf, tli, &segment_name, source_http_addr).await
+            }
+            None => {
+                ensure!(
+                    seg_end <= inmem.backup_lsn,
+                    "segment {} is not offloaded yet",
+                    segment_name
+                );
+                fetch_from_remote_storage(conf, segment_path.clone()).await
+            }
+        }
+        .with_context(|| format!("failed to fetch copy of segment {}", segment_name))?;
+        ensure!(
+            segment.len() == wal_seg_size,
+            "copy of segment {} has size {}, expected {}",
+            segment_name,
+            segment.len(),
+            wal_seg_size
+        );
+        check_segment(&segment, seg_start, state.local_start_lsn)
+            .with_context(|| format!("copy of segment {} is corrupted", segment_name))?;
+
+        let tmp_path = segment_path.with_extension("repair");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(&segment).await?;
+        if !conf.no_sync {
+            file.sync_all().await?;
+        }
+        tokio::fs::rename(&tmp_path, &segment_path).await?;
+        info!("repaired WAL segment {}", segment_name);
+        REPAIRED_SEGMENTS.inc();
+
+        status
+            .corrupted_segments
+            .retain(|c| c.segment != segment_name);
+        set_status(tli.zttid, status.clone());
+    }
+    Ok(status)
+}
+
+async fn fetch_from_peer(
+    conf: &SafeKeeperConf,
+    tli: &Timeline,
+    segment_name: &str,
+    source_http_addr: &str,
+) -> Result<Vec<u8>> {
+    let url = format!(
+        "http://{}/v1/tenant/{}/timeline/{}/file/{}",
+        source_http_addr, tli.zttid.tenant_id, tli.zttid.timeline_id, segment_name
+    );
+    let mut request = reqwest::Client::new().get(url);
+    if let Some(auth_token) = &conf.auth_token {
+        request = request.bearer_auth(auth_token);
+    }
+    let response = request.send().await?.error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+async fn fetch_from_remote_storage(
+    conf: &SafeKeeperConf,
+    segment_path: PathBuf,
+) -> Result<Vec<u8>> {
+    if conf.remote_storage.is_none() {
+        bail!("remote storage is not configured");
+    }
+    wal_backup::init_remote_storage(conf);
+    let (mut reader, download) = wal_backup::read_object(segment_path, 0).await;
+    let mut segment = Vec::new();
+    let res = reader.read_to_end(&mut segment).await;
+    // Stream just ends if download fails, report the cause.
+    download.await??;
+    res?;
+    Ok(segment)
+}
@@ -3,6 +3,7 @@
 
 use crate::handler::SafekeeperPostgresHandler;
 use crate::timeline::{ReplicaState, Timeline, TimelineTools};
+use crate::wal_backup;
 use crate::wal_storage::WalReader;
 use anyhow::{bail, Context, Result};
 
@@ -23,7 +24,10 @@ use utils::{
     bin_ser::BeSer,
     lsn::Lsn,
     postgres_backend::PostgresBackend,
-    pq_proto::{BeMessage, FeMessage, ReplicationFeedback, WalSndKeepAlive, XLogDataBody},
+    pq_proto::{
+        BeMessage, FeMessage, ReplicationFeedback, WalSndKeepAlive, XLogDataBody,
+        STANDBY_APPLICATION_NAME,
+    },
     sock_split::ReadStream,
 };
 
@@ -54,7 +58,7 @@ impl HotStandbyFeedback {
 }
 
 /// Standby status update
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct StandbyReply {
     pub write_lsn: Lsn, // last lsn received by pageserver
     pub flush_lsn: Lsn, // pageserver's disk consistent lSN
@@ -95,6 +99,7 @@ impl ReplicationConn {
     fn background_thread(
         mut stream_in: ReadStream,
         replica_guard: Arc<ReplicationConnGuard>,
+        slot: Option<String>,
     ) -> Result<()> {
         let replica_id = replica_guard.replica;
         let timeline = &replica_guard.timeline;
@@ -115,14 +120,20 @@ impl ReplicationConn {
                             timeline.update_replica_state(replica_id, state);
                         }
                         Some(STANDBY_STATUS_UPDATE_TAG_BYTE) => {
-                            let _reply = StandbyReply::des(&m[1..])
+                            let reply = StandbyReply::des(&m[1..])
                                 .context("failed to deserialize StandbyReply")?;
-                            // This must be a regular postgres replica,
-                            // because pageserver doesn't send this type of messages to safekeeper.
-                            // Currently this is not implemented, so this message is ignored.
+                            // This must be a regular postgres replica (hot standby compute)
+                            // or an external consumer like pg_receivewal, because pageserver
+                            // doesn't send this type of messages to safekeeper. It doesn't
+                            // vote, and its position holds back WAL removal only through
+                            // the replication slot, if any.
+                            trace!("StandbyReply is {:?}", reply);
+                            if let Some(slot) = &slot {
+                                timeline.advance_replication_slot(slot, reply.flush_lsn)?;
+                            }
+                            state.standby_reply = Some(reply);
 
-                            warn!("unexpected StandbyReply. Read-only postgres replicas are not supported in safekeepers yet.");
-                            // timeline.update_replica_state(replica_id, Some(state));
+                            timeline.update_replica_state(replica_id, state);
                         }
                         Some(NEON_STATUS_UPDATE_TAG_BYTE) => {
                             // Note: deserializing is on m[9..] because we skip the tag byte and len bytes.
@@ -164,9 +175,17 @@ impl ReplicationConn {
         spg: &mut SafekeeperPostgresHandler,
         pgb: &mut PostgresBackend,
         mut start_pos: Lsn,
+        slot: Option<String>,
     ) -> Result<()> {
         let _enter = info_span!("WAL sender", timeline = %spg.ztimelineid.unwrap()).entered();
 
+        if let Some(slot) = &slot {
+            let (inmem, _) = spg.timeline.get().get_state();
+            if !inmem.replication_slots.iter().any(|s| &s.name == slot) {
+                bail!("replication slot {:?} does not exist", slot);
+            }
+        }
+
         // spawn the background thread which receives HotStandbyFeedback messages.
         let bg_timeline = Arc::clone(spg.timeline.get());
         let bg_stream_in = self.stream_in.take().unwrap();
@@ -188,7 +207,7 @@ impl ReplicationConn {
         let _ = thread::Builder::new()
             .name("HotStandbyFeedback thread".into())
             .spawn(move || {
-                if let Err(err) = Self::background_thread(bg_stream_in, bg_replica_guard) {
+                if let Err(err) = Self::background_thread(bg_stream_in, bg_replica_guard, slot) {
                     error!("Replication background thread failed: {}", err);
                 }
             })?;
@@ -221,6 +240,10 @@ impl ReplicationConn {
                 None
             };
 
+            // Standby pageserver connections only track how far the safekeeper has committed,
+            // so they get a keepalive with the latest commit_lsn instead of the WAL itself.
+            let positions_only = spg.appname.as_deref() == Some(STANDBY_APPLICATION_NAME);
+
             info!("Start replication from {:?} till {:?}", start_pos, stop_pos);
 
             // switch to copy
@@ -228,11 +251,18 @@ impl ReplicationConn {
 
             let mut end_pos = Lsn(0);
 
+            // WAL removed locally is offloaded, see remove_old_wal; pageservers
+            // catching up from an old LSN get it from remote storage.
+            let enable_remote_read =
+                spg.conf.wal_backup_enabled && spg.conf.remote_storage.is_some();
+            if enable_remote_read {
+                wal_backup::init_remote_storage(&spg.conf);
+            }
             let mut wal_reader = WalReader::new(
                 spg.conf.timeline_dir(&spg.timeline.get().zttid),
                 &persisted_state,
                 start_pos,
-                spg.conf.wal_backup_enabled,
+                enable_remote_read,
             )?;
 
             // buffer for wal sending, limited by MAX_SEND_SIZE
@@ -273,6 +303,17 @@ impl ReplicationConn {
                     }
                 }
 
+                if positions_only {
+                    pgb.write_message(&BeMessage::KeepAlive(WalSndKeepAlive {
+                        sent_ptr: end_pos.0,
+                        timestamp: get_current_timestamp(),
+                        request_reply: false,
+                    }))
+                    .context("Failed to send KeepAlive message")?;
+                    start_pos = end_pos;
+                    continue;
+                }
+
                 let send_size = end_pos.checked_sub(start_pos).unwrap().0 as usize;
                 let send_size = min(send_size, send_buf.len());
 
@@ -5,7 +5,7 @@ use anyhow::{bail, Context, Result};
 
 use etcd_broker::subscription_value::SkTimelineInfo;
 use lazy_static::lazy_static;
-use postgres_ffi::xlog_utils::XLogSegNo;
+use postgres_ffi::xlog_utils::{XLogSegNo, XLogSegNoOffsetToRecPtr};
 
 use serde::Serialize;
 use tokio::sync::watch;
@@ -13,8 +13,10 @@ use tokio::sync::watch;
 use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::fs::{self};
+use std::path::Path;
 
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
 
 use tokio::sync::mpsc::Sender;
 use tracing::*;
@@ -25,14 +27,17 @@ use utils::{
     zid::{NodeId, ZTenantId, ZTenantTimelineId},
 };
 
-use crate::control_file;
+use crate::control_file::{self, CONTROL_FILE_NAME};
 use crate::safekeeper::{
-    AcceptorProposerMessage, ProposerAcceptorMessage, SafeKeeper, SafeKeeperState,
-    SafekeeperMemState,
+    AcceptorProposerMessage, ProposerAcceptorMessage, RetentionPolicy, SafeKeeper, SafeKeeperState,
+    SafekeeperMemState, Term,
 };
-use crate::send_wal::HotStandbyFeedback;
+use crate::send_wal::{HotStandbyFeedback, StandbyReply};
 
 use crate::metrics::FullTimelineInfo;
+use crate::quota;
+use crate::recovery::{self, Donor};
+use crate::scrub;
 use crate::wal_storage;
 use crate::wal_storage::Storage as wal_storage_iface;
 use crate::SafeKeeperConf;
@@ -48,6 +53,8 @@ pub struct ReplicaState {
     pub hs_feedback: HotStandbyFeedback,
     /// Replication specific feedback received from pageserver, if any
     pub pageserver_feedback: Option<ReplicationFeedback>,
+    /// Latest status update from a read-only hot standby compute, if any
+    pub standby_reply: Option<StandbyReply>,
 }
 
 impl Default for ReplicaState {
@@ -67,6 +74,7 @@ impl ReplicaState {
                 catalog_xmin: u64::MAX,
             },
             pageserver_feedback: None,
+            standby_reply: None,
         }
     }
 }
@@ -91,6 +99,8 @@ struct SharedState {
     active: bool,
     num_computes: u32,
     last_removed_segno: XLogSegNo,
+    /// True while WAL is being fetched from a peer safekeeper.
+    recovery_active: bool,
 }
 
 impl SharedState {
@@ -113,6 +123,7 @@ impl SharedState {
             active: false,
             num_computes: 0,
             last_removed_segno: 0,
+            recovery_active: false,
         })
     }
 
@@ -131,8 +142,22 @@ impl SharedState {
             active: false,
             num_computes: 0,
             last_removed_segno: 0,
+            recovery_active: false,
         })
     }
+    /// Persistent state with in-memory values of its fields, which are
+    /// flushed lazily.
+    fn state_with_inmem(&self) -> SafeKeeperState {
+        let mut state = self.sk.state.clone();
+        state.commit_lsn = self.sk.inmem.commit_lsn;
+        state.backup_lsn = self.sk.inmem.backup_lsn;
+        state.peer_horizon_lsn = self.sk.inmem.peer_horizon_lsn;
+        state.remote_consistent_lsn = self.sk.inmem.remote_consistent_lsn;
+        state.proposer_uuid = self.sk.inmem.proposer_uuid;
+        state.replication_slots = self.sk.inmem.replication_slots.clone();
+        state
+    }
+
     fn is_active(&self) -> bool {
         self.is_wal_backup_required()
             // FIXME: add tracking of relevant pageservers and check them here individually,
@@ -194,6 +219,36 @@ impl SharedState {
         self.sk.state.server.wal_seg_size as usize
     }
 
+    /// Should we fetch WAL from the peer which has sent us `sk_info`? Only
+    /// committed WAL of our own last term is fetched, and only when there is
+    /// no compute: otherwise the proposer sends us the WAL anyway.
+    fn recovery_donor(&self, sk_info: &SkTimelineInfo, sk_id: NodeId) -> Option<Donor> {
+        if self.recovery_active || self.num_computes > 0 {
+            return None;
+        }
+        // We don't know where WAL begins yet.
+        if self.sk.state.timeline_start_lsn == Lsn(0) {
+            return None;
+        }
+        match (
+            sk_info.last_log_term,
+            sk_info.commit_lsn,
+            &sk_info.safekeeper_connstr,
+        ) {
+            (Some(term), Some(commit_lsn), Some(pg_connstr))
+                if term == self.sk.get_epoch() && commit_lsn > self.sk.flush_lsn() =>
+            {
+                Some(Donor {
+                    sk_id,
+                    pg_connstr: pg_connstr.clone(),
+                    term,
+                    commit_lsn,
+                })
+            }
+            _ => None,
+        }
+    }
+
     /// Get combined state of all alive replicas
     pub fn get_replicas_state(&self) -> ReplicaState {
         let mut acc = ReplicaState::new();
@@ -434,10 +489,64 @@ impl Timeline {
         (shared_state.sk.inmem.clone(), shared_state.sk.state.clone())
     }
 
+    /// Set policy of keeping WAL locally, see `RetentionPolicy`.
+    pub fn set_retention_policy(&self, policy: RetentionPolicy) -> Result<()> {
+        self.mutex.lock().unwrap().sk.set_retention_policy(policy)
+    }
+
+    /// Create replication slot of an external WAL consumer, returns LSN WAL is
+    /// kept since.
+    pub fn create_replication_slot(&self, name: &str) -> Result<Lsn> {
+        self.mutex.lock().unwrap().sk.create_replication_slot(name)
+    }
+
+    pub fn drop_replication_slot(&self, name: &str) -> Result<()> {
+        self.mutex.lock().unwrap().sk.drop_replication_slot(name)
+    }
+
+    /// Advance replication slot on consumer's feedback.
+    pub fn advance_replication_slot(&self, name: &str, flush_lsn: Lsn) -> Result<()> {
+        self.mutex
+            .lock()
+            .unwrap()
+            .sk
+            .advance_replication_slot(name, flush_lsn)
+    }
+
     pub fn get_wal_backup_lsn(&self) -> Lsn {
         self.mutex.lock().unwrap().sk.inmem.backup_lsn
     }
 
+    /// Timeline state describing WAL offloaded up to `backup_lsn`, to be
+    /// offloaded along with it. Term history covers all local WAL, which
+    /// includes the offloaded one as only committed WAL is offloaded.
+    pub fn get_state_for_backup(&self, backup_lsn: Lsn) -> SafeKeeperState {
+        let mut state = self.mutex.lock().unwrap().state_with_inmem();
+        state.backup_lsn = backup_lsn;
+        state
+    }
+
+    /// Timeline state along with the end of local WAL it describes, to copy
+    /// the timeline to another safekeeper. Term history covers all local WAL
+    /// as it is persisted before WAL of the term is accepted.
+    pub fn get_snapshot(&self) -> (SafeKeeperState, Lsn) {
+        let shared_state = self.mutex.lock().unwrap();
+        (
+            shared_state.state_with_inmem(),
+            shared_state.sk.wal_store.flush_lsn(),
+        )
+    }
+
+    /// Start timeline activity (broker updates, WAL offloading) for timeline
+    /// appeared without compute connection, e.g. restored or pulled.
+    pub async fn activate(&self) -> Result<()> {
+        let is_wal_backup_action_pending = self.mutex.lock().unwrap().update_status(self.zttid);
+        if is_wal_backup_action_pending {
+            self.wal_backup_launcher_tx.send(self.zttid).await?;
+        }
+        Ok(())
+    }
+
     pub fn set_wal_backup_lsn(&self, backup_lsn: Lsn) {
         self.mutex.lock().unwrap().sk.inmem.backup_lsn = backup_lsn;
         // we should check whether to shut down offloader, but this will be done
@@ -448,6 +557,7 @@ impl Timeline {
     pub fn get_public_info(&self, conf: &SafeKeeperConf) -> anyhow::Result<SkTimelineInfo> {
         let shared_state = self.mutex.lock().unwrap();
         Ok(SkTimelineInfo {
+            term: Some(shared_state.sk.state.acceptor_state.term),
             last_log_term: Some(shared_state.sk.get_epoch()),
             flush_lsn: Some(shared_state.sk.wal_store.flush_lsn()),
             // note: this value is not flushed to control file yet and can be lost
@@ -463,14 +573,17 @@ impl Timeline {
         })
     }
 
-    /// Update timeline state with peer safekeeper data.
+    /// Update timeline state with peer safekeeper data, starting recovery
+    /// from the peer if it has committed WAL we are missing.
     pub async fn record_safekeeper_info(
         &self,
+        conf: &SafeKeeperConf,
         sk_info: &SkTimelineInfo,
-        _sk_id: NodeId,
+        sk_id: NodeId,
     ) -> Result<()> {
         let is_wal_backup_action_pending: bool;
         let commit_lsn: Lsn;
+        let mut donor: Option<Donor> = None;
         {
             let mut shared_state = self.mutex.lock().unwrap();
             // WAL seg size not initialized yet (no message from compute ever
@@ -478,11 +591,21 @@ impl Timeline {
             if shared_state.get_wal_seg_size() == 0 {
                 return Ok(());
             }
-            shared_state.sk.record_safekeeper_info(sk_info)?;
+            shared_state.sk.record_safekeeper_info(sk_id, sk_info)?;
+            if conf.peer_recovery_enabled && sk_id != conf.my_id {
+                donor = shared_state.recovery_donor(sk_info, sk_id);
+                shared_state.recovery_active |= donor.is_some();
+            }
             is_wal_backup_action_pending = shared_state.update_status(self.zttid);
             commit_lsn = shared_state.sk.inmem.commit_lsn;
         }
         self.commit_lsn_watch_tx.send(commit_lsn)?;
+        if let Some(donor) = donor {
+            tokio::spawn(
+                recovery::recovery_main(self.zttid, donor)
+                    .instrument(info_span!("peer recovery", zttid = %self.zttid)),
+            );
+        }
         // Wake up wal backup launcher, if it is time to stop the offloading.
         if is_wal_backup_action_pending {
             self.wal_backup_launcher_tx.send(self.zttid).await?;
@@ -490,6 +613,36 @@ impl Timeline {
         Ok(())
     }
 
+    /// Prepare to append WAL fetched from a peer, returning LSN to start from.
+    pub fn start_recovery(&self) -> Result<Lsn> {
+        let mut shared_state = self.mutex.lock().unwrap();
+        if shared_state.num_computes > 0 {
+            bail!("compute is connected, it will send the WAL");
+        }
+        shared_state.sk.start_recovery()
+    }
+
+    /// Append WAL fetched from a peer of the given term. Fails once a compute
+    /// connects, as from now on WAL is what the proposer says.
+    pub fn write_recovered_wal(&self, term: Term, start_lsn: Lsn, buf: &[u8]) -> Result<()> {
+        let commit_lsn: Lsn;
+        {
+            let mut shared_state = self.mutex.lock().unwrap();
+            if shared_state.num_computes > 0 {
+                bail!("compute is connected, it will send the WAL");
+            }
+            shared_state.sk.write_recovered_wal(term, start_lsn, buf)?;
+            commit_lsn = shared_state.sk.inmem.commit_lsn;
+        }
+        self.commit_lsn_watch_tx.send(commit_lsn)?;
+        Ok(())
+    }
+
+    /// Allow starting recovery again.
+    pub fn finish_recovery(&self) {
+        self.mutex.lock().unwrap().recovery_active = false;
+    }
+
     pub fn add_replica(&self, state: ReplicaState) -> usize {
         let mut shared_state = self.mutex.lock().unwrap();
         shared_state.add_replica(state)
@@ -511,8 +664,37 @@ impl Timeline {
         shared_state.sk.wal_store.flush_lsn()
     }
 
-    pub fn remove_old_wal(&self, wal_backup_enabled: bool) -> Result<()> {
-        let horizon_segno: XLogSegNo;
+    /// Bytes of WAL kept locally, from the oldest segment on disk. In-memory
+    /// last_removed_segno doesn't do as it starts from scratch on restart.
+    pub fn get_retained_wal_bytes(&self, conf: &SafeKeeperConf) -> Result<u64> {
+        let (wal_seg_size, local_start_lsn, flush_lsn) = {
+            let shared_state = self.mutex.lock().unwrap();
+            (
+                shared_state.get_wal_seg_size(),
+                shared_state.sk.state.local_start_lsn,
+                shared_state.sk.wal_store.flush_lsn(),
+            )
+        };
+        if wal_seg_size == 0 {
+            return Ok(0);
+        }
+        let timeline_dir = conf.timeline_dir(&self.zttid);
+        let oldest_segno = match wal_storage::oldest_segment(&timeline_dir, wal_seg_size)? {
+            Some(segno) => segno,
+            None => return Ok(0),
+        };
+        let start_lsn = max(
+            local_start_lsn,
+            Lsn(XLogSegNoOffsetToRecPtr(oldest_segno, 0, wal_seg_size)),
+        );
+        Ok(flush_lsn.checked_sub(start_lsn).unwrap_or(Lsn(0)).0)
+    }
+
+    pub fn remove_old_wal(&self, conf: &SafeKeeperConf) -> Result<()> {
+        let mut horizon_segno: XLogSegNo;
+        let last_removed_segno: XLogSegNo;
+        let wal_seg_size: usize;
+        let min_age: Duration;
         let remover: Box<dyn Fn(u64) -> Result<(), anyhow::Error>>;
         {
             let shared_state = self.mutex.lock().unwrap();
@@ -520,16 +702,34 @@ impl Timeline {
             if shared_state.get_wal_seg_size() == 0 {
                 return Ok(());
             }
-            horizon_segno = shared_state.sk.get_horizon_segno(wal_backup_enabled);
+            horizon_segno = shared_state.sk.get_horizon_segno(conf.wal_backup_enabled);
             remover = shared_state.sk.wal_store.remove_up_to();
             if horizon_segno <= 1 || horizon_segno <= shared_state.last_removed_segno {
                 return Ok(());
             }
+            last_removed_segno = shared_state.last_removed_segno;
+            wal_seg_size = shared_state.get_wal_seg_size();
+            min_age = Duration::from_secs(shared_state.sk.state.retention_policy.min_age_secs);
             // release the lock before removing
         }
         let _enter =
             info_span!("", timeline = %self.zttid.tenant_id, tenant = %self.zttid.timeline_id)
                 .entered();
+        if min_age > Duration::ZERO {
+            let since = match SystemTime::now().checked_sub(min_age) {
+                Some(since) => since,
+                None => return Ok(()), // keep everything
+            };
+            let timeline_dir = conf.timeline_dir(&self.zttid);
+            if let Some(segno) =
+                wal_storage::oldest_segment_modified_since(&timeline_dir, wal_seg_size, since)?
+            {
+                horizon_segno = min(horizon_segno, segno);
+            }
+            if horizon_segno <= 1 || horizon_segno <= last_removed_segno {
+                return Ok(());
+            }
+        }
         remover(horizon_segno - 1)?;
         self.mutex.lock().unwrap().last_removed_segno = horizon_segno;
         Ok(())
@@ -662,6 +862,36 @@ impl GlobalTimelines {
         }
     }
 
+    /// Move timeline prepared in `restored_dir` (restored from remote storage
+    /// or pulled from a peer) in place and load it, unless the timeline has
+    /// appeared meanwhile.
+    pub fn install_restored(
+        conf: &SafeKeeperConf,
+        zttid: ZTenantTimelineId,
+        restored_dir: &Path,
+    ) -> Result<Arc<Timeline>> {
+        let mut state = TIMELINES_STATE.lock().unwrap();
+        let timeline_dir = conf.timeline_dir(&zttid);
+        if state.timelines.contains_key(&zttid) || timeline_dir.join(CONTROL_FILE_NAME).exists() {
+            bail!("timeline {} already exists", zttid);
+        }
+        // Without control file there is nothing usable in the directory.
+        if timeline_dir.exists() {
+            fs::remove_dir_all(&timeline_dir)?;
+        }
+        fs::rename(restored_dir, &timeline_dir)?;
+
+        let shared_state =
+            SharedState::restore(conf, &zttid).context("failed to restore shared state")?;
+        let new_tli = Arc::new(Timeline::new(
+            zttid,
+            state.wal_backup_launcher_tx.as_ref().unwrap().clone(),
+            shared_state,
+        ));
+        state.timelines.insert(zttid, Arc::clone(&new_tli));
+        Ok(new_tli)
+    }
+
     /// Get loaded timeline, if it exists.
     pub fn get_loaded(zttid: ZTenantTimelineId) -> Option<Arc<Timeline>> {
         let state = TIMELINES_STATE.lock().unwrap();
@@ -679,6 +909,25 @@ impl GlobalTimelines {
             .collect()
     }
 
+    /// WAL retained by loaded timelines, summed up per tenant.
+    pub fn retained_wal_by_tenant(conf: &SafeKeeperConf) -> HashMap<ZTenantId, u64> {
+        let timelines: Vec<Arc<Timeline>> = TIMELINES_STATE
+            .lock()
+            .unwrap()
+            .timelines
+            .values()
+            .cloned()
+            .collect();
+        let mut retained = HashMap::new();
+        for tli in timelines {
+            match tli.get_retained_wal_bytes(conf) {
+                Ok(bytes) => *retained.entry(tli.zttid.tenant_id).or_insert(0) += bytes,
+                Err(e) => warn!("failed to get WAL retained by {}: {:#}", tli.zttid, e),
+            }
+        }
+        retained
+    }
+
     /// Return FullTimelineInfo for all active timelines.
     pub fn active_timelines_metrics() -> Vec<FullTimelineInfo> {
         let state = TIMELINES_STATE.lock().unwrap();
@@ -694,6 +943,7 @@ impl GlobalTimelines {
         zttid: &ZTenantTimelineId,
         was_active: bool,
     ) -> Result<TimelineDeleteForceResult> {
+        scrub::forget_timeline(zttid);
         match std::fs::remove_dir_all(conf.timeline_dir(zttid)) {
             Ok(_) => Ok(TimelineDeleteForceResult {
                 dir_existed: true,
@@ -739,6 +989,7 @@ impl GlobalTimelines {
         tenant_id: &ZTenantId,
     ) -> Result<HashMap<ZTenantTimelineId, TimelineDeleteForceResult>> {
         info!("deleting all timelines for tenant {}", tenant_id);
+        quota::forget_tenant(tenant_id);
         let mut to_delete = HashMap::new();
         {
             // Keep mutex in this scope.
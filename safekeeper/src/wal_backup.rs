@@ -1,20 +1,24 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
 use etcd_broker::subscription_key::{
     NodeKind, OperationKind, SkOperationKind, SubscriptionKey, SubscriptionKind,
 };
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader};
 use tokio::task::JoinHandle;
 
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::collections::HashMap;
+use std::io::Cursor;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
+use lazy_static::lazy_static;
+use metrics::{register_histogram, register_int_counter, Histogram, IntCounter};
 use postgres_ffi::xlog_utils::{
-    XLogFileName, XLogSegNo, XLogSegNoOffsetToRecPtr, MAX_SEND_SIZE, PG_TLI,
+    XLogFileName, XLogSegNo, XLogSegNoOffsetToRecPtr, MAX_SEND_SIZE, PG_TLI, XLOG_BLCKSZ,
 };
-use remote_storage::{GenericRemoteStorage, RemoteStorage};
+use remote_storage::{GenericRemoteStorage, RemoteStorage, StorageMetadata};
 use tokio::fs::File;
 use tokio::runtime::Builder;
 
@@ -27,6 +31,8 @@ use tracing::*;
 use utils::{lsn::Lsn, zid::ZTenantTimelineId};
 
 use crate::broker::{Election, ElectionLeader};
+use crate::control_file::{FileStorage, CONTROL_FILE_NAME};
+use crate::safekeeper::SafeKeeperState;
 use crate::timeline::{GlobalTimelines, Timeline};
 use crate::{broker, SafeKeeperConf};
 
@@ -37,6 +43,30 @@ const BROKER_CONNECTION_RETRY_DELAY_MS: u64 = 1000;
 const UPLOAD_FAILURE_RETRY_MIN_MS: u64 = 10;
 const UPLOAD_FAILURE_RETRY_MAX_MS: u64 = 5000;
 
+/// Object metadata key with the compression of an offloaded segment; absent
+/// for segments uploaded as is.
+const COMPRESSION_METADATA_KEY: &str = "compression";
+const ZSTD_COMPRESSION: &str = "zstd";
+
+lazy_static! {
+    static ref BACKUP_WAL_BYTES: IntCounter = register_int_counter!(
+        "safekeeper_backup_wal_bytes_total",
+        "Bytes of WAL segments offloaded to remote storage, before compression"
+    )
+    .expect("Failed to register safekeeper_backup_wal_bytes_total counter");
+    static ref BACKUP_WAL_UPLOADED_BYTES: IntCounter = register_int_counter!(
+        "safekeeper_backup_wal_uploaded_bytes_total",
+        "Bytes of WAL segments uploaded to remote storage, after compression if enabled"
+    )
+    .expect("Failed to register safekeeper_backup_wal_uploaded_bytes_total counter");
+    static ref BACKUP_WAL_COMPRESSION_RATIO: Histogram = register_histogram!(
+        "safekeeper_backup_wal_compression_ratio",
+        "Ratio of original to compressed size of offloaded WAL segments",
+        vec![1.0, 1.5, 2.0, 3.0, 4.0, 6.0, 8.0, 16.0, 64.0]
+    )
+    .expect("Failed to register safekeeper_backup_wal_compression_ratio histogram");
+}
+
 pub fn wal_backup_launcher_thread_main(
     conf: SafeKeeperConf,
     wal_backup_launcher_rx: Receiver<ZTenantTimelineId>,
@@ -101,8 +131,14 @@ fn consider_start_task(
     let timeline_dir = conf.timeline_dir(&zttid);
 
     let handle = tokio::spawn(
-        backup_task_main(zttid, timeline_dir, shutdown_rx, election)
-            .instrument(info_span!("WAL backup task", zttid = %zttid)),
+        backup_task_main(
+            zttid,
+            timeline_dir,
+            conf.wal_backup_compression,
+            shutdown_rx,
+            election,
+        )
+        .instrument(info_span!("WAL backup task", zttid = %zttid)),
     );
 
     task.handle = Some(WalBackupTaskHandle {
@@ -125,12 +161,7 @@ async fn wal_backup_launcher_main_loop(
         conf.remote_storage
     );
 
-    let conf_ = conf.clone();
-    REMOTE_STORAGE.get_or_init(|| {
-        conf_.remote_storage.as_ref().map(|c| {
-            GenericRemoteStorage::new(conf_.workdir, c).expect("failed to create remote storage")
-        })
-    });
+    init_remote_storage(&conf);
 
     // Presense in this map means launcher is aware s3 offloading is needed for
     // the timeline, but task is started only if it makes sense for to offload
@@ -185,6 +216,7 @@ async fn wal_backup_launcher_main_loop(
 struct WalBackupTask {
     timeline: Arc<Timeline>,
     timeline_dir: PathBuf,
+    compress: bool,
     wal_seg_size: usize,
     commit_lsn_watch_rx: watch::Receiver<Lsn>,
     leader: Option<ElectionLeader>,
@@ -195,6 +227,7 @@ struct WalBackupTask {
 async fn backup_task_main(
     zttid: ZTenantTimelineId,
     timeline_dir: PathBuf,
+    compress: bool,
     mut shutdown_rx: Receiver<()>,
     election: Election,
 ) {
@@ -212,6 +245,7 @@ async fn backup_task_main(
         commit_lsn_watch_rx: timeline.get_commit_lsn_watch_rx(),
         timeline,
         timeline_dir,
+        compress,
         leader: None,
         election,
     };
@@ -317,6 +351,7 @@ impl WalBackupTask {
                     commit_lsn,
                     self.wal_seg_size,
                     &self.timeline_dir,
+                    self.compress,
                 )
                 .await
                 {
@@ -324,6 +359,13 @@ impl WalBackupTask {
                         backup_lsn = backup_lsn_result;
                         self.timeline.set_wal_backup_lsn(backup_lsn_result);
                         retry_attempt = 0;
+
+                        // Failure here is not fatal: restore would just get
+                        // less WAL, next offloaded segment will retry.
+                        let state = self.timeline.get_state_for_backup(backup_lsn);
+                        if let Err(e) = backup_timeline_state(&self.timeline_dir, &state).await {
+                            warn!("failed to offload timeline state: {:?}", e);
+                        }
                     }
                     Err(e) => {
                         error!(
@@ -344,11 +386,12 @@ pub async fn backup_lsn_range(
     end_lsn: Lsn,
     wal_seg_size: usize,
     timeline_dir: &Path,
+    compress: bool,
 ) -> Result<Lsn> {
     let mut res = start_lsn;
     let segments = get_segments(start_lsn, end_lsn, wal_seg_size);
     for s in &segments {
-        backup_single_segment(s, timeline_dir)
+        backup_single_segment(s, timeline_dir, compress)
             .await
             .with_context(|| format!("offloading segno {}", s.seg_no))?;
 
@@ -363,10 +406,10 @@ pub async fn backup_lsn_range(
     Ok(res)
 }
 
-async fn backup_single_segment(seg: &Segment, timeline_dir: &Path) -> Result<()> {
+async fn backup_single_segment(seg: &Segment, timeline_dir: &Path, compress: bool) -> Result<()> {
     let segment_file_name = seg.file_path(timeline_dir)?;
 
-    backup_object(&segment_file_name, seg.size()).await?;
+    backup_object(&segment_file_name, seg.size(), compress).await?;
     debug!("Backup of {} done", segment_file_name.display());
 
     Ok(())
@@ -417,12 +460,84 @@ fn get_segments(start: Lsn, end: Lsn, seg_size: usize) -> Vec<Segment> {
 
 static REMOTE_STORAGE: OnceCell<Option<GenericRemoteStorage>> = OnceCell::new();
 
-async fn backup_object(source_file: &Path, size: usize) -> Result<()> {
-    let storage = REMOTE_STORAGE.get().expect("failed to get remote storage");
+/// Create remote storage from the config, if not yet. It is shared by WAL
+/// backup and restore, whichever needs it first.
+pub fn init_remote_storage(conf: &SafeKeeperConf) {
+    REMOTE_STORAGE.get_or_init(|| {
+        conf.remote_storage.as_ref().map(|c| {
+            GenericRemoteStorage::new(conf.workdir.clone(), c)
+                .expect("failed to create remote storage")
+        })
+    });
+}
 
+async fn backup_object(source_file: &Path, size: usize, compress: bool) -> Result<()> {
     let file = File::open(&source_file).await?;
+    BACKUP_WAL_BYTES.inc_by(size as u64);
+    if !compress {
+        upload_object(source_file, file, size, None).await?;
+        BACKUP_WAL_UPLOADED_BYTES.inc_by(size as u64);
+        return Ok(());
+    }
+
+    // Upload needs the size in advance, so compress the segment in memory.
+    let mut compressed = Vec::new();
+    ZstdEncoder::new(BufReader::new(file))
+        .read_to_end(&mut compressed)
+        .await
+        .context("failed to compress segment")?;
+    let compressed_size = compressed.len();
+    let metadata = StorageMetadata::new(HashMap::from([(
+        COMPRESSION_METADATA_KEY.to_string(),
+        ZSTD_COMPRESSION.to_string(),
+    )]));
+    upload_object(
+        source_file,
+        Cursor::new(compressed),
+        compressed_size,
+        Some(metadata),
+    )
+    .await?;
+    BACKUP_WAL_UPLOADED_BYTES.inc_by(compressed_size as u64);
+    BACKUP_WAL_COMPRESSION_RATIO.observe(size as f64 / max(compressed_size, 1) as f64);
+    Ok(())
+}
+
+/// Upload timeline state matching the offloaded WAL next to it, in the control
+/// file format; timeline is restored from them.
+async fn backup_timeline_state(timeline_dir: &Path, state: &SafeKeeperState) -> Result<()> {
+    let buf = FileStorage::serialize_state(state)?;
+    let size = buf.len();
+    upload_object(
+        &timeline_dir.join(CONTROL_FILE_NAME),
+        Cursor::new(buf),
+        size,
+        None,
+    )
+    .await?;
+    debug!("Backup of timeline state up to {} done", state.backup_lsn);
+    Ok(())
+}
 
-    // Storage is initialized by launcher at this point.
+/// Download timeline state offloaded along with the WAL.
+pub async fn read_timeline_state(timeline_dir: &Path) -> Result<SafeKeeperState> {
+    let (mut reader, copy_result) = read_object(timeline_dir.join(CONTROL_FILE_NAME), 0).await;
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf).await?;
+    copy_result.await??;
+    FileStorage::deserialize_state(&buf)
+}
+
+/// Upload `file` to the remote object corresponding to local `source_file`.
+async fn upload_object(
+    source_file: &Path,
+    file: impl AsyncRead + Unpin + Send + Sync + 'static,
+    size: usize,
+    metadata: Option<StorageMetadata>,
+) -> Result<()> {
+    let storage = REMOTE_STORAGE.get().expect("failed to get remote storage");
+
+    // Storage is initialized by launcher or restore at this point.
     match storage.as_ref().unwrap() {
         GenericRemoteStorage::Local(local_storage) => {
             let destination = local_storage.remote_object_id(source_file)?;
@@ -432,7 +547,9 @@ async fn backup_object(source_file: &Path, size: usize) -> Result<()> {
                 source_file.display(),
                 destination.display()
             );
-            local_storage.upload(file, size, &destination, None).await
+            local_storage
+                .upload(file, size, &destination, metadata)
+                .await
         }
         GenericRemoteStorage::S3(s3_storage) => {
             let s3key = s3_storage.remote_object_id(source_file)?;
@@ -442,55 +559,152 @@ async fn backup_object(source_file: &Path, size: usize) -> Result<()> {
                 source_file.display(),
                 s3key
             );
-            s3_storage.upload(file, size, &s3key, None).await
+            s3_storage.upload(file, size, &s3key, metadata).await
+        }
+        GenericRemoteStorage::Azure(azure_storage) => {
+            let blob_name = azure_storage.remote_object_id(source_file)?;
+
+            debug!(
+                "Azure upload about to start from {} to {:?}",
+                source_file.display(),
+                blob_name
+            );
+            azure_storage.upload(file, size, &blob_name, metadata).await
+        }
+        GenericRemoteStorage::Gcs(gcs_storage) => {
+            let object_name = gcs_storage.remote_object_id(source_file)?;
+
+            debug!(
+                "GCS upload about to start from {} to {:?}",
+                source_file.display(),
+                object_name
+            );
+            gcs_storage.upload(file, size, &object_name, metadata).await
         }
     }?;
 
     Ok(())
 }
 
+/// Stream the object corresponding to local `file_path` from `offset`,
+/// decompressing it if it was compressed on upload.
 pub async fn read_object(
     file_path: PathBuf,
     offset: u64,
-) -> (impl AsyncRead, JoinHandle<Result<()>>) {
-    let storage = REMOTE_STORAGE.get().expect("failed to get remote storage");
-
+) -> (impl AsyncRead + Unpin, JoinHandle<Result<()>>) {
     let (mut pipe_writer, pipe_reader) = tokio::io::duplex(MAX_SEND_SIZE);
 
     let copy_result = tokio::spawn(async move {
-        let res = match storage.as_ref().unwrap() {
-            GenericRemoteStorage::Local(local_storage) => {
-                let source = local_storage.remote_object_id(&file_path)?;
-
-                info!(
-                    "local download about to start from {} at offset {}",
-                    source.display(),
-                    offset
-                );
-                local_storage
-                    .download_byte_range(&source, offset, None, &mut pipe_writer)
-                    .await
-            }
-            GenericRemoteStorage::S3(s3_storage) => {
-                let s3key = s3_storage.remote_object_id(&file_path)?;
-
-                info!(
-                    "S3 download about to start from {:?} at offset {}",
-                    s3key, offset
-                );
-                s3_storage
-                    .download_byte_range(&s3key, offset, None, &mut pipe_writer)
-                    .await
-            }
-        };
-
-        if let Err(e) = res {
-            error!("failed to download WAL segment from remote storage: {}", e);
-            Err(e)
-        } else {
-            Ok(())
+        let res = download_object(&file_path, offset, &mut pipe_writer).await;
+        if let Err(e) = &res {
+            error!(
+                "failed to download WAL segment from remote storage: {:#}",
+                e
+            );
         }
+        res
     });
 
     (pipe_reader, copy_result)
 }
+
+async fn download_object(
+    file_path: &Path,
+    offset: u64,
+    to: &mut (impl AsyncWrite + Unpin + Send + Sync),
+) -> Result<()> {
+    // Metadata comes along with any range, learn the format from a small one.
+    let metadata = download_range(
+        file_path,
+        0,
+        Some(XLOG_BLCKSZ as u64),
+        &mut tokio::io::sink(),
+    )
+    .await?;
+    let compression = metadata
+        .as_ref()
+        .and_then(|m| m.get(COMPRESSION_METADATA_KEY));
+    match compression {
+        None => {
+            download_range(file_path, offset, None, to).await?;
+        }
+        Some(ZSTD_COMPRESSION) => {
+            // Compressed stream can't be entered in the middle: decompress it
+            // from the start, skipping data up to the offset.
+            let (mut compressed_writer, compressed_reader) = tokio::io::duplex(MAX_SEND_SIZE);
+            let download = async move {
+                download_range(file_path, 0, None, &mut compressed_writer).await?;
+                Ok::<_, anyhow::Error>(())
+            };
+            let decompress = async move {
+                let mut decoder = ZstdDecoder::new(BufReader::new(compressed_reader));
+                tokio::io::copy(&mut (&mut decoder).take(offset), &mut tokio::io::sink()).await?;
+                tokio::io::copy(&mut decoder, to).await?;
+                Ok::<_, anyhow::Error>(())
+            };
+            tokio::try_join!(download, decompress)
+                .with_context(|| format!("failed to decompress {}", file_path.display()))?;
+        }
+        Some(other) => bail!("unknown compression '{}' of {}", other, file_path.display()),
+    }
+    Ok(())
+}
+
+/// Download byte range of the object corresponding to local `file_path`.
+async fn download_range(
+    file_path: &Path,
+    start_inclusive: u64,
+    end_exclusive: Option<u64>,
+    to: &mut (impl AsyncWrite + Unpin + Send + Sync),
+) -> Result<Option<StorageMetadata>> {
+    let storage = REMOTE_STORAGE.get().expect("failed to get remote storage");
+
+    let metadata = match storage.as_ref().unwrap() {
+        GenericRemoteStorage::Local(local_storage) => {
+            let source = local_storage.remote_object_id(file_path)?;
+
+            info!(
+                "local download about to start from {} at offset {}",
+                source.display(),
+                start_inclusive
+            );
+            local_storage
+                .download_byte_range(&source, start_inclusive, end_exclusive, to)
+                .await
+        }
+        GenericRemoteStorage::S3(s3_storage) => {
+            let s3key = s3_storage.remote_object_id(file_path)?;
+
+            info!(
+                "S3 download about to start from {:?} at offset {}",
+                s3key, start_inclusive
+            );
+            s3_storage
+                .download_byte_range(&s3key, start_inclusive, end_exclusive, to)
+                .await
+        }
+        GenericRemoteStorage::Azure(azure_storage) => {
+            let blob_name = azure_storage.remote_object_id(file_path)?;
+
+            info!(
+                "Azure download about to start from {:?} at offset {}",
+                blob_name, start_inclusive
+            );
+            azure_storage
+                .download_byte_range(&blob_name, start_inclusive, end_exclusive, to)
+                .await
+        }
+        GenericRemoteStorage::Gcs(gcs_storage) => {
+            let object_name = gcs_storage.remote_object_id(file_path)?;
+
+            info!(
+                "GCS download about to start from {:?} at offset {}",
+                object_name, start_inclusive
+            );
+            gcs_storage
+                .download_byte_range(&object_name, start_inclusive, end_exclusive, to)
+                .await
+        }
+    }?;
+    Ok(metadata)
+}
@@ -0,0 +1,356 @@
+//! Restore of a timeline whose local data safekeeper has lost (e.g. along
+//! with the disk) from remote storage. WAL backup offloads the timeline state
+//! along with segments, see `wal_backup::backup_timeline_state`; it becomes
+//! the control file, and the last offloaded segments become local WAL. After
+//! that safekeeper is a lagging member of the quorum, it catches up with
+//! peers as usual.
+//!
+//! Restore is started either explicitly via the HTTP API or by the broker
+//! loop, when a peer reports offloaded WAL of a timeline we are member of but
+//! don't have.
+//!
+//! The offloaded term might be behind the one safekeeper had voted in before
+//! losing the data, and voting in that term again could elect two proposers.
+//! So the restored term is raised to the highest one peers report in the
+//! broker, and restore waits until enough members have reported it for every
+//! election quorum to include one of them.
+
+use anyhow::{bail, Context, Result};
+use etcd_broker::subscription_value::SkTimelineInfo;
+use lazy_static::lazy_static;
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tracing::*;
+
+use postgres_ffi::xlog_utils::{
+    find_end_of_wal, XLogFileName, XLogSegNo, XLogSegNoOffsetToRecPtr, PG_TLI,
+};
+use utils::{
+    lsn::Lsn,
+    zid::{NodeId, ZTenantTimelineId},
+};
+
+use crate::control_file::{FileStorage, CONTROL_FILE_NAME};
+use crate::safekeeper::{MemberSet, SafeKeeperState, Term};
+use crate::timeline::{GlobalTimelines, Timeline};
+use crate::wal_backup;
+use crate::SafeKeeperConf;
+
+/// Don't retry failed automatic restore of a timeline more often than this.
+const RESTORE_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+enum RestoreStatus {
+    InProgress,
+    /// Automatic restore failed at this moment.
+    Failed(Instant),
+    /// We are not a member of the timeline, no automatic restore then.
+    NotMember,
+}
+
+lazy_static! {
+    static ref RESTORES: Mutex<HashMap<ZTenantTimelineId, RestoreStatus>> =
+        Mutex::new(HashMap::new());
+    /// Terms peers report for the timelines we don't have.
+    static ref PEER_TERMS: Mutex<HashMap<ZTenantTimelineId, HashMap<NodeId, Term>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Registers restore of the timeline, so that at most one runs at a time.
+/// Outcome worth remembering is recorded on drop. Pulling the timeline from
+/// a peer, see `pull_timeline`, takes it as well.
+pub(crate) struct RestoreGuard {
+    zttid: ZTenantTimelineId,
+    outcome: Option<RestoreStatus>,
+}
+
+impl RestoreGuard {
+    /// Explicit restore ignores outcomes of previous attempts.
+    pub(crate) fn new(zttid: ZTenantTimelineId, explicit: bool) -> Option<RestoreGuard> {
+        let mut restores = RESTORES.lock().unwrap();
+        match restores.get(&zttid) {
+            Some(RestoreStatus::InProgress) => return None,
+            Some(RestoreStatus::Failed(at))
+                if !explicit && at.elapsed() < RESTORE_RETRY_INTERVAL =>
+            {
+                return None
+            }
+            Some(RestoreStatus::NotMember) if !explicit => return None,
+            _ => {}
+        }
+        restores.insert(zttid, RestoreStatus::InProgress);
+        Some(RestoreGuard {
+            zttid,
+            outcome: None,
+        })
+    }
+}
+
+impl Drop for RestoreGuard {
+    fn drop(&mut self) {
+        let mut restores = RESTORES.lock().unwrap();
+        match self.outcome.take() {
+            Some(outcome) => restores.insert(self.zttid, outcome),
+            None => restores.remove(&self.zttid),
+        };
+    }
+}
+
+/// Restore the timeline on user request.
+pub async fn restore(conf: &SafeKeeperConf, zttid: ZTenantTimelineId) -> Result<Arc<Timeline>> {
+    let _guard = match RestoreGuard::new(zttid, true) {
+        Some(guard) => guard,
+        None => bail!("restore of timeline {} is already in progress", zttid),
+    };
+    let state = download_timeline_state(conf, &zttid).await?;
+    restore_timeline(conf, zttid, state).await
+}
+
+/// Called by the broker loop on info from peer `sk_id` about a timeline we
+/// don't have: if the peer has offloaded WAL, start restore in background.
+/// It proceeds only if the offloaded state says we are a member.
+pub fn maybe_start_restore(
+    conf: &SafeKeeperConf,
+    zttid: ZTenantTimelineId,
+    sk_info: &SkTimelineInfo,
+    sk_id: NodeId,
+) {
+    if conf.remote_storage.is_none() || sk_id == conf.my_id {
+        return;
+    }
+    if let Some(term) = sk_info.term {
+        let mut peer_terms = PEER_TERMS.lock().unwrap();
+        let peer_term = peer_terms
+            .entry(zttid)
+            .or_default()
+            .entry(sk_id)
+            .or_insert(term);
+        *peer_term = max(*peer_term, term);
+    }
+    if sk_info.backup_lsn.unwrap_or(Lsn::INVALID) == Lsn::INVALID
+        // timeline exists, but failed to load
+        || conf.timeline_dir(&zttid).join(CONTROL_FILE_NAME).exists()
+    {
+        return;
+    }
+    let guard = match RestoreGuard::new(zttid, false) {
+        Some(guard) => guard,
+        None => return,
+    };
+    tokio::spawn(
+        restore_main(conf.clone(), zttid, guard)
+            .instrument(info_span!("restore", timeline = %zttid.timeline_id)),
+    );
+}
+
+async fn restore_main(conf: SafeKeeperConf, zttid: ZTenantTimelineId, mut guard: RestoreGuard) {
+    match restore_if_member(&conf, zttid).await {
+        Ok(Some(tli)) => info!("restored timeline up to {}", tli.get_end_of_wal()),
+        Ok(None) => {
+            debug!("not a member of the timeline, skipping restore");
+            guard.outcome = Some(RestoreStatus::NotMember);
+        }
+        Err(e) => {
+            warn!("failed to restore timeline: {:#}", e);
+            guard.outcome = Some(RestoreStatus::Failed(Instant::now()));
+        }
+    }
+}
+
+async fn restore_if_member(
+    conf: &SafeKeeperConf,
+    zttid: ZTenantTimelineId,
+) -> Result<Option<Arc<Timeline>>> {
+    let state = download_timeline_state(conf, &zttid).await?;
+    if !state.configuration().contains(conf.my_id) {
+        return Ok(None);
+    }
+    restore_timeline(conf, zttid, state).await.map(Some)
+}
+
+/// Fetch timeline state offloaded along with WAL.
+async fn download_timeline_state(
+    conf: &SafeKeeperConf,
+    zttid: &ZTenantTimelineId,
+) -> Result<SafeKeeperState> {
+    if conf.remote_storage.is_none() {
+        bail!("remote storage is not configured");
+    }
+    wal_backup::init_remote_storage(conf);
+    wal_backup::read_timeline_state(&conf.timeline_dir(zttid))
+        .await
+        .context("failed to download timeline state")
+}
+
+/// Restore timeline from remote storage given its offloaded state, and load
+/// it.
+async fn restore_timeline(
+    conf: &SafeKeeperConf,
+    zttid: ZTenantTimelineId,
+    mut state: SafeKeeperState,
+) -> Result<Arc<Timeline>> {
+    let wal_seg_size = state.server.wal_seg_size as usize;
+    if wal_seg_size == 0 {
+        bail!("offloaded state of timeline {} has no server info", zttid);
+    }
+    let peers_term = highest_peers_term(conf, &zttid, &state)?;
+    if peers_term > state.acceptor_state.term {
+        info!(
+            "raising offloaded term {} to {} reported by peers",
+            state.acceptor_state.term, peers_term
+        );
+        state.acceptor_state.term = peers_term;
+    }
+    // backup_lsn is the end of the last offloaded segment.
+    let end_segno = state.backup_lsn.segment_number(wal_seg_size);
+    let first_segno = state.timeline_start_lsn.segment_number(wal_seg_size);
+    if end_segno <= first_segno {
+        bail!("no WAL of timeline {} offloaded", zttid);
+    }
+
+    // Download WAL peers and pageserver might still need, but at least the
+    // last segment to continue writing into. Older WAL is read from remote
+    // storage on demand.
+    let horizon_lsn = max(
+        min(state.remote_consistent_lsn, state.peer_horizon_lsn),
+        state.timeline_start_lsn,
+    );
+    let start_segno = min(horizon_lsn.segment_number(wal_seg_size), end_segno - 1);
+
+    let timeline_dir = conf.timeline_dir(&zttid);
+    let restore_dir = timeline_dir.with_extension("restore");
+    // leftovers of interrupted restore
+    if restore_dir.exists() {
+        fs::remove_dir_all(&restore_dir)?;
+    }
+    fs::create_dir_all(&restore_dir)?;
+
+    info!(
+        "restoring segments {}..{} offloaded up to {}",
+        start_segno, end_segno, state.backup_lsn
+    );
+    for segno in start_segno..end_segno {
+        download_segment(
+            &timeline_dir,
+            &restore_dir,
+            segno,
+            wal_seg_size,
+            segno + 1 == end_segno,
+        )
+        .await?;
+    }
+
+    // Offloaded segments are complete, but the last record in them might be
+    // not; resume right after the last complete one.
+    let end_lsn =
+        Lsn(find_end_of_wal(&restore_dir, wal_seg_size, true, state.timeline_start_lsn)?.0);
+    info!("restored WAL up to {}", end_lsn);
+
+    state.commit_lsn = end_lsn;
+    state.backup_lsn = min(state.backup_lsn, end_lsn);
+    state.peer_horizon_lsn = min(state.peer_horizon_lsn, end_lsn);
+    state.remote_consistent_lsn = min(state.remote_consistent_lsn, end_lsn);
+    state.local_start_lsn = max(
+        Lsn(XLogSegNoOffsetToRecPtr(start_segno, 0, wal_seg_size)),
+        state.timeline_start_lsn,
+    );
+    state.acceptor_state.term_history = state.acceptor_state.term_history.up_to(end_lsn);
+    state.config_history = state.config_history.up_to(end_lsn);
+
+    FileStorage::create_control_file(conf, &restore_dir, &state)?;
+
+    let tli = GlobalTimelines::install_restored(conf, zttid, &restore_dir)?;
+    PEER_TERMS.lock().unwrap().remove(&zttid);
+    tli.activate().await?;
+    Ok(tli)
+}
+
+/// Highest term the timeline members have reported, failing if too few of
+/// them did.
+fn highest_peers_term(
+    conf: &SafeKeeperConf,
+    zttid: &ZTenantTimelineId,
+    state: &SafeKeeperState,
+) -> Result<Term> {
+    let peer_terms = PEER_TERMS
+        .lock()
+        .unwrap()
+        .get(zttid)
+        .cloned()
+        .unwrap_or_default();
+    let configuration = state.configuration();
+    let member_sets = std::iter::once(&configuration.members).chain(&configuration.new_members);
+    for members in member_sets {
+        let required = required_peer_reports(members, conf.my_id);
+        let reported = members
+            .0
+            .iter()
+            .filter(|id| peer_terms.contains_key(*id))
+            .count();
+        if reported < required {
+            bail!(
+                "term of timeline {} is reported by {} of members {:?}, {} needed",
+                zttid,
+                reported,
+                members,
+                required
+            );
+        }
+    }
+    Ok(peer_terms.values().copied().max().unwrap_or(0))
+}
+
+/// Number of peers among the members whose terms should be known for them to
+/// include a peer of any election quorum we are part of.
+fn required_peer_reports(members: &MemberSet, my_id: NodeId) -> usize {
+    if !members.contains(my_id) {
+        return 0;
+    }
+    let peers = members.0.len() - 1;
+    // peers voting along with us in a quorum
+    let voters = members.0.len() / 2;
+    if voters == 0 {
+        0
+    } else {
+        peers - voters + 1
+    }
+}
+
+/// Download WAL segment into `restore_dir`. Remote object is named after the
+/// segment place in `timeline_dir`. The last one is saved as partial, as
+/// writing continues there.
+async fn download_segment(
+    timeline_dir: &Path,
+    restore_dir: &Path,
+    segno: XLogSegNo,
+    wal_seg_size: usize,
+    partial: bool,
+) -> Result<()> {
+    let segment_name = XLogFileName(PG_TLI, segno, wal_seg_size);
+    let mut local_name = segment_name.clone();
+    if partial {
+        local_name.push_str(".partial");
+    }
+
+    let (mut reader, copy_result) =
+        wal_backup::read_object(timeline_dir.join(&segment_name), 0).await;
+    let mut file = File::create(restore_dir.join(&local_name)).await?;
+    let size = tokio::io::copy(&mut reader, &mut file).await?;
+    copy_result
+        .await?
+        .with_context(|| format!("failed to download segment {}", segment_name))?;
+    if size != wal_seg_size as u64 {
+        bail!(
+            "downloaded segment {} has size {}, expected {}",
+            segment_name,
+            size,
+            wal_seg_size
+        );
+    }
+    file.sync_all().await?;
+    Ok(())
+}
@@ -11,6 +11,7 @@ use anyhow::{anyhow, bail, Context, Result};
 use std::io::{self, Seek, SeekFrom};
 use std::pin::Pin;
 use tokio::io::AsyncRead;
+use tokio::task::JoinHandle;
 
 use lazy_static::lazy_static;
 use postgres_ffi::xlog_utils::{
@@ -21,6 +22,7 @@ use std::cmp::min;
 use std::fs::{self, remove_file, File, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use tracing::*;
 
@@ -475,6 +477,37 @@ impl Storage for PhysicalStorage {
     }
 }
 
+/// Find the oldest WAL segment in timeline_dir.
+pub fn oldest_segment(timeline_dir: &Path, wal_seg_size: usize) -> Result<Option<XLogSegNo>> {
+    oldest_segment_modified_since(timeline_dir, wal_seg_size, SystemTime::UNIX_EPOCH)
+}
+
+/// Find the oldest WAL segment in timeline_dir modified at `since` or later,
+/// i.e. the first one retention by age keeps. Segments are written in order,
+/// so all next ones are kept as well.
+pub fn oldest_segment_modified_since(
+    timeline_dir: &Path,
+    wal_seg_size: usize,
+    since: SystemTime,
+) -> Result<Option<XLogSegNo>> {
+    let mut oldest = None;
+    for entry in fs::read_dir(&timeline_dir)? {
+        let entry = entry?;
+        let fname = entry.file_name();
+        if let Some(fname_str) = fname.to_str() {
+            if !IsXLogFileName(fname_str) && !IsPartialXLogFileName(fname_str) {
+                continue;
+            }
+            if entry.metadata()?.modified()? < since {
+                continue;
+            }
+            let (segno, _) = XLogFromFileName(fname_str, wal_seg_size);
+            oldest = Some(oldest.map_or(segno, |oldest| min(oldest, segno)));
+        }
+    }
+    Ok(oldest)
+}
+
 /// Remove all WAL segments in timeline_dir <= given segno.
 fn remove_up_to(timeline_dir: &Path, wal_seg_size: usize, segno_up_to: XLogSegNo) -> Result<()> {
     let mut n_removed = 0;
@@ -510,6 +543,8 @@ pub struct WalReader {
     wal_seg_size: usize,
     pos: Lsn,
     wal_segment: Option<Pin<Box<dyn AsyncRead>>>,
+    /// Download feeding `wal_segment`, if it is read from remote storage.
+    remote_download: Option<JoinHandle<Result<()>>>,
 
     enable_remote_read: bool,
     // S3 will be used to read WAL if LSN is not available locally
@@ -541,6 +576,7 @@ impl WalReader {
             wal_seg_size: state.server.wal_seg_size as usize,
             pos: start_pos,
             wal_segment: None,
+            remote_download: None,
             enable_remote_read,
             local_start_lsn: state.local_start_lsn,
         })
@@ -559,20 +595,33 @@ impl WalReader {
 
         // Read some data from the file.
         let buf = &mut buf[0..send_size];
-        let send_size = wal_segment.read_exact(buf).await?;
+        let send_size = match wal_segment.read_exact(buf).await {
+            Ok(send_size) => send_size,
+            Err(e) => {
+                // Remote stream just ends if download fails, report the cause.
+                if let Some(download) = self.remote_download.take() {
+                    download.await?.with_context(|| {
+                        format!("failed to read WAL at {} from remote storage", self.pos)
+                    })?;
+                }
+                return Err(e).with_context(|| format!("failed to read WAL at {}", self.pos));
+            }
+        };
         self.pos += send_size as u64;
 
         // Decide whether to reuse this file. If we don't set wal_segment here
         // a new reader will be opened next time.
         if self.pos.segment_offset(self.wal_seg_size) != 0 {
             self.wal_segment = Some(wal_segment);
+        } else {
+            self.remote_download = None;
         }
 
         Ok(send_size)
     }
 
     /// Open WAL segment at the current position of the reader.
-    async fn open_segment(&self) -> Result<Pin<Box<dyn AsyncRead>>> {
+    async fn open_segment(&mut self) -> Result<Pin<Box<dyn AsyncRead>>> {
         let xlogoff = self.pos.segment_offset(self.wal_seg_size) as usize;
         let segno = self.pos.segment_number(self.wal_seg_size);
         let wal_file_name = XLogFileName(PG_TLI, segno, self.wal_seg_size);
@@ -604,11 +653,21 @@ impl WalReader {
 
         // Try to open remote file, if remote reads are enabled
         if self.enable_remote_read {
-            let (reader, _) = read_object(wal_file_path, xlogoff as u64).await;
+            debug!(
+                "reading WAL segment {} from remote storage at offset {}",
+                wal_file_path.display(),
+                xlogoff
+            );
+            let (reader, download) = read_object(wal_file_path, xlogoff as u64).await;
+            self.remote_download = Some(download);
             return Ok(Box::pin(reader));
         }
 
-        bail!("WAL segment is not found")
+        bail!(
+            "WAL segment {} is not found, requested WAL at {}",
+            wal_file_path.display(),
+            self.pos
+        )
     }
 
     /// Helper function for opening a wal file.
@@ -655,3 +714,116 @@ fn wal_file_paths(
     let wal_file_partial_path = timeline_dir.join(wal_file_name + ".partial");
     Ok((wal_file_path, wal_file_partial_path))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::{NonZeroU32, NonZeroUsize};
+
+    use remote_storage::{RemoteStorageConfig, RemoteStorageKind};
+
+    use super::*;
+    use crate::wal_backup;
+
+    const WAL_SEG_SIZE: usize = 16 * XLOG_BLCKSZ;
+
+    /// Byte of the test WAL at the given position.
+    fn wal_byte(lsn: Lsn) -> u8 {
+        (lsn.0 % 251) as u8
+    }
+
+    fn write_segment(path: &Path, segno: XLogSegNo) {
+        let start = segno * WAL_SEG_SIZE as u64;
+        let data: Vec<u8> = (start..start + WAL_SEG_SIZE as u64)
+            .map(|lsn| wal_byte(Lsn(lsn)))
+            .collect();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    /// Read WAL up to the given position, checking what is read.
+    async fn read_up_to(reader: &mut WalReader, end_pos: Lsn) {
+        // odd size for reads not to be aligned with the segment boundaries
+        let mut buf = vec![0u8; 3000];
+        while reader.pos < end_pos {
+            let start_pos = reader.pos;
+            let len = min(buf.len() as u64, end_pos.0 - start_pos.0) as usize;
+            let read = reader.read(&mut buf[..len]).await.unwrap();
+            for (i, byte) in buf[..read].iter().enumerate() {
+                assert_eq!(*byte, wal_byte(start_pos + i as u64), "at {}", start_pos);
+            }
+        }
+    }
+
+    /// WAL removed locally is streamed from the remote storage, switching to
+    /// it both at the segment boundary and in the middle of a segment.
+    #[tokio::test]
+    async fn read_wal_removed_locally() {
+        let workdir = tempfile::tempdir().unwrap();
+        let remote_dir = tempfile::tempdir().unwrap();
+        let conf = SafeKeeperConf {
+            workdir: workdir.path().to_owned(),
+            remote_storage: Some(RemoteStorageConfig {
+                max_concurrent_syncs: NonZeroUsize::new(1).unwrap(),
+                max_sync_errors: NonZeroU32::new(1).unwrap(),
+                max_upload_bytes_per_second: None,
+                max_download_bytes_per_second: None,
+                max_index_part_versions: NonZeroUsize::new(1).unwrap(),
+                storage: RemoteStorageKind::LocalFs(remote_dir.path().to_owned()),
+                encryption: None,
+            }),
+            ..Default::default()
+        };
+        wal_backup::init_remote_storage(&conf);
+
+        // Segments 1 and 2 are backed up, segment 3 is being written.
+        let timeline_dir = workdir.path().join("timeline");
+        for segno in 1..=2 {
+            let (path, _) = wal_file_paths(&timeline_dir, segno, WAL_SEG_SIZE).unwrap();
+            write_segment(&path, segno);
+            let remote_path = remote_dir
+                .path()
+                .join(path.strip_prefix(workdir.path()).unwrap());
+            write_segment(&remote_path, segno);
+        }
+        let (_, partial_path) = wal_file_paths(&timeline_dir, 3, WAL_SEG_SIZE).unwrap();
+        write_segment(&partial_path, 3);
+
+        let seg_start = |segno: u64| Lsn(segno * WAL_SEG_SIZE as u64);
+        let mut state = SafeKeeperState::empty();
+        state.server.wal_seg_size = WAL_SEG_SIZE as u32;
+        state.timeline_start_lsn = seg_start(1);
+        state.local_start_lsn = seg_start(1);
+
+        let mut reader =
+            WalReader::new(timeline_dir.clone(), &state, seg_start(1) + 1000, true).unwrap();
+        read_up_to(&mut reader, seg_start(1) + 5000).await;
+        assert!(reader.remote_download.is_none());
+
+        // WAL removal doesn't wait for readers: the rest of the open segment
+        // is read locally, the next one from the remote storage.
+        for segno in 1..=2 {
+            let (path, _) = wal_file_paths(&timeline_dir, segno, WAL_SEG_SIZE).unwrap();
+            fs::remove_file(path).unwrap();
+        }
+        read_up_to(&mut reader, seg_start(2) + 5000).await;
+        assert!(reader.remote_download.is_some());
+        // and back to the local WAL
+        read_up_to(&mut reader, seg_start(3) + 5000).await;
+        assert!(reader.remote_download.is_none());
+
+        // local_start_lsn doesn't move on WAL removal, so a new reader, e.g.
+        // of a reconnecting pageserver, looks for the WAL locally first and
+        // switches to the remote storage in the middle of the segment.
+        let mut reader =
+            WalReader::new(timeline_dir.clone(), &state, seg_start(2) + 1234, true).unwrap();
+        read_up_to(&mut reader, seg_start(2) + 5000).await;
+        assert!(reader.remote_download.is_some());
+        read_up_to(&mut reader, seg_start(3) + 5000).await;
+        assert!(reader.remote_download.is_none());
+
+        // without the remote reads, removed WAL is not found
+        let mut reader = WalReader::new(timeline_dir, &state, seg_start(2) + 1234, false).unwrap();
+        let mut buf = vec![0u8; 3000];
+        assert!(reader.read(&mut buf).await.is_err());
+    }
+}
@@ -121,3 +121,36 @@ def test_pageserver_http_api_client_auth_enabled(neon_env_builder: NeonEnvBuilde
 
     client = env.pageserver.http_client(auth_token=management_token)
     check_client(client, env.initial_tenant)
+
+
+def test_pageserver_http_management_operations(neon_simple_env: NeonEnv):
+    env = neon_simple_env
+    client = env.pageserver.http_client()
+
+    tenant_id, timeline_id = env.neon_cli.create_tenant()
+    pg = env.postgres.create_start(DEFAULT_BRANCH_NAME, tenant_id=tenant_id)
+    pg.safe_psql("CREATE TABLE t AS SELECT g FROM generate_series(1, 10000) g")
+    pg.stop()
+
+    client.timeline_checkpoint(tenant_id, timeline_id)
+    client.timeline_compact(tenant_id, timeline_id)
+
+    gc_result = client.timeline_gc(tenant_id, timeline_id, gc_horizon=0)
+    for field in [
+            "layers_total",
+            "layers_needed_by_cutoff",
+            "layers_needed_by_pitr",
+            "layers_needed_by_branches",
+            "layers_needed_by_pins",
+            "layers_not_updated",
+            "layers_removed",
+            "elapsed",
+    ]:
+        assert isinstance(gc_result[field], int)
+    assert gc_result["layers_total"] > 0
+
+    res = client.timeline_get_lsn_by_timestamp(tenant_id, timeline_id, "1970-01-01T00:00:00Z")
+    assert res["kind"] == "past"
+
+    with pytest.raises(NeonPageserverApiException, match="Invalid timestamp"):
+        client.timeline_get_lsn_by_timestamp(tenant_id, timeline_id, "not a timestamp")
@@ -91,3 +91,47 @@ def test_readonly_node(neon_simple_env: NeonEnv):
         env.postgres.create_start(branch_name='test_readonly_node',
                                   node_name='test_readonly_node_preinitdb',
                                   lsn='0/42')
+
+
+#
+# Read-only node at a fixed LSN keeps the data it reads from being garbage
+# collected, for as long as its page stream connection is open.
+#
+def test_readonly_node_gc(neon_simple_env: NeonEnv):
+    env = neon_simple_env
+    timeline_id = env.neon_cli.create_branch('test_readonly_node_gc', 'empty')
+    pgmain = env.postgres.create_start('test_readonly_node_gc')
+
+    main_cur = pgmain.connect().cursor()
+    main_cur.execute('CREATE EXTENSION neon_test_utils')
+    main_cur.execute('CREATE TABLE foo (t text)')
+    main_cur.execute('''
+        INSERT INTO foo
+            SELECT 'long string to consume some space' || g
+            FROM generate_series(1, 100) g
+    ''')
+    main_cur.execute('SELECT pg_current_wal_insert_lsn()')
+    lsn_a = main_cur.fetchone()[0]
+
+    pg_snapshot = env.postgres.create_start(branch_name='test_readonly_node_gc',
+                                            node_name='test_readonly_node_gc_snapshot',
+                                            lsn=lsn_a)
+    snapshot_cur = pg_snapshot.connect().cursor()
+    snapshot_cur.execute('SELECT count(*) FROM foo')
+    assert snapshot_cur.fetchone() == (100, )
+
+    # Overwrite the table a few times and garbage collect everything but the latest state
+    for _ in range(3):
+        main_cur.execute('UPDATE foo SET t = t || \'x\'')
+    main_cur.execute('VACUUM foo')
+
+    client = env.pageserver.http_client()
+    client.timeline_checkpoint(env.initial_tenant, timeline_id)
+    gc_result = client.timeline_gc(env.initial_tenant, timeline_id, gc_horizon=0)
+    log.info(f'gc result: {gc_result}')
+    assert gc_result['layers_needed_by_pins'] > 0
+
+    # Evict cached pages, so that they are fetched from the pageserver again
+    snapshot_cur.execute('SELECT clear_buffer_cache()')
+    snapshot_cur.execute('SELECT count(*) FROM foo WHERE t NOT LIKE \'%x\'')
+    assert snapshot_cur.fetchone() == (100, )
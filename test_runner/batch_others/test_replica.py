@@ -0,0 +1,56 @@
+from contextlib import closing
+
+from fixtures.log_helper import log
+from fixtures.neon_fixtures import NeonEnvBuilder, wait_until
+
+
+#
+# Test read-only hot standby, which starts at the end of the timeline and
+# follows the primary by streaming WAL from safekeepers.
+#
+def test_replica(neon_env_builder: NeonEnvBuilder):
+    neon_env_builder.num_safekeepers = 3
+    env = neon_env_builder.init_start()
+
+    env.neon_cli.create_branch('test_replica')
+    primary = env.postgres.create_start('test_replica')
+    log.info("postgres is running on 'test_replica' branch")
+
+    with closing(primary.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute('CREATE TABLE t(key int primary key, value text)')
+            cur.execute("INSERT INTO t SELECT generate_series(1,100000), 'payload'")
+
+    replica = env.postgres.create_start('test_replica',
+                                        node_name='test_replica_standby',
+                                        replica=True)
+
+    with closing(replica.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute('SELECT pg_is_in_recovery()')
+            assert cur.fetchone() == (True, )
+
+            cur.execute('SELECT count(*) FROM t')
+            assert cur.fetchone() == (100000, )
+
+    # Changes made on the primary after the replica start should become visible
+    with closing(primary.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("INSERT INTO t SELECT generate_series(100001,200000), 'payload'")
+
+    def replica_caught_up():
+        with closing(replica.connect()) as conn:
+            with conn.cursor() as cur:
+                cur.execute('SELECT count(*) FROM t')
+                assert cur.fetchone() == (200000, )
+
+    wait_until(30, 1, replica_caught_up)
+
+    # Replica is read-only
+    with closing(replica.connect()) as conn:
+        with conn.cursor() as cur:
+            try:
+                cur.execute("INSERT INTO t VALUES (0, 'payload')")
+                assert False, "insert on the replica should fail"
+            except Exception as e:
+                assert 'read-only transaction' in str(e)
@@ -1,3 +1,4 @@
+import psycopg2
 import pytest
 import random
 import time
@@ -16,7 +17,7 @@ from pathlib import Path
 from fixtures.neon_fixtures import PgBin, Etcd, Postgres, RemoteStorageUsers, Safekeeper, NeonEnv, NeonEnvBuilder, PortDistributor, SafekeeperPort, neon_binpath, PgProtocol
 from fixtures.utils import get_dir_size, lsn_to_hex, mkdir_if_needed, lsn_from_hex
 from fixtures.log_helper import log
-from typing import List, Optional, Any
+from typing import Dict, List, Optional, Any
 from uuid import uuid4
 
 
@@ -350,6 +351,42 @@ def test_broker(neon_env_builder: NeonEnvBuilder):
         time.sleep(0.5)
 
 
+# Test that safekeeper which missed some WAL fetches it from peers without compute
+def test_peer_recovery(neon_env_builder: NeonEnvBuilder):
+    neon_env_builder.num_safekeepers = 3
+    env = neon_env_builder.init_start()
+
+    env.neon_cli.create_branch("test_peer_recovery", "main")
+    pg = env.postgres.create_start('test_peer_recovery')
+    pg.safe_psql("CREATE TABLE t(key int primary key, value text)")
+
+    tenant_id = pg.safe_psql("show neon.tenant_id")[0][0]
+    timeline_id = pg.safe_psql("show neon.timeline_id")[0][0]
+
+    lagging_sk = env.safekeepers[2]
+    lagging_sk.stop()
+
+    pg.safe_psql("INSERT INTO t SELECT generate_series(1,100000), 'payload'")
+    target_lsn = pg.safe_psql("SELECT pg_current_wal_flush_lsn()")[0][0]
+    # one more transaction to make sure the WAL above is committed everywhere
+    pg.safe_psql("INSERT INTO t VALUES (0, 'payload')")
+    pg.stop()
+
+    lagging_sk.start()
+    cli = lagging_sk.http_client()
+    started_at = time.time()
+    while True:
+        flush_lsn = cli.timeline_status(tenant_id, timeline_id).flush_lsn
+        if lsn_from_hex(flush_lsn) >= lsn_from_hex(target_lsn):
+            break
+        elapsed = time.time() - started_at
+        if elapsed > 30:
+            raise RuntimeError(
+                f"timed out waiting {elapsed:.0f}s for recovery up to {target_lsn}, flush_lsn is {flush_lsn}"
+            )
+        time.sleep(0.5)
+
+
 # Test that old WAL consumed by peers and pageserver is removed from safekeepers.
 @pytest.mark.parametrize('auth_enabled', [False, True])
 def test_wal_removal(neon_env_builder: NeonEnvBuilder, auth_enabled: bool):
@@ -422,6 +459,157 @@ def test_wal_removal(neon_env_builder: NeonEnvBuilder, auth_enabled: bool):
         time.sleep(0.5)
 
 
+# Test that WAL retention policy keeps WAL consumers don't need anymore.
+@pytest.mark.parametrize('policy', [{'min_bytes': 1024 * 1024 * 1024}, {'min_age_secs': 3600}])
+def test_wal_retention(neon_env_builder: NeonEnvBuilder, policy: Dict[str, int]):
+    neon_env_builder.num_safekeepers = 1
+    # to advance remote_consistent_lsn
+    neon_env_builder.enable_local_fs_remote_storage()
+    env = neon_env_builder.init_start()
+
+    env.neon_cli.create_branch('test_wal_retention')
+    pg = env.postgres.create_start('test_wal_retention')
+
+    tenant_id = pg.safe_psql("show neon.tenant_id")[0][0]
+    timeline_id = pg.safe_psql("show neon.timeline_id")[0][0]
+
+    sk = env.safekeepers[0]
+    http_cli = sk.http_client()
+    http_cli.timeline_retention_change(tenant_id, timeline_id, **policy)
+    assert http_cli.timeline_retention(tenant_id, timeline_id) == {
+        'min_bytes': 0, 'min_age_secs': 0, **policy
+    }
+
+    pg.safe_psql('CREATE TABLE t(key int primary key, value text)')
+    pg.safe_psql("INSERT INTO t SELECT generate_series(1,100000), 'payload'")
+    with closing(env.pageserver.connect()) as psconn:
+        with psconn.cursor() as pscur:
+            pscur.execute(f"checkpoint {tenant_id} {timeline_id}")
+    # Pretend WAL is offloaded to s3.
+    http_cli.record_safekeeper_info(tenant_id, timeline_id, {'backup_lsn': 'FFFFFFFF/FEFFFFFF'})
+
+    first_segment = os.path.join(sk.data_dir(), tenant_id, timeline_id, '000000010000000000000001')
+    # removal runs every 5 seconds
+    time.sleep(10)
+    assert os.path.exists(first_segment)
+
+    # policy survives restart
+    sk.stop().start()
+    assert http_cli.timeline_retention(tenant_id, timeline_id) == {
+        'min_bytes': 0, 'min_age_secs': 0, **policy
+    }
+
+    # without the policy, WAL is removed as usual
+    http_cli.timeline_retention_change(tenant_id, timeline_id)
+    started_at = time.time()
+    while os.path.exists(first_segment):
+        elapsed = time.time() - started_at
+        if elapsed > 20:
+            raise RuntimeError(f"timed out waiting {elapsed:.0f}s for first segment get removed")
+        time.sleep(0.5)
+
+
+# Test that replication slot of an external consumer keeps WAL it needs.
+def test_replication_slot(neon_env_builder: NeonEnvBuilder):
+    neon_env_builder.num_safekeepers = 1
+    # to advance remote_consistent_lsn
+    neon_env_builder.enable_local_fs_remote_storage()
+    env = neon_env_builder.init_start()
+
+    env.neon_cli.create_branch('test_replication_slot')
+    pg = env.postgres.create_start('test_replication_slot')
+
+    tenant_id = pg.safe_psql("show neon.tenant_id")[0][0]
+    timeline_id = pg.safe_psql("show neon.timeline_id")[0][0]
+
+    sk = env.safekeepers[0]
+    rows = sk.replication_command(tenant_id, timeline_id,
+                                  'CREATE_REPLICATION_SLOT "test_slot" PHYSICAL RESERVE_WAL')
+    assert rows[0][0] == 'test_slot'
+    log.info(f"slot created at {rows[0][1]}")
+    with pytest.raises(psycopg2.Error, match='already exists'):
+        sk.replication_command(tenant_id,
+                               timeline_id,
+                               'CREATE_REPLICATION_SLOT "test_slot" PHYSICAL')
+    with pytest.raises(psycopg2.Error, match='only permanent physical'):
+        sk.replication_command(tenant_id,
+                               timeline_id,
+                               'CREATE_REPLICATION_SLOT "other" LOGICAL pgoutput')
+
+    pg.safe_psql('CREATE TABLE t(key int primary key, value text)')
+    pg.safe_psql("INSERT INTO t SELECT generate_series(1,100000), 'payload'")
+    with closing(env.pageserver.connect()) as psconn:
+        with psconn.cursor() as pscur:
+            pscur.execute(f"checkpoint {tenant_id} {timeline_id}")
+    # Pretend WAL is offloaded to s3.
+    http_cli = sk.http_client()
+    http_cli.record_safekeeper_info(tenant_id, timeline_id, {'backup_lsn': 'FFFFFFFF/FEFFFFFF'})
+
+    first_segment = os.path.join(sk.data_dir(), tenant_id, timeline_id, '000000010000000000000001')
+    # removal runs every 5 seconds
+    time.sleep(10)
+    assert os.path.exists(first_segment)
+
+    # slot survives restart
+    sk.stop().start()
+    with pytest.raises(psycopg2.Error, match='already exists'):
+        sk.replication_command(tenant_id,
+                               timeline_id,
+                               'CREATE_REPLICATION_SLOT "test_slot" PHYSICAL')
+
+    sk.replication_command(tenant_id, timeline_id, 'DROP_REPLICATION_SLOT "test_slot"')
+    started_at = time.time()
+    while os.path.exists(first_segment):
+        elapsed = time.time() - started_at
+        if elapsed > 20:
+            raise RuntimeError(f"timed out waiting {elapsed:.0f}s for first segment get removed")
+        time.sleep(0.5)
+
+
+# Test that safekeeper stops accepting WAL of a tenant retaining too much of it.
+def test_tenant_wal_quota(neon_env_builder: NeonEnvBuilder):
+    neon_env_builder.num_safekeepers = 1
+    env = neon_env_builder.init_start()
+
+    env.neon_cli.create_branch('test_tenant_wal_quota')
+    pg = env.postgres.create_start('test_tenant_wal_quota')
+
+    tenant_id = pg.safe_psql("show neon.tenant_id")[0][0]
+
+    http_cli = env.safekeepers[0].http_client()
+    assert http_cli.tenant_wal_quota(tenant_id)['max_wal_bytes'] is None
+    max_wal_bytes = 1024 * 1024
+    assert http_cli.tenant_wal_quota_set(tenant_id, max_wal_bytes)['max_wal_bytes'] == max_wal_bytes
+
+    pg.safe_psql('CREATE TABLE t(key int primary key, value text)')
+    pg.safe_psql("INSERT INTO t SELECT generate_series(1,100000), 'payload'")
+
+    # usage is refreshed every 5 seconds
+    started_at = time.time()
+    while True:
+        quota = http_cli.tenant_wal_quota(tenant_id)
+        if quota['retained_wal_bytes'] > max_wal_bytes:
+            break
+        elapsed = time.time() - started_at
+        if elapsed > 20:
+            raise RuntimeError(f"timed out waiting {elapsed:.0f}s for WAL usage, it is {quota}")
+        time.sleep(0.5)
+
+    # WAL is not accepted anymore, so commit hangs
+    insert = threading.Thread(target=pg.safe_psql, args=["INSERT INTO t VALUES (0, 'payload')"])
+    insert.start()
+    insert.join(timeout=5)
+    assert insert.is_alive()
+    metrics = http_cli.get_metrics_str()
+    assert f'safekeeper_tenant_quota_rejected_appends_total{{tenant_id="{tenant_id}"}}' in metrics
+
+    # back to the default, which is unlimited
+    assert http_cli.tenant_wal_quota_reset(tenant_id)['max_wal_bytes'] is None
+    insert.join(timeout=30)
+    assert not insert.is_alive()
+    assert pg.safe_psql("SELECT count(*) FROM t")[0][0] == 100001
+
+
 def wait_segment_offload(tenant_id, timeline_id, live_sk, seg_end):
     started_at = time.time()
     http_cli = live_sk.http_client()
@@ -506,9 +694,166 @@ def test_wal_backup(neon_env_builder: NeonEnvBuilder, storage_type: str):
     wait_segment_offload(tenant_id, timeline_id, env.safekeepers[1], '0/5000000')
 
 
-@pytest.mark.parametrize('storage_type', ['mock_s3', 'local_fs'])
-def test_s3_wal_replay(neon_env_builder: NeonEnvBuilder, storage_type: str):
+# Test that safekeeper which lost timeline data restores it from remote storage
+# and rejoins the quorum.
+def test_restore_from_remote(neon_env_builder: NeonEnvBuilder):
+    neon_env_builder.num_safekeepers = 3
+    neon_env_builder.enable_local_fs_remote_storage()
+    neon_env_builder.remote_storage_users = RemoteStorageUsers.SAFEKEEPER
+    env = neon_env_builder.init_start()
+
+    env.neon_cli.create_branch('test_restore_from_remote')
+    pg = env.postgres.create_start('test_restore_from_remote')
+
+    tenant_id = pg.safe_psql("show neon.tenant_id")[0][0]
+    timeline_id = pg.safe_psql("show neon.timeline_id")[0][0]
+
+    pg.safe_psql("create table t(key int, value text)")
+    # fills more than a segment
+    pg.safe_psql("insert into t select generate_series(1,250000), 'payload'")
+    last_lsn = pg.safe_psql("SELECT pg_current_wal_flush_lsn()")[0][0]
+    wait_segment_offload(tenant_id, timeline_id, env.safekeepers[0], '0/2000000')
+    pg.stop()
+
+    victim = env.safekeepers[2]
+    victim.stop()
+    shutil.rmtree(os.path.join(victim.data_dir(), tenant_id, timeline_id))
+    victim.start()
+
+    # timeline state is offloaded right after segment, retry till it's there
+    cli = victim.http_client()
+    started_at = time.time()
+    while True:
+        try:
+            status = cli.timeline_restore(tenant_id, timeline_id)
+            break
+        except cli.HTTPError as e:
+            log.info(f"restore failed: {e}")
+        elapsed = time.time() - started_at
+        if elapsed > 20:
+            raise RuntimeError(f"timed out waiting {elapsed:.0f}s for timeline restore")
+        time.sleep(0.5)
+    log.info(f"restored timeline status is {status}")
+    assert lsn_from_hex(status.flush_lsn) > lsn_from_hex('0/1000000')
+
+    # restored timeline is already there
+    with pytest.raises(cli.HTTPError) as excinfo:
+        cli.timeline_restore(tenant_id, timeline_id)
+    assert excinfo.value.response.status_code == 409
+
+    # the rest of WAL comes from peers
+    started_at = time.time()
+    while True:
+        flush_lsn = cli.timeline_status(tenant_id, timeline_id).flush_lsn
+        if lsn_from_hex(flush_lsn) >= lsn_from_hex(last_lsn):
+            break
+        elapsed = time.time() - started_at
+        if elapsed > 30:
+            raise RuntimeError(
+                f"timed out waiting {elapsed:.0f}s for recovery up to {last_lsn}, flush_lsn is {flush_lsn}"
+            )
+        time.sleep(0.5)
+
+    # with another safekeeper down, quorum relies on the restored one
+    env.safekeepers[0].stop()
+    pg.start()
+    pg.safe_psql("insert into t values (0, 'payload')")
+    assert pg.safe_psql("select count(*) from t")[0][0] == 250001
+
+
+# Move timeline replica to another place by copying it from a peer.
+def test_pull_timeline(neon_env_builder: NeonEnvBuilder):
+    neon_env_builder.num_safekeepers = 3
+    env = neon_env_builder.init_start()
+
+    env.neon_cli.create_branch('test_pull_timeline')
+    pg = env.postgres.create_start('test_pull_timeline')
+
+    tenant_id = pg.safe_psql("show neon.tenant_id")[0][0]
+    timeline_id = pg.safe_psql("show neon.timeline_id")[0][0]
+
+    pg.safe_psql("create table t(key int, value text)")
+    # fills more than a segment
+    pg.safe_psql("insert into t select generate_series(1,250000), 'payload'")
+    pg.stop()
+
+    victim = env.safekeepers[2]
+    victim.stop()
+    shutil.rmtree(os.path.join(victim.data_dir(), tenant_id, timeline_id))
+    victim.start()
+
+    source = env.safekeepers[1]
+    source_flush_lsn = source.http_client().timeline_status(tenant_id, timeline_id).flush_lsn
+    cli = victim.http_client()
+    status = cli.timeline_pull(tenant_id, timeline_id, f"localhost:{source.port.http}")
+    log.info(f"pulled timeline status is {status}")
+    assert lsn_from_hex(status.flush_lsn) >= lsn_from_hex(source_flush_lsn)
+
+    # pulled timeline is already there
+    with pytest.raises(cli.HTTPError) as excinfo:
+        cli.timeline_pull(tenant_id, timeline_id, f"localhost:{source.port.http}")
+    assert excinfo.value.response.status_code == 409
+
+    # with the source down, quorum relies on the pulled replica
+    source.stop()
+    pg.start()
+    pg.safe_psql("insert into t values (0, 'payload')")
+    assert pg.safe_psql("select count(*) from t")[0][0] == 250001
+
+
+# Test that scrub finds corrupted WAL segment and repair fetches it from a peer.
+def test_wal_scrub(neon_env_builder: NeonEnvBuilder):
     neon_env_builder.num_safekeepers = 3
+    env = neon_env_builder.init_start()
+
+    env.neon_cli.create_branch('test_wal_scrub')
+    pg = env.postgres.create_start('test_wal_scrub')
+
+    tenant_id = pg.safe_psql("show neon.tenant_id")[0][0]
+    timeline_id = pg.safe_psql("show neon.timeline_id")[0][0]
+
+    pg.safe_psql("create table t(key int, value text)")
+    # fills more than a segment
+    pg.safe_psql("insert into t select generate_series(1,250000), 'payload'")
+    pg.stop()
+
+    sk = env.safekeepers[0]
+    cli = sk.http_client()
+    with pytest.raises(cli.HTTPError) as excinfo:
+        cli.timeline_scrub_status(tenant_id, timeline_id)
+    assert excinfo.value.response.status_code == 404
+
+    status = cli.timeline_scrub(tenant_id, timeline_id)
+    log.info(f"scrub status is {status}")
+    assert status['records'] > 0
+    assert status['corrupted_segments'] == []
+
+    segment_name = '000000010000000000000001'
+    with open(os.path.join(sk.data_dir(), tenant_id, timeline_id, segment_name), 'r+b') as f:
+        f.seek(8 * 1024 * 1024 + 100)
+        f.write(b'garbage')
+
+    status = cli.timeline_scrub(tenant_id, timeline_id)
+    log.info(f"scrub status is {status}")
+    assert [c['segment'] for c in status['corrupted_segments']] == [segment_name]
+    assert cli.timeline_scrub_status(tenant_id, timeline_id) == status
+
+    peer = env.safekeepers[1]
+    status = cli.timeline_scrub_repair(tenant_id, timeline_id, f"localhost:{peer.port.http}")
+    assert status['corrupted_segments'] == []
+    assert cli.timeline_scrub(tenant_id, timeline_id)['corrupted_segments'] == []
+
+    # repaired WAL is good for the compute as well
+    pg.start()
+    assert pg.safe_psql("select count(*) from t")[0][0] == 250000
+
+
+# Compressed segments are read back the same way, check once.
+@pytest.mark.parametrize('storage_type, compression', [('mock_s3', False), ('local_fs', False),
+                                                       ('local_fs', True)])
+def test_s3_wal_replay(neon_env_builder: NeonEnvBuilder, storage_type: str, compression: bool):
+    neon_env_builder.num_safekeepers = 3
+    neon_env_builder.safekeepers_wal_backup_compression = compression
     if storage_type == 'local_fs':
         neon_env_builder.enable_local_fs_remote_storage()
     elif storage_type == 'mock_s3':
@@ -803,6 +1148,94 @@ def test_timeline_status(neon_env_builder: NeonEnvBuilder, auth_enabled: bool):
     assert tli_status.timeline_start_lsn == timeline_start_lsn
 
 
+# Remove a safekeeper from the timeline members with the joint configuration,
+# while the compute keeps writing. Past the switch, safekeepers commit WAL only
+# once the majority of each member set has it.
+def test_membership_change(neon_env_builder: NeonEnvBuilder):
+    neon_env_builder.num_safekeepers = 3
+    env = neon_env_builder.init_start()
+
+    env.neon_cli.create_branch('test_membership_change')
+    pg = env.postgres.create_start('test_membership_change')
+
+    tenant_id = pg.safe_psql("show neon.tenant_id")[0][0]
+    timeline_id = pg.safe_psql("show neon.timeline_id")[0][0]
+    pg.safe_psql("create table t(key int primary key, value text)")
+
+    clients = [sk.http_client() for sk in env.safekeepers]
+    for cli in clients:
+        status = cli.timeline_membership(tenant_id, timeline_id)
+        assert status['configuration']['generation'] == 0
+        assert status['is_member']
+        assert status['config_history'] == []
+
+    old_members = [sk.id for sk in env.safekeepers]
+    new_members = old_members[1:]
+    # switch all safekeepers at the same LSN, past the current end of WAL, so
+    # that the change can't complete before new WAL is written
+    switch_lsn = max(
+        lsn_from_hex(cli.timeline_status(tenant_id, timeline_id).flush_lsn) for cli in clients) + 1
+    joint_configuration = {
+        'generation': 1,
+        'members': old_members,
+        'new_members': new_members,
+    }
+    # With one of the two new members down, the old members still form a
+    # quorum, but the new ones don't: WAL past the switch is not committed.
+    # The victim is switched first, so it has no WAL past the switch for sure.
+    victim = env.safekeepers[2]
+    for sk, cli in reversed(list(zip(env.safekeepers, clients))):
+        status = cli.timeline_membership_change(tenant_id,
+                                                timeline_id,
+                                                joint_configuration,
+                                                lsn=switch_lsn)
+        log.info(f"safekeeper {sk.id} membership after the joint configuration switch: {status}")
+        assert status['configuration'] == joint_configuration
+        if sk is victim:
+            victim.stop()
+
+    pg.safe_psql("insert into t select generate_series(1, 10000), 'payload'")
+    for cli in clients[:2]:
+        tli_status = cli.timeline_status(tenant_id, timeline_id)
+        assert lsn_from_hex(tli_status.flush_lsn) > switch_lsn
+        assert lsn_from_hex(tli_status.commit_lsn) <= switch_lsn
+        status = cli.timeline_membership(tenant_id, timeline_id)
+        assert status['configuration'] == joint_configuration
+
+    # once the new members catch up, the change completes
+    victim.start()
+    started_at = time.time()
+    while True:
+        statuses = [cli.timeline_membership(tenant_id, timeline_id) for cli in clients]
+        if all(status['configuration']['generation'] == 2 for status in statuses):
+            break
+        if time.time() - started_at > 30:
+            raise RuntimeError(f"timed out waiting for membership change, statuses: {statuses}")
+        pg.safe_psql("insert into t values (0, 'payload') on conflict (key) do nothing")
+        time.sleep(0.5)
+
+    for cli, status in zip(clients, statuses):
+        assert status['configuration']['members'] == new_members
+        assert status['configuration']['new_members'] is None
+        tli_status = cli.timeline_status(tenant_id, timeline_id)
+        assert lsn_from_hex(tli_status.commit_lsn) > switch_lsn
+    assert [status['is_member'] for status in statuses] == [False, True, True]
+
+    # another change is refused without the joint configuration
+    with pytest.raises(clients[1].HTTPError, match='Conflict'):
+        clients[1].timeline_membership_change(tenant_id,
+                                              timeline_id, {
+                                                  'generation': 3,
+                                                  'members': old_members,
+                                                  'new_members': None,
+                                              },
+                                              lsn=switch_lsn)
+
+    # writes are not affected
+    pg.safe_psql("insert into t values (10001, 'payload')")
+    assert pg.safe_psql("select count(*) from t where key > 0")[0][0] == 10001
+
+
 class SafekeeperEnv:
     def __init__(self,
                  repo_dir: Path,
@@ -982,14 +1415,14 @@ def test_replace_safekeeper(neon_env_builder: NeonEnvBuilder):
     env.safekeepers[1].start()
     env.safekeepers[2].stop(immediate=True)
     execute_payload(pg)
-    env.safekeepers[2].start()
+    victim.start()
 
     env.safekeepers[0].stop(immediate=True)
     env.safekeepers[1].stop(immediate=True)
     env.safekeepers[2].stop(immediate=True)
     env.safekeepers[0].start()
     env.safekeepers[1].start()
-    env.safekeepers[2].start()
+    victim.start()
 
     execute_payload(pg)
     show_statuses(env.safekeepers, tenant_id, timeline_id)
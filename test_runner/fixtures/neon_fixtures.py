@@ -502,6 +502,8 @@ class NeonEnvBuilder:
             safekeepers_id_start: int = 0,
             # fsync is disabled by default to make the tests go faster
             safekeepers_enable_fsync: bool = False,
+            # zstd compression of WAL segments offloaded by safekeepers
+            safekeepers_wal_backup_compression: bool = False,
             auth_enabled: bool = False,
             rust_log_override: Optional[str] = None,
             default_branch_name=DEFAULT_BRANCH_NAME):
@@ -516,6 +518,7 @@ class NeonEnvBuilder:
         self.num_safekeepers = num_safekeepers
         self.safekeepers_id_start = safekeepers_id_start
         self.safekeepers_enable_fsync = safekeepers_enable_fsync
+        self.safekeepers_wal_backup_compression = safekeepers_wal_backup_compression
         self.auth_enabled = auth_enabled
         self.default_branch_name = default_branch_name
         self.env: Optional[NeonEnv] = None
@@ -674,6 +677,10 @@ class NeonEnv:
                 toml += textwrap.dedent(f"""
                 auth_enabled = true
                 """)
+            if config.safekeepers_wal_backup_compression:
+                toml += textwrap.dedent(f"""
+                wal_backup_compression = true
+                """)
             if bool(self.remote_storage_users
                     & RemoteStorageUsers.SAFEKEEPER) and self.remote_storage is not None:
                 toml += textwrap.dedent(f"""
@@ -882,6 +889,53 @@ class NeonPageserverHttpClient(requests.Session):
         assert isinstance(res_json, dict)
         return res_json
 
+    def timeline_gc(self,
+                    tenant_id: uuid.UUID,
+                    timeline_id: uuid.UUID,
+                    gc_horizon: Optional[int] = None) -> Dict[Any, Any]:
+        res = self.put(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/do_gc",
+            json={'gc_horizon': gc_horizon},
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_compact(self, tenant_id: uuid.UUID, timeline_id: uuid.UUID):
+        res = self.put(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/compact"
+        )
+        self.verbose_error(res)
+
+    def timeline_checkpoint(self, tenant_id: uuid.UUID, timeline_id: uuid.UUID):
+        res = self.put(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/checkpoint"
+        )
+        self.verbose_error(res)
+
+    def timeline_get_lsn_by_timestamp(self,
+                                      tenant_id: uuid.UUID,
+                                      timeline_id: uuid.UUID,
+                                      timestamp: str) -> Dict[Any, Any]:
+        res = self.get(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/get_lsn_by_timestamp",
+            params={'timestamp': timestamp},
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def configure_failpoints(self, config_strings: List[Tuple[str, str]]):
+        res = self.put(
+            f"http://localhost:{self.port}/v1/failpoints",
+            json=[{
+                'name': name, 'actions': actions
+            } for name, actions in config_strings],
+        )
+        self.verbose_error(res)
+
     def get_metrics(self) -> str:
         res = self.get(f"http://localhost:{self.port}/metrics")
         self.verbose_error(res)
@@ -1116,6 +1170,7 @@ class NeonCli:
         tenant_id: Optional[uuid.UUID] = None,
         lsn: Optional[str] = None,
         port: Optional[int] = None,
+        replica: bool = False,
     ) -> 'subprocess.CompletedProcess[str]':
         args = [
             'pg',
@@ -1129,6 +1184,8 @@ class NeonCli:
             args.extend(['--lsn', lsn])
         if port is not None:
             args.extend(['--port', str(port)])
+        if replica:
+            args.append('--replica')
         if node_name is not None:
             args.append(node_name)
 
@@ -1541,9 +1598,11 @@ class Postgres(PgProtocol):
         node_name: Optional[str] = None,
         lsn: Optional[str] = None,
         config_lines: Optional[List[str]] = None,
+        replica: bool = False,
     ) -> 'Postgres':
         """
         Create the pg data directory.
+        With replica=True, the node is a read-only hot standby following the end of the timeline.
         Returns self.
         """
 
@@ -1555,7 +1614,8 @@ class Postgres(PgProtocol):
                                     node_name=self.node_name,
                                     tenant_id=self.tenant_id,
                                     lsn=lsn,
-                                    port=self.port)
+                                    port=self.port,
+                                    replica=replica)
         path = pathlib.Path('pgdatadirs') / 'tenants' / self.tenant_id.hex / self.node_name
         self.pgdata_dir = os.path.join(self.env.repo_dir, path)
 
@@ -1673,6 +1733,7 @@ class Postgres(PgProtocol):
         node_name: Optional[str] = None,
         lsn: Optional[str] = None,
         config_lines: Optional[List[str]] = None,
+        replica: bool = False,
     ) -> 'Postgres':
         """
         Create a Postgres instance, apply config
@@ -1685,6 +1746,7 @@ class Postgres(PgProtocol):
             node_name=node_name,
             config_lines=config_lines,
             lsn=lsn,
+            replica=replica,
         ).start()
 
         return self
@@ -1708,7 +1770,8 @@ class PostgresFactory:
                      node_name: Optional[str] = None,
                      tenant_id: Optional[uuid.UUID] = None,
                      lsn: Optional[str] = None,
-                     config_lines: Optional[List[str]] = None) -> Postgres:
+                     config_lines: Optional[List[str]] = None,
+                     replica: bool = False) -> Postgres:
 
         pg = Postgres(
             self.env,
@@ -1723,6 +1786,7 @@ class PostgresFactory:
             node_name=node_name,
             config_lines=config_lines,
             lsn=lsn,
+            replica=replica,
         )
 
     def create(self,
@@ -1730,7 +1794,8 @@ class PostgresFactory:
                node_name: Optional[str] = None,
                tenant_id: Optional[uuid.UUID] = None,
                lsn: Optional[str] = None,
-               config_lines: Optional[List[str]] = None) -> Postgres:
+               config_lines: Optional[List[str]] = None,
+               replica: bool = False) -> Postgres:
 
         pg = Postgres(
             self.env,
@@ -1746,6 +1811,7 @@ class PostgresFactory:
             node_name=node_name,
             lsn=lsn,
             config_lines=config_lines,
+            replica=replica,
         )
 
     def stop_all(self) -> 'PostgresFactory':
@@ -1827,6 +1893,24 @@ class Safekeeper:
                 assert isinstance(res, dict)
                 return res
 
+    def replication_command(self, tenant_id: str, timeline_id: str,
+                            cmd: str) -> List[Tuple[Any, ...]]:
+        """
+        Run replication command like CREATE_REPLICATION_SLOT on the timeline,
+        return rows of the result, if any.
+        """
+        connstr = f"host=localhost port={self.port.pg} replication=0 options='-c ztimelineid={timeline_id} ztenantid={tenant_id}'"
+
+        with closing(psycopg2.connect(connstr)) as conn:
+            # server doesn't support transactions
+            conn.autocommit = True
+            with conn.cursor() as cur:
+                log.info(f"replication command on port {self.port.pg}: {cmd}")
+                cur.execute(cmd)
+                if cur.description is None:
+                    return []
+                return cur.fetchall()
+
     def http_client(self, auth_token: Optional[str] = None) -> SafekeeperHttpClient:
         return SafekeeperHttpClient(port=self.port.http, auth_token=auth_token)
 
@@ -1841,6 +1925,7 @@ class SafekeeperTimelineStatus:
     timeline_start_lsn: str
     backup_lsn: str
     remote_consistent_lsn: str
+    commit_lsn: str
 
 
 @dataclass
@@ -1873,7 +1958,106 @@ class SafekeeperHttpClient(requests.Session):
                                         flush_lsn=resj['flush_lsn'],
                                         timeline_start_lsn=resj['timeline_start_lsn'],
                                         backup_lsn=resj['backup_lsn'],
-                                        remote_consistent_lsn=resj['remote_consistent_lsn'])
+                                        remote_consistent_lsn=resj['remote_consistent_lsn'],
+                                        commit_lsn=resj['commit_lsn'])
+
+    def timeline_membership(self, tenant_id: str, timeline_id: str) -> Dict[str, Any]:
+        res = self.get(
+            f"http://localhost:{self.port}/v1/timeline/{tenant_id}/{timeline_id}/membership")
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_membership_change(self,
+                                   tenant_id: str,
+                                   timeline_id: str,
+                                   configuration: Dict[str, Any],
+                                   lsn: int) -> Dict[str, Any]:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/timeline/{tenant_id}/{timeline_id}/membership",
+            json={
+                'configuration': configuration, 'lsn': lsn
+            })
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_retention(self, tenant_id: str, timeline_id: str) -> Dict[str, Any]:
+        res = self.get(
+            f"http://localhost:{self.port}/v1/timeline/{tenant_id}/{timeline_id}/retention")
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_retention_change(self,
+                                  tenant_id: str,
+                                  timeline_id: str,
+                                  min_bytes: int = 0,
+                                  min_age_secs: int = 0) -> Dict[str, Any]:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/timeline/{tenant_id}/{timeline_id}/retention",
+            json={
+                'min_bytes': min_bytes, 'min_age_secs': min_age_secs
+            })
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_restore(self, tenant_id: str, timeline_id: str) -> SafekeeperTimelineStatus:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/timeline/{tenant_id}/{timeline_id}/restore")
+        res.raise_for_status()
+        resj = res.json()
+        return SafekeeperTimelineStatus(acceptor_epoch=resj['acceptor_state']['epoch'],
+                                        flush_lsn=resj['flush_lsn'],
+                                        timeline_start_lsn=resj['timeline_start_lsn'],
+                                        backup_lsn=resj['backup_lsn'],
+                                        remote_consistent_lsn=resj['remote_consistent_lsn'],
+                                        commit_lsn=resj['commit_lsn'])
+
+    def timeline_pull(self, tenant_id: str, timeline_id: str,
+                      source_http_addr: str) -> SafekeeperTimelineStatus:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/pull",
+            json={'source_http_addr': source_http_addr})
+        res.raise_for_status()
+        resj = res.json()
+        return SafekeeperTimelineStatus(acceptor_epoch=resj['acceptor_state']['epoch'],
+                                        flush_lsn=resj['flush_lsn'],
+                                        timeline_start_lsn=resj['timeline_start_lsn'],
+                                        backup_lsn=resj['backup_lsn'],
+                                        remote_consistent_lsn=resj['remote_consistent_lsn'],
+                                        commit_lsn=resj['commit_lsn'])
+
+    def timeline_scrub_status(self, tenant_id: str, timeline_id: str) -> Dict[str, Any]:
+        res = self.get(f"http://localhost:{self.port}/v1/timeline/{tenant_id}/{timeline_id}/scrub")
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_scrub(self, tenant_id: str, timeline_id: str) -> Dict[str, Any]:
+        res = self.post(f"http://localhost:{self.port}/v1/timeline/{tenant_id}/{timeline_id}/scrub")
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_scrub_repair(self,
+                              tenant_id: str,
+                              timeline_id: str,
+                              source_http_addr: Optional[str] = None) -> Dict[str, Any]:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/timeline/{tenant_id}/{timeline_id}/scrub/repair",
+            json={'source_http_addr': source_http_addr})
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
 
     def record_safekeeper_info(self, tenant_id: str, timeline_id: str, body):
         res = self.post(
@@ -1896,6 +2080,28 @@ class SafekeeperHttpClient(requests.Session):
         assert isinstance(res_json, dict)
         return res_json
 
+    def tenant_wal_quota(self, tenant_id: str) -> Dict[str, Any]:
+        res = self.get(f"http://localhost:{self.port}/v1/tenant/{tenant_id}/wal_quota")
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def tenant_wal_quota_set(self, tenant_id: str, max_wal_bytes: Optional[int]) -> Dict[str, Any]:
+        res = self.post(f"http://localhost:{self.port}/v1/tenant/{tenant_id}/wal_quota",
+                        json={'max_wal_bytes': max_wal_bytes})
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def tenant_wal_quota_reset(self, tenant_id: str) -> Dict[str, Any]:
+        res = self.delete(f"http://localhost:{self.port}/v1/tenant/{tenant_id}/wal_quota")
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
     def get_metrics_str(self) -> str:
         request_result = self.get(f"http://localhost:{self.port}/metrics")
         request_result.raise_for_status()